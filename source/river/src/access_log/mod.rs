@@ -0,0 +1,423 @@
+//! Access Logging
+//!
+//! Services with an `access-log` section emit one record for each completed request,
+//! from the `logging` phase of request handling. Records are formatted as JSON, in the
+//! Apache "combined" format, or with a user provided [Template], and written to a
+//! [Sink].
+
+use std::{
+    fmt::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use pingora_proxy::Session;
+
+use crate::config::internal::{AccessLogConfig, AccessLogFormat};
+
+pub use self::sink::{AccessLogReopener, Sink};
+
+mod sink;
+
+/// The access log of a single service
+pub struct AccessLog {
+    service: String,
+    format: AccessLogFormat,
+    sink: Sink,
+}
+
+impl AccessLog {
+    /// Create the access log for the named service, opening its output
+    pub fn from_conf(service: &str, conf: AccessLogConfig) -> std::io::Result<Self> {
+        Ok(Self {
+            service: service.to_string(),
+            format: conf.format,
+            sink: Sink::open(&conf.output)?,
+        })
+    }
+
+    /// Write a record for a completed request
+    pub fn log(&self, session: &Session, duration: Duration, upstream: Option<&str>) {
+        let record = Record::from_session(&self.service, session, duration, upstream);
+        let mut line = String::new();
+        match &self.format {
+            AccessLogFormat::Json => record.write_json(&mut line),
+            AccessLogFormat::Combined => record.write_combined(&mut line),
+            AccessLogFormat::Template(t) => t.render(&record, &mut line),
+        }
+        self.sink.write_line(&line);
+    }
+}
+
+/// The fields available for a single request
+#[derive(Debug)]
+struct Record<'a> {
+    service: &'a str,
+    time: SystemTime,
+    remote_addr: String,
+    method: &'a str,
+    uri: String,
+    protocol: String,
+    status: Option<u16>,
+    bytes_received: usize,
+    bytes_sent: usize,
+    duration: Duration,
+    referer: &'a str,
+    user_agent: &'a str,
+    upstream: &'a str,
+}
+
+impl<'a> Record<'a> {
+    fn from_session(
+        service: &'a str,
+        session: &'a Session,
+        duration: Duration,
+        upstream: Option<&'a str>,
+    ) -> Self {
+        let req = session.req_header();
+        let header = |name: http::header::HeaderName| {
+            req.headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("-")
+        };
+        Self {
+            service,
+            time: SystemTime::now(),
+            remote_addr: session
+                .client_addr()
+                .and_then(|a| a.as_inet())
+                .map(|a| a.ip().to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            method: req.method.as_str(),
+            uri: req.uri.to_string(),
+            protocol: format!("{:?}", req.version),
+            status: session.response_written().map(|r| r.status.as_u16()),
+            bytes_received: session.as_downstream().body_bytes_read(),
+            bytes_sent: session.as_downstream().body_bytes_sent(),
+            duration,
+            referer: header(http::header::REFERER),
+            user_agent: header(http::header::USER_AGENT),
+            upstream: upstream.unwrap_or("-"),
+        }
+    }
+
+    /// Apache "combined" log format, e.g.:
+    ///
+    /// ```text
+    /// 127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326 "-" "curl/8.0"
+    /// ```
+    ///
+    /// Values sent by the client are [Escaped], so that they can't end a quoted
+    /// field or forge a record.
+    fn write_combined(&self, out: &mut String) {
+        let _ = write!(
+            out,
+            "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+            self.remote_addr,
+            clf_time(self.time),
+            Escaped(self.method),
+            Escaped(&self.uri),
+            self.protocol,
+            self.status_str(),
+            self.bytes_sent,
+            Escaped(self.referer),
+            Escaped(self.user_agent),
+        );
+    }
+
+    fn write_json(&self, out: &mut String) {
+        let value = serde_json::json!({
+            "time": rfc3339_time(self.time),
+            "service": self.service,
+            "remote_addr": self.remote_addr,
+            "method": self.method,
+            "uri": self.uri,
+            "protocol": self.protocol,
+            "status": self.status,
+            "bytes_received": self.bytes_received,
+            "bytes_sent": self.bytes_sent,
+            "duration_ms": self.duration.as_secs_f64() * 1000.0,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "upstream": self.upstream,
+        });
+        out.push_str(&value.to_string());
+    }
+
+    fn status_str(&self) -> String {
+        self.status
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    /// Write a template variable, text values are [Escaped] as in the "combined"
+    /// format
+    fn write_var(&self, var: Var, out: &mut String) {
+        let _ = match var {
+            Var::Time => write!(out, "{}", rfc3339_time(self.time)),
+            Var::Service => write!(out, "{}", Escaped(self.service)),
+            Var::RemoteAddr => write!(out, "{}", self.remote_addr),
+            Var::Method => write!(out, "{}", Escaped(self.method)),
+            Var::Uri => write!(out, "{}", Escaped(&self.uri)),
+            Var::Protocol => write!(out, "{}", self.protocol),
+            Var::Status => write!(out, "{}", self.status_str()),
+            Var::BytesReceived => write!(out, "{}", self.bytes_received),
+            Var::BytesSent => write!(out, "{}", self.bytes_sent),
+            Var::DurationMs => write!(out, "{:.3}", self.duration.as_secs_f64() * 1000.0),
+            Var::Referer => write!(out, "{}", Escaped(self.referer)),
+            Var::UserAgent => write!(out, "{}", Escaped(self.user_agent)),
+            Var::Upstream => write!(out, "{}", Escaped(self.upstream)),
+        };
+    }
+}
+
+/// A value written as Apache escapes it in access logs: `"` and `\` are preceded by
+/// a backslash, and other non-printable bytes are written as `\xhh`
+struct Escaped<'a>(&'a str);
+
+impl std::fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in self.0.bytes() {
+            match b {
+                b'"' | b'\\' => write!(f, "\\{}", b as char)?,
+                b' '..=b'~' => f.write_char(b as char)?,
+                _ => write!(f, "\\x{b:02x}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A custom access log format, like `"$remote_addr $method $uri $status"`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Var(Var),
+}
+
+/// Variables available in a [Template]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Var {
+    Time,
+    Service,
+    RemoteAddr,
+    Method,
+    Uri,
+    Protocol,
+    Status,
+    BytesReceived,
+    BytesSent,
+    DurationMs,
+    Referer,
+    UserAgent,
+    Upstream,
+}
+
+impl Var {
+    const ALL: &'static [(&'static str, Var)] = &[
+        ("time", Var::Time),
+        ("service", Var::Service),
+        ("remote_addr", Var::RemoteAddr),
+        ("method", Var::Method),
+        ("uri", Var::Uri),
+        ("protocol", Var::Protocol),
+        ("status", Var::Status),
+        ("bytes_received", Var::BytesReceived),
+        ("bytes_sent", Var::BytesSent),
+        ("duration_ms", Var::DurationMs),
+        ("referer", Var::Referer),
+        ("user_agent", Var::UserAgent),
+        ("upstream", Var::Upstream),
+    ];
+}
+
+impl Template {
+    /// Parse a template, where `$name` is replaced by the named variable, and `$$`
+    /// is a literal `$`
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                literal.push(c);
+                continue;
+            }
+            if chars.peek() == Some(&'$') {
+                chars.next();
+                literal.push('$');
+                continue;
+            }
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_lowercase() || *c == '_') {
+                name.push(c);
+            }
+            let Some((_, var)) = Var::ALL.iter().find(|(n, _)| *n == name) else {
+                let known = Var::ALL
+                    .iter()
+                    .map(|(n, _)| format!("${n}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                return Err(format!(
+                    "Unknown variable '${name}', expected one of: {known}"
+                ));
+            };
+            if !literal.is_empty() {
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(Part::Var(*var));
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self { parts })
+    }
+
+    fn render(&self, record: &Record<'_>, out: &mut String) {
+        for part in &self.parts {
+            match part {
+                Part::Literal(l) => out.push_str(l),
+                Part::Var(v) => record.write_var(*v, out),
+            }
+        }
+    }
+}
+
+/// Writes the template in the format accepted by [Template::parse]
+impl std::fmt::Display for Template {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for part in &self.parts {
+            match part {
+                Part::Literal(l) => f.write_str(&l.replace('$', "$$"))?,
+                Part::Var(v) => {
+                    let (name, _) = Var::ALL.iter().find(|(_, var)| var == v).unwrap();
+                    write!(f, "${name}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split a timestamp into UTC calendar fields: (year, month, day, hour, min, sec)
+fn utc_fields(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        (rem / 3600) as u32,
+        (rem % 3600 / 60) as u32,
+        (rem % 60) as u32,
+    )
+}
+
+/// Format a timestamp as used by the Common Log Format, e.g. `10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, h, m, s) = utc_fields(time);
+    format!(
+        "{day:02}/{}/{year}:{h:02}:{m:02}:{s:02} +0000",
+        MONTHS[month as usize - 1]
+    )
+}
+
+/// Format a timestamp as RFC 3339, e.g. `2000-10-10T13:55:36Z`
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, h, m, s) = utc_fields(time);
+    format!("{year:04}-{month:02}-{day:02}T{h:02}:{m:02}:{s:02}Z")
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{clf_time, rfc3339_time, Part, Record, Template, Var};
+
+    #[test]
+    fn timestamps() {
+        let time = UNIX_EPOCH + Duration::from_secs(971186136);
+        assert_eq!(clf_time(time), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(rfc3339_time(time), "2000-10-10T13:55:36Z");
+
+        let leap = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(rfc3339_time(leap), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn template() {
+        let t = Template::parse("$remote_addr \"$method\" $$$status").unwrap();
+        assert_eq!(
+            t.parts,
+            vec![
+                Part::Var(Var::RemoteAddr),
+                Part::Literal(" \"".into()),
+                Part::Var(Var::Method),
+                Part::Literal("\" $".into()),
+                Part::Var(Var::Status),
+            ]
+        );
+
+        assert_eq!(t.to_string(), "$remote_addr \"$method\" $$$status");
+
+        assert!(Template::parse("$nope").is_err());
+        assert!(Template::parse("trailing $").is_err());
+    }
+
+    #[test]
+    fn escaping() {
+        let record = Record {
+            service: "Example",
+            time: UNIX_EPOCH + Duration::from_secs(971186136),
+            remote_addr: "127.0.0.1".into(),
+            method: "GET",
+            uri: "/\"quoted\"".into(),
+            protocol: "HTTP/1.1".into(),
+            status: Some(200),
+            bytes_received: 0,
+            bytes_sent: 2326,
+            duration: Duration::from_millis(5),
+            referer: "\" 200 0 \"forged",
+            user_agent: "back\\slash\ttab",
+            upstream: "-",
+        };
+
+        let mut line = String::new();
+        record.write_combined(&mut line);
+        assert_eq!(
+            line,
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /\"quoted\" HTTP/1.1" 200 2326 "\" 200 0 \"forged" "back\\slash\x09tab""#
+        );
+
+        let mut line = String::new();
+        let t = Template::parse("$uri \"$referer\" \"$user_agent\"").unwrap();
+        t.render(&record, &mut line);
+        assert_eq!(
+            line,
+            r#"/\"quoted\" "\" 200 0 \"forged" "back\\slash\x09tab""#
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Access log outputs
+//!
+//! Files are reopened when River receives `SIGUSR1`, so that tools like `logrotate`
+//! can move the current file out of the way, and have River start writing to a new
+//! file at the configured path.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex, Weak},
+};
+
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::internal::AccessLogOutput;
+
+/// All open access log files, so they can be reopened on `SIGUSR1`
+static OPEN_FILES: LazyLock<Mutex<Vec<Weak<FileSink>>>> = LazyLock::new(Default::default);
+
+/// Syslog priority of access log records: facility "local0", severity "info"
+const SYSLOG_PRIORITY: u8 = 16 * 8 + 6;
+
+/// Where access log records are written to
+pub enum Sink {
+    Stdout,
+    File(Arc<FileSink>),
+    UnixDatagram { socket: UnixDatagram, path: PathBuf },
+}
+
+impl Sink {
+    /// Open the configured output
+    pub fn open(output: &AccessLogOutput) -> std::io::Result<Self> {
+        match output {
+            AccessLogOutput::Stdout => Ok(Sink::Stdout),
+            AccessLogOutput::File(path) => {
+                let sink = Arc::new(FileSink {
+                    file: Mutex::new(open_append(path)?),
+                    path: path.clone(),
+                });
+                OPEN_FILES.lock().unwrap().push(Arc::downgrade(&sink));
+                Ok(Sink::File(sink))
+            }
+            AccessLogOutput::UnixDatagram(path) => {
+                let socket = UnixDatagram::unbound()?;
+                // Drop records rather than blocking requests if the receiver is slow
+                socket.set_nonblocking(true)?;
+                Ok(Sink::UnixDatagram {
+                    socket,
+                    path: path.clone(),
+                })
+            }
+        }
+    }
+
+    /// Write a single record, without a trailing newline
+    ///
+    /// Failures are logged, but otherwise ignored: they should not fail the request.
+    pub fn write_line(&self, line: &str) {
+        let res = match self {
+            Sink::Stdout => {
+                let mut out = std::io::stdout().lock();
+                writeln!(out, "{line}")
+            }
+            Sink::File(sink) => {
+                let mut file = sink.file.lock().unwrap();
+                writeln!(file, "{line}")
+            }
+            Sink::UnixDatagram { socket, path } => {
+                let msg = format!("<{SYSLOG_PRIORITY}>river: {line}");
+                socket.send_to(msg.as_bytes(), path).map(drop)
+            }
+        };
+        if let Err(e) = res {
+            tracing::warn!("Failed to write access log record: {e}");
+        }
+    }
+}
+
+/// An access log file
+pub struct FileSink {
+    file: Mutex<File>,
+    path: PathBuf,
+}
+
+impl FileSink {
+    /// Reopen the file at the configured path, creating it if necessary
+    fn reopen(&self) {
+        match open_append(&self.path) {
+            Ok(new) => {
+                *self.file.lock().unwrap() = new;
+                tracing::info!(path = ?self.path, "Reopened access log");
+            }
+            Err(e) => {
+                // Keep writing to the previous file
+                tracing::error!(path = ?self.path, "Failed to reopen access log: {e}");
+            }
+        }
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Background service that reopens all access log files on `SIGUSR1`
+pub struct AccessLogReopener;
+
+#[async_trait]
+impl BackgroundService for AccessLogReopener {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut usr1 = match signal(SignalKind::user_defined1()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGUSR1, access logs won't be reopened: {e}");
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = usr1.recv() => {
+                    let files = OPEN_FILES
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter_map(Weak::upgrade)
+                        .collect::<Vec<_>>();
+                    for file in files {
+                        file.reopen();
+                    }
+                }
+            }
+        }
+    }
+}
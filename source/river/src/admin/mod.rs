@@ -0,0 +1,467 @@
+//! Admin API
+//!
+//! When `system.admin` is configured, River serves a small HTTP API for looking at
+//! a running instance. It only listens on loopback addresses or unix sockets, and
+//! may additionally require a bearer token.
+//!
+//! * `GET /config` - The rendered configuration, as of the last reload
+//! * `GET /services` - All services, and their listeners
+//! * `GET /upstreams` - The health and selection counts of all backends
+//! * `GET /ratelimits` - The state of all rate limiting rules
+//! * `GET /healthz` - Always succeeds while River is running
+//! * `GET /readyz` - Succeeds if every pool of backends has a ready backend
+//! * `GET /logging` - The current application log filter
+//! * `PUT /logging` - Replace the application log filter with the request body
+//! * `GET /services/{name}/backends/{addr}` - The status of one backend
+//! * `POST /services/{name}/backends/{addr}/{drain|enable|disable}` - Change whether
+//!   a backend may be selected, see [BackendAction]
+//! * `POST /reload` - Reload the configuration, see [crate::reload]
+
+use std::{collections::BTreeMap, fs::Permissions, os::unix::fs::PermissionsExt};
+
+use async_trait::async_trait;
+use http::{header, HeaderValue, Method, Response, StatusCode};
+use pingora_core::{
+    apps::http_app::{HttpServer, ServeHttp},
+    protocols::http::ServerSession,
+    services::listening::Service,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    config::internal::{AdminConfig, AdminListener, Config, ListenerConfig, ListenerKind},
+    logging,
+    reload::{self, Applied},
+};
+
+use self::registry::{BackendAction, UpstreamStatus};
+
+pub mod registry;
+
+/// All endpoints, used to tell unknown paths from unsupported methods
+const ENDPOINTS: &[&str] = &[
+    "/config",
+    "/services",
+    "/upstreams",
+    "/ratelimits",
+    "/logging",
+    "/healthz",
+    "/readyz",
+    "/reload",
+];
+
+/// Create the admin API service
+pub fn river_admin_service(
+    conf: AdminConfig,
+    config: Config,
+) -> Box<dyn pingora::services::Service> {
+    let app = AdminApp {
+        token: conf.token.clone(),
+        config,
+    };
+    let mut service = Service::new("River Admin API".to_string(), HttpServer::new_app(app));
+    match &conf.listen {
+        AdminListener::Tcp(addr) => service.add_tcp(&addr.to_string()),
+        AdminListener::Uds(path) => {
+            let path = path.to_str().expect("admin socket path should be UTF-8");
+            service.add_uds(path, Some(Permissions::from_mode(0o600)));
+        }
+    }
+    Box::new(service)
+}
+
+struct AdminApp {
+    token: Option<String>,
+    /// The configuration River was started with, which the listeners of the running
+    /// services come from. `GET /config` uses [reload::applied] instead.
+    config: Config,
+}
+
+#[async_trait]
+impl ServeHttp for AdminApp {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let req = session.req_header();
+        if !authorized(
+            self.token.as_deref(),
+            req.headers.get(header::AUTHORIZATION),
+        ) {
+            tracing::debug!(path = req.uri.path(), "Unauthorized admin request");
+            let mut resp = text(StatusCode::UNAUTHORIZED, "unauthorized");
+            resp.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return resp;
+        }
+        let method = req.method.clone();
+        let path = req.uri.path().to_string();
+
+        match (method, path.as_str()) {
+            (Method::GET, "/config") => match reload::applied() {
+                Some(applied) => text(StatusCode::OK, &show_config(&applied)),
+                None => text(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "configuration is not loaded",
+                ),
+            },
+            (Method::GET, "/services") => json_response(StatusCode::OK, self.services()),
+            (Method::GET, "/upstreams") => json_response(StatusCode::OK, upstreams()),
+            (Method::GET, "/ratelimits") => json_response(StatusCode::OK, rate_limits()),
+            (Method::GET, "/logging") => match logging::current_filter() {
+                Some(filter) => text(StatusCode::OK, &filter),
+                None => text(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "logging is not initialized",
+                ),
+            },
+            (Method::PUT, "/logging") => set_log_filter(session).await,
+            (Method::GET, "/healthz") => text(StatusCode::OK, "ok"),
+            (Method::GET, "/readyz") => {
+                let pending = not_ready(&upstream_statuses());
+                if pending.is_empty() {
+                    text(StatusCode::OK, "ready")
+                } else {
+                    json_response(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        json!({ "not_ready": pending }),
+                    )
+                }
+            }
+            (Method::POST, "/reload") => match reload::reload() {
+                Ok(report) => json_response(
+                    StatusCode::OK,
+                    json!({
+                        "applied": report.applied,
+                        "requires_upgrade": report.requires_upgrade,
+                    }),
+                ),
+                Err(e) => text(StatusCode::UNPROCESSABLE_ENTITY, &e),
+            },
+            (method, path) if path.starts_with("/services/") => backend_request(&method, path),
+            (_, path) if ENDPOINTS.contains(&path) => {
+                text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            _ => text(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+/// The configuration in the debug format, without the values of secret files
+fn show_config(applied: &Applied) -> String {
+    applied.secrets.redact(&format!("{:#?}", applied.config))
+}
+
+/// Replace the log filter with the request body, like `info,pingora_core=warn`
+async fn set_log_filter(session: &mut ServerSession) -> Response<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        match session.read_request_body().await {
+            Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(e) => {
+                return text(
+                    StatusCode::BAD_REQUEST,
+                    &format!("failed to read body: {e}"),
+                )
+            }
+        }
+    }
+    let Ok(filter) = std::str::from_utf8(&body) else {
+        return text(StatusCode::BAD_REQUEST, "filter should be UTF-8");
+    };
+    match logging::set_filter(filter.trim()) {
+        Ok(()) => text(StatusCode::OK, filter.trim()),
+        Err(e) => text(StatusCode::BAD_REQUEST, &e),
+    }
+}
+
+impl AdminApp {
+    /// All configured services, with their kind and listeners
+    fn services(&self) -> Value {
+        let proxies = self
+            .config
+            .basic_proxies
+            .iter()
+            .map(|p| service_json(&p.name, "http-proxy", &p.listeners));
+        let file_servers = self
+            .config
+            .file_servers
+            .iter()
+            .map(|f| service_json(&f.name, "file-server", &f.listeners));
+        let stream_proxies = self
+            .config
+            .stream_proxies
+            .iter()
+            .map(|s| service_json(&s.name, "stream-proxy", &s.listeners));
+        Value::Array(proxies.chain(file_servers).chain(stream_proxies).collect())
+    }
+}
+
+fn service_json(name: &str, kind: &str, listeners: &[ListenerConfig]) -> Value {
+    let listeners = listeners
+        .iter()
+        .map(|l| match &l.source {
+            ListenerKind::Tcp { addr, tls, .. } => json!({ "addr": addr, "tls": tls.is_some() }),
+            ListenerKind::Uds(path) => json!({ "path": path.display().to_string() }),
+            ListenerKind::Systemd { name, tls, .. } => {
+                json!({ "systemd": name, "tls": tls.is_some() })
+            }
+        })
+        .collect::<Vec<_>>();
+    json!({ "name": name, "kind": kind, "listeners": listeners })
+}
+
+fn upstream_statuses() -> BTreeMap<String, Vec<UpstreamStatus>> {
+    registry::services()
+        .into_iter()
+        .map(|(name, state)| (name, state.upstreams()))
+        .collect()
+}
+
+fn upstreams() -> Value {
+    let services = upstream_statuses()
+        .into_iter()
+        .map(|(name, upstreams)| (name, upstreams.iter().map(upstream_json).collect()))
+        .collect();
+    Value::Object(services)
+}
+
+fn upstream_json(upstream: &UpstreamStatus) -> Value {
+    json!({
+        "pool": upstream.pool,
+        "address": upstream.address,
+        "ready": upstream.ready,
+        "state": upstream.state.as_str(),
+        "selected": upstream.selected,
+        "active": upstream.active,
+    })
+}
+
+/// `GET /services/{name}/backends/{addr}`, or
+/// `POST /services/{name}/backends/{addr}/{action}`
+///
+/// Responds with the status of the backend in each pool it is part of.
+fn backend_request(method: &Method, path: &str) -> Response<Vec<u8>> {
+    let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+    let (name, addr, action) = match segments[..] {
+        ["services", name, "backends", addr] => (name, addr, None),
+        ["services", name, "backends", addr, action] => (name, addr, Some(action)),
+        _ => return text(StatusCode::NOT_FOUND, "not found"),
+    };
+    let (Some(name), Some(addr)) = (percent_decode(name), percent_decode(addr)) else {
+        return text(StatusCode::BAD_REQUEST, "invalid path");
+    };
+    let Some(service) = registry::services().remove(&name) else {
+        return text(StatusCode::NOT_FOUND, "unknown service");
+    };
+
+    let statuses = match (method, action) {
+        (&Method::GET, None) => service
+            .upstreams()
+            .into_iter()
+            .filter(|u| u.address == addr)
+            .collect(),
+        (&Method::POST, Some(action)) => match action.parse::<BackendAction>() {
+            Ok(action) => service.set_backend_state(&addr, action),
+            Err(_) => return text(StatusCode::NOT_FOUND, "not found"),
+        },
+        _ => return text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+    };
+    if statuses.is_empty() {
+        return text(StatusCode::NOT_FOUND, "unknown backend");
+    }
+    json_response(StatusCode::OK, statuses.iter().map(upstream_json).collect())
+}
+
+/// Decode `%XX` escapes in a path segment, like `%5B::1%5D:80`
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(segment.len());
+    let mut bytes = segment.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            out.push(b);
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn rate_limits() -> Value {
+    let services = registry::services()
+        .into_iter()
+        .map(|(name, state)| {
+            let rules = state
+                .rate_limits()
+                .into_iter()
+                .map(|r| {
+                    json!({
+                        "rule": r.rule,
+                        "buckets": r.buckets,
+                        "max_buckets": r.max_buckets,
+                        "tokens": r.tokens,
+                    })
+                })
+                .collect();
+            (name, Value::Array(rules))
+        })
+        .collect();
+    Value::Object(services)
+}
+
+/// The pools of backends that have no ready backends, as `service` or `service/pool`
+///
+/// Pools without any backends, like the fallback pool of a TLS passthrough stream
+/// proxy, are not considered.
+fn not_ready(services: &BTreeMap<String, Vec<UpstreamStatus>>) -> Vec<String> {
+    let mut pools: BTreeMap<String, bool> = BTreeMap::new();
+    for (name, upstreams) in services {
+        for upstream in upstreams {
+            let pool = match &upstream.pool {
+                Some(pool) => format!("{name}/{pool}"),
+                None => name.clone(),
+            };
+            *pools.entry(pool).or_default() |= upstream.ready;
+        }
+    }
+    pools
+        .into_iter()
+        .filter_map(|(pool, ready)| (!ready).then_some(pool))
+        .collect()
+}
+
+/// Does the request have the required bearer token, if any?
+fn authorized(token: Option<&str>, auth: Option<&HeaderValue>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let Some(given) = auth
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // Compare the whole token, so the time taken doesn't reveal how much matched
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn text(status: StatusCode, body: &str) -> Response<Vec<u8>> {
+    respond(
+        status,
+        "text/plain; charset=utf-8",
+        format!("{body}\n").into_bytes(),
+    )
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Vec<u8>> {
+    respond(status, "application/json", body.to_string().into_bytes())
+}
+
+fn respond(status: StatusCode, content_type: &'static str, body: Vec<u8>) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .expect("admin responses should be valid")
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use http::HeaderValue;
+
+    use crate::testing::TempDir;
+
+    use super::{
+        authorized, not_ready, percent_decode, registry::BackendState, show_config, Applied,
+        UpstreamStatus,
+    };
+
+    #[test]
+    fn bearer_tokens() {
+        let header = |v: &'static str| HeaderValue::from_static(v);
+        assert!(authorized(None, None));
+        assert!(authorized(Some("secret"), Some(&header("Bearer secret"))));
+        assert!(!authorized(Some("secret"), None));
+        assert!(!authorized(Some("secret"), Some(&header("Bearer secreT"))));
+        assert!(!authorized(Some("secret"), Some(&header("Bearer secret2"))));
+        assert!(!authorized(Some("secret"), Some(&header("Basic secret"))));
+    }
+
+    #[test]
+    fn config_secrets() {
+        let dir = TempDir::new();
+        let path = dir.join("river.kdl");
+        std::fs::write(
+            &path,
+            r#"
+            system {
+                admin {
+                    listen "127.0.0.1:9901"
+                    token "${file:./assets/test-secret}"
+                }
+            }
+            services {
+                Example {
+                    listeners {
+                        "0.0.0.0:8080"
+                    }
+                    connectors {
+                        "127.0.0.1:8000"
+                    }
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let (config, secrets) = crate::config::kdl::load(&path).unwrap();
+
+        let shown = show_config(&Applied { config, secrets });
+        assert!(shown.contains("127.0.0.1:9901"));
+        assert!(!shown.contains("not-a-real-token"));
+    }
+
+    #[test]
+    fn readiness() {
+        let upstream = |pool: Option<&str>, ready| UpstreamStatus {
+            pool: pool.map(str::to_string),
+            address: "127.0.0.1:8000".into(),
+            ready,
+            state: BackendState::Enabled,
+            selected: 0,
+            active: 0,
+        };
+        let mut services = BTreeMap::new();
+        services.insert(
+            "web".to_string(),
+            vec![upstream(None, false), upstream(None, true)],
+        );
+        services.insert(
+            "tls".to_string(),
+            vec![
+                upstream(Some("a.example.com"), true),
+                upstream(Some("b.example.com"), false),
+            ],
+        );
+        services.insert("empty".to_string(), vec![]);
+        assert_eq!(not_ready(&services), vec!["tls/b.example.com".to_string()]);
+    }
+
+    #[test]
+    fn path_segments() {
+        assert_eq!(percent_decode("127.0.0.1:80").unwrap(), "127.0.0.1:80");
+        assert_eq!(percent_decode("%5B::1%5d%3A80").unwrap(), "[::1]:80");
+        assert!(percent_decode("%5").is_none());
+        assert!(percent_decode("%zz").is_none());
+        assert!(percent_decode("%+5").is_none());
+    }
+}
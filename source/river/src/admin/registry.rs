@@ -0,0 +1,305 @@
+//! Runtime state of services, shared with the admin API
+//!
+//! Services register themselves here when they are created, so that the admin API
+//! can look at their load balancers and rate limiters while they are running, and
+//! so that their configuration can be reloaded, see [crate::reload].
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, LazyLock, RwLock,
+    },
+};
+
+use futures_util::FutureExt;
+use pingora_core::upstreams::peer::Peer;
+use pingora_load_balancing::{
+    discovery,
+    selection::{BackendIter, BackendSelection},
+    Backend, Backends, LoadBalancer,
+};
+
+use crate::{
+    config::internal::Config, proxy::rate_limiting::RateLimitStatus, reload::ServiceReload,
+};
+
+/// All registered services, by name
+static SERVICES: LazyLock<RwLock<BTreeMap<String, Arc<dyn ServiceState>>>> =
+    LazyLock::new(Default::default);
+
+/// Register the runtime state of a service
+pub fn register(name: &str, state: Arc<dyn ServiceState>) {
+    SERVICES.write().unwrap().insert(name.to_string(), state);
+}
+
+/// All registered services, by name
+pub fn services() -> BTreeMap<String, Arc<dyn ServiceState>> {
+    SERVICES.read().unwrap().clone()
+}
+
+/// The runtime state of a service, as shown by the admin API
+pub trait ServiceState: Send + Sync {
+    /// The status of all backends, in all pools of this service
+    fn upstreams(&self) -> Vec<UpstreamStatus>;
+    /// The status of all rate limiting rules of this service
+    fn rate_limits(&self) -> Vec<RateLimitStatus>;
+    /// Change the state of the backend with this address, in all pools
+    ///
+    /// Returns the new status of each changed backend, which is empty if there is no
+    /// backend with this address.
+    fn set_backend_state(&self, addr: &str, action: BackendAction) -> Vec<UpstreamStatus>;
+    /// Prepare to apply the settings of this service from a reloaded configuration
+    ///
+    /// Nothing is changed until the returned [ServiceReload] is committed.
+    fn prepare_reload(self: Arc<Self>, config: &Config) -> Result<ServiceReload, String>;
+}
+
+/// Counters kept for each backend
+///
+/// These are stored in the extensions of each [Backend][pingora_load_balancing::Backend],
+/// next to the peer, so they are available wherever the backend was selected.
+#[derive(Debug, Default)]
+pub struct BackendStats {
+    /// The number of times this backend has been selected
+    selected: AtomicU64,
+    /// The number of requests or connections currently using this backend
+    active: AtomicUsize,
+    /// The [BackendAction] last applied to this backend
+    action: AtomicU8,
+}
+
+impl BackendStats {
+    /// This backend was selected for a request or connection
+    ///
+    /// The request is counted as active until the returned guard is dropped.
+    pub fn selected(self: &Arc<Self>) -> ActiveRequest {
+        self.selected.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveRequest(self.clone())
+    }
+
+    fn state(&self) -> BackendState {
+        match BackendAction::from_u8(self.action.load(Ordering::Relaxed)) {
+            BackendAction::Enable => BackendState::Enabled,
+            BackendAction::Disable => BackendState::Disabled,
+            BackendAction::Drain if self.active.load(Ordering::Relaxed) == 0 => {
+                BackendState::Drained
+            }
+            BackendAction::Drain => BackendState::Draining,
+        }
+    }
+}
+
+/// A request or connection using a backend, see [BackendStats::selected]
+#[derive(Debug)]
+pub struct ActiveRequest(Arc<BackendStats>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A change to the state of a backend, made with the admin API
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum BackendAction {
+    /// Allow the backend to be selected again
+    Enable = 0,
+    /// Stop selecting the backend, and report when it is no longer in use
+    Drain = 1,
+    /// Stop selecting the backend
+    Disable = 2,
+}
+
+impl BackendAction {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => BackendAction::Drain,
+            2 => BackendAction::Disable,
+            _ => BackendAction::Enable,
+        }
+    }
+}
+
+impl std::str::FromStr for BackendAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "enable" => Ok(BackendAction::Enable),
+            "drain" => Ok(BackendAction::Drain),
+            "disable" => Ok(BackendAction::Disable),
+            other => Err(format!(
+                "'{other}' should be one of 'enable', 'drain', or 'disable'"
+            )),
+        }
+    }
+}
+
+/// The state of a backend, as changed by [BackendAction]s
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendState {
+    Enabled,
+    /// Draining, but still in use
+    Draining,
+    /// Draining, and no longer in use
+    Drained,
+    Disabled,
+}
+
+impl BackendState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BackendState::Enabled => "enabled",
+            BackendState::Draining => "draining",
+            BackendState::Drained => "drained",
+            BackendState::Disabled => "disabled",
+        }
+    }
+}
+
+/// The status of a single backend
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamStatus {
+    /// The pool this backend is part of, for services with more than one pool
+    pub pool: Option<String>,
+    pub address: String,
+    /// Is this backend healthy and enabled, and so able to be selected?
+    pub ready: bool,
+    pub state: BackendState,
+    /// The number of times this backend has been selected
+    pub selected: u64,
+    /// The number of requests or connections currently using this backend
+    pub active: usize,
+}
+
+/// Create a load balancer for a fixed set of peers
+///
+/// Each peer is stored in the extensions of its backend, next to its [BackendStats].
+/// When replacing a load balancer, backends that have the same address as one in
+/// `previous` keep its stats, and stay drained or disabled.
+pub fn static_load_balancer<BS, P>(
+    peers: Vec<P>,
+    previous: Option<&LoadBalancer<BS>>,
+) -> LoadBalancer<BS>
+where
+    BS: BackendSelection + Send + Sync + 'static,
+    BS::Iter: BackendIter,
+    P: Peer + Send + Sync + 'static,
+{
+    let previous = previous
+        .map(|lb| lb.backends().get_backend())
+        .unwrap_or_default();
+
+    // TODO: This maybe could be done cleaner? This is a sort-of inlined
+    // version of `LoadBalancer::try_from_iter` with the ability to add
+    // metadata extensions
+    let mut backends = BTreeSet::new();
+    for peer in peers {
+        let mut backend = Backend::new(&peer.address().to_string()).unwrap();
+        let stats = previous
+            .iter()
+            .find(|b| b.addr == backend.addr)
+            .and_then(|b| b.ext.get::<Arc<BackendStats>>().cloned())
+            .unwrap_or_default();
+        assert!(backend.ext.insert::<P>(peer).is_none());
+        backend.ext.insert(stats);
+        backends.insert(backend);
+    }
+    let disco = discovery::Static::new(backends);
+    let lb = LoadBalancer::<BS>::from_backends(Backends::new(disco));
+    lb.update()
+        .now_or_never()
+        .expect("static should not block")
+        .expect("static should not error");
+
+    // New backends are enabled, so disable those that were drained or disabled before
+    let backends = lb.backends();
+    for backend in backends.get_backend().iter() {
+        let action = backend
+            .ext
+            .get::<Arc<BackendStats>>()
+            .map(|s| BackendAction::from_u8(s.action.load(Ordering::Relaxed)));
+        if matches!(action, Some(BackendAction::Drain | BackendAction::Disable)) {
+            backends.set_enable(backend, false);
+        }
+    }
+    lb
+}
+
+/// The status of all backends of a load balancer
+pub fn pool_status<BS>(pool: Option<&str>, lb: &LoadBalancer<BS>) -> Vec<UpstreamStatus>
+where
+    BS: BackendSelection + Send + Sync + 'static,
+    BS::Iter: BackendIter,
+{
+    let backends = lb.backends();
+    backends
+        .get_backend()
+        .iter()
+        .map(|backend| backend_status(pool, backends, backend))
+        .collect()
+}
+
+/// Change the state of the backend with this address in a load balancer, see
+/// [ServiceState::set_backend_state]
+pub fn pool_set_backend_state<BS>(
+    pool: Option<&str>,
+    lb: &LoadBalancer<BS>,
+    addr: &str,
+    action: BackendAction,
+) -> Option<UpstreamStatus>
+where
+    BS: BackendSelection + Send + Sync + 'static,
+    BS::Iter: BackendIter,
+{
+    let backends = lb.backends();
+    let all = backends.get_backend();
+    let backend = all.iter().find(|b| b.addr.to_string() == addr)?;
+
+    // Requests that already selected this backend are not affected
+    backends.set_enable(backend, action == BackendAction::Enable);
+    if let Some(stats) = backend.ext.get::<Arc<BackendStats>>() {
+        stats.action.store(action as u8, Ordering::Relaxed);
+    }
+    tracing::info!(pool, addr, ?action, "Changed backend state");
+    Some(backend_status(pool, backends, backend))
+}
+
+fn backend_status(pool: Option<&str>, backends: &Backends, backend: &Backend) -> UpstreamStatus {
+    let stats = backend.ext.get::<Arc<BackendStats>>();
+    UpstreamStatus {
+        pool: pool.map(str::to_string),
+        address: backend.addr.to_string(),
+        ready: backends.ready(backend),
+        state: stats.map_or(BackendState::Enabled, |s| s.state()),
+        selected: stats.map_or(0, |s| s.selected.load(Ordering::Relaxed)),
+        active: stats.map_or(0, |s| s.active.load(Ordering::Relaxed)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{BackendAction, BackendState, BackendStats};
+
+    #[test]
+    fn drain() {
+        let stats = Arc::new(BackendStats::default());
+        assert_eq!(stats.state(), BackendState::Enabled);
+
+        let request = stats.selected();
+        stats.action.store(
+            BackendAction::Drain as u8,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+        assert_eq!(stats.state(), BackendState::Draining);
+
+        // The backend is drained once the last request has finished
+        drop(request);
+        assert_eq!(stats.state(), BackendState::Drained);
+    }
+}
@@ -1,40 +1,166 @@
 //! Configuration sourced from the CLI
+//!
+//! Every option may also be set with a `RIVER_*` environment variable, which is
+//! used when the option is not given on the command line.
 
-use clap::Parser;
+use clap::{CommandFactory, Parser, ValueEnum};
 use std::path::PathBuf;
 
 /// River: A reverse proxy from Prossimo
 #[derive(Parser, Debug)]
 pub struct Cli {
-    /// Validate all configuration data and exit
-    #[arg(long)]
+    /// Validate all configuration data and exit, reporting all errors that were found
+    #[arg(long, env = "RIVER_VALIDATE_CONFIGS")]
     pub validate_configs: bool,
 
+    /// Format of the report of --validate-configs, ignored otherwise
+    #[arg(long, env = "RIVER_FORMAT", value_name = "FORMAT")]
+    pub format: Option<ReportFormat>,
+
     /// Path to the configuration file in TOML format
-    #[arg(long)]
+    #[arg(long, env = "RIVER_CONFIG_TOML")]
     pub config_toml: Option<PathBuf>,
 
     /// Path to the configuration file in KDL format
-    #[arg(long)]
+    #[arg(long, env = "RIVER_CONFIG_KDL")]
     pub config_kdl: Option<PathBuf>,
 
     /// Number of threads used in the worker pool for EACH service
-    #[arg(long)]
+    #[arg(long, env = "RIVER_THREADS_PER_SERVICE")]
     pub threads_per_service: Option<usize>,
 
+    /// Name of this instance, used for the default paths of the pidfile and upgrade
+    /// socket. Instances running on the same host need different names
+    #[arg(long, env = "RIVER_INSTANCE_NAME")]
+    pub instance_name: Option<String>,
+
     /// Should the server be daemonized after starting?
-    #[arg(long)]
+    #[arg(long, env = "RIVER_DAEMONIZE")]
     pub daemonize: bool,
 
+    /// The user to run as after binding listeners
+    #[arg(long, env = "RIVER_USER")]
+    pub user: Option<String>,
+
+    /// The group to run as after binding listeners
+    #[arg(long, env = "RIVER_GROUP")]
+    pub group: Option<String>,
+
     /// Should the server take over an existing server?
-    #[arg(long)]
+    #[arg(long, env = "RIVER_UPGRADE")]
     pub upgrade: bool,
 
     /// Path to upgrade socket
-    #[arg(long)]
+    #[arg(long, env = "RIVER_UPGRADE_SOCKET")]
     pub upgrade_socket: Option<PathBuf>,
 
+    /// Seconds to keep serving in-flight requests and connections during a graceful
+    /// upgrade or shutdown, before services are told to shut down
+    #[arg(long, env = "RIVER_GRACE_PERIOD_SECS")]
+    pub grace_period_secs: Option<u64>,
+
+    /// Seconds services have to stop after being told to shut down, before anything
+    /// still running is terminated
+    #[arg(long, env = "RIVER_SHUTDOWN_TIMEOUT_SECS")]
+    pub shutdown_timeout_secs: Option<u64>,
+
     /// Path to the pidfile, used for upgrade
-    #[arg(long)]
+    #[arg(long, env = "RIVER_PIDFILE")]
     pub pidfile: Option<PathBuf>,
+
+    /// Application log level, one of "trace", "debug", "info", "warn", "error", or "off"
+    #[arg(long, env = "RIVER_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Application log format, one of "full", "compact", "pretty", or "json"
+    #[arg(long, env = "RIVER_LOG_FORMAT")]
+    pub log_format: Option<String>,
+
+    /// Write application logs to this file, instead of stdout
+    #[arg(long, env = "RIVER_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Per-module log filter, like "pingora_core=warn". May be given multiple times, or
+    /// as a comma separated list
+    #[arg(long, env = "RIVER_LOG_FILTER", value_delimiter = ',')]
+    pub log_filter: Vec<String>,
+
+    /// List the environment variables that may be used instead of options, and exit
+    #[arg(long)]
+    pub list_env_vars: bool,
+
+    /// Print a configuration file with every setting and its default value, and exit
+    #[arg(long, value_name = "FORMAT")]
+    pub emit_default_config: Option<ConfigFormat>,
+
+    /// Print the configuration in KDL format, after applying the configuration file,
+    /// environment variables, and command line options, and exit
+    #[arg(long)]
+    pub emit_effective_config: bool,
+}
+
+/// Formats of configuration files
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ConfigFormat {
+    Kdl,
+    Toml,
+}
+
+/// Formats of the report of `--validate-configs`
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum ReportFormat {
+    /// Readable diagnostics, printed to stderr
+    #[default]
+    Human,
+    /// A JSON object, printed to stdout
+    Json,
+}
+
+/// All environment variables that may be used instead of options, with their help text
+pub fn env_vars() -> Vec<(String, String)> {
+    Cli::command()
+        .get_arguments()
+        .filter_map(|arg| {
+            let env = arg.get_env()?.to_string_lossy().into_owned();
+            let help = arg.get_help().map(ToString::to_string).unwrap_or_default();
+            Some((env, help))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use clap::{CommandFactory, Parser};
+
+    use super::Cli;
+
+    /// Options that print something and exit, rather than configuring River
+    const ACTIONS: &[&str] = &[
+        "list-env-vars",
+        "emit-default-config",
+        "emit-effective-config",
+        "help",
+    ];
+
+    #[test]
+    fn env_var_names() {
+        let cmd = Cli::command();
+        for arg in cmd.get_arguments() {
+            let long = arg.get_long().unwrap();
+            if ACTIONS.contains(&long) {
+                assert!(arg.get_env().is_none());
+                continue;
+            }
+            let expected = format!("RIVER_{}", long.replace('-', "_").to_uppercase());
+            assert_eq!(arg.get_env().unwrap().to_str(), Some(expected.as_str()));
+        }
+    }
+
+    #[test]
+    fn format_without_validating() {
+        // `RIVER_FORMAT` may be set for every run, so it must not stop River starting
+        let cli = Cli::try_parse_from(["river", "--format", "json"]).unwrap();
+        assert!(!cli.validate_configs);
+        assert!(cli.format.is_some());
+    }
 }
@@ -6,17 +6,35 @@
 //! This is used as the buffer between any external stable UI, and internal
 //! impl details which may change at any time.
 
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fs::Metadata,
+    net::SocketAddr,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use cidr::IpCidr;
+use http::{HeaderName, HeaderValue};
+use nix::unistd::{Gid, Group, Uid, User};
 use pingora::{
+    protocols::ALPN,
     server::configuration::{Opt as PingoraOpt, ServerConf as PingoraServerConf},
-    upstreams::peer::HttpPeer,
+    upstreams::peer::{BasicPeer, HttpPeer},
 };
 use tracing::warn;
+use tracing_subscriber::filter::LevelFilter;
 
-use crate::proxy::{
-    rate_limiting::AllRateConfig,
-    request_selector::{null_selector, RequestSelector},
+use crate::{
+    access_log::Template,
+    config::runtime,
+    proxy::{
+        rate_limiting::{multi::MultiRequestKeyKind, AllRateConfig, RegexShim},
+        request_selector::{
+            null_selector, source_addr_and_uri_path_selector, uri_path_selector, RequestSelector,
+        },
+    },
 };
 
 /// River's internal configuration
@@ -24,12 +42,24 @@ use crate::proxy::{
 pub struct Config {
     pub validate_configs: bool,
     pub threads_per_service: usize,
+    /// Distinguishes instances running on the same host, see [runtime]
+    pub instance_name: String,
     pub daemonize: bool,
+    /// The user to run as after binding listeners
+    pub user: Option<String>,
+    /// The group to run as after binding listeners
+    pub group: Option<String>,
     pub pid_file: Option<PathBuf>,
     pub upgrade_socket: Option<PathBuf>,
     pub upgrade: bool,
+    pub shutdown: ShutdownConfig,
     pub basic_proxies: Vec<ProxyConfig>,
     pub file_servers: Vec<FileServerConfig>,
+    pub stream_proxies: Vec<StreamProxyConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub log: LogConfig,
+    pub tracing: Option<TracingConfig>,
+    pub admin: Option<AdminConfig>,
 }
 
 impl Config {
@@ -50,93 +80,520 @@ impl Config {
         PingoraServerConf {
             daemon: self.daemonize,
             error_log: None,
-            // TODO: These are bad assumptions - non-developers will not have "target"
-            // files, and we shouldn't necessarily use utf-8 strings with fixed separators
+            // TODO: We shouldn't necessarily use utf-8 strings with fixed separators
             // here.
-            pid_file: self
-                .pid_file
-                .as_ref()
-                .cloned()
-                .unwrap_or_else(|| PathBuf::from("/tmp/river.pidfile"))
-                .to_string_lossy()
-                .into(),
-            upgrade_sock: self
-                .upgrade_socket
-                .as_ref()
-                .cloned()
-                .unwrap_or_else(|| PathBuf::from("/tmp/river-upgrade.sock"))
-                .to_string_lossy()
-                .into(),
-            user: None,
-            group: None,
+            pid_file: self.pid_file_path().to_string_lossy().into(),
+            upgrade_sock: self.upgrade_socket_path().to_string_lossy().into(),
+            user: self.user.clone(),
+            group: self.group.clone(),
             threads: self.threads_per_service,
+            grace_period_seconds: self.shutdown.grace_period.map(|d| d.as_secs()),
+            graceful_shutdown_timeout_seconds: self.shutdown.shutdown_timeout.map(|d| d.as_secs()),
             work_stealing: true,
             ca_file: None,
             ..PingoraServerConf::default()
         }
     }
 
-    pub fn validate(&self) {
+    /// The listeners of all services
+    pub fn listeners(&self) -> impl Iterator<Item = &ListenerConfig> {
+        let proxies = self.basic_proxies.iter().flat_map(|p| p.listeners.iter());
+        let file_servers = self.file_servers.iter().flat_map(|f| f.listeners.iter());
+        let stream_proxies = self.stream_proxies.iter().flat_map(|s| s.listeners.iter());
+        proxies.chain(file_servers).chain(stream_proxies)
+    }
+
+    /// The path of the pidfile, if not configured it is in the runtime directory
+    pub fn pid_file_path(&self) -> PathBuf {
+        self.pid_file
+            .clone()
+            .unwrap_or_else(|| runtime::default_pid_file(&self.instance_name))
+    }
+
+    /// The path of the upgrade socket, if not configured it is in the runtime directory
+    pub fn upgrade_socket_path(&self) -> PathBuf {
+        self.upgrade_socket
+            .clone()
+            .unwrap_or_else(|| runtime::default_upgrade_socket(&self.instance_name))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
         // This is currently mostly ad-hoc checks, we should potentially be a bit
         // more systematic about this.
+        if !runtime::is_instance_name(&self.instance_name) {
+            return Err(format!(
+                "Invalid instance name '{}', it may only contain letters, digits, '-', '_', and '.'",
+                self.instance_name
+            ));
+        }
+        runtime::check(self)?;
         if self.daemonize {
             if let Some(pf) = self.pid_file.as_ref() {
                 // NOTE: currently due to https://github.com/cloudflare/pingora/issues/331,
                 // we are not able to use relative paths.
-                assert!(pf.is_absolute(), "pid file path must be absolute, see https://github.com/cloudflare/pingora/issues/331");
-            } else {
-                panic!("Daemonize commanded but no pid file set!");
+                if !pf.is_absolute() {
+                    return Err("pid file path must be absolute, see https://github.com/cloudflare/pingora/issues/331".into());
+                }
             }
         } else if let Some(pf) = self.pid_file.as_ref() {
             if !pf.is_absolute() {
                 warn!("pid file path must be absolute. Currently: {:?}, see https://github.com/cloudflare/pingora/issues/331", pf);
             }
         }
+        self.validate_privilege_drop()?;
         if self.upgrade {
-            assert!(
-                cfg!(target_os = "linux"),
-                "Upgrade is only supported on linux!"
-            );
+            if !cfg!(target_os = "linux") {
+                return Err("Upgrade is only supported on linux!".into());
+            }
             if let Some(us) = self.upgrade_socket.as_ref() {
                 // NOTE: currently due to https://github.com/cloudflare/pingora/issues/331,
                 // we are not able to use relative paths.
-                assert!(us.is_absolute(), "upgrade socket path must be absolute, see https://github.com/cloudflare/pingora/issues/331");
-            } else {
-                panic!("Upgrade commanded but upgrade socket path not set!");
+                if !us.is_absolute() {
+                    return Err("upgrade socket path must be absolute, see https://github.com/cloudflare/pingora/issues/331".into());
+                }
             }
         } else if let Some(us) = self.upgrade_socket.as_ref() {
             if !us.is_absolute() {
                 warn!("upgrade socket path must be absolute. Currently: {:?}, see https://github.com/cloudflare/pingora/issues/331", us);
             }
         }
+        Ok(())
+    }
+
+    /// Check that River can run as the configured `user` and `group`
+    ///
+    /// River switches to them after the configuration is loaded and listeners are
+    /// bound (see [crate::privileges]). When daemonizing, Pingora switches to them
+    /// before the pid file is written.
+    fn validate_privilege_drop(&self) -> Result<(), String> {
+        let Some((uid, gid)) = self.run_as()? else {
+            return Ok(());
+        };
+        // The pid file is only written when daemonizing
+        if !self.daemonize {
+            return Ok(());
+        }
+
+        // The pid file is replaced by each new instance during an upgrade, so its
+        // directory must stay writable after dropping privileges
+        //
+        // The default runtime directory is created for the user when starting, see
+        // [runtime::prepare]
+        if let Some(pid_file) = &self.pid_file {
+            let dir = pid_file.parent().unwrap_or(Path::new("/"));
+            let meta = std::fs::metadata(dir).map_err(|e| {
+                format!("Error reading pid file directory '{}': {e}", dir.display())
+            })?;
+            if !writable_by(&meta, uid, gid) {
+                return Err(format!(
+                    "pid file directory '{}' is not writable by the configured user and group",
+                    dir.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The user and group River runs as after dropping privileges, or `None` if
+    /// neither `user` nor `group` is configured
+    ///
+    /// If only `user` is set, the primary group of that user is used, as Pingora
+    /// does. If only `group` is set, the user stays the same.
+    pub fn run_as(&self) -> Result<Option<(Uid, Gid)>, String> {
+        if self.user.is_none() && self.group.is_none() {
+            return Ok(None);
+        }
+        let user = match &self.user {
+            Some(name) => Some(
+                User::from_name(name)
+                    .map_err(|e| format!("Error looking up user '{name}': {e}"))?
+                    .ok_or_else(|| format!("User '{name}' does not exist"))?,
+            ),
+            None => None,
+        };
+        let group = match &self.group {
+            Some(name) => Some(
+                Group::from_name(name)
+                    .map_err(|e| format!("Error looking up group '{name}': {e}"))?
+                    .ok_or_else(|| format!("Group '{name}' does not exist"))?,
+            ),
+            None => None,
+        };
+
+        let uid = user.as_ref().map(|u| u.uid).unwrap_or_else(Uid::effective);
+        let gid = match (&group, &user) {
+            (Some(g), _) => g.gid,
+            (None, Some(u)) => u.gid,
+            (None, None) => Gid::effective(),
+        };
+        Ok(Some((uid, gid)))
     }
 }
 
+/// Can a process with this user and group write to the file?
+///
+/// Supplementary groups are not checked.
+fn writable_by(meta: &Metadata, uid: Uid, gid: Gid) -> bool {
+    let mode = meta.mode();
+    if uid.is_root() {
+        true
+    } else if meta.uid() == uid.as_raw() {
+        mode & 0o200 != 0
+    } else if meta.gid() == gid.as_raw() {
+        mode & 0o020 != 0
+    } else {
+        mode & 0o002 != 0
+    }
+}
+
+/// How an instance stops during a graceful upgrade or shutdown, see the
+/// `system.upgrade` section
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShutdownConfig {
+    /// Time to keep serving in-flight requests and connections before services are
+    /// told to shut down. If not set, Pingora's default is used.
+    pub(crate) grace_period: Option<Duration>,
+    /// Time services have to stop after being told to shut down, before anything
+    /// still running is terminated. If not set, Pingora's default is used.
+    pub(crate) shutdown_timeout: Option<Duration>,
+}
+
+/// Application logging, see [crate::logging]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LogConfig {
+    /// The default level. If neither this nor `filters` are set, `RUST_LOG` is used
+    pub(crate) level: Option<LevelFilter>,
+    pub(crate) format: LogFormat,
+    pub(crate) output: LogOutput,
+    /// Additional directives, like `pingora_core=warn`
+    pub(crate) filters: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(LogFormat::Full),
+            "compact" => Ok(LogFormat::Compact),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!(
+                "'{other}' should be one of 'full', 'compact', 'pretty', or 'json'"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum LogOutput {
+    #[default]
+    Stdout,
+    Stderr,
+    File(PathBuf),
+}
+
+/// Serving of Prometheus metrics
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsConfig {
+    /// The address the metrics endpoint listens on
+    pub(crate) listen: SocketAddr,
+}
+
+/// The admin API, see [crate::admin]
+#[derive(Clone, PartialEq)]
+pub struct AdminConfig {
+    pub(crate) listen: AdminListener,
+    /// If set, requests must have an `Authorization: Bearer TOKEN` header
+    pub(crate) token: Option<String>,
+}
+
+impl std::fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The configuration is logged, and served by the admin API itself
+        f.debug_struct("AdminConfig")
+            .field("listen", &self.listen)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// Where the admin API listens. Only local connections are supported
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminListener {
+    /// A loopback address
+    Tcp(SocketAddr),
+    /// A unix domain socket, only accessible to the user running River
+    Uds(PathBuf),
+}
+
+impl std::str::FromStr for AdminListener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            if !addr.ip().is_loopback() {
+                return Err(
+                    "The admin API may only listen on a loopback address, or a unix socket".into(),
+                );
+            }
+            Ok(AdminListener::Tcp(addr))
+        } else if s.starts_with('/') {
+            Ok(AdminListener::Uds(PathBuf::from(s)))
+        } else {
+            Err("'listen' should be a socket address, like \"127.0.0.1:9901\", or an absolute path to a unix socket".into())
+        }
+    }
+}
+
+/// Export of request spans with OpenTelemetry, see [crate::telemetry]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracingConfig {
+    /// The OTLP/gRPC endpoint of the collector, like `http://127.0.0.1:4317`
+    pub(crate) otlp_endpoint: String,
+    /// The fraction of new traces that are sampled, from 0.0 to 1.0
+    ///
+    /// Requests that are part of an existing trace follow the sampling decision
+    /// of their parent.
+    pub(crate) sample_ratio: f64,
+}
+
 ///
 #[derive(Debug, Default, Clone, PartialEq)]
 pub struct RateLimitingConfig {
     pub(crate) rules: Vec<AllRateConfig>,
 }
 
+impl RateLimitingConfig {
+    /// Can all rules be applied to a connection, rather than a request? Only these
+    /// can be used by stream proxies
+    pub fn per_connection(&self) -> bool {
+        self.rules.iter().all(|r| {
+            matches!(
+                r,
+                AllRateConfig::Multi {
+                    kind: MultiRequestKeyKind::SourceIp,
+                    ..
+                }
+            )
+        })
+    }
+}
+
 /// Add Path Control Modifiers
 ///
-/// Note that we use `BTreeMap` and NOT `HashMap`, as we want to maintain the
-/// ordering from the configuration file.
+/// Filters are run in the order they are written in the configuration file.
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct PathControl {
-    pub(crate) request_filters: Vec<BTreeMap<String, String>>,
-    pub(crate) upstream_request_filters: Vec<BTreeMap<String, String>>,
-    pub(crate) upstream_response_filters: Vec<BTreeMap<String, String>>,
+    pub(crate) request_filters: Vec<RequestFilterConfig>,
+    pub(crate) upstream_request_filters: Vec<HeaderModifierConfig>,
+    pub(crate) upstream_response_filters: Vec<HeaderModifierConfig>,
+}
+
+/// A filter that may reject a request before it is proxied
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestFilterConfig {
+    /// Reject requests from addresses in any of these ranges
+    BlockCidrRange { addrs: Vec<IpCidr> },
+}
+
+impl RequestFilterConfig {
+    /// Parse a filter from its settings, like `kind="block-cidr-range" addrs="10.0.0.0/8"`
+    pub fn from_settings(mut settings: BTreeMap<String, String>) -> Result<Self, String> {
+        let kind = take_setting("kind", &mut settings)?;
+        let filter = match kind.as_str() {
+            "block-cidr-range" => {
+                let addrs = parse_cidr_ranges(&take_setting("addrs", &mut settings)?)?;
+                Self::BlockCidrRange { addrs }
+            }
+            other => {
+                return Err(format!(
+                    "Unknown request filter: '{other}', expected 'block-cidr-range'"
+                ))
+            }
+        };
+        ensure_no_settings(&settings)?;
+        Ok(filter)
+    }
+}
+
+/// A filter that may reject a connection to a stream proxy, before connecting upstream
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionFilterConfig {
+    /// Reject connections from addresses in any of these ranges
+    BlockCidrRange { addrs: Vec<IpCidr> },
+    /// Reject connections from addresses NOT in any of these ranges
+    AllowCidrRange { addrs: Vec<IpCidr> },
+}
+
+impl ConnectionFilterConfig {
+    /// Parse a filter from its settings, like `kind="allow-cidr-range" addrs="10.0.0.0/8"`
+    pub fn from_settings(mut settings: BTreeMap<String, String>) -> Result<Self, String> {
+        let kind = take_setting("kind", &mut settings)?;
+        let filter = match kind.as_str() {
+            "block-cidr-range" => {
+                let addrs = parse_cidr_ranges(&take_setting("addrs", &mut settings)?)?;
+                Self::BlockCidrRange { addrs }
+            }
+            "allow-cidr-range" => {
+                let addrs = parse_cidr_ranges(&take_setting("addrs", &mut settings)?)?;
+                Self::AllowCidrRange { addrs }
+            }
+            other => {
+                return Err(format!(
+                    "Unknown connection filter: '{other}', expected 'block-cidr-range' or 'allow-cidr-range'"
+                ))
+            }
+        };
+        ensure_no_settings(&settings)?;
+        Ok(filter)
+    }
+}
+
+/// A change to the headers of a request sent upstream, or of a response
+/// received from upstream
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderModifierConfig {
+    /// Remove all headers with keys matching the pattern
+    RemoveHeaderKeyRegex { pattern: RegexShim },
+    /// Add a header, replacing any existing headers with the same key
+    UpsertHeader { key: String, value: String },
+}
+
+impl HeaderModifierConfig {
+    /// Parse a modifier from its settings, like `kind="upsert-header" key="x-a" value="b"`
+    pub fn from_settings(mut settings: BTreeMap<String, String>) -> Result<Self, String> {
+        let kind = take_setting("kind", &mut settings)?;
+        let modifier = match kind.as_str() {
+            "remove-header-key-regex" => {
+                let pattern = take_setting("pattern", &mut settings)?;
+                let pattern = RegexShim::new(&pattern)
+                    .map_err(|e| format!("Bad pattern: '{pattern}': {e}"))?;
+                Self::RemoveHeaderKeyRegex { pattern }
+            }
+            "upsert-header" => {
+                let key = take_setting("key", &mut settings)?;
+                let value = take_setting("value", &mut settings)?;
+                if HeaderName::from_bytes(key.as_bytes()).is_err() {
+                    return Err(format!("'{key}' is not a valid header name"));
+                }
+                if HeaderValue::from_str(&value).is_err() {
+                    return Err(format!("'{value}' is not a valid header value"));
+                }
+                Self::UpsertHeader { key, value }
+            }
+            other => {
+                return Err(format!(
+                "Unknown filter: '{other}', expected 'remove-header-key-regex' or 'upsert-header'"
+            ))
+            }
+        };
+        ensure_no_settings(&settings)?;
+        Ok(modifier)
+    }
+}
+
+/// Parse a comma separated list of ranges, like `10.0.0.0/8, 2001:0db8::0/32`
+pub fn parse_cidr_ranges(addrs: &str) -> Result<Vec<IpCidr>, String> {
+    addrs
+        .split(',')
+        .map(|addr| {
+            let addr = addr.trim();
+            addr.parse::<IpCidr>()
+                .map_err(|_| format!("Failed to parse '{addr}' as a valid CIDR notation range"))
+        })
+        .collect()
+}
+
+/// Remove a required setting of a filter
+fn take_setting(key: &str, settings: &mut BTreeMap<String, String>) -> Result<String, String> {
+    settings
+        .remove(key)
+        .ok_or_else(|| format!("Missing key: '{key}'"))
+}
+
+/// Reject any settings that were not used by a filter
+fn ensure_no_settings(settings: &BTreeMap<String, String>) -> Result<(), String> {
+    if settings.is_empty() {
+        return Ok(());
+    }
+    let keys = settings.keys().map(String::as_str).collect::<Vec<_>>();
+    Err(format!("Extra keys found: '{}'", keys.join(", ")))
 }
 
 //
 // File Server Configuration
 //
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileServerConfig {
     pub(crate) name: String,
     pub(crate) listeners: Vec<ListenerConfig>,
     pub(crate) base_path: Option<PathBuf>,
+    pub(crate) access_log: Option<AccessLogConfig>,
+}
+
+//
+// Access Log Configuration
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessLogConfig {
+    pub(crate) format: AccessLogFormat,
+    pub(crate) output: AccessLogOutput,
+}
+
+/// How each access log record is formatted
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccessLogFormat {
+    /// One JSON object per line
+    Json,
+    /// The Apache "combined" log format
+    Combined,
+    /// A custom format
+    Template(Template),
+}
+
+/// Where access log records are written to
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccessLogOutput {
+    Stdout,
+    /// Appended to a file, which is reopened on SIGUSR1
+    File(PathBuf),
+    /// Sent to a Unix datagram socket, such as `/dev/log`, in syslog format
+    UnixDatagram(PathBuf),
+}
+
+//
+// Stream Proxy Configuration
+//
+#[derive(Debug, Clone)]
+pub struct StreamProxyConfig {
+    pub(crate) name: String,
+    pub(crate) listeners: Vec<ListenerConfig>,
+    pub(crate) upstream_options: UpstreamOptions,
+    pub(crate) upstreams: Vec<BasicPeer>,
+    pub(crate) connection_filters: Vec<ConnectionFilterConfig>,
+    pub(crate) rate_limiting: RateLimitingConfig,
+    /// Upstreams selected by the SNI of the TLS ClientHello, keyed by server name.
+    /// If set, TLS is not terminated, and `upstreams` are used when no name matches.
+    pub(crate) tls_passthrough: Option<BTreeMap<String, Vec<BasicPeer>>>,
+}
+
+/// Can TLS passthrough connections be routed by this name? It must be a server
+/// name, or a wildcard such as `*.example.com`
+pub fn is_server_name_pattern(name: &str) -> bool {
+    match name.strip_prefix("*.") {
+        Some(rest) => !rest.is_empty() && !rest.contains('*'),
+        None => !name.is_empty() && !name.contains('*'),
+    }
 }
 
 //
@@ -151,12 +608,97 @@ pub struct ProxyConfig {
     pub(crate) upstreams: Vec<HttpPeer>,
     pub(crate) path_control: PathControl,
     pub(crate) rate_limiting: RateLimitingConfig,
+    pub(crate) websocket: WebSocketConfig,
+    pub(crate) access_log: Option<AccessLogConfig>,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// An upstream connector of a proxy service
+///
+/// `proto` is one of `h1-only`, `h2-only`, `h2-or-h1`, or `h2c`. TLS is used when
+/// `tls_sni` is set, and is required for HTTP2, other than cleartext `h2c`.
+pub fn http_peer(
+    addr: SocketAddr,
+    proto: Option<&str>,
+    tls_sni: Option<&str>,
+) -> Result<HttpPeer, String> {
+    // Cleartext HTTP2 with prior knowledge, no TLS is used
+    if proto == Some("h2c") {
+        if tls_sni.is_some() {
+            return Err(
+                "'proto=\"h2c\"' is cleartext HTTP2, and can't be used with 'tls-sni'".into(),
+            );
+        }
+        let mut peer = HttpPeer::new(addr, false, String::new());
+        peer.options.alpn = ALPN::H2;
+        return Ok(peer);
+    }
+
+    let proto = match proto {
+        None => None,
+        Some("h1-only") => Some(ALPN::H1),
+        Some("h2-only") => Some(ALPN::H2),
+        Some("h1-or-h2") => {
+            tracing::warn!("accepting 'h1-or-h2' as meaning 'h2-or-h1'");
+            Some(ALPN::H2H1)
+        }
+        Some("h2-or-h1") => Some(ALPN::H2H1),
+        Some(other) => {
+            return Err(format!(
+                "'proto' should be one of 'h1-only', 'h2-only', 'h2-or-h1', or 'h2c', found '{other}'"
+            ));
+        }
+    };
+
+    let (tls, sni, alpn) = match (proto, tls_sni) {
+        (None, None) | (Some(ALPN::H1), None) => (false, String::new(), ALPN::H1),
+        (None, Some(sni)) => (true, sni.to_string(), ALPN::H2H1),
+        (Some(_), None) => {
+            return Err(
+                "'tls-sni' is required for HTTP2 support, use 'proto=\"h2c\"' for cleartext HTTP2"
+                    .into(),
+            );
+        }
+        (Some(p), Some(sni)) => (true, sni.to_string(), p),
+    };
+
+    let mut peer = HttpPeer::new(addr, tls, sni);
+    peer.options.alpn = alpn;
+    Ok(peer)
+}
+
+/// Handling of WebSocket upgrades
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebSocketConfig {
+    /// Should WebSocket upgrade requests be proxied?
+    pub(crate) allow: bool,
+    /// Idle timeout for connections that have been upgraded. If not set, the
+    /// regular timeouts are used.
+    pub(crate) idle_timeout: Option<Duration>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            allow: true,
+            idle_timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct TlsConfig {
     pub(crate) cert_path: PathBuf,
     pub(crate) key_path: PathBuf,
+    pub(crate) ocsp_stapling: Option<OcspSource>,
+}
+
+/// Where OCSP responses for stapling are obtained from
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub enum OcspSource {
+    /// Fetch responses from the OCSP responder named in the certificate
+    Responder,
+    /// Load a DER encoded response from the given file
+    File(PathBuf),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -172,6 +714,152 @@ pub enum ListenerKind {
         offer_h2: bool,
     },
     Uds(PathBuf),
+    /// A listening TCP socket passed by systemd socket activation, identified by the
+    /// `FileDescriptorName=` of the socket unit
+    Systemd {
+        name: String,
+        tls: Option<TlsConfig>,
+        offer_h2: bool,
+    },
+}
+
+/// Listeners with this prefix use a socket passed by systemd, e.g. `systemd:https`
+pub const SYSTEMD_LISTENER_PREFIX: &str = "systemd:";
+
+impl ListenerKind {
+    /// A TCP listener, which uses TLS if both `cert_path` and `key_path` are set
+    pub fn tcp(
+        addr: &str,
+        cert_path: Option<PathBuf>,
+        key_path: Option<PathBuf>,
+        offer_h2: Option<bool>,
+        ocsp_stapling: Option<bool>,
+        ocsp_response_path: Option<PathBuf>,
+    ) -> Result<Self, String> {
+        let (tls, offer_h2) = Self::tls_options(
+            cert_path,
+            key_path,
+            offer_h2,
+            ocsp_stapling,
+            ocsp_response_path,
+        )?;
+        Ok(ListenerKind::Tcp {
+            addr: addr.to_string(),
+            tls,
+            offer_h2,
+        })
+    }
+
+    /// A listener using the socket passed by systemd with the given name, which
+    /// uses TLS if both `cert_path` and `key_path` are set
+    pub fn systemd(
+        name: &str,
+        cert_path: Option<PathBuf>,
+        key_path: Option<PathBuf>,
+        offer_h2: Option<bool>,
+        ocsp_stapling: Option<bool>,
+        ocsp_response_path: Option<PathBuf>,
+    ) -> Result<Self, String> {
+        // Names are passed in `LISTEN_FDNAMES`, separated by colons
+        let valid = !name.is_empty()
+            && name.len() <= 255
+            && name.chars().all(|c| c.is_ascii_graphic() && c != ':');
+        if !valid {
+            return Err(format!("'{name}' is not a valid systemd socket name"));
+        }
+        let (tls, offer_h2) = Self::tls_options(
+            cert_path,
+            key_path,
+            offer_h2,
+            ocsp_stapling,
+            ocsp_response_path,
+        )?;
+        Ok(ListenerKind::Systemd {
+            name: name.to_string(),
+            tls,
+            offer_h2,
+        })
+    }
+
+    /// The TLS configuration of this listener, if it terminates TLS
+    pub fn tls(&self) -> Option<&TlsConfig> {
+        match self {
+            ListenerKind::Tcp { tls, .. } | ListenerKind::Systemd { tls, .. } => tls.as_ref(),
+            ListenerKind::Uds(_) => None,
+        }
+    }
+
+    /// Does this listener accept cleartext HTTP2 with prior knowledge (h2c)?
+    pub fn h2c(&self) -> bool {
+        matches!(
+            self,
+            ListenerKind::Tcp {
+                tls: None,
+                offer_h2: true,
+                ..
+            } | ListenerKind::Systemd {
+                tls: None,
+                offer_h2: true,
+                ..
+            }
+        )
+    }
+
+    fn tls_options(
+        cert_path: Option<PathBuf>,
+        key_path: Option<PathBuf>,
+        offer_h2: Option<bool>,
+        ocsp_stapling: Option<bool>,
+        ocsp_response_path: Option<PathBuf>,
+    ) -> Result<(Option<TlsConfig>, bool), String> {
+        // OCSP stapling is only meaningful for TLS listeners
+        if (ocsp_stapling.is_some() || ocsp_response_path.is_some()) && cert_path.is_none() {
+            return Err("'ocsp-stapling' requires TLS, specify 'cert-path' and 'key-path'".into());
+        }
+        let ocsp_stapling = match (ocsp_stapling.unwrap_or(false), ocsp_response_path) {
+            (false, None) => None,
+            (true, None) => Some(OcspSource::Responder),
+            (true, Some(path)) => Some(OcspSource::File(path)),
+            (false, Some(_)) => {
+                return Err("'ocsp-response-path' requires 'ocsp-stapling=true'".into());
+            }
+        };
+
+        let tls = match (cert_path, key_path) {
+            (None, None) => None,
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path,
+                key_path,
+                ocsp_stapling,
+            }),
+            _ => {
+                return Err("'cert-path' and 'key-path' must either BOTH be present, or NEITHER should be present".into());
+            }
+        };
+
+        // HTTP2 is offered by default with TLS. Without TLS, offering H2 means
+        // accepting only cleartext HTTP2 with prior knowledge (h2c)
+        let offer_h2 = offer_h2.unwrap_or(tls.is_some());
+
+        Ok((tls, offer_h2))
+    }
+}
+
+/// Check that h2c listeners are not mixed with other listeners in one service
+///
+/// Pingora enables h2c per service rather than per listener, and then handles
+/// every connection of the service as HTTP2, including TLS connections that
+/// negotiated HTTP1.1.
+pub fn check_h2c_listeners(listeners: &[ListenerConfig]) -> Result<(), String> {
+    let h2c = listeners.iter().filter(|l| l.source.h2c()).count();
+    if h2c != 0 && h2c != listeners.len() {
+        return Err(
+            "listeners with 'offer-h2=true' and without TLS (h2c) only accept HTTP2, \
+            and can't be combined with other listeners in one service"
+                .into(),
+        );
+    }
+    Ok(())
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -193,6 +881,17 @@ impl Default for UpstreamOptions {
     }
 }
 
+/// Selection keys available for HTTP proxies
+pub const HTTP_SELECTOR_KEYS: &[(&str, RequestSelector)] = &[
+    ("UriPath", uri_path_selector),
+    ("SourceAddrAndUriPath", source_addr_and_uri_path_selector),
+];
+
+/// Selection keys available for stream proxies
+///
+/// Streams are always hashed by their source address, so no [RequestSelector] is used
+pub const STREAM_SELECTOR_KEYS: &[(&str, RequestSelector)] = &[("SourceAddr", null_selector)];
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum SelectionKind {
     RoundRobin,
@@ -201,6 +900,22 @@ pub enum SelectionKind {
     Ketama,
 }
 
+impl std::str::FromStr for SelectionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "RoundRobin" => Ok(SelectionKind::RoundRobin),
+            "Random" => Ok(SelectionKind::Random),
+            "FNV" => Ok(SelectionKind::Fnv),
+            "Ketama" => Ok(SelectionKind::Ketama),
+            other => Err(format!(
+                "'{other}' should be one of 'RoundRobin', 'Random', 'FNV', or 'Ketama'"
+            )),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum HealthCheckKind {
     None,
@@ -220,12 +935,21 @@ impl Default for Config {
         Self {
             validate_configs: false,
             threads_per_service: 8,
+            instance_name: runtime::DEFAULT_INSTANCE_NAME.to_string(),
             basic_proxies: vec![],
             file_servers: vec![],
+            stream_proxies: vec![],
+            metrics: None,
+            log: LogConfig::default(),
+            tracing: None,
+            admin: None,
             daemonize: false,
+            user: None,
+            group: None,
             pid_file: None,
             upgrade: false,
             upgrade_socket: None,
+            shutdown: ShutdownConfig::default(),
         }
     }
 }
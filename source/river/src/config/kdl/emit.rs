@@ -0,0 +1,752 @@
+//! Rendering a [Config] as a KDL document
+//!
+//! This is the reverse of `TryFrom<KdlDocument> for Config`. Every setting is written
+//! out, including those left at their default value, so that the document can be used
+//! as a starting point for a new configuration file. Optional sections that are not
+//! set are included as comments, with an example value.
+
+use std::{collections::BTreeMap, net::SocketAddr, path::Path};
+
+use kdl::KdlValue;
+use pingora::{
+    protocols::ALPN,
+    upstreams::peer::{BasicPeer, HttpPeer, Peer},
+};
+
+use crate::{
+    config::internal::{
+        AccessLogConfig, AccessLogFormat, AccessLogOutput, AdminConfig, AdminListener, Config,
+        ConnectionFilterConfig, DiscoveryKind, FileServerConfig, HeaderModifierConfig,
+        HealthCheckKind, ListenerConfig, ListenerKind, LogConfig, LogFormat, LogOutput,
+        MetricsConfig, OcspSource, PathControl, ProxyConfig, RateLimitingConfig,
+        RequestFilterConfig, SelectionKind, StreamProxyConfig, TracingConfig, UpstreamOptions,
+        WebSocketConfig, HTTP_SELECTOR_KEYS, STREAM_SELECTOR_KEYS, SYSTEMD_LISTENER_PREFIX,
+    },
+    proxy::{
+        rate_limiting::{
+            multi::{MultiRaterConfig, MultiRequestKeyKind},
+            single::SingleRequestKeyKind,
+            AllRateConfig, RegexShim,
+        },
+        request_selector::RequestSelector,
+    },
+};
+
+/// Render the configuration as a commented KDL document
+pub fn emit(config: &Config) -> String {
+    let mut out = Writer::default();
+    out.comment(
+        "River configuration\n\
+         \n\
+         Every setting is shown with its current value. Optional sections that are not\n\
+         set are shown as comments, with an example value.",
+    );
+    out.blank();
+    emit_system(&mut out, config);
+    out.blank();
+    emit_services(&mut out, config);
+    out.text
+}
+
+/// Writes lines of a KDL document, keeping track of indentation
+#[derive(Default)]
+struct Writer {
+    text: String,
+    depth: usize,
+    /// Are lines being written as comments, see [Writer::commented]
+    commented: bool,
+}
+
+impl Writer {
+    fn line(&mut self, line: impl AsRef<str>) {
+        self.text.push_str(&"    ".repeat(self.depth));
+        if self.commented {
+            self.text.push_str("// ");
+        }
+        self.text.push_str(line.as_ref());
+        self.text.push('\n');
+    }
+
+    fn blank(&mut self) {
+        self.text.push('\n');
+    }
+
+    fn comment(&mut self, text: &str) {
+        for line in text.lines() {
+            self.text.push_str(&"    ".repeat(self.depth));
+            match line {
+                "" => self.text.push_str("//\n"),
+                line => {
+                    self.text.push_str("// ");
+                    self.text.push_str(line);
+                    self.text.push('\n');
+                }
+            }
+        }
+    }
+
+    fn open(&mut self, name: &str) {
+        self.line(format!("{name} {{"));
+        self.depth += 1;
+    }
+
+    fn close(&mut self) {
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    /// Write an example of a setting that isn't set, as comments
+    fn commented(&mut self, f: impl FnOnce(&mut Self)) {
+        self.commented = true;
+        f(self);
+        self.commented = false;
+    }
+}
+
+/// A quoted KDL string
+fn string(s: &str) -> String {
+    // Escaped, so that values aren't substituted when the file is loaded
+    KdlValue::String(s.replace("${", "$${")).to_string()
+}
+
+fn path(p: &Path) -> String {
+    string(&p.to_string_lossy())
+}
+
+/// A node name or property key, which is quoted unless it is a plain identifier
+fn identifier(name: &str) -> String {
+    let plain = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && !matches!(name, "true" | "false" | "null");
+    if plain {
+        name.to_string()
+    } else {
+        string(name)
+    }
+}
+
+fn emit_system(out: &mut Writer, config: &Config) {
+    out.comment("System configuration items - applies to the entire application");
+    out.open("system");
+
+    out.comment("Number of threads used in the worker pool for EACH service");
+    out.line(format!(
+        "threads-per-service {}",
+        config.threads_per_service
+    ));
+    out.blank();
+
+    out.comment(
+        "Name of this instance. The pidfile and upgrade socket default to paths in\n\
+         $XDG_RUNTIME_DIR/river/<name>, or /run/river/<name> if that is not set,\n\
+         so instances on the same host need different names",
+    );
+    out.line(format!("instance-name {}", string(&config.instance_name)));
+    out.blank();
+
+    out.comment("Should the server daemonize and run in the background?");
+    out.line(format!("daemonize {}", config.daemonize));
+    out.blank();
+
+    out.comment(
+        "The user and group to run as after binding listeners. River starts as the\n\
+         user that launched it, and switches to these once the configuration is\n\
+         loaded",
+    );
+    match &config.user {
+        Some(user) => out.line(format!("user {}", string(user))),
+        None => out.commented(|out| out.line("user \"river\"")),
+    }
+    match &config.group {
+        Some(group) => out.line(format!("group {}", string(group))),
+        None => out.commented(|out| out.line("group \"river\"")),
+    }
+    out.blank();
+
+    out.comment(
+        "Path to the pidfile used when daemonizing, defaults to \"river.pid\" in the\n\
+         instance's runtime directory\n\
+         \n\
+         NOTE: This must be an absolute path.\n\
+         See issue https://github.com/memorysafety/river/issues/50",
+    );
+    match &config.pid_file {
+        Some(pid_file) => out.line(format!("pid-file {}", path(pid_file))),
+        None => out.commented(|out| out.line("pid-file \"/run/river/default/river.pid\"")),
+    }
+    out.blank();
+
+    out.comment(
+        "Path to upgrade socket, defaults to \"upgrade.sock\" in the instance's\n\
+         runtime directory\n\
+         \n\
+         NOTE: This must be an absolute path.\n\
+         See issue https://github.com/memorysafety/river/issues/50\n\
+         NOTE: The upgrade command is only supported on Linux",
+    );
+    match &config.upgrade_socket {
+        Some(socket) => out.line(format!("upgrade-socket {}", path(socket))),
+        None => out.commented(|out| out.line("upgrade-socket \"/run/river/default/upgrade.sock\"")),
+    }
+    out.blank();
+
+    out.comment(
+        "During a graceful upgrade or shutdown, in-flight requests and connections\n\
+         are served for the grace period. Services are then told to shut down, and\n\
+         anything still running after the shutdown timeout is terminated. If not set,\n\
+         Pingora's defaults are used",
+    );
+    out.open("upgrade");
+    match config.shutdown.grace_period {
+        Some(period) => out.line(format!("grace-period-secs {}", period.as_secs())),
+        None => out.commented(|out| out.line("grace-period-secs 60")),
+    }
+    match config.shutdown.shutdown_timeout {
+        Some(timeout) => out.line(format!("shutdown-timeout-secs {}", timeout.as_secs())),
+        None => out.commented(|out| out.line("shutdown-timeout-secs 5")),
+    }
+    out.close();
+    out.blank();
+
+    out.comment(
+        "Application logs. Filters use the same syntax as RUST_LOG, and can\n\
+         be used to change the level of specific modules. If neither the level\n\
+         nor any filters are set, RUST_LOG is used",
+    );
+    emit_log(out, &config.log);
+    out.blank();
+
+    out.comment("Prometheus metrics, served in the text format");
+    match &config.metrics {
+        Some(metrics) => emit_metrics(out, metrics),
+        None => out.commented(|out| {
+            emit_metrics(
+                out,
+                &MetricsConfig {
+                    listen: SocketAddr::from(([127, 0, 0, 1], 9100)),
+                },
+            )
+        }),
+    }
+    out.blank();
+
+    out.comment("Export request spans to an OpenTelemetry collector, over OTLP/gRPC");
+    match &config.tracing {
+        Some(tracing) => emit_tracing(out, tracing),
+        None => out.commented(|out| {
+            emit_tracing(
+                out,
+                &TracingConfig {
+                    otlp_endpoint: "http://127.0.0.1:4317".into(),
+                    sample_ratio: 1.0,
+                },
+            )
+        }),
+    }
+    out.blank();
+
+    out.comment(
+        "The admin API, for looking at the running instance. Only loopback\n\
+         addresses and unix sockets are allowed",
+    );
+    match &config.admin {
+        Some(admin) => emit_admin(out, admin),
+        None => out.commented(|out| {
+            emit_admin(
+                out,
+                &AdminConfig {
+                    listen: AdminListener::Tcp(SocketAddr::from(([127, 0, 0, 1], 9901))),
+                    token: Some("change-me".into()),
+                },
+            )
+        }),
+    }
+
+    out.close();
+}
+
+fn emit_log(out: &mut Writer, log: &LogConfig) {
+    out.open("log");
+    match log.level {
+        Some(level) => out.line(format!("level {}", string(&level.to_string()))),
+        None => out.commented(|out| out.line("level \"info\"")),
+    }
+    let format = match log.format {
+        LogFormat::Full => "full",
+        LogFormat::Compact => "compact",
+        LogFormat::Pretty => "pretty",
+        LogFormat::Json => "json",
+    };
+    out.line(format!("format {}", string(format)));
+    match &log.output {
+        LogOutput::Stdout => out.line("output \"stdout\""),
+        LogOutput::Stderr => out.line("output \"stderr\""),
+        LogOutput::File(file) => out.line(format!("output \"file\" path={}", path(file))),
+    }
+    if log.filters.is_empty() {
+        out.commented(|out| out.line("filters \"pingora_core=warn\""));
+    } else {
+        let filters = log.filters.iter().map(|f| string(f)).collect::<Vec<_>>();
+        out.line(format!("filters {}", filters.join(" ")));
+    }
+    out.close();
+}
+
+fn emit_metrics(out: &mut Writer, metrics: &MetricsConfig) {
+    out.open("metrics");
+    out.line(format!("listen {}", string(&metrics.listen.to_string())));
+    out.close();
+}
+
+fn emit_tracing(out: &mut Writer, tracing: &TracingConfig) {
+    out.open("tracing");
+    out.line(format!("otlp-endpoint {}", string(&tracing.otlp_endpoint)));
+    // Debug formatting always includes a decimal point, e.g. `1.0`
+    out.line(format!("sample-ratio {:?}", tracing.sample_ratio));
+    out.close();
+}
+
+fn emit_admin(out: &mut Writer, admin: &AdminConfig) {
+    out.open("admin");
+    match &admin.listen {
+        AdminListener::Tcp(addr) => out.line(format!("listen {}", string(&addr.to_string()))),
+        AdminListener::Uds(socket) => out.line(format!("listen {}", path(socket))),
+    }
+    match &admin.token {
+        Some(token) => out.line(format!("token {}", string(token))),
+        None => out.commented(|out| out.line("token \"change-me\"")),
+    }
+    out.close();
+}
+
+fn emit_services(out: &mut Writer, config: &Config) {
+    out.comment("Services are the main abstraction of River");
+    out.open("services");
+    let mut first = true;
+    let mut separate = |out: &mut Writer| {
+        if !std::mem::take(&mut first) {
+            out.blank();
+        }
+    };
+    for proxy in &config.basic_proxies {
+        separate(out);
+        emit_proxy(out, proxy);
+    }
+    for file_server in &config.file_servers {
+        separate(out);
+        emit_file_server(out, file_server);
+    }
+    for stream_proxy in &config.stream_proxies {
+        separate(out);
+        emit_stream_proxy(out, stream_proxy);
+    }
+    out.close();
+}
+
+fn emit_proxy(out: &mut Writer, proxy: &ProxyConfig) {
+    out.comment(&format!("An HTTP proxy service named \"{}\"", proxy.name));
+    out.open(&identifier(&proxy.name));
+
+    out.comment(
+        "Listeners are the \"downstream\" interfaces that we listen to. At least one\n\
+         is required",
+    );
+    emit_listeners(out, &proxy.listeners, true);
+    out.blank();
+
+    out.comment(
+        "Connectors are the \"upstream\" interfaces that we connect with. At least one\n\
+         is required. \"load-balance\" configures how requests are distributed between\n\
+         them",
+    );
+    out.open("connectors");
+    emit_load_balance(out, &proxy.upstream_options, HTTP_SELECTOR_KEYS);
+    for peer in &proxy.upstreams {
+        emit_http_connector(out, peer);
+    }
+    out.close();
+    out.blank();
+
+    out.comment(
+        "Rate limiting rules. ALL rules are applied, and a request must receive a\n\
+         token from each rule that applies to it",
+    );
+    emit_rate_limiting(out, &proxy.rate_limiting, true);
+    out.blank();
+
+    out.comment("Handling of WebSocket upgrade requests");
+    emit_websocket(out, &proxy.websocket);
+    out.blank();
+
+    emit_access_log(out, proxy.access_log.as_ref());
+    out.blank();
+
+    out.comment("Path control are modifiers for requests and responses");
+    emit_path_control(out, &proxy.path_control);
+
+    out.close();
+}
+
+fn emit_file_server(out: &mut Writer, file_server: &FileServerConfig) {
+    out.comment(&format!("A file server named \"{}\"", file_server.name));
+    out.open(&identifier(&file_server.name));
+
+    out.comment("At least one listener is required");
+    emit_listeners(out, &file_server.listeners, true);
+    out.blank();
+
+    out.comment("The base path is the root of the served files");
+    out.open("file-server");
+    match &file_server.base_path {
+        Some(base_path) => out.line(format!("base-path {}", path(base_path))),
+        None => out.commented(|out| out.line("base-path \".\"")),
+    }
+    out.close();
+    out.blank();
+
+    emit_access_log(out, file_server.access_log.as_ref());
+
+    out.close();
+}
+
+fn emit_stream_proxy(out: &mut Writer, stream_proxy: &StreamProxyConfig) {
+    out.comment(&format!("A stream proxy named \"{}\"", stream_proxy.name));
+    out.open(&identifier(&stream_proxy.name));
+
+    out.comment("At least one listener is required");
+    emit_listeners(out, &stream_proxy.listeners, false);
+    out.blank();
+
+    out.comment(
+        "Connections are forwarded to these connectors. With \"tls-passthrough\",\n\
+         they are used when no server name matches, and may be empty",
+    );
+    out.open("connectors");
+    emit_load_balance(out, &stream_proxy.upstream_options, STREAM_SELECTOR_KEYS);
+    for peer in &stream_proxy.upstreams {
+        emit_stream_connector(out, peer);
+    }
+    out.close();
+    out.blank();
+
+    out.comment("Only \"source-ip\" rules can be used by stream proxies");
+    emit_rate_limiting(out, &stream_proxy.rate_limiting, false);
+    out.blank();
+
+    out.open("stream-proxy");
+    out.comment("Connections from downstream are filtered before connecting upstream");
+    out.open("connection-filters");
+    emit_connection_filters(out, &stream_proxy.connection_filters);
+    out.close();
+    out.comment(
+        "Route TLS connections by their server name, without terminating them.\n\
+         Listeners can't use TLS when this is set",
+    );
+    match &stream_proxy.tls_passthrough {
+        Some(routes) => emit_tls_passthrough(out, routes),
+        None => out.commented(|out| {
+            let example = BTreeMap::from([(
+                "db.example.com".to_string(),
+                vec![BasicPeer::new("10.0.0.1:5432")],
+            )]);
+            emit_tls_passthrough(out, &example)
+        }),
+    }
+    out.close();
+
+    out.close();
+}
+
+fn emit_tls_passthrough(out: &mut Writer, routes: &BTreeMap<String, Vec<BasicPeer>>) {
+    out.open("tls-passthrough");
+    for (name, peers) in routes {
+        out.open(&string(name));
+        for peer in peers {
+            emit_stream_connector(out, peer);
+        }
+        out.close();
+    }
+    out.close();
+}
+
+/// Offering HTTP2 only applies to HTTP services, and is rejected for stream proxies
+fn emit_listeners(out: &mut Writer, listeners: &[ListenerConfig], http: bool) {
+    out.open("listeners");
+    for listener in listeners {
+        let (addr, tls, offer_h2) = match &listener.source {
+            ListenerKind::Tcp {
+                addr,
+                tls,
+                offer_h2,
+            } => (addr.clone(), tls, offer_h2),
+            ListenerKind::Systemd {
+                name,
+                tls,
+                offer_h2,
+            } => (format!("{SYSTEMD_LISTENER_PREFIX}{name}"), tls, offer_h2),
+            ListenerKind::Uds(socket) => {
+                out.line(path(socket));
+                continue;
+            }
+        };
+        let mut line = string(&addr);
+        if let Some(tls) = tls {
+            line += &format!(
+                " cert-path={} key-path={}",
+                path(&tls.cert_path),
+                path(&tls.key_path)
+            );
+            match &tls.ocsp_stapling {
+                None => {}
+                Some(OcspSource::Responder) => line += " ocsp-stapling=true",
+                Some(OcspSource::File(file)) => {
+                    line += &format!(" ocsp-stapling=true ocsp-response-path={}", path(file))
+                }
+            }
+        }
+        if http {
+            line += &format!(" offer-h2={offer_h2}");
+        }
+        out.line(line);
+    }
+    out.close();
+}
+
+fn emit_load_balance(
+    out: &mut Writer,
+    options: &UpstreamOptions,
+    keys: &[(&str, RequestSelector)],
+) {
+    out.open("load-balance");
+    let (selection, keyed) = match options.selection {
+        SelectionKind::RoundRobin => ("RoundRobin", false),
+        SelectionKind::Random => ("Random", false),
+        SelectionKind::Fnv => ("FNV", true),
+        SelectionKind::Ketama => ("Ketama", true),
+    };
+    let key = keys
+        .iter()
+        .find(|(_, selector)| std::ptr::fn_addr_eq(*selector, options.selector))
+        .filter(|_| keyed);
+    match key {
+        Some((key, _)) => out.line(format!(
+            "selection {} key={}",
+            string(selection),
+            string(key)
+        )),
+        None => out.line(format!("selection {}", string(selection))),
+    }
+    let discovery = match options.discovery {
+        DiscoveryKind::Static => "Static",
+    };
+    out.line(format!("discovery {}", string(discovery)));
+    let health_check = match options.health_checks {
+        HealthCheckKind::None => "None",
+    };
+    out.line(format!("health-check {}", string(health_check)));
+    out.close();
+}
+
+fn emit_http_connector(out: &mut Writer, peer: &HttpPeer) {
+    let addr = string(&peer._address.to_string());
+    let line = match (peer.tls(), &peer.options.alpn) {
+        (false, ALPN::H2) => format!("{addr} proto=\"h2c\""),
+        (false, _) => format!("{addr} proto=\"h1-only\""),
+        (true, alpn) => {
+            let proto = match alpn {
+                ALPN::H1 => "h1-only",
+                ALPN::H2 => "h2-only",
+                _ => "h2-or-h1",
+            };
+            format!(
+                "{addr} tls-sni={} proto={}",
+                string(&peer.sni),
+                string(proto)
+            )
+        }
+    };
+    out.line(line);
+}
+
+/// NOTE: BasicPeer uses TLS when the SNI is not empty
+fn emit_stream_connector(out: &mut Writer, peer: &BasicPeer) {
+    let addr = string(&peer._address.to_string());
+    match peer.sni.as_str() {
+        "" => out.line(addr),
+        sni => out.line(format!("{addr} tls-sni={}", string(sni))),
+    }
+}
+
+/// Only `source-ip` rules are used as examples for stream proxies
+fn emit_rate_limiting(out: &mut Writer, rate_limiting: &RateLimitingConfig, http: bool) {
+    out.open("rate-limiting");
+    for rule in &rate_limiting.rules {
+        emit_rule(out, rule);
+    }
+    if rate_limiting.rules.is_empty() {
+        out.commented(|out| {
+            let config = MultiRaterConfig {
+                threads: 0,
+                max_buckets: 4000,
+                max_tokens_per_bucket: 10,
+                refill_interval_millis: 10,
+                refill_qty: 1,
+            };
+            emit_rule(
+                out,
+                &AllRateConfig::Multi {
+                    kind: MultiRequestKeyKind::SourceIp,
+                    config: config.clone(),
+                },
+            );
+            if http {
+                if let Ok(pattern) = RegexShim::new("static/.*") {
+                    emit_rule(
+                        out,
+                        &AllRateConfig::Multi {
+                            kind: MultiRequestKeyKind::Uri { pattern },
+                            config,
+                        },
+                    );
+                }
+            }
+        });
+    }
+    out.close();
+}
+
+fn emit_rule(out: &mut Writer, rule: &AllRateConfig) {
+    let line = match rule {
+        AllRateConfig::Multi { kind, config } => {
+            let kind = match kind {
+                MultiRequestKeyKind::SourceIp => "kind=\"source-ip\"".to_string(),
+                MultiRequestKeyKind::Uri { pattern } => {
+                    format!("kind=\"specific-uri\" pattern={}", string(pattern.as_str()))
+                }
+            };
+            format!(
+                "rule {kind} max-buckets={} tokens-per-bucket={} refill-qty={} refill-rate-ms={}",
+                config.max_buckets,
+                config.max_tokens_per_bucket,
+                config.refill_qty,
+                config.refill_interval_millis
+            )
+        }
+        AllRateConfig::Single { kind, config } => {
+            let SingleRequestKeyKind::UriGroup { pattern } = kind;
+            format!(
+                "rule kind=\"any-matching-uri\" pattern={} tokens-per-bucket={} refill-qty={} refill-rate-ms={}",
+                string(pattern.as_str()),
+                config.max_tokens_per_bucket,
+                config.refill_qty,
+                config.refill_interval_millis
+            )
+        }
+    };
+    out.line(line);
+}
+
+fn emit_websocket(out: &mut Writer, websocket: &WebSocketConfig) {
+    out.open("websocket");
+    out.line(format!("allow {}", websocket.allow));
+    out.comment("If not set, upgraded connections use the regular timeouts");
+    match websocket.idle_timeout {
+        Some(timeout) => out.line(format!("idle-timeout-secs {}", timeout.as_secs())),
+        None => out.commented(|out| out.line("idle-timeout-secs 600")),
+    }
+    out.close();
+}
+
+fn emit_access_log(out: &mut Writer, access_log: Option<&AccessLogConfig>) {
+    out.comment(
+        "Access logs record one line per completed request. The format is one of\n\
+         \"json\", \"combined\" (Apache-style), or \"template\" with a custom pattern. The\n\
+         output is one of \"stdout\", \"file\" with a path, or \"unix-datagram\" with the\n\
+         path of a syslog socket",
+    );
+    let Some(access_log) = access_log else {
+        out.commented(|out| {
+            out.open("access-log");
+            out.line("format \"combined\"");
+            out.line("output \"stdout\"");
+            out.close();
+        });
+        return;
+    };
+    out.open("access-log");
+    match &access_log.format {
+        AccessLogFormat::Json => out.line("format \"json\""),
+        AccessLogFormat::Combined => out.line("format \"combined\""),
+        AccessLogFormat::Template(template) => out.line(format!(
+            "format \"template\" pattern={}",
+            string(&template.to_string())
+        )),
+    }
+    match &access_log.output {
+        AccessLogOutput::Stdout => out.line("output \"stdout\""),
+        AccessLogOutput::File(file) => out.line(format!("output \"file\" path={}", path(file))),
+        AccessLogOutput::UnixDatagram(socket) => {
+            out.line(format!("output \"unix-datagram\" path={}", path(socket)))
+        }
+    }
+    out.close();
+}
+
+fn emit_path_control(out: &mut Writer, path_control: &PathControl) {
+    out.open("path-control");
+    out.open("request-filters");
+    for filter in &path_control.request_filters {
+        match filter {
+            RequestFilterConfig::BlockCidrRange { addrs } => {
+                let addrs = addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                out.line(format!(
+                    "filter kind=\"block-cidr-range\" addrs={}",
+                    string(&addrs.join(", "))
+                ));
+            }
+        }
+    }
+    out.close();
+    out.open("upstream-request");
+    emit_header_modifiers(out, &path_control.upstream_request_filters);
+    out.close();
+    out.open("upstream-response");
+    emit_header_modifiers(out, &path_control.upstream_response_filters);
+    out.close();
+    out.close();
+}
+
+fn emit_header_modifiers(out: &mut Writer, modifiers: &[HeaderModifierConfig]) {
+    for modifier in modifiers {
+        match modifier {
+            HeaderModifierConfig::RemoveHeaderKeyRegex { pattern } => out.line(format!(
+                "filter kind=\"remove-header-key-regex\" pattern={}",
+                string(pattern.as_str())
+            )),
+            HeaderModifierConfig::UpsertHeader { key, value } => out.line(format!(
+                "filter kind=\"upsert-header\" key={} value={}",
+                string(key),
+                string(value)
+            )),
+        }
+    }
+}
+
+fn emit_connection_filters(out: &mut Writer, filters: &[ConnectionFilterConfig]) {
+    for filter in filters {
+        let (kind, addrs) = match filter {
+            ConnectionFilterConfig::BlockCidrRange { addrs } => ("block-cidr-range", addrs),
+            ConnectionFilterConfig::AllowCidrRange { addrs } => ("allow-cidr-range", addrs),
+        };
+        let addrs = addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        out.line(format!(
+            "filter kind=\"{kind}\" addrs={}",
+            string(&addrs.join(", "))
+        ));
+    }
+}
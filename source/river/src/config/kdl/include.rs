@@ -0,0 +1,164 @@
+//! Loading of KDL configuration files that are split over several files
+//!
+//! The main configuration file may include other files, which may only contain
+//! `services` and `templates` blocks:
+//!
+//! ```kdl
+//! include "conf.d/*.kdl"
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use kdl::KdlDocument;
+use miette::{IntoDiagnostic, WrapErr};
+
+use super::{interpolate::interpolate, render, utils, Bad, Secrets};
+use crate::config::internal::Config;
+
+/// A document included by the main configuration file
+pub(super) struct Included {
+    pub(super) path: PathBuf,
+    pub(super) doc: KdlDocument,
+}
+
+/// Load a KDL configuration file, along with all of the files it includes
+///
+/// Relative paths in `include` are relative to the directory of the including
+/// file. A `*` in the file name matches any number of characters, and matching
+/// files are loaded in alphabetical order.
+///
+/// The values of secret files used by the configuration are returned as well.
+pub fn load(path: &Path) -> miette::Result<(Config, Secrets)> {
+    let mut secrets = Secrets::default();
+    let mut doc = read_document(path)?;
+    interpolate(&mut doc, &mut secrets).map_err(|e| Bad::in_file(e, path))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let mut included: Vec<Included> = vec![];
+    for node in doc.nodes() {
+        if node.name().value() != "include" {
+            continue;
+        }
+        let pattern = utils::extract_one_str_arg(&doc, node, "include", node.entries(), |s| {
+            Some(s.to_string())
+        })
+        .map_err(|e| Bad::in_file(e, path))?;
+        let paths = expand(base, &pattern)
+            .map_err(|e| Bad::in_file(Bad::docspan(e, &doc, node.span()).into(), path))?;
+
+        for inc_path in paths {
+            // Overlapping patterns only load a file once
+            if included.iter().any(|i| i.path == inc_path) {
+                continue;
+            }
+            let mut inc_doc = read_document(&inc_path)?;
+            interpolate(&mut inc_doc, &mut secrets).map_err(|e| Bad::in_file(e, &inc_path))?;
+            if let Some(node) = inc_doc
+                .nodes()
+                .iter()
+                .find(|n| !matches!(n.name().value(), "services" | "templates"))
+            {
+                let err = Bad::docspan(
+                    "included files may only contain 'services' and 'templates'",
+                    &inc_doc,
+                    node.span(),
+                );
+                return Err(Bad::in_file(err.into(), &inc_path));
+            }
+            included.push(Included {
+                path: inc_path,
+                doc: inc_doc,
+            });
+        }
+    }
+
+    let config = render(&doc, &included).map_err(|e| Bad::in_file(e, path))?;
+    Ok((config, secrets))
+}
+
+/// Read and parse a single KDL file
+fn read_document(path: &Path) -> miette::Result<KdlDocument> {
+    let contents = std::fs::read_to_string(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Error loading KDL file '{}'", path.display()))?;
+    contents
+        .parse()
+        .wrap_err_with(|| format!("Error parsing KDL file '{}'", path.display()))
+}
+
+/// Get the paths matching an `include` pattern, relative to `base`
+fn expand(base: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let full = base.join(pattern);
+    let Some(file_pattern) = full.file_name().and_then(|n| n.to_str()) else {
+        return Err(format!("'{pattern}' is not a file name"));
+    };
+    let dir = full.parent().unwrap_or(Path::new(""));
+    if dir.to_string_lossy().contains('*') {
+        return Err("wildcards are only supported in the file name".into());
+    }
+    if !file_pattern.contains('*') {
+        return Ok(vec![full]);
+    }
+
+    let read_from = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let entries = std::fs::read_dir(read_from)
+        .map_err(|e| format!("Error reading directory '{}': {e}", read_from.display()))?;
+    let mut paths = vec![];
+    for entry in entries {
+        let entry =
+            entry.map_err(|e| format!("Error reading directory '{}': {e}", read_from.display()))?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        // Like a shell, hidden files are only matched explicitly
+        if name.starts_with('.') || !wildcard_match(file_pattern, &name) {
+            continue;
+        }
+        if entry.path().is_file() {
+            paths.push(dir.join(name));
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Match a name against a pattern, where `*` matches any number of characters
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*').collect::<Vec<_>>();
+    let Some(mut rest) = name.strip_prefix(parts.remove(0)) else {
+        return false;
+    };
+    let Some(last) = parts.pop() else {
+        // There was no wildcard at all
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod test {
+    use super::wildcard_match;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*.kdl", "a.kdl"));
+        assert!(wildcard_match("*.kdl", ".kdl"));
+        assert!(wildcard_match("svc-*.kdl", "svc-api.kdl"));
+        assert!(wildcard_match("*-*.kdl", "a-b.kdl"));
+        assert!(wildcard_match("a.kdl", "a.kdl"));
+        assert!(!wildcard_match("a.kdl", "b.kdl"));
+        assert!(!wildcard_match("*.kdl", "a.kdl.bak"));
+        assert!(!wildcard_match("svc-*.kdl", "api.kdl"));
+        assert!(!wildcard_match("*a*a", "a"));
+    }
+}
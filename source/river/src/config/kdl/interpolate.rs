@@ -0,0 +1,286 @@
+//! Substitution of environment variables and secret files in KDL strings
+//!
+//! Both node names and string values may contain:
+//!
+//! * `${env:NAME}` - the value of an environment variable, which must be set
+//! * `${env:NAME:-default}` - the same, with a default if it is unset or empty
+//! * `${file:/run/secrets/name}` - the contents of a file, without a trailing
+//!   newline. These are treated as secrets, see [Secrets]
+//!
+//! A literal `${` can be written as `$${`.
+
+use std::fmt;
+
+use kdl::{KdlDocument, KdlNode, KdlValue};
+use miette::SourceSpan;
+
+use super::Bad;
+
+/// The values read from files while loading a configuration
+///
+/// These are redacted when the configuration is logged or served by the admin API,
+/// and replaced by the expression they came from by `--emit-effective-config`.
+#[derive(Default, Clone)]
+pub struct Secrets(Vec<Secret>);
+
+#[derive(Clone)]
+struct Secret {
+    path: String,
+    value: String,
+}
+
+impl Secrets {
+    /// Replace every secret value in the text
+    pub fn redact(&self, text: &str) -> String {
+        self.replace(text, |_| "<redacted>".into())
+    }
+
+    /// Replace every secret value in the text with the `${file:...}` expression it
+    /// was read from
+    pub fn unresolve(&self, text: &str) -> String {
+        self.replace(text, |secret| format!("${{file:{}}}", secret.path))
+    }
+
+    fn replace(&self, text: &str, with: impl Fn(&Secret) -> String) -> String {
+        let mut out = text.to_string();
+        for secret in &self.0 {
+            let replacement = with(secret);
+            out = out.replace(&secret.value, &replacement);
+            // Also catch secrets escaped by `Debug` or KDL, like ones containing quotes
+            let escaped = escape_quoted(&secret.value);
+            if escaped != secret.value {
+                out = out.replace(&escaped, &escape_quoted(&replacement));
+            }
+        }
+        out
+    }
+}
+
+/// Escape the text like a quoted string
+fn escape_quoted(text: &str) -> String {
+    let escaped = format!("{text:?}");
+    escaped[1..escaped.len() - 1].to_string()
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secrets({} redacted)", self.0.len())
+    }
+}
+
+/// The document as it was parsed, used for error messages
+struct Original {
+    doc: KdlDocument,
+    text: String,
+}
+
+/// Substitute all of the `${...}` expressions in the document
+pub(super) fn interpolate(doc: &mut KdlDocument, secrets: &mut Secrets) -> miette::Result<()> {
+    let original = Original {
+        doc: doc.clone(),
+        text: doc.to_string(),
+    };
+    interpolate_nodes(&original, doc.nodes_mut(), secrets)
+}
+
+fn interpolate_nodes(
+    original: &Original,
+    nodes: &mut [KdlNode],
+    secrets: &mut Secrets,
+) -> miette::Result<()> {
+    for node in nodes {
+        let name = node.name();
+        if let Some(value) = substitute(original, name.value(), name.span(), secrets)? {
+            node.name_mut().set_value(value);
+        }
+
+        for entry in node.entries_mut() {
+            let Some(val) = entry.value().as_string() else {
+                continue;
+            };
+            let Some(value) = substitute(original, val, entry.span(), secrets)? else {
+                continue;
+            };
+            // The written form of the entry is kept, so that spans of errors
+            // found later still match the file
+            if let KdlValue::String(s) | KdlValue::RawString(s) = entry.value_mut() {
+                *s = value;
+            }
+        }
+
+        if let Some(children) = node.children_mut() {
+            interpolate_nodes(original, children.nodes_mut(), secrets)?;
+        }
+    }
+    Ok(())
+}
+
+/// Substitute the expressions of a single string, if it has any
+fn substitute(
+    original: &Original,
+    val: &str,
+    span: &SourceSpan,
+    secrets: &mut Secrets,
+) -> miette::Result<Option<String>> {
+    if !val.contains("${") {
+        return Ok(None);
+    }
+
+    let mut out = String::new();
+    let mut rest = val;
+    while let Some(idx) = rest.find("${") {
+        let (before, after) = rest.split_at(idx);
+        out.push_str(before);
+
+        // An escaped `$${`, the first `$` has already been copied
+        if before.ends_with('$') {
+            out.push('{');
+            rest = &after[2..];
+            continue;
+        }
+
+        let Some(end) = after.find('}') else {
+            return Err(Bad::docspan(
+                "'${' is missing a closing '}', use '$${' for a literal '${'",
+                &original.doc,
+                &token_span(original, span, after),
+            )
+            .into());
+        };
+        let token = &after[..=end];
+        let value = resolve(&token[2..end], secrets)
+            .map_err(|e| Bad::docspan(e, &original.doc, &token_span(original, span, token)))?;
+        out.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(Some(out))
+}
+
+/// Get the value of a single expression, without the `${` and `}`
+fn resolve(expr: &str, secrets: &mut Secrets) -> Result<String, String> {
+    if let Some(var) = expr.strip_prefix("env:") {
+        let (name, default) = match var.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (var, None),
+        };
+        if name.is_empty() {
+            return Err("missing the name of the environment variable".into());
+        }
+        return match (std::env::var(name), default) {
+            (Ok(val), Some(default)) if val.is_empty() => Ok(default.to_string()),
+            (Ok(val), _) => Ok(val),
+            (Err(std::env::VarError::NotPresent), Some(default)) => Ok(default.to_string()),
+            (Err(std::env::VarError::NotPresent), None) => {
+                Err(format!("environment variable '{name}' is not set"))
+            }
+            (Err(std::env::VarError::NotUnicode(_)), _) => Err(format!(
+                "environment variable '{name}' is not valid unicode"
+            )),
+        };
+    }
+
+    if let Some(path) = expr.strip_prefix("file:") {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Error reading secret file '{path}': {e}"))?;
+        let val = contents.trim_end_matches(['\r', '\n']).to_string();
+        if !val.is_empty() {
+            secrets.0.push(Secret {
+                path: path.to_string(),
+                value: val.clone(),
+            });
+        }
+        return Ok(val);
+    }
+
+    Err(format!(
+        "Unknown substitution '${{{expr}}}', expected '${{env:NAME}}' or '${{file:PATH}}'"
+    ))
+}
+
+/// Narrow the span of a node name or entry to an expression inside of it
+///
+/// Falls back to the whole span if the expression isn't written as-is, for
+/// example because of escape sequences.
+fn token_span(original: &Original, span: &SourceSpan, token: &str) -> SourceSpan {
+    let written = original
+        .text
+        .get(span.offset()..span.offset() + span.len())
+        .unwrap_or_default();
+    match written.find(token) {
+        Some(idx) => (span.offset() + idx, token.len()).into(),
+        None => *span,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn env() {
+        std::env::set_var("RIVER_TEST_INTERPOLATE_HOST", "example.com");
+        std::env::remove_var("RIVER_TEST_INTERPOLATE_UNSET");
+        let mut secrets = Secrets::default();
+        assert_eq!(
+            resolve("env:RIVER_TEST_INTERPOLATE_HOST", &mut secrets),
+            Ok("example.com".to_string())
+        );
+        assert_eq!(
+            resolve("env:RIVER_TEST_INTERPOLATE_HOST:-other.com", &mut secrets),
+            Ok("example.com".to_string())
+        );
+        assert_eq!(
+            resolve("env:RIVER_TEST_INTERPOLATE_UNSET:-other.com", &mut secrets),
+            Ok("other.com".to_string())
+        );
+        assert_eq!(
+            resolve("env:RIVER_TEST_INTERPOLATE_UNSET:-", &mut secrets),
+            Ok(String::new())
+        );
+        assert!(resolve("env:RIVER_TEST_INTERPOLATE_UNSET", &mut secrets).is_err());
+        assert!(resolve("env:", &mut secrets).is_err());
+        assert!(resolve("vault:secret", &mut secrets).is_err());
+        assert!(secrets.0.is_empty());
+    }
+
+    #[test]
+    fn escape() {
+        let original = Original {
+            doc: KdlDocument::new(),
+            text: String::new(),
+        };
+        let span = (0, 0).into();
+        let mut secrets = Secrets::default();
+        assert_eq!(
+            substitute(&original, "$$remote_addr", &span, &mut secrets).unwrap(),
+            None
+        );
+        assert_eq!(
+            substitute(&original, "a $${env:HOME} b", &span, &mut secrets).unwrap(),
+            Some("a ${env:HOME} b".to_string())
+        );
+        assert!(substitute(&original, "a ${env:HOME", &span, &mut secrets).is_err());
+    }
+
+    #[test]
+    fn redact() {
+        let secret = |path: &str, value: &str| Secret {
+            path: path.into(),
+            value: value.into(),
+        };
+        let secrets = Secrets(vec![
+            secret("/run/secrets/token", "hunter2"),
+            secret("/run/secrets/\"quoted\"", "quo\"te"),
+        ]);
+        let text = r#"Config { token: "hunter2", other: "quo\"te" }"#;
+        assert_eq!(
+            secrets.redact(text),
+            r#"Config { token: "<redacted>", other: "<redacted>" }"#
+        );
+        assert_eq!(
+            secrets.unresolve(text),
+            r#"Config { token: "${file:/run/secrets/token}", other: "${file:/run/secrets/\"quoted\"}" }"#
+        );
+    }
+}
@@ -1,153 +1,460 @@
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     net::SocketAddr,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 use crate::{
-    config::internal::{
-        Config, DiscoveryKind, FileServerConfig, HealthCheckKind, ListenerConfig, ListenerKind,
-        PathControl, ProxyConfig, SelectionKind, TlsConfig, UpstreamOptions,
+    access_log::Template,
+    config::{
+        internal::{
+            check_h2c_listeners, http_peer, is_server_name_pattern, AccessLogConfig,
+            AccessLogFormat, AccessLogOutput, AdminConfig, AdminListener, Config,
+            ConnectionFilterConfig, DiscoveryKind, FileServerConfig, HeaderModifierConfig,
+            HealthCheckKind, ListenerConfig, ListenerKind, LogConfig, LogFormat, LogOutput,
+            MetricsConfig, PathControl, ProxyConfig, RequestFilterConfig, SelectionKind,
+            ShutdownConfig, StreamProxyConfig, TracingConfig, UpstreamOptions, WebSocketConfig,
+            HTTP_SELECTOR_KEYS, STREAM_SELECTOR_KEYS, SYSTEMD_LISTENER_PREFIX,
+        },
+        runtime,
     },
+    logging,
     proxy::{
         rate_limiting::{
             multi::{MultiRaterConfig, MultiRequestKeyKind},
             single::{SingleInstanceConfig, SingleRequestKeyKind},
             AllRateConfig, RegexShim,
         },
-        request_selector::{
-            null_selector, source_addr_and_uri_path_selector, uri_path_selector, RequestSelector,
-        },
+        request_selector::{null_selector, RequestSelector},
     },
 };
 use kdl::{KdlDocument, KdlEntry, KdlNode, KdlValue};
-use miette::{bail, Diagnostic, SourceSpan};
-use pingora::{protocols::ALPN, upstreams::peer::HttpPeer};
+use miette::{Diagnostic, MietteError, MietteSpanContents, SourceCode, SourceSpan, SpanContents};
+use pingora::upstreams::peer::{BasicPeer, HttpPeer};
+use tracing_subscriber::filter::LevelFilter;
 
-use super::internal::RateLimitingConfig;
+use super::{internal::RateLimitingConfig, Errors};
 
+mod emit;
+mod include;
+mod interpolate;
 #[cfg(test)]
 mod test;
 mod utils;
 
+pub use emit::emit;
+pub use include::load;
+pub use interpolate::Secrets;
+
+use include::Included;
+
 /// This is the primary interface for parsing the document.
+///
+/// Documents that include other files should be loaded with [load] instead.
 impl TryFrom<KdlDocument> for Config {
     type Error = miette::Error;
 
-    fn try_from(value: KdlDocument) -> Result<Self, Self::Error> {
-        let SystemData {
-            threads_per_service,
-            daemonize,
-            upgrade_socket,
-            pid_file,
-        } = extract_system_data(&value)?;
-        let (basic_proxies, file_servers) = extract_services(threads_per_service, &value)?;
-
-        Ok(Config {
-            threads_per_service,
-            daemonize,
-            upgrade_socket,
-            pid_file,
-            basic_proxies,
-            file_servers,
-            ..Config::default()
-        })
+    fn try_from(mut value: KdlDocument) -> Result<Self, Self::Error> {
+        interpolate::interpolate(&mut value, &mut Secrets::default())?;
+        if let Some(node) = value.get("include") {
+            return Err(Bad::docspan(
+                "'include' can only be used when loading a configuration file",
+                &value,
+                node.span(),
+            )
+            .into());
+        }
+        render(&value, &[])
     }
 }
 
+/// Render the configuration of the main document, and the services of the
+/// documents it includes
+///
+/// Rather than stopping at the first error, the `system` section and every
+/// service are checked, and all of their errors are returned together.
+fn render(doc: &KdlDocument, included: &[Included]) -> miette::Result<Config> {
+    let mut errors = vec![];
+    let SystemData {
+        threads_per_service,
+        instance_name,
+        daemonize,
+        user,
+        group,
+        upgrade_socket,
+        shutdown,
+        pid_file,
+        metrics,
+        log,
+        tracing,
+        admin,
+    } = extract_system_data(doc).unwrap_or_else(|e| {
+        errors.push(e);
+        SystemData::default()
+    });
+    let Services {
+        proxies: basic_proxies,
+        file_servers,
+        stream_proxies,
+    } = extract_services(threads_per_service, doc, included, &mut errors);
+    Errors::check(errors)?;
+
+    Ok(Config {
+        threads_per_service,
+        instance_name,
+        daemonize,
+        user,
+        group,
+        upgrade_socket,
+        shutdown,
+        pid_file,
+        basic_proxies,
+        file_servers,
+        stream_proxies,
+        metrics,
+        log,
+        tracing,
+        admin,
+        ..Config::default()
+    })
+}
+
 struct SystemData {
     threads_per_service: usize,
+    instance_name: String,
     daemonize: bool,
+    user: Option<String>,
+    group: Option<String>,
     upgrade_socket: Option<PathBuf>,
+    shutdown: ShutdownConfig,
     pid_file: Option<PathBuf>,
+    metrics: Option<MetricsConfig>,
+    log: LogConfig,
+    tracing: Option<TracingConfig>,
+    admin: Option<AdminConfig>,
 }
 
 impl Default for SystemData {
     fn default() -> Self {
         Self {
             threads_per_service: 8,
+            instance_name: runtime::DEFAULT_INSTANCE_NAME.to_string(),
             daemonize: false,
+            user: None,
+            group: None,
             upgrade_socket: None,
+            shutdown: ShutdownConfig::default(),
             pid_file: None,
+            metrics: None,
+            log: LogConfig::default(),
+            tracing: None,
+            admin: None,
         }
     }
 }
 
-/// Extract all services from the top level document
+/// All services defined in the `services` block, by kind
+struct Services {
+    proxies: Vec<ProxyConfig>,
+    file_servers: Vec<FileServerConfig>,
+    stream_proxies: Vec<StreamProxyConfig>,
+}
+
+/// Extract all services from the `services` blocks of the top level document,
+/// and of the documents it includes
+///
+/// Errors are added to `errors`, and the services without errors are returned.
 fn extract_services(
     threads_per_service: usize,
     doc: &KdlDocument,
-) -> miette::Result<(Vec<ProxyConfig>, Vec<FileServerConfig>)> {
-    let service_node = utils::required_child_doc(doc, doc, "services")?;
-    let services = utils::wildcard_argless_child_docs(doc, service_node)?;
-
-    let proxy_node_set =
-        HashSet::from(["listeners", "connectors", "path-control", "rate-limiting"]);
-    let file_server_node_set = HashSet::from(["listeners", "file-server"]);
-
-    let mut proxies = vec![];
-    let mut file_servers = vec![];
-
-    for (name, service) in services {
-        // First, visit all of the children nodes, and make sure each child
-        // node only appears once. This is used to detect duplicate sections
-        let mut fingerprint_set: HashSet<&str> = HashSet::new();
-        for ch in service.nodes() {
-            let name = ch.name().value();
-            let dupe = !fingerprint_set.insert(name);
-            if dupe {
-                return Err(
-                    Bad::docspan(format!("Duplicate section: '{name}'!"), doc, ch.span()).into(),
-                );
+    included: &[Included],
+    errors: &mut Vec<miette::Report>,
+) -> Services {
+    let mut blocks = vec![];
+    if included.is_empty() || doc.get("services").is_some() {
+        match utils::required_child_doc(doc, doc, "services") {
+            Ok(service_node) => blocks.push((doc, None, service_node)),
+            Err(e) => errors.push(e),
+        }
+    }
+    for inc in included {
+        match utils::required_child_doc(&inc.doc, &inc.doc, "services") {
+            Ok(service_node) => blocks.push((&inc.doc, Some(inc.path.as_path()), service_node)),
+            Err(e) => errors.push(Bad::in_file(e, &inc.path)),
+        }
+    }
+
+    let mut services = Services {
+        proxies: vec![],
+        file_servers: vec![],
+        stream_proxies: vec![],
+    };
+    // Where each service was defined, `None` being the top level document
+    let mut defined: HashMap<&str, Option<&Path>> = HashMap::new();
+
+    for (doc, file, service_node) in blocks {
+        // Errors in included documents are reported with the name of the file
+        let in_file = |e: miette::Report| match file {
+            Some(path) => Bad::in_file(e, path),
+            None => e,
+        };
+        let block = extract_templates(doc).and_then(|templates| {
+            let list = utils::wildcard_argless_child_docs(doc, service_node)?;
+            Ok((templates, list))
+        });
+        let (templates, list) = match block {
+            Ok(block) => block,
+            Err(e) => {
+                errors.push(in_file(e));
+                continue;
+            }
+        };
+        for (name, service) in list {
+            let res = match defined.insert(name, file) {
+                Some(first) => {
+                    let place = match first {
+                        Some(path) => format!("'{}'", path.display()),
+                        None => "the main configuration file".to_string(),
+                    };
+                    Err(Bad::docspan(
+                        format!("Service '{name}' is already defined in {place}"),
+                        doc,
+                        service.span(),
+                    )
+                    .into())
+                }
+                None => apply_templates(doc, &templates, service).and_then(|service| {
+                    extract_any_service(threads_per_service, doc, name, &service, &mut services)
+                }),
+            };
+            if let Err(e) = res {
+                errors.push(in_file(e));
             }
         }
+    }
 
-        // Now: what do we do with this node?
-        if fingerprint_set.is_subset(&proxy_node_set) {
-            // If the contained nodes are a strict subset of proxy node config fields,
-            // then treat this section as a proxy node
-            proxies.push(extract_service(threads_per_service, doc, name, service)?);
-        } else if fingerprint_set.is_subset(&file_server_node_set) {
-            // If the contained nodes are a strict subset of the file server config
-            // fields, then treat this section as a file server node
-            file_servers.push(extract_file_server(doc, name, service)?);
-        } else {
-            // Otherwise, we're not sure what this node is supposed to be!
-            //
-            // Obtain the superset of ALL potential nodes, which is essentially
-            // our configuration grammar.
-            let superset: HashSet<&str> = proxy_node_set
-                .union(&file_server_node_set)
-                .cloned()
-                .collect();
-
-            // Then figure out what fields our fingerprint set contains that
-            // is "novel", or basically fields we don't know about
-            let what = fingerprint_set
-                .difference(&superset)
-                .copied()
-                .collect::<Vec<&str>>()
-                .join(", ");
-
-            // Then inform the user about the reason for our discontent
+    if errors.is_empty()
+        && services.proxies.is_empty()
+        && services.file_servers.is_empty()
+        && services.stream_proxies.is_empty()
+    {
+        let span = utils::optional_child_doc(doc, doc, "services").map_or(doc.span(), |s| s.span());
+        errors.push(Bad::docspan("No services defined", doc, span).into());
+    }
+
+    services
+}
+
+/// Extract the `templates` block of a document, if any
+///
+/// ```kdl
+/// templates {
+///     Common {
+///         rate-limiting {
+///             rule kind="source-ip" max-buckets=4000 tokens-per-bucket=10 refill-qty=1 refill-rate-ms=10
+///         }
+///     }
+/// }
+/// ```
+fn extract_templates(doc: &KdlDocument) -> miette::Result<HashMap<&str, &KdlDocument>> {
+    let mut templates = HashMap::new();
+    let Some(templates_node) = utils::optional_child_doc(doc, doc, "templates") else {
+        return Ok(templates);
+    };
+    for (name, template) in utils::wildcard_argless_child_docs(doc, templates_node)? {
+        let mut sections = HashSet::new();
+        for section in template.nodes() {
+            let section_name = section.name().value();
+            if section_name == "use" {
+                return Err(Bad::docspan(
+                    "templates can't use other templates",
+                    doc,
+                    section.span(),
+                )
+                .into());
+            }
+            if !sections.insert(section_name) {
+                return Err(Bad::docspan(
+                    format!("Duplicate section: '{section_name}'!"),
+                    doc,
+                    section.span(),
+                )
+                .into());
+            }
+        }
+        if templates.insert(name, template).is_some() {
             return Err(Bad::docspan(
-                format!("Unknown configuration section(s): {what}"),
+                format!("Template '{name}' is already defined"),
                 doc,
-                service.span(),
+                template.span(),
             )
             .into());
         }
     }
+    Ok(templates)
+}
+
+/// Add the sections a service inherits from templates with `use "name"`
+///
+/// A section of the service itself overrides the same section of its templates,
+/// as a whole. Two templates of the same service can't have the same section,
+/// unless the service overrides it.
+fn apply_templates(
+    doc: &KdlDocument,
+    templates: &HashMap<&str, &KdlDocument>,
+    service: &KdlDocument,
+) -> miette::Result<KdlDocument> {
+    let mut resolved = service.clone();
+    resolved.nodes_mut().retain(|n| n.name().value() != "use");
+
+    let mut used = HashSet::new();
+    // The template each inherited section came from
+    let mut inherited: HashMap<&str, &str> = HashMap::new();
+    for node in service.nodes().iter().filter(|n| n.name().value() == "use") {
+        if node.entries().is_empty() {
+            return Err(
+                Bad::docspan("'use' requires the names of templates", doc, node.span()).into(),
+            );
+        }
+        for entry in node.entries() {
+            let template_name = entry
+                .value()
+                .as_string()
+                .filter(|_| entry.name().is_none())
+                .or_bail("expected the name of a template", doc, entry.span())?;
+            let template = templates.get(template_name).or_bail(
+                format!(
+                    "Unknown template '{template_name}'. Templates must be defined in the same file as the services that use them"
+                ),
+                doc,
+                entry.span(),
+            )?;
+            if !used.insert(template_name) {
+                return Err(Bad::docspan(
+                    format!("Template '{template_name}' is already used"),
+                    doc,
+                    entry.span(),
+                )
+                .into());
+            }
 
-    if proxies.is_empty() && file_servers.is_empty() {
-        return Err(Bad::docspan("No services defined", doc, service_node.span()).into());
+            for section in template.nodes() {
+                let section_name = section.name().value();
+                let overridden = service
+                    .nodes()
+                    .iter()
+                    .any(|n| n.name().value() == section_name);
+                if overridden {
+                    continue;
+                }
+                if let Some(first) = inherited.insert(section_name, template_name) {
+                    return Err(Bad::docspan(
+                        format!(
+                            "Section '{section_name}' is in both templates '{first}' and '{template_name}', it must be set by the service itself"
+                        ),
+                        doc,
+                        entry.span(),
+                    )
+                    .into());
+                }
+                resolved.nodes_mut().push(section.clone());
+            }
+        }
     }
+    Ok(resolved)
+}
 
-    Ok((proxies, file_servers))
+/// Extract a single service of any kind, depending on the sections it contains
+fn extract_any_service(
+    threads_per_service: usize,
+    doc: &KdlDocument,
+    name: &str,
+    service: &KdlDocument,
+    services: &mut Services,
+) -> miette::Result<()> {
+    let proxy_node_set = HashSet::from([
+        "listeners",
+        "connectors",
+        "path-control",
+        "rate-limiting",
+        "websocket",
+        "access-log",
+    ]);
+    let file_server_node_set = HashSet::from(["listeners", "file-server", "access-log"]);
+    let stream_proxy_node_set =
+        HashSet::from(["listeners", "connectors", "stream-proxy", "rate-limiting"]);
+
+    // First, visit all of the children nodes, and make sure each child
+    // node only appears once. This is used to detect duplicate sections
+    let mut fingerprint_set: HashSet<&str> = HashSet::new();
+    for ch in service.nodes() {
+        let name = ch.name().value();
+        let dupe = !fingerprint_set.insert(name);
+        if dupe {
+            return Err(
+                Bad::docspan(format!("Duplicate section: '{name}'!"), doc, ch.span()).into(),
+            );
+        }
+    }
+
+    // Now: what do we do with this node?
+    if fingerprint_set.is_subset(&proxy_node_set) {
+        // If the contained nodes are a strict subset of proxy node config fields,
+        // then treat this section as a proxy node
+        services
+            .proxies
+            .push(extract_service(threads_per_service, doc, name, service)?);
+    } else if fingerprint_set.is_subset(&file_server_node_set) {
+        // If the contained nodes are a strict subset of the file server config
+        // fields, then treat this section as a file server node
+        services
+            .file_servers
+            .push(extract_file_server(doc, name, service)?);
+    } else if fingerprint_set.is_subset(&stream_proxy_node_set) {
+        // If the contained nodes are a strict subset of the stream proxy config
+        // fields, then treat this section as a stream proxy node. The
+        // `stream-proxy` section is what sets this apart from a proxy node.
+        services.stream_proxies.push(extract_stream_proxy(
+            threads_per_service,
+            doc,
+            name,
+            service,
+        )?);
+    } else {
+        // Otherwise, we're not sure what this node is supposed to be!
+        //
+        // Obtain the superset of ALL potential nodes, which is essentially
+        // our configuration grammar.
+        let superset: HashSet<&str> = proxy_node_set
+            .iter()
+            .chain(file_server_node_set.iter())
+            .chain(stream_proxy_node_set.iter())
+            .cloned()
+            .collect();
+
+        // Then figure out what fields our fingerprint set contains that
+        // is "novel", or basically fields we don't know about
+        let what = fingerprint_set
+            .difference(&superset)
+            .copied()
+            .collect::<Vec<&str>>()
+            .join(", ");
+
+        // Then inform the user about the reason for our discontent
+        return Err(Bad::docspan(
+            format!("Unknown configuration section(s): {what}"),
+            doc,
+            service.span(),
+        )
+        .into());
+    }
+
+    Ok(())
 }
 
 /// Collects all the filters, where the node name must be "filter", and the rest of the args
-/// are collected as a BTreeMap of String:String values
+/// are the settings of the filter, checked by `parse`
 ///
 /// ```kdl
 /// upstream-request {
@@ -157,56 +464,93 @@ fn extract_services(
 /// }
 /// ```
 ///
-/// creates something like:
-///
-/// ```json
-/// [
-///     { kind: "remove-header-key-regex", pattern: ".*SECRET.*" },
-///     { kind: "remove-header-key-regex", pattern: ".*secret.*" },
-///     { kind: "upsert-header", key: "x-proxy-friend", value: "river" }
-/// ]
-/// ```
-fn collect_filters(
+/// Errors in the settings of a filter point at its `filter` node.
+fn collect_filters<T>(
     doc: &KdlDocument,
     node: &KdlDocument,
-) -> miette::Result<Vec<BTreeMap<String, String>>> {
+    parse: fn(BTreeMap<String, String>) -> Result<T, String>,
+) -> miette::Result<Vec<T>> {
     let filters = utils::data_nodes(doc, node)?;
     let mut fout = vec![];
-    for (_node, name, args) in filters {
-        if name != "filter" {
-            bail!("Invalid Filter Rule");
-        }
-        let args = utils::str_str_args(doc, args)?;
-        fout.push(
-            args.iter()
-                .map(|(k, v)| (k.to_string(), v.to_string()))
-                .collect(),
-        );
+    let mut errors = vec![];
+    for (node, name, args) in filters {
+        let filter = if name != "filter" {
+            Err(Bad::docspan("Invalid Filter Rule", doc, node.span()).into())
+        } else {
+            utils::str_str_args(doc, args).and_then(|args| {
+                let settings = args
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect();
+                parse(settings).map_err(|e| Bad::docspan(e, doc, node.span()).into())
+            })
+        };
+        match filter {
+            Ok(filter) => fout.push(filter),
+            Err(e) => errors.push(e),
+        }
     }
+    Errors::check(errors)?;
     Ok(fout)
 }
 
-/// Extracts a single file server from the `services` block
-fn extract_file_server(
+/// Extracts the `listeners` section of an HTTP service
+fn extract_http_listeners(
     doc: &KdlDocument,
-    name: &str,
     node: &KdlDocument,
-) -> miette::Result<FileServerConfig> {
-    // Listeners
-    //
+) -> miette::Result<Vec<ListenerConfig>> {
     let listener_node = utils::required_child_doc(doc, node, "listeners")?;
     let listeners = utils::data_nodes(doc, listener_node)?;
     if listeners.is_empty() {
         return Err(Bad::docspan("nonzero listeners required", doc, listener_node.span()).into());
     }
     let mut list_cfgs = vec![];
+    let mut errors = vec![];
     for (node, name, args) in listeners {
-        let listener = extract_listener(doc, node, name, args)?;
-        list_cfgs.push(listener);
+        match extract_listener(doc, node, name, args) {
+            Ok(listener) => list_cfgs.push(listener),
+            Err(e) => errors.push(e),
+        }
     }
+    Errors::check(errors)?;
+    check_h2c_listeners(&list_cfgs).map_err(|e| Bad::docspan(e, doc, listener_node.span()))?;
+    Ok(list_cfgs)
+}
+
+/// Extracts a single file server from the `services` block
+fn extract_file_server(
+    doc: &KdlDocument,
+    name: &str,
+    node: &KdlDocument,
+) -> miette::Result<FileServerConfig> {
+    let mut errors = vec![];
+
+    // Listeners
+    //
+    let list_cfgs = keep(extract_http_listeners(doc, node), &mut errors);
 
     // Base Path
     //
+    let base_path = keep(extract_base_path(doc, node), &mut errors);
+
+    // Access log (optional)
+    let access_log = match utils::optional_child_doc(doc, node, "access-log") {
+        Some(al_node) => keep(extract_access_log(doc, al_node).map(Some), &mut errors),
+        None => None,
+    };
+
+    Errors::check(errors)?;
+
+    Ok(FileServerConfig {
+        name: name.to_string(),
+        listeners: list_cfgs,
+        base_path,
+        access_log,
+    })
+}
+
+/// Extracts the `file-server` section of a file server
+fn extract_base_path(doc: &KdlDocument, node: &KdlDocument) -> miette::Result<Option<PathBuf>> {
     let fs_node = utils::required_child_doc(doc, node, "file-server")?;
     let data_nodes = utils::data_nodes(doc, fs_node)?;
     let mut map = HashMap::new();
@@ -221,106 +565,566 @@ fn extract_file_server(
     } else {
         None
     };
+    Ok(base_path)
+}
+
+/// Extracts a single service from the `services` block
+fn extract_service(
+    threads_per_service: usize,
+    doc: &KdlDocument,
+    name: &str,
+    node: &KdlDocument,
+) -> miette::Result<ProxyConfig> {
+    let mut errors = vec![];
+
+    // Listeners
+    //
+    let list_cfgs = keep(extract_http_listeners(doc, node), &mut errors);
+
+    // Connectors
+    //
+    let (conn_cfgs, load_balance) = keep(extract_connectors(doc, node), &mut errors);
+
+    // Path Control (optional)
+    //
+    let pc = match utils::optional_child_doc(doc, node, "path-control") {
+        Some(pc_node) => keep(extract_path_control(doc, pc_node), &mut errors),
+        None => PathControl::default(),
+    };
+
+    // Rate limiting
+    let rl = match utils::optional_child_doc(doc, node, "rate-limiting") {
+        Some(rl_node) => keep(
+            extract_rate_limiting(threads_per_service, doc, rl_node),
+            &mut errors,
+        ),
+        None => RateLimitingConfig::default(),
+    };
+
+    // WebSockets (optional)
+    let websocket = match utils::optional_child_doc(doc, node, "websocket") {
+        Some(ws_node) => keep(extract_websocket(doc, ws_node), &mut errors),
+        None => WebSocketConfig::default(),
+    };
+
+    // Access log (optional)
+    let access_log = match utils::optional_child_doc(doc, node, "access-log") {
+        Some(al_node) => keep(extract_access_log(doc, al_node).map(Some), &mut errors),
+        None => None,
+    };
+
+    Errors::check(errors)?;
+    Ok(ProxyConfig {
+        name: name.to_string(),
+        listeners: list_cfgs,
+        upstreams: conn_cfgs,
+        path_control: pc,
+        upstream_options: load_balance,
+        rate_limiting: rl,
+        websocket,
+        access_log,
+    })
+}
+
+/// Extracts the `connectors` section of a proxy service, and its load balancing
+/// options
+fn extract_connectors(
+    doc: &KdlDocument,
+    node: &KdlDocument,
+) -> miette::Result<(Vec<HttpPeer>, UpstreamOptions)> {
+    let conn_node = utils::required_child_doc(doc, node, "connectors")?;
+    let conns = utils::data_nodes(doc, conn_node)?;
+    let mut conn_cfgs = vec![];
+    let mut load_balance: Option<UpstreamOptions> = None;
+    let mut errors = vec![];
+    for (node, name, args) in conns {
+        if name == "load-balance" {
+            if load_balance.is_some() {
+                errors.push(
+                    Bad::docspan("Don't have two 'load-balance' sections", doc, node.span()).into(),
+                );
+                continue;
+            }
+            load_balance = Some(keep(
+                extract_load_balance(doc, node, HTTP_SELECTOR_KEYS),
+                &mut errors,
+            ));
+            continue;
+        }
+        match extract_connector(doc, node, name, args) {
+            Ok(conn) => conn_cfgs.push(conn),
+            Err(e) => errors.push(e),
+        }
+    }
+    if conn_cfgs.is_empty() && errors.is_empty() {
+        errors
+            .push(Bad::docspan("We require at least one connector", doc, conn_node.span()).into());
+    }
+    Errors::check(errors)?;
+    Ok((conn_cfgs, load_balance.unwrap_or_default()))
+}
+
+/// Extracts the `path-control` section of a proxy service
+fn extract_path_control(doc: &KdlDocument, node: &KdlDocument) -> miette::Result<PathControl> {
+    let mut pc = PathControl::default();
+    let mut errors = vec![];
+
+    // request-filters (optional)
+    if let Some(ureq_node) = utils::optional_child_doc(doc, node, "request-filters") {
+        pc.request_filters = keep(
+            collect_filters(doc, ureq_node, RequestFilterConfig::from_settings),
+            &mut errors,
+        );
+    }
+
+    // upstream-request (optional)
+    if let Some(ureq_node) = utils::optional_child_doc(doc, node, "upstream-request") {
+        pc.upstream_request_filters = keep(
+            collect_filters(doc, ureq_node, HeaderModifierConfig::from_settings),
+            &mut errors,
+        );
+    }
+
+    // upstream-response (optional)
+    if let Some(uresp_node) = utils::optional_child_doc(doc, node, "upstream-response") {
+        pc.upstream_response_filters = keep(
+            collect_filters(doc, uresp_node, HeaderModifierConfig::from_settings),
+            &mut errors,
+        );
+    }
+
+    Errors::check(errors)?;
+    Ok(pc)
+}
+
+/// Extracts the `access-log` section of a service
+///
+/// ```kdl
+/// access-log {
+///     format "template" pattern="$remote_addr $method $uri $status"
+///     output "file" path="/var/log/river/access.log"
+/// }
+/// ```
+fn extract_access_log(doc: &KdlDocument, node: &KdlDocument) -> miette::Result<AccessLogConfig> {
+    let mut format = AccessLogFormat::Combined;
+    let mut output = AccessLogOutput::Stdout;
+    for (node, name, args) in utils::data_nodes(doc, node)? {
+        match name {
+            "format" => {
+                let (kind, args) =
+                    utils::extract_one_str_arg_with_kv_args(doc, node, name, args, |s| {
+                        Some(s.to_string())
+                    })?;
+                let pattern = args.get("pattern");
+                format = match (kind.as_str(), pattern) {
+                    ("json", None) => AccessLogFormat::Json,
+                    ("combined", None) => AccessLogFormat::Combined,
+                    ("template", Some(pattern)) => {
+                        let template = Template::parse(pattern)
+                            .map_err(|e| Bad::docspan(e, doc, node.span()))?;
+                        AccessLogFormat::Template(template)
+                    }
+                    _ => {
+                        return Err(Bad::docspan(
+                            "expected 'format \"json\"', 'format \"combined\"', or 'format \"template\" pattern=\"...\"'",
+                            doc,
+                            node.span(),
+                        )
+                        .into());
+                    }
+                };
+            }
+            "output" => {
+                let (kind, args) =
+                    utils::extract_one_str_arg_with_kv_args(doc, node, name, args, |s| {
+                        Some(s.to_string())
+                    })?;
+                let path = args.get("path").map(PathBuf::from);
+                output = match (kind.as_str(), path) {
+                    ("stdout", None) => AccessLogOutput::Stdout,
+                    ("file", Some(path)) => AccessLogOutput::File(path),
+                    ("unix-datagram", Some(path)) => AccessLogOutput::UnixDatagram(path),
+                    _ => {
+                        return Err(Bad::docspan(
+                            "expected 'output \"stdout\"', 'output \"file\" path=\"...\"', or 'output \"unix-datagram\" path=\"...\"'",
+                            doc,
+                            node.span(),
+                        )
+                        .into());
+                    }
+                };
+            }
+            other => {
+                return Err(
+                    Bad::docspan(format!("Unknown setting: '{other}'"), doc, node.span()).into(),
+                );
+            }
+        }
+    }
+    Ok(AccessLogConfig { format, output })
+}
+
+/// Extracts a single stream proxy from the `services` block
+fn extract_stream_proxy(
+    threads_per_service: usize,
+    doc: &KdlDocument,
+    name: &str,
+    node: &KdlDocument,
+) -> miette::Result<StreamProxyConfig> {
+    let mut errors = vec![];
+
+    // Listeners
+    //
+    let listener_node = utils::required_child_doc(doc, node, "listeners")?;
+    let listeners = utils::data_nodes(doc, listener_node)?;
+    if listeners.is_empty() {
+        return Err(Bad::docspan("nonzero listeners required", doc, listener_node.span()).into());
+    }
+    let mut list_cfgs = vec![];
+    for (node, name, args) in listeners {
+        if args
+            .iter()
+            .any(|a| a.name().map(|n| n.value()) == Some("offer-h2"))
+        {
+            errors.push(
+                Bad::docspan(
+                    "'offer-h2' can't be used with a stream proxy",
+                    doc,
+                    node.span(),
+                )
+                .into(),
+            );
+            continue;
+        }
+        let mut listener = match extract_listener(doc, node, name, args) {
+            Ok(listener) => listener,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        // Streams are forwarded as-is, we never offer HTTP2 via ALPN
+        if let ListenerKind::Tcp { offer_h2, .. } | ListenerKind::Systemd { offer_h2, .. } =
+            &mut listener.source
+        {
+            *offer_h2 = false;
+        }
+        list_cfgs.push(listener);
+    }
+
+    // Stream proxy settings
+    //
+    let mut connection_filters = vec![];
+    let mut tls_passthrough = None;
+    if let Some(sp_node) = utils::optional_child_doc(doc, node, "stream-proxy") {
+        for (node, name, _args) in utils::data_nodes(doc, sp_node)? {
+            match name {
+                "connection-filters" => {
+                    let filters = node
+                        .children()
+                        .or_bail(
+                            "'connection-filters' should have children",
+                            doc,
+                            node.span(),
+                        )
+                        .and_then(|filters| {
+                            collect_filters(doc, filters, ConnectionFilterConfig::from_settings)
+                        });
+                    connection_filters = keep(filters, &mut errors);
+                }
+                "tls-passthrough" => match extract_tls_passthrough(doc, node) {
+                    Ok(routes) => tls_passthrough = Some(routes),
+                    Err(e) => errors.push(e),
+                },
+                other => {
+                    errors.push(
+                        Bad::docspan(format!("Unknown setting: '{other}'"), doc, node.span())
+                            .into(),
+                    );
+                }
+            }
+        }
+    }
+
+    // Passed through TLS connections are never terminated
+    if tls_passthrough.is_some() {
+        let nodes = utils::data_nodes(doc, listener_node)?;
+        for ((node, _name, _args), list_cfg) in nodes.into_iter().zip(list_cfgs.iter()) {
+            if list_cfg.source.tls().is_some() {
+                errors.push(
+                    Bad::docspan(
+                        "listeners can't use TLS together with 'tls-passthrough'",
+                        doc,
+                        node.span(),
+                    )
+                    .into(),
+                );
+            }
+        }
+    }
+
+    // Connectors
+    //
+    // With TLS passthrough, these are optional, and used when no SNI route matches
+    let mut conn_cfgs = vec![];
+    let mut load_balance: Option<UpstreamOptions> = None;
+    let conn_node = match tls_passthrough {
+        Some(_) => utils::optional_child_doc(doc, node, "connectors"),
+        None => Some(utils::required_child_doc(doc, node, "connectors")?),
+    };
+    if let Some(conn_node) = conn_node {
+        for (node, name, args) in utils::data_nodes(doc, conn_node)? {
+            if name == "load-balance" {
+                if load_balance.is_some() {
+                    errors.push(
+                        Bad::docspan("Don't have two 'load-balance' sections", doc, node.span())
+                            .into(),
+                    );
+                    continue;
+                }
+                load_balance = Some(keep(
+                    extract_load_balance(doc, node, STREAM_SELECTOR_KEYS),
+                    &mut errors,
+                ));
+                continue;
+            }
+            if tls_passthrough.is_some() && !args.is_empty() {
+                errors.push(
+                    Bad::docspan(
+                        "connectors can't use TLS together with 'tls-passthrough'",
+                        doc,
+                        node.span(),
+                    )
+                    .into(),
+                );
+                continue;
+            }
+            match extract_stream_connector(doc, node, name, args) {
+                Ok(conn) => conn_cfgs.push(conn),
+                Err(e) => errors.push(e),
+            }
+        }
+        if conn_cfgs.is_empty() && tls_passthrough.is_none() && errors.is_empty() {
+            errors.push(
+                Bad::docspan("We require at least one connector", doc, conn_node.span()).into(),
+            );
+        }
+    }
+
+    // Rate limiting (optional)
+    //
+    // Only rules that can be applied to a connection, rather than a request, are allowed
+    let mut rl = RateLimitingConfig::default();
+    if let Some(rl_node) = utils::optional_child_doc(doc, node, "rate-limiting") {
+        rl = keep(
+            extract_rate_limiting(threads_per_service, doc, rl_node),
+            &mut errors,
+        );
+        if !rl.per_connection() {
+            errors.push(
+                Bad::docspan(
+                    "stream proxies only support 'source-ip' rate limiting rules",
+                    doc,
+                    rl_node.span(),
+                )
+                .into(),
+            );
+        }
+    }
+
+    Errors::check(errors)?;
+
+    Ok(StreamProxyConfig {
+        name: name.to_string(),
+        listeners: list_cfgs,
+        upstream_options: load_balance.unwrap_or_default(),
+        upstreams: conn_cfgs,
+        connection_filters,
+        rate_limiting: rl,
+        tls_passthrough,
+    })
+}
+
+/// Extracts the `tls-passthrough` section of a stream proxy
+///
+/// ```kdl
+/// tls-passthrough {
+///     "db.example.com" {
+///         "10.0.0.1:5432"
+///     }
+///     "*.example.com" {
+///         "10.0.0.2:5432"
+///     }
+/// }
+/// ```
+fn extract_tls_passthrough(
+    doc: &KdlDocument,
+    node: &KdlNode,
+) -> miette::Result<BTreeMap<String, Vec<BasicPeer>>> {
+    let routes =
+        node.children()
+            .or_bail("'tls-passthrough' should have children", doc, node.span())?;
+
+    let mut out = BTreeMap::new();
+    for (node, name, args) in utils::data_nodes(doc, routes)? {
+        if !is_server_name_pattern(name) || !args.is_empty() {
+            return Err(Bad::docspan(
+                "expected a server name, or a wildcard such as '*.example.com'",
+                doc,
+                node.span(),
+            )
+            .into());
+        }
+        let pool = node.children().or_bail(
+            format!("'{name}' should contain one or more connectors"),
+            doc,
+            node.span(),
+        )?;
+
+        let mut peers = vec![];
+        for (node, name, args) in utils::data_nodes(doc, pool)? {
+            if !args.is_empty() {
+                return Err(Bad::docspan(
+                    "connectors can't use TLS together with 'tls-passthrough'",
+                    doc,
+                    node.span(),
+                )
+                .into());
+            }
+            peers.push(extract_stream_connector(doc, node, name, args)?);
+        }
+        if peers.is_empty() {
+            return Err(Bad::docspan(
+                format!("'{name}' should contain one or more connectors"),
+                doc,
+                node.span(),
+            )
+            .into());
+        }
 
-    Ok(FileServerConfig {
-        name: name.to_string(),
-        listeners: list_cfgs,
-        base_path,
-    })
+        // Server names are case insensitive
+        if out.insert(name.to_ascii_lowercase(), peers).is_some() {
+            return Err(
+                Bad::docspan(format!("Duplicate server name: '{name}'"), doc, node.span()).into(),
+            );
+        }
+    }
+    if out.is_empty() {
+        return Err(Bad::docspan(
+            "'tls-passthrough' requires at least one server name",
+            doc,
+            node.span(),
+        )
+        .into());
+    }
+    Ok(out)
 }
 
-/// Extracts a single service from the `services` block
-fn extract_service(
-    threads_per_service: usize,
+/// Extracts a single connector from the `connectors` section of a stream proxy
+fn extract_stream_connector(
     doc: &KdlDocument,
+    node: &KdlNode,
     name: &str,
-    node: &KdlDocument,
-) -> miette::Result<ProxyConfig> {
-    // Listeners
-    //
-    let listener_node = utils::required_child_doc(doc, node, "listeners")?;
-    let listeners = utils::data_nodes(doc, listener_node)?;
-    if listeners.is_empty() {
-        return Err(Bad::docspan("nonzero listeners required", doc, listener_node.span()).into());
-    }
-    let mut list_cfgs = vec![];
-    for (node, name, args) in listeners {
-        let listener = extract_listener(doc, node, name, args)?;
-        list_cfgs.push(listener);
-    }
+    args: &[KdlEntry],
+) -> miette::Result<BasicPeer> {
+    let Ok(sadd) = name.parse::<SocketAddr>() else {
+        return Err(Bad::docspan("Not a valid socket address", doc, node.span()).into());
+    };
 
-    // Connectors
-    //
-    let conn_node = utils::required_child_doc(doc, node, "connectors")?;
-    let conns = utils::data_nodes(doc, conn_node)?;
-    let mut conn_cfgs = vec![];
-    let mut load_balance: Option<UpstreamOptions> = None;
-    for (node, name, args) in conns {
-        if name == "load-balance" {
-            if load_balance.is_some() {
-                panic!("Don't have two 'load-balance' sections");
+    let mut sni = String::new();
+    for (key, val) in utils::str_str_args(doc, args)? {
+        match key {
+            "tls-sni" => sni = val.to_string(),
+            other => {
+                return Err(Bad::docspan(
+                    format!("Unknown argument for a stream connector: '{other}'"),
+                    doc,
+                    node.span(),
+                )
+                .into());
             }
-            load_balance = Some(extract_load_balance(doc, node)?);
-            continue;
         }
-        let conn = extract_connector(doc, node, name, args)?;
-        conn_cfgs.push(conn);
     }
-    if conn_cfgs.is_empty() {
-        return Err(
-            Bad::docspan("We require at least one connector", doc, conn_node.span()).into(),
-        );
-    }
-
-    // Path Control (optional)
-    //
-    let mut pc = PathControl::default();
-    if let Some(pc_node) = utils::optional_child_doc(doc, node, "path-control") {
-        // request-filters (optional)
-        if let Some(ureq_node) = utils::optional_child_doc(doc, pc_node, "request-filters") {
-            pc.request_filters = collect_filters(doc, ureq_node)?;
-        }
-
-        // upstream-request (optional)
-        if let Some(ureq_node) = utils::optional_child_doc(doc, pc_node, "upstream-request") {
-            pc.upstream_request_filters = collect_filters(doc, ureq_node)?;
-        }
 
-        // upstream-response (optional)
-        if let Some(uresp_node) = utils::optional_child_doc(doc, pc_node, "upstream-response") {
-            pc.upstream_response_filters = collect_filters(doc, uresp_node)?
-        }
-    }
+    // NOTE: BasicPeer uses TLS when the SNI is not empty
+    let mut peer = BasicPeer::new(&sadd.to_string());
+    peer.sni = sni;
+    Ok(peer)
+}
 
-    // Rate limiting
+/// Extracts the `rate-limiting` section of a service
+fn extract_rate_limiting(
+    threads_per_service: usize,
+    doc: &KdlDocument,
+    node: &KdlDocument,
+) -> miette::Result<RateLimitingConfig> {
     let mut rl = RateLimitingConfig::default();
-    if let Some(rl_node) = utils::optional_child_doc(doc, node, "rate-limiting") {
-        let nodes = utils::data_nodes(doc, rl_node)?;
-        for (node, name, args) in nodes.iter() {
-            if *name == "rule" {
-                let vals = utils::str_value_args(doc, args)?;
+    let mut errors = vec![];
+    let nodes = utils::data_nodes(doc, node)?;
+    for (node, name, args) in nodes.iter() {
+        let rule = if *name == "rule" {
+            utils::str_value_args(doc, args).and_then(|vals| {
                 let valslice = vals
                     .iter()
                     .map(|(k, v)| (*k, v.value()))
                     .collect::<BTreeMap<&str, &KdlValue>>();
-                rl.rules
-                    .push(make_rate_limiter(threads_per_service, doc, node, valslice)?);
-            } else {
+                make_rate_limiter(threads_per_service, doc, node, valslice)
+            })
+        } else {
+            Err(Bad::docspan(format!("Unknown name: '{name}'"), doc, node.span()).into())
+        };
+        match rule {
+            Ok(rule) => rl.rules.push(rule),
+            Err(e) => errors.push(e),
+        }
+    }
+    Errors::check(errors)?;
+    Ok(rl)
+}
+
+/// Extracts the `websocket` section of a proxy service
+fn extract_websocket(doc: &KdlDocument, node: &KdlDocument) -> miette::Result<WebSocketConfig> {
+    let mut ws = WebSocketConfig::default();
+    for (node, name, args) in utils::data_nodes(doc, node)? {
+        match name {
+            "allow" => {
+                ws.allow = utils::extract_one_bool_arg(doc, node, name, args)?;
+            }
+            "idle-timeout-secs" => {
+                let secs = utils::extract_one_u64_arg(doc, node, name, args)?;
+                ws.idle_timeout = Some(Duration::from_secs(secs));
+            }
+            other => {
                 return Err(
-                    Bad::docspan(format!("Unknown name: '{name}'"), doc, node.span()).into(),
+                    Bad::docspan(format!("Unknown setting: '{other}'"), doc, node.span()).into(),
                 );
             }
         }
     }
+    Ok(ws)
+}
 
-    Ok(ProxyConfig {
-        name: name.to_string(),
-        listeners: list_cfgs,
-        upstreams: conn_cfgs,
-        path_control: pc,
-        upstream_options: load_balance.unwrap_or_default(),
-        rate_limiting: rl,
-    })
+/// Extracts the `system.upgrade` section
+fn extract_shutdown(doc: &KdlDocument, node: &KdlDocument) -> miette::Result<ShutdownConfig> {
+    let mut shutdown = ShutdownConfig::default();
+    for (node, name, args) in utils::data_nodes(doc, node)? {
+        match name {
+            "grace-period-secs" => {
+                let secs = utils::extract_one_u64_arg(doc, node, name, args)?;
+                shutdown.grace_period = Some(Duration::from_secs(secs));
+            }
+            "shutdown-timeout-secs" => {
+                let secs = utils::extract_one_u64_arg(doc, node, name, args)?;
+                shutdown.shutdown_timeout = Some(Duration::from_secs(secs));
+            }
+            other => {
+                return Err(
+                    Bad::docspan(format!("Unknown setting: '{other}'"), doc, node.span()).into(),
+                );
+            }
+        }
+    }
+    Ok(shutdown)
 }
 
 fn make_rate_limiter(
@@ -424,7 +1228,11 @@ fn make_rate_limiter(
 }
 
 /// Extracts the `load-balance` structure from the `connectors` section
-fn extract_load_balance(doc: &KdlDocument, node: &KdlNode) -> miette::Result<UpstreamOptions> {
+fn extract_load_balance(
+    doc: &KdlDocument,
+    node: &KdlNode,
+    keys: &[(&str, RequestSelector)],
+) -> miette::Result<UpstreamOptions> {
     let items = utils::data_nodes(
         doc,
         node.children()
@@ -439,19 +1247,10 @@ fn extract_load_balance(doc: &KdlDocument, node: &KdlNode) -> miette::Result<Ups
     for (node, name, args) in items {
         match name {
             "selection" => {
-                let (sel, args) = utils::extract_one_str_arg_with_kv_args(
-                    doc,
-                    node,
-                    name,
-                    args,
-                    |val| match val {
-                        "RoundRobin" => Some(SelectionKind::RoundRobin),
-                        "Random" => Some(SelectionKind::Random),
-                        "FNV" => Some(SelectionKind::Fnv),
-                        "Ketama" => Some(SelectionKind::Ketama),
-                        _ => None,
-                    },
-                )?;
+                let (sel, args) =
+                    utils::extract_one_str_arg_with_kv_args(doc, node, name, args, |val| {
+                        val.parse::<SelectionKind>().ok()
+                    })?;
                 match sel {
                     SelectionKind::RoundRobin | SelectionKind::Random => {
                         // No key required, selection is random
@@ -463,12 +1262,11 @@ fn extract_load_balance(doc: &KdlDocument, node: &KdlNode) -> miette::Result<Ups
                             node.span(),
                         )?;
 
-                        selector = match sel_ty.as_str() {
-                            "UriPath" => uri_path_selector,
-                            "SourceAddrAndUriPath" => source_addr_and_uri_path_selector,
-                            other => {
+                        selector = match keys.iter().find(|(k, _)| *k == sel_ty.as_str()) {
+                            Some((_, sel)) => *sel,
+                            None => {
                                 return Err(Bad::docspan(
-                                    format!("Unknown key: '{other}'"),
+                                    format!("Unknown key: '{sel_ty}'"),
                                     doc,
                                     node.span(),
                                 )
@@ -535,43 +1333,12 @@ fn extract_connector(
         .into_iter()
         .collect::<HashMap<&str, &str>>();
 
-    let proto = match args.get("proto").copied() {
-        None => None,
-        Some("h1-only") => Some(ALPN::H1),
-        Some("h2-only") => Some(ALPN::H2),
-        Some("h1-or-h2") => {
-            tracing::warn!("accepting 'h1-or-h2' as meaning 'h2-or-h1'");
-            Some(ALPN::H2H1)
-        }
-        Some("h2-or-h1") => Some(ALPN::H2H1),
-        Some(other) => {
-            return Err(Bad::docspan(
-                format!(
-                    "'proto' should be one of 'h1-only', 'h2-only', or 'h2-or-h1', found '{other}'"
-                ),
-                doc,
-                node.span(),
-            )
-            .into());
-        }
-    };
-    let tls_sni = args.get("tls-sni");
-
-    let (tls, sni, alpn) = match (proto, tls_sni) {
-        (None, None) | (Some(ALPN::H1), None) => (false, String::new(), ALPN::H1),
-        (None, Some(sni)) => (true, sni.to_string(), ALPN::H2H1),
-        (Some(_), None) => {
-            return Err(
-                Bad::docspan("'tls-sni' is required for HTTP2 support", doc, node.span()).into(),
-            );
-        }
-        (Some(p), Some(sni)) => (true, sni.to_string(), p),
-    };
-
-    let mut peer = HttpPeer::new(sadd, tls, sni);
-    peer.options.alpn = alpn;
-
-    Ok(peer)
+    http_peer(
+        sadd,
+        args.get("proto").copied(),
+        args.get("tls-sni").copied(),
+    )
+    .map_err(|e| Bad::docspan(e, doc, node.span()).into())
 }
 
 // services { Service { listeners { ... } } }
@@ -585,61 +1352,42 @@ fn extract_listener(
         .into_iter()
         .collect::<HashMap<&str, &KdlEntry>>();
 
-    // Is this a bindable name?
-    if name.parse::<SocketAddr>().is_ok() {
+    // Is this a bindable name, or a socket passed by systemd?
+    let socket_name = name.strip_prefix(SYSTEMD_LISTENER_PREFIX);
+    if socket_name.is_some() || name.parse::<SocketAddr>().is_ok() {
         // Cool: do we have reasonable args for this?
         let cert_path = utils::map_ensure_str(doc, args.get("cert-path").copied())?;
         let key_path = utils::map_ensure_str(doc, args.get("key-path").copied())?;
         let offer_h2 = utils::map_ensure_bool(doc, args.get("offer-h2").copied())?;
+        let ocsp_stapling = utils::map_ensure_bool(doc, args.get("ocsp-stapling").copied())?;
+        let ocsp_path = utils::map_ensure_str(doc, args.get("ocsp-response-path").copied())?;
 
-        match (cert_path, key_path, offer_h2) {
-            // No config? No problem!
-            (None, None, None) => Ok(ListenerConfig {
-                source: ListenerKind::Tcp {
-                    addr: name.to_string(),
-                    tls: None,
-                    offer_h2: false,
-                },
-            }),
-            // We must have both of cert-path and key-path if both are present
-            // ignore "offer-h2" if this is incorrect
-            (None, Some(_), _) | (Some(_), None, _) => {
-                return Err(Bad::docspan(
-                    "'cert-path' and 'key-path' must either BOTH be present, or NEITHER should be present",
-                    doc,
-                    node.span(),
-                )
-                .into());
-            }
-            // We can't offer H2 if we don't have TLS (at least for now, unless we
-            // expose H2C settings in pingora)
-            (None, None, Some(_)) => {
-                return Err(Bad::docspan(
-                    "'offer-h2' requires TLS, specify 'cert-path' and 'key-path'",
-                    doc,
-                    node.span(),
-                )
-                .into());
-            }
-            (Some(cpath), Some(kpath), offer_h2) => Ok(ListenerConfig {
-                source: ListenerKind::Tcp {
-                    addr: name.to_string(),
-                    tls: Some(TlsConfig {
-                        cert_path: cpath.into(),
-                        key_path: kpath.into(),
-                    }),
-                    // Default to enabling H2 if unspecified
-                    offer_h2: offer_h2.unwrap_or(true),
-                },
-            }),
-        }
-    } else if let Ok(pb) = name.parse::<PathBuf>() {
-        // TODO: Should we check that this path exists? Otherwise it seems to always match
+        let kind = match socket_name {
+            Some(_) => ListenerKind::systemd,
+            None => ListenerKind::tcp,
+        };
+        let source = kind(
+            socket_name.unwrap_or(name),
+            cert_path.map(PathBuf::from),
+            key_path.map(PathBuf::from),
+            offer_h2,
+            ocsp_stapling,
+            ocsp_path.map(PathBuf::from),
+        )
+        .map_err(|e| Bad::docspan(e, doc, node.span()))?;
+        Ok(ListenerConfig { source })
+    } else if !name.is_empty() {
+        // TODO: Should we check that this path exists? Otherwise any name is a path
         Ok(ListenerConfig {
-            source: ListenerKind::Uds(pb),
+            source: ListenerKind::Uds(PathBuf::from(name)),
         })
     } else {
-        Err(Bad::docspan("'{name}' is not a socketaddr or path?", doc, node.span()).into())
+        Err(Bad::docspan(
+            format!("'{name}' is not a socketaddr or path?"),
+            doc,
+            node.span(),
+        )
+        .into())
     }
 }
 
@@ -651,12 +1399,34 @@ fn extract_system_data(doc: &KdlDocument) -> miette::Result<SystemData> {
     };
     let tps = extract_threads_per_service(doc, sys)?;
 
+    let instance_name = if let Some(n) = sys.get("instance-name") {
+        utils::extract_one_str_arg(doc, n, "instance-name", n.entries(), |s| {
+            runtime::is_instance_name(s).then(|| s.to_string())
+        })?
+    } else {
+        runtime::DEFAULT_INSTANCE_NAME.to_string()
+    };
+
     let daemonize = if let Some(n) = sys.get("daemonize") {
         utils::extract_one_bool_arg(doc, n, "daemonize", n.entries())?
     } else {
         false
     };
 
+    let user = if let Some(n) = sys.get("user") {
+        let x = utils::extract_one_str_arg(doc, n, "user", n.entries(), |s| Some(s.to_string()))?;
+        Some(x)
+    } else {
+        None
+    };
+
+    let group = if let Some(n) = sys.get("group") {
+        let x = utils::extract_one_str_arg(doc, n, "group", n.entries(), |s| Some(s.to_string()))?;
+        Some(x)
+    } else {
+        None
+    };
+
     let upgrade_socket = if let Some(n) = sys.get("upgrade-socket") {
         let x = utils::extract_one_str_arg(doc, n, "upgrade-socket", n.entries(), |s| {
             Some(PathBuf::from(s))
@@ -666,6 +1436,11 @@ fn extract_system_data(doc: &KdlDocument) -> miette::Result<SystemData> {
         None
     };
 
+    let shutdown = match utils::optional_child_doc(doc, sys, "upgrade") {
+        Some(node) => extract_shutdown(doc, node)?,
+        None => ShutdownConfig::default(),
+    };
+
     let pid_file = if let Some(n) = sys.get("pid-file") {
         let x = utils::extract_one_str_arg(doc, n, "pid-file", n.entries(), |s| {
             Some(PathBuf::from(s))
@@ -675,11 +1450,200 @@ fn extract_system_data(doc: &KdlDocument) -> miette::Result<SystemData> {
         None
     };
 
+    let metrics = match utils::optional_child_doc(doc, sys, "metrics") {
+        Some(node) => Some(extract_metrics(doc, node)?),
+        None => None,
+    };
+
+    let log = match utils::optional_child_doc(doc, sys, "log") {
+        Some(node) => extract_log(doc, node)?,
+        None => LogConfig::default(),
+    };
+
+    let tracing = match utils::optional_child_doc(doc, sys, "tracing") {
+        Some(node) => Some(extract_tracing(doc, node)?),
+        None => None,
+    };
+
+    let admin = match utils::optional_child_doc(doc, sys, "admin") {
+        Some(node) => Some(extract_admin(doc, node)?),
+        None => None,
+    };
+
     Ok(SystemData {
         threads_per_service: tps,
+        instance_name,
         daemonize,
+        user,
+        group,
         upgrade_socket,
+        shutdown,
         pid_file,
+        metrics,
+        log,
+        tracing,
+        admin,
+    })
+}
+
+// system { log { level "info"; format "json"; output "stderr"; filters "pingora_core=warn" } }
+fn extract_log(doc: &KdlDocument, node: &KdlDocument) -> miette::Result<LogConfig> {
+    let mut log = LogConfig::default();
+    for (node, name, args) in utils::data_nodes(doc, node)? {
+        match name {
+            "level" => {
+                let level = utils::extract_one_str_arg(doc, node, name, args, |s| {
+                    s.parse::<LevelFilter>().ok()
+                })?;
+                log.level = Some(level);
+            }
+            "format" => {
+                log.format = utils::extract_one_str_arg(doc, node, name, args, |s| {
+                    s.parse::<LogFormat>().ok()
+                })?;
+            }
+            "output" => {
+                let (kind, args) =
+                    utils::extract_one_str_arg_with_kv_args(doc, node, name, args, |s| {
+                        Some(s.to_string())
+                    })?;
+                let path = args.get("path").map(PathBuf::from);
+                log.output = match (kind.as_str(), path) {
+                    ("stdout", None) => LogOutput::Stdout,
+                    ("stderr", None) => LogOutput::Stderr,
+                    ("file", Some(path)) => LogOutput::File(path),
+                    _ => {
+                        return Err(Bad::docspan(
+                            "expected 'output \"stdout\"', 'output \"stderr\"', or 'output \"file\" path=\"...\"'",
+                            doc,
+                            node.span(),
+                        )
+                        .into());
+                    }
+                };
+            }
+            "filters" => {
+                for arg in args {
+                    let directive = arg.value().as_string().or_bail(
+                        "filters should be strings, like \"pingora_core=warn\"",
+                        doc,
+                        arg.span(),
+                    )?;
+                    logging::validate_directive(directive)
+                        .map_err(|e| Bad::docspan(e, doc, arg.span()))?;
+                    log.filters.push(directive.to_string());
+                }
+            }
+            other => {
+                return Err(
+                    Bad::docspan(format!("Unknown setting: '{other}'"), doc, node.span()).into(),
+                );
+            }
+        }
+    }
+    Ok(log)
+}
+
+// system { metrics { listen "ADDR" } }
+fn extract_metrics(doc: &KdlDocument, node: &KdlDocument) -> miette::Result<MetricsConfig> {
+    let mut listen = None;
+    for (node, name, args) in utils::data_nodes(doc, node)? {
+        match name {
+            "listen" => {
+                listen = Some(utils::extract_one_str_arg(doc, node, name, args, |s| {
+                    s.parse::<SocketAddr>().ok()
+                })?);
+            }
+            other => {
+                return Err(
+                    Bad::docspan(format!("Unknown setting: '{other}'"), doc, node.span()).into(),
+                );
+            }
+        }
+    }
+    let listen = listen.or_bail(
+        "system > metrics requires a 'listen' address",
+        doc,
+        node.span(),
+    )?;
+    Ok(MetricsConfig { listen })
+}
+
+// system { admin { listen "127.0.0.1:9901"; token "..." } }
+fn extract_admin(doc: &KdlDocument, node: &KdlDocument) -> miette::Result<AdminConfig> {
+    let mut listen = None;
+    let mut token = None;
+    for (node, name, args) in utils::data_nodes(doc, node)? {
+        match name {
+            "listen" => {
+                let addr =
+                    utils::extract_one_str_arg(doc, node, name, args, |s| Some(s.to_string()))?;
+                let addr = addr
+                    .parse::<AdminListener>()
+                    .map_err(|e| Bad::docspan(e, doc, node.span()))?;
+                listen = Some(addr);
+            }
+            "token" => {
+                token = Some(utils::extract_one_str_arg(doc, node, name, args, |s| {
+                    (!s.is_empty()).then(|| s.to_string())
+                })?);
+            }
+            other => {
+                return Err(
+                    Bad::docspan(format!("Unknown setting: '{other}'"), doc, node.span()).into(),
+                );
+            }
+        }
+    }
+    let listen = listen.or_bail(
+        "system > admin requires a 'listen' address",
+        doc,
+        node.span(),
+    )?;
+    Ok(AdminConfig { listen, token })
+}
+
+// system { tracing { otlp-endpoint "http://127.0.0.1:4317"; sample-ratio 0.1 } }
+fn extract_tracing(doc: &KdlDocument, node: &KdlDocument) -> miette::Result<TracingConfig> {
+    let mut otlp_endpoint = None;
+    let mut sample_ratio = 1.0;
+    for (node, name, args) in utils::data_nodes(doc, node)? {
+        match name {
+            "otlp-endpoint" => {
+                otlp_endpoint = Some(utils::extract_one_str_arg(doc, node, name, args, |s| {
+                    let uri = s.parse::<http::Uri>().ok()?;
+                    uri.scheme().and(Some(s.to_string()))
+                })?);
+            }
+            "sample-ratio" => {
+                let ratio = match args {
+                    [one] => one
+                        .value()
+                        .as_f64()
+                        .or_else(|| one.value().as_i64().map(|v| v as f64)),
+                    _ => None,
+                };
+                sample_ratio = ratio.filter(|r| (0.0..=1.0).contains(r)).or_bail(
+                    "'sample-ratio' should be a number from 0.0 to 1.0",
+                    doc,
+                    node.span(),
+                )?;
+            }
+            other => {
+                return Err(
+                    Bad::docspan(format!("Unknown setting: '{other}'"), doc, node.span()).into(),
+                );
+            }
+        }
+    }
+    let otlp_endpoint = otlp_endpoint.or_bail(
+        "system > tracing requires an 'otlp-endpoint'",
+        doc,
+        node.span(),
+    )?;
+    Ok(TracingConfig {
+        otlp_endpoint,
+        sample_ratio,
     })
 }
 
@@ -709,6 +1673,15 @@ fn extract_threads_per_service(doc: &KdlDocument, sys: &KdlDocument) -> miette::
     )
 }
 
+/// The value of a section, or its default if it has errors, which are kept so
+/// that the following sections are still checked
+fn keep<T: Default>(res: miette::Result<T>, errors: &mut Vec<miette::Report>) -> T {
+    res.unwrap_or_else(|e| {
+        errors.push(e);
+        T::default()
+    })
+}
+
 #[derive(thiserror::Error, Debug, Diagnostic)]
 #[error("Incorrect configuration contents")]
 struct Bad {
@@ -716,12 +1689,44 @@ struct Bad {
     error: String,
 
     #[source_code]
-    src: String,
+    src: Source,
 
     #[label("incorrect")]
     err_span: SourceSpan,
 }
 
+/// The text of the document an error was found in, and the name of its file
+/// once known
+#[derive(Debug)]
+struct Source {
+    name: Option<String>,
+    text: String,
+}
+
+impl SourceCode for Source {
+    fn read_span<'a>(
+        &'a self,
+        span: &SourceSpan,
+        context_lines_before: usize,
+        context_lines_after: usize,
+    ) -> Result<Box<dyn SpanContents<'a> + 'a>, MietteError> {
+        let contents = self
+            .text
+            .read_span(span, context_lines_before, context_lines_after)?;
+        let Some(name) = &self.name else {
+            return Ok(contents);
+        };
+        Ok(Box::new(MietteSpanContents::new_named(
+            name.clone(),
+            contents.data(),
+            *contents.span(),
+            contents.line(),
+            contents.column(),
+            contents.line_count(),
+        )))
+    }
+}
+
 trait OptExtParse {
     type Good;
 
@@ -754,8 +1759,34 @@ impl Bad {
     fn docspan(msg: impl Into<String>, doc: &KdlDocument, span: &SourceSpan) -> Self {
         Self {
             error: msg.into(),
-            src: doc.to_string(),
+            src: Source {
+                name: None,
+                text: doc.to_string(),
+            },
             err_span: span.to_owned(),
         }
     }
+
+    /// Name the file of the document an error was found in, for configurations
+    /// made of several files
+    ///
+    /// Errors that already have a file name are left as they are.
+    fn in_file(err: miette::Report, path: &Path) -> miette::Report {
+        let err = match err.downcast::<Errors>() {
+            Ok(Errors { errors }) => {
+                let errors = errors.into_iter().map(|e| Bad::in_file(e, path)).collect();
+                return Errors { errors }.into();
+            }
+            Err(err) => err,
+        };
+        match err.downcast::<Bad>() {
+            Ok(mut bad) => {
+                if bad.src.name.is_none() {
+                    bad.src.name = Some(path.display().to_string());
+                }
+                bad.into()
+            }
+            Err(err) => err,
+        }
+    }
 }
@@ -1,15 +1,25 @@
-use std::{collections::BTreeMap, net::SocketAddr};
+use std::{net::SocketAddr, time::Duration};
 
-use pingora::upstreams::peer::HttpPeer;
+use pingora::{
+    protocols::ALPN,
+    upstreams::peer::{HttpPeer, Scheme},
+};
+use tracing_subscriber::filter::LevelFilter;
 
 use crate::{
+    access_log::Template,
     config::internal::{
-        FileServerConfig, ListenerConfig, ListenerKind, ProxyConfig, UpstreamOptions,
+        AccessLogConfig, AccessLogFormat, AccessLogOutput, AdminConfig, AdminListener,
+        ConnectionFilterConfig, FileServerConfig, HeaderModifierConfig, ListenerConfig,
+        ListenerKind, LogConfig, LogFormat, LogOutput, MetricsConfig, OcspSource, ProxyConfig,
+        RequestFilterConfig, SelectionKind, ShutdownConfig, TlsConfig, TracingConfig,
+        UpstreamOptions, WebSocketConfig,
     },
     proxy::{
         rate_limiting::{multi::MultiRaterConfig, AllRateConfig, RegexShim},
         request_selector::uri_path_selector,
     },
+    testing::TempDir,
 };
 
 #[test]
@@ -43,6 +53,7 @@ fn load_test() {
                             tls: Some(crate::config::internal::TlsConfig {
                                 cert_path: "./assets/test.crt".into(),
                                 key_path: "./assets/test.key".into(),
+                                ocsp_stapling: None,
                             }),
                             offer_h2: true,
                         },
@@ -55,34 +66,30 @@ fn load_test() {
                 )],
                 path_control: crate::config::internal::PathControl {
                     upstream_request_filters: vec![
-                        BTreeMap::from([
-                            ("kind".to_string(), "remove-header-key-regex".to_string()),
-                            ("pattern".to_string(), ".*(secret|SECRET).*".to_string()),
-                        ]),
-                        BTreeMap::from([
-                            ("key".to_string(), "x-proxy-friend".to_string()),
-                            ("kind".to_string(), "upsert-header".to_string()),
-                            ("value".to_string(), "river".to_string()),
-                        ]),
+                        HeaderModifierConfig::RemoveHeaderKeyRegex {
+                            pattern: RegexShim::new(".*(secret|SECRET).*").unwrap(),
+                        },
+                        HeaderModifierConfig::UpsertHeader {
+                            key: "x-proxy-friend".into(),
+                            value: "river".into(),
+                        },
                     ],
                     upstream_response_filters: vec![
-                        BTreeMap::from([
-                            ("kind".to_string(), "remove-header-key-regex".to_string()),
-                            ("pattern".to_string(), ".*ETag.*".to_string()),
-                        ]),
-                        BTreeMap::from([
-                            ("key".to_string(), "x-with-love-from".to_string()),
-                            ("kind".to_string(), "upsert-header".to_string()),
-                            ("value".to_string(), "river".to_string()),
-                        ]),
+                        HeaderModifierConfig::RemoveHeaderKeyRegex {
+                            pattern: RegexShim::new(".*ETag.*").unwrap(),
+                        },
+                        HeaderModifierConfig::UpsertHeader {
+                            key: "x-with-love-from".into(),
+                            value: "river".into(),
+                        },
                     ],
-                    request_filters: vec![BTreeMap::from([
-                        ("kind".to_string(), "block-cidr-range".to_string()),
-                        (
-                            "addrs".to_string(),
-                            "192.168.0.0/16, 10.0.0.0/8, 2001:0db8::0/32".to_string(),
-                        ),
-                    ])],
+                    request_filters: vec![RequestFilterConfig::BlockCidrRange {
+                        addrs: vec![
+                            "192.168.0.0/16".parse().unwrap(),
+                            "10.0.0.0/8".parse().unwrap(),
+                            "2001:0db8::0/32".parse().unwrap(),
+                        ],
+                    }],
                 },
                 upstream_options: UpstreamOptions {
                     selection: crate::config::internal::SelectionKind::Ketama,
@@ -126,6 +133,16 @@ fn load_test() {
                         },
                     ],
                 },
+                websocket: WebSocketConfig {
+                    allow: true,
+                    idle_timeout: Some(Duration::from_secs(600)),
+                },
+                access_log: Some(AccessLogConfig {
+                    format: AccessLogFormat::Template(
+                        Template::parse("$remote_addr $method $uri $status $duration_ms").unwrap(),
+                    ),
+                    output: AccessLogOutput::Stdout,
+                }),
             },
             ProxyConfig {
                 name: "Example2".into(),
@@ -144,6 +161,8 @@ fn load_test() {
                 },
                 upstream_options: UpstreamOptions::default(),
                 rate_limiting: crate::config::internal::RateLimitingConfig { rules: vec![] },
+                websocket: WebSocketConfig::default(),
+                access_log: None,
             },
         ],
         file_servers: vec![FileServerConfig {
@@ -162,21 +181,57 @@ fn load_test() {
                         tls: Some(crate::config::internal::TlsConfig {
                             cert_path: "./assets/test.crt".into(),
                             key_path: "./assets/test.key".into(),
+                            ocsp_stapling: None,
                         }),
                         offer_h2: true,
                     },
                 },
             ],
             base_path: Some(".".into()),
+            access_log: Some(AccessLogConfig {
+                format: AccessLogFormat::Combined,
+                output: AccessLogOutput::File("/tmp/river-access.log".into()),
+            }),
         }],
+        stream_proxies: vec![],
+        log: LogConfig {
+            level: Some(LevelFilter::INFO),
+            format: LogFormat::Compact,
+            output: LogOutput::Stderr,
+            filters: vec!["pingora_core=warn".into(), "river::proxy=debug".into()],
+        },
+        metrics: Some(MetricsConfig {
+            listen: "127.0.0.1:9100".parse().unwrap(),
+        }),
+        admin: Some(AdminConfig {
+            listen: AdminListener::Tcp("127.0.0.1:9901".parse().unwrap()),
+            token: Some("change-me".into()),
+        }),
+        tracing: Some(TracingConfig {
+            otlp_endpoint: "http://127.0.0.1:4317".into(),
+            sample_ratio: 0.25,
+        }),
+        instance_name: "default".into(),
         daemonize: false,
+        user: None,
+        group: None,
         pid_file: Some("/tmp/river.pidfile".into()),
         upgrade_socket: Some("/tmp/river-upgrade.sock".into()),
         upgrade: false,
+        shutdown: ShutdownConfig {
+            grace_period: Some(Duration::from_secs(30)),
+            shutdown_timeout: Some(Duration::from_secs(10)),
+        },
     };
 
     assert_eq!(val.validate_configs, expected.validate_configs);
     assert_eq!(val.threads_per_service, expected.threads_per_service);
+    assert_eq!(val.instance_name, expected.instance_name);
+    assert_eq!(val.metrics, expected.metrics);
+    assert_eq!(val.log, expected.log);
+    assert_eq!(val.tracing, expected.tracing);
+    assert_eq!(val.admin, expected.admin);
+    assert_eq!(val.shutdown, expected.shutdown);
     assert_eq!(val.basic_proxies.len(), expected.basic_proxies.len());
     assert_eq!(val.file_servers.len(), expected.file_servers.len());
 
@@ -188,6 +243,8 @@ fn load_test() {
             upstreams,
             path_control,
             rate_limiting,
+            websocket,
+            access_log,
         } = abp;
         assert_eq!(*name, ebp.name);
         assert_eq!(*listeners, ebp.listeners);
@@ -202,6 +259,8 @@ fn load_test() {
             });
         assert_eq!(*path_control, ebp.path_control);
         assert_eq!(*rate_limiting, ebp.rate_limiting);
+        assert_eq!(*websocket, ebp.websocket);
+        assert_eq!(*access_log, ebp.access_log);
     }
 
     for (afs, efs) in val.file_servers.iter().zip(expected.file_servers.iter()) {
@@ -209,10 +268,12 @@ fn load_test() {
             name,
             listeners,
             base_path,
+            access_log,
         } = afs;
         assert_eq!(*name, efs.name);
         assert_eq!(*listeners, efs.listeners);
         assert_eq!(*base_path, efs.base_path);
+        assert_eq!(*access_log, efs.access_log);
     }
 }
 
@@ -282,3 +343,1025 @@ fn one_service() {
         ("127.0.0.1:8000".parse::<SocketAddr>().unwrap()).into()
     );
 }
+
+/// OCSP stapling can be fetched from the responder, or loaded from a file
+const OCSP_STAPLING_TEST: &str = r#"
+services {
+    Example {
+        listeners {
+            "127.0.0.1:443" cert-path="./assets/test.crt" key-path="./assets/test.key" ocsp-stapling=true
+            "127.0.0.1:8443" cert-path="./assets/test.crt" key-path="./assets/test.key" ocsp-stapling=true ocsp-response-path="./assets/test.ocsp"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+    }
+}
+"#;
+
+#[test]
+fn ocsp_stapling() {
+    let doc: ::kdl::KdlDocument = OCSP_STAPLING_TEST.parse().unwrap_or_else(|e| {
+        panic!("Error parsing KDL file: {e:?}");
+    });
+    let val: crate::config::internal::Config = doc.try_into().unwrap_or_else(|e| {
+        panic!("Error rendering config from KDL file: {e:?}");
+    });
+    let sources = val.basic_proxies[0]
+        .listeners
+        .iter()
+        .map(|l| match &l.source {
+            ListenerKind::Tcp { tls: Some(tls), .. } => tls.ocsp_stapling.clone(),
+            other => panic!("Unexpected listener: {other:?}"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        sources,
+        vec![
+            Some(OcspSource::Responder),
+            Some(OcspSource::File("./assets/test.ocsp".into())),
+        ]
+    );
+}
+
+/// OCSP stapling without TLS: not allowed
+const OCSP_WITHOUT_TLS_TEST: &str = r#"
+services {
+    Example {
+        listeners {
+            "127.0.0.1:80" ocsp-stapling=true
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+    }
+}
+"#;
+
+#[test]
+fn ocsp_without_tls() {
+    let doc: ::kdl::KdlDocument = OCSP_WITHOUT_TLS_TEST.parse().unwrap_or_else(|e| {
+        panic!("Error parsing KDL file: {e:?}");
+    });
+    let val: Result<crate::config::internal::Config, _> = doc.try_into();
+    assert!(val.is_err());
+}
+
+#[test]
+fn two_load_balance_sections() {
+    let doc: ::kdl::KdlDocument = r#"
+services {
+    Example {
+        listeners {
+            "127.0.0.1:80"
+        }
+        connectors {
+            load-balance {
+                selection "RoundRobin"
+            }
+            load-balance {
+                selection "Random"
+            }
+            "127.0.0.1:8000"
+        }
+    }
+}
+"#
+    .parse()
+    .unwrap();
+    let val: Result<crate::config::internal::Config, _> = doc.try_into();
+    let err = format!("{:?}", val.err().unwrap());
+    assert!(
+        err.contains("Don't have two 'load-balance' sections"),
+        "{err}"
+    );
+}
+
+/// Cleartext HTTP2 (h2c) listeners and connectors
+const H2C_TEST: &str = r#"
+services {
+    Example {
+        listeners {
+            "127.0.0.1:80" offer-h2=true
+        }
+        connectors {
+            "127.0.0.1:8000" proto="h2c"
+        }
+    }
+}
+"#;
+
+#[test]
+fn h2c() {
+    let doc: ::kdl::KdlDocument = H2C_TEST.parse().unwrap_or_else(|e| {
+        panic!("Error parsing KDL file: {e:?}");
+    });
+    let val: crate::config::internal::Config = doc.try_into().unwrap_or_else(|e| {
+        panic!("Error rendering config from KDL file: {e:?}");
+    });
+    assert_eq!(
+        val.basic_proxies[0].listeners[0].source,
+        ListenerKind::Tcp {
+            addr: "127.0.0.1:80".into(),
+            tls: None,
+            offer_h2: true,
+        }
+    );
+    let peer = &val.basic_proxies[0].upstreams[0];
+    assert_eq!(peer.scheme, Scheme::HTTP);
+    assert!(matches!(peer.options.alpn, ALPN::H2));
+}
+
+/// h2c is enabled for the whole service, so h2c listeners can't be combined with
+/// other listeners
+const H2C_MIXED_LISTENERS_TEST: &str = r#"
+services {
+    Example {
+        listeners {
+            "127.0.0.1:80" offer-h2=true
+            "127.0.0.1:443" cert-path="./assets/test.crt" key-path="./assets/test.key"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+    }
+}
+"#;
+
+#[test]
+fn h2c_mixed_listeners() {
+    let doc: ::kdl::KdlDocument = H2C_MIXED_LISTENERS_TEST.parse().unwrap_or_else(|e| {
+        panic!("Error parsing KDL file: {e:?}");
+    });
+    let val: Result<crate::config::internal::Config, _> = doc.try_into();
+    let err = format!("{:?}", val.err().unwrap());
+    assert!(err.contains("(h2c)"), "{err}");
+
+    // Cleartext HTTP1.x listeners are rejected too
+    let doc: ::kdl::KdlDocument = H2C_MIXED_LISTENERS_TEST
+        .replace(
+            r#"cert-path="./assets/test.crt" key-path="./assets/test.key""#,
+            "",
+        )
+        .parse()
+        .unwrap();
+    let val: Result<crate::config::internal::Config, _> = doc.try_into();
+    assert!(val.is_err());
+}
+
+/// h2c is cleartext, it can't be combined with TLS
+const H2C_WITH_SNI_TEST: &str = r#"
+services {
+    Example {
+        listeners {
+            "127.0.0.1:80"
+        }
+        connectors {
+            "127.0.0.1:8000" proto="h2c" tls-sni="example.com"
+        }
+    }
+}
+"#;
+
+#[test]
+fn h2c_with_sni() {
+    let doc: ::kdl::KdlDocument = H2C_WITH_SNI_TEST.parse().unwrap_or_else(|e| {
+        panic!("Error parsing KDL file: {e:?}");
+    });
+    let val: Result<crate::config::internal::Config, _> = doc.try_into();
+    assert!(val.is_err());
+}
+
+/// Stream proxies are marked by the `stream-proxy` section
+const STREAM_PROXY_TEST: &str = r#"
+services {
+    Postgres {
+        listeners {
+            "127.0.0.1:5432"
+            "127.0.0.1:6432" cert-path="./assets/test.crt" key-path="./assets/test.key"
+        }
+        connectors {
+            load-balance {
+                selection "Ketama" key="SourceAddr"
+            }
+            "10.0.0.1:5432"
+            "10.0.0.2:5432" tls-sni="db.example.com"
+        }
+        stream-proxy {
+            connection-filters {
+                filter kind="block-cidr-range" addrs="192.168.0.0/16"
+            }
+        }
+        rate-limiting {
+            rule kind="source-ip" max-buckets=4000 tokens-per-bucket=10 refill-qty=1 refill-rate-ms=100
+        }
+    }
+}
+"#;
+
+#[test]
+fn stream_proxy() {
+    let doc: ::kdl::KdlDocument = STREAM_PROXY_TEST.parse().unwrap_or_else(|e| {
+        panic!("Error parsing KDL file: {e:?}");
+    });
+    let val: crate::config::internal::Config = doc.try_into().unwrap_or_else(|e| {
+        panic!("Error rendering config from KDL file: {e:?}");
+    });
+    assert!(val.basic_proxies.is_empty());
+    assert_eq!(val.stream_proxies.len(), 1);
+
+    let sp = &val.stream_proxies[0];
+    assert_eq!(sp.name, "Postgres");
+    // HTTP2 is never offered for streams, even with TLS
+    assert!(sp.listeners.iter().all(|l| matches!(
+        l.source,
+        ListenerKind::Tcp {
+            offer_h2: false,
+            ..
+        }
+    )));
+    assert_eq!(sp.upstream_options.selection, SelectionKind::Ketama);
+    assert_eq!(
+        sp.upstreams
+            .iter()
+            .map(|p| (p._address.clone(), p.sni.as_str()))
+            .collect::<Vec<_>>(),
+        vec![
+            ("10.0.0.1:5432".parse::<SocketAddr>().unwrap().into(), ""),
+            (
+                "10.0.0.2:5432".parse::<SocketAddr>().unwrap().into(),
+                "db.example.com"
+            ),
+        ]
+    );
+    assert_eq!(
+        sp.connection_filters,
+        vec![ConnectionFilterConfig::BlockCidrRange {
+            addrs: vec!["192.168.0.0/16".parse().unwrap()],
+        }]
+    );
+    assert_eq!(sp.rate_limiting.rules.len(), 1);
+}
+
+/// Stream proxies can't rate limit on request contents
+const STREAM_PROXY_URI_RATE_LIMIT_TEST: &str = r#"
+services {
+    Postgres {
+        listeners {
+            "127.0.0.1:5432"
+        }
+        connectors {
+            "10.0.0.1:5432"
+        }
+        stream-proxy
+        rate-limiting {
+            rule kind="any-matching-uri" pattern=".*" tokens-per-bucket=10 refill-qty=1 refill-rate-ms=100
+        }
+    }
+}
+"#;
+
+#[test]
+fn stream_proxy_uri_rate_limit() {
+    let doc: ::kdl::KdlDocument = STREAM_PROXY_URI_RATE_LIMIT_TEST
+        .parse()
+        .unwrap_or_else(|e| {
+            panic!("Error parsing KDL file: {e:?}");
+        });
+    let val: Result<crate::config::internal::Config, _> = doc.try_into();
+    assert!(val.is_err());
+}
+
+/// TLS passthrough routes connections by SNI, with connectors as the fallback
+const TLS_PASSTHROUGH_TEST: &str = r#"
+services {
+    Tenants {
+        listeners {
+            "127.0.0.1:443"
+        }
+        connectors {
+            "10.0.0.1:443"
+        }
+        stream-proxy {
+            tls-passthrough {
+                "A.example.com" {
+                    "10.0.1.1:443"
+                    "10.0.1.2:443"
+                }
+                "*.example.com" {
+                    "10.0.2.1:443"
+                }
+            }
+            connection-filters {
+                filter kind="allow-cidr-range" addrs="10.0.0.0/8"
+            }
+        }
+    }
+}
+"#;
+
+#[test]
+fn tls_passthrough() {
+    let doc: ::kdl::KdlDocument = TLS_PASSTHROUGH_TEST.parse().unwrap_or_else(|e| {
+        panic!("Error parsing KDL file: {e:?}");
+    });
+    let val: crate::config::internal::Config = doc.try_into().unwrap_or_else(|e| {
+        panic!("Error rendering config from KDL file: {e:?}");
+    });
+    let sp = &val.stream_proxies[0];
+    assert_eq!(sp.upstreams.len(), 1);
+    let routes = sp.tls_passthrough.as_ref().unwrap();
+    assert_eq!(
+        routes
+            .iter()
+            .map(|(name, peers)| (name.as_str(), peers.len()))
+            .collect::<Vec<_>>(),
+        vec![("*.example.com", 1), ("a.example.com", 2)]
+    );
+    assert!(routes.values().flatten().all(|p| p.sni.is_empty()));
+}
+
+/// TLS passthrough can't be combined with terminating TLS
+const TLS_PASSTHROUGH_TERMINATED_TEST: &str = r#"
+services {
+    Tenants {
+        listeners {
+            "127.0.0.1:443" cert-path="./assets/test.crt" key-path="./assets/test.key"
+        }
+        stream-proxy {
+            tls-passthrough {
+                "a.example.com" {
+                    "10.0.1.1:443"
+                }
+            }
+        }
+    }
+}
+"#;
+
+#[test]
+fn tls_passthrough_terminated() {
+    let doc: ::kdl::KdlDocument = TLS_PASSTHROUGH_TERMINATED_TEST.parse().unwrap_or_else(|e| {
+        panic!("Error parsing KDL file: {e:?}");
+    });
+    let val: Result<crate::config::internal::Config, _> = doc.try_into();
+    assert!(val.is_err());
+}
+
+/// Access log templates are checked when loading the configuration
+const ACCESS_LOG_BAD_TEMPLATE_TEST: &str = r#"
+services {
+    Example {
+        listeners {
+            "127.0.0.1:80"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+        access-log {
+            format "template" pattern="$remote_addr $nope"
+        }
+    }
+}
+"#;
+
+#[test]
+fn access_log_bad_template() {
+    let doc: ::kdl::KdlDocument = ACCESS_LOG_BAD_TEMPLATE_TEST.parse().unwrap_or_else(|e| {
+        panic!("Error parsing KDL file: {e:?}");
+    });
+    let val: Result<crate::config::internal::Config, _> = doc.try_into();
+    assert!(val.is_err());
+}
+
+/// Sample ratios must be between 0.0 and 1.0
+const TRACING_BAD_RATIO_TEST: &str = r#"
+system {
+    tracing {
+        otlp-endpoint "http://127.0.0.1:4317"
+        sample-ratio 1.5
+    }
+}
+"#;
+
+#[test]
+fn tracing_bad_ratio() {
+    let doc: ::kdl::KdlDocument = TRACING_BAD_RATIO_TEST.parse().unwrap_or_else(|e| {
+        panic!("Error parsing KDL file: {e:?}");
+    });
+    let val: Result<crate::config::internal::Config, _> = doc.try_into();
+    assert!(val.is_err());
+}
+
+/// The admin API must not be reachable from other hosts
+const ADMIN_PUBLIC_ADDRESS_TEST: &str = r#"
+system {
+    admin {
+        listen "0.0.0.0:9901"
+    }
+}
+"#;
+
+#[test]
+fn admin_public_address() {
+    let doc: ::kdl::KdlDocument = ADMIN_PUBLIC_ADDRESS_TEST.parse().unwrap_or_else(|e| {
+        panic!("Error parsing KDL file: {e:?}");
+    });
+    let val: Result<crate::config::internal::Config, _> = doc.try_into();
+    assert!(val.is_err());
+}
+
+/// Emit the configuration as KDL, and check that loading it again gives the same
+/// configuration
+fn assert_round_trip(config: &crate::config::internal::Config) {
+    let emitted = super::emit(config);
+    let doc: ::kdl::KdlDocument = emitted.parse().unwrap_or_else(|e| {
+        panic!("Error parsing emitted KDL: {e:?}\n{emitted}");
+    });
+    let val: crate::config::internal::Config = doc.try_into().unwrap_or_else(|e| {
+        panic!("Error rendering config from emitted KDL: {e:?}\n{emitted}");
+    });
+
+    // As in the TOML tests, `Config` doesn't impl PartialEq
+    assert_eq!(format!("{config:?}"), format!("{val:?}"));
+    assert_eq!(emitted, super::emit(&val));
+}
+
+#[test]
+fn emit_test_config() {
+    let kdl_contents = std::fs::read_to_string("./assets/test-config.kdl").unwrap();
+    let doc: ::kdl::KdlDocument = kdl_contents.parse().unwrap();
+    let val: crate::config::internal::Config = doc.try_into().unwrap();
+    assert_round_trip(&val);
+
+    for test in [
+        STREAM_PROXY_TEST,
+        TLS_PASSTHROUGH_TEST,
+        OCSP_STAPLING_TEST,
+        H2C_TEST,
+    ] {
+        let doc: ::kdl::KdlDocument = test.parse().unwrap();
+        let val: crate::config::internal::Config = doc.try_into().unwrap();
+        assert_round_trip(&val);
+    }
+}
+
+#[test]
+fn emit_default_config() {
+    let config = crate::config::default_config();
+    assert_round_trip(&config);
+
+    // Unset sections are shown as examples
+    let emitted = super::emit(&config);
+    assert!(emitted.contains("    // metrics {\n"));
+    assert!(emitted.contains("        // access-log {\n"));
+}
+
+#[test]
+fn include() {
+    let (val, _secrets) = super::load(std::path::Path::new("./assets/test-include.kdl")).unwrap();
+    assert_eq!(val.threads_per_service, 2);
+    assert_eq!(
+        val.basic_proxies
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["Main", "Api"]
+    );
+    assert_eq!(val.file_servers.len(), 1);
+    assert_eq!(val.file_servers[0].name, "Static");
+}
+
+#[test]
+fn include_without_file() {
+    let doc: ::kdl::KdlDocument = std::fs::read_to_string("./assets/test-include.kdl")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let val: Result<crate::config::internal::Config, _> = doc.try_into();
+    assert!(val.is_err());
+}
+
+#[test]
+fn include_errors_name_file() {
+    let dir = TempDir::new();
+    std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+    std::fs::write(
+        dir.join("main.kdl"),
+        "include \"conf.d/*.kdl\"\nservices {\n    Main {\n        listeners {\n            \"0.0.0.0:8080\"\n        }\n        connectors {\n            \"127.0.0.1:8000\"\n        }\n    }\n}\n",
+    )
+    .unwrap();
+
+    // The same service name in two files
+    std::fs::write(
+        dir.join("conf.d/a.kdl"),
+        "services {\n    Main {\n        listeners {\n            \"0.0.0.0:8081\"\n        }\n        connectors {\n            \"127.0.0.1:8001\"\n        }\n    }\n}\n",
+    )
+    .unwrap();
+    let err = super::load(&dir.join("main.kdl")).unwrap_err();
+    let bad = err.downcast_ref::<super::Bad>().unwrap();
+    assert_eq!(
+        bad.error,
+        "Service 'Main' is already defined in the main configuration file"
+    );
+    assert_eq!(
+        bad.src.name,
+        Some(dir.join("conf.d/a.kdl").display().to_string())
+    );
+
+    // Included files can't change the system configuration
+    std::fs::write(
+        dir.join("conf.d/a.kdl"),
+        "system {\n    daemonize true\n}\n",
+    )
+    .unwrap();
+    let err = super::load(&dir.join("main.kdl")).unwrap_err();
+    let bad = err.downcast_ref::<super::Bad>().unwrap();
+    assert_eq!(
+        bad.error,
+        "included files may only contain 'services' and 'templates'"
+    );
+    assert_eq!(
+        bad.src.name,
+        Some(dir.join("conf.d/a.kdl").display().to_string())
+    );
+}
+
+const INTERPOLATION_TEST: &str = r#"
+system {
+    admin {
+        listen "127.0.0.1:9901"
+        token "${file:./assets/test-secret}"
+    }
+}
+services {
+    Example {
+        listeners {
+            "${env:RIVER_TEST_LISTEN_ADDR:-0.0.0.0:8080}"
+        }
+        connectors {
+            "10.0.0.1:443" tls-sni="${env:RIVER_TEST_UPSTREAM_SNI}"
+        }
+        access-log {
+            format "template" pattern="$remote_addr $$"
+        }
+    }
+}
+"#;
+
+#[test]
+fn emit_secrets() {
+    let dir = TempDir::new();
+    let path = dir.join("river.kdl");
+    std::fs::write(&path, INTERPOLATION_TEST).unwrap();
+    std::env::set_var("RIVER_TEST_UPSTREAM_SNI", "upstream.example.com");
+    let (config, secrets) = super::load(&path).unwrap();
+
+    let emitted = crate::config::effective_config(&config, &secrets);
+    assert!(!emitted.contains("not-a-real-token"));
+    assert!(emitted.contains(r#"token "${file:./assets/test-secret}""#));
+
+    // The secret is read from the file again when the output is loaded
+    std::fs::write(&path, &emitted).unwrap();
+    let (val, _secrets) = super::load(&path).unwrap();
+    assert_eq!(
+        val.admin.unwrap().token.as_deref(),
+        Some("not-a-real-token")
+    );
+}
+
+#[test]
+fn interpolation() {
+    std::env::set_var("RIVER_TEST_UPSTREAM_SNI", "upstream.example.com");
+    std::env::remove_var("RIVER_TEST_LISTEN_ADDR");
+    let doc: ::kdl::KdlDocument = INTERPOLATION_TEST.parse().unwrap();
+    let val: crate::config::internal::Config = doc.try_into().unwrap();
+
+    let proxy = &val.basic_proxies[0];
+    assert_eq!(
+        proxy.listeners[0].source,
+        ListenerKind::Tcp {
+            addr: "0.0.0.0:8080".into(),
+            tls: None,
+            offer_h2: false,
+        }
+    );
+    assert_eq!(proxy.upstreams[0].sni, "upstream.example.com");
+    assert_eq!(
+        val.admin.unwrap().token.as_deref(),
+        Some("not-a-real-token")
+    );
+    assert_eq!(
+        proxy.access_log.as_ref().unwrap().format,
+        AccessLogFormat::Template(Template::parse("$remote_addr $$").unwrap())
+    );
+}
+
+#[test]
+fn interpolation_missing_env() {
+    let kdl = INTERPOLATION_TEST.replace("RIVER_TEST_UPSTREAM_SNI", "RIVER_TEST_MISSING_SNI");
+    std::env::remove_var("RIVER_TEST_MISSING_SNI");
+    let doc: ::kdl::KdlDocument = kdl.parse().unwrap();
+    let err = crate::config::internal::Config::try_from(doc).unwrap_err();
+    let bad = err.downcast_ref::<super::Bad>().unwrap();
+    assert_eq!(
+        bad.error,
+        "environment variable 'RIVER_TEST_MISSING_SNI' is not set"
+    );
+
+    // The span is the expression, not the whole entry
+    let token = "${env:RIVER_TEST_MISSING_SNI}";
+    assert_eq!(bad.err_span, (kdl.find(token).unwrap(), token.len()).into());
+}
+
+const TEMPLATES_TEST: &str = r#"
+templates {
+    Limited {
+        rate-limiting {
+            rule kind="source-ip" max-buckets=4000 tokens-per-bucket=10 refill-qty=1 refill-rate-ms=10
+        }
+        websocket {
+            allow false
+        }
+    }
+    Private {
+        path-control {
+            request-filters {
+                filter kind="block-cidr-range" addrs="10.0.0.0/8"
+            }
+        }
+    }
+}
+services {
+    First {
+        use "Limited" "Private"
+        listeners {
+            "127.0.0.1:8080"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+    }
+    Second {
+        use "Limited"
+        listeners {
+            "127.0.0.1:8081"
+        }
+        connectors {
+            "127.0.0.1:8001"
+        }
+        // Overrides the whole section of the template
+        websocket {
+            allow true
+        }
+    }
+}
+"#;
+
+#[test]
+fn templates() {
+    let doc: ::kdl::KdlDocument = TEMPLATES_TEST.parse().unwrap();
+    let val: crate::config::internal::Config = doc.try_into().unwrap();
+
+    let first = &val.basic_proxies[0];
+    assert_eq!(first.rate_limiting.rules.len(), 1);
+    assert!(!first.websocket.allow);
+    assert_eq!(first.path_control.request_filters.len(), 1);
+
+    let second = &val.basic_proxies[1];
+    assert_eq!(second.rate_limiting.rules.len(), 1);
+    assert!(second.websocket.allow);
+    assert!(second.path_control.request_filters.is_empty());
+}
+
+#[test]
+fn template_errors() {
+    for (from, to, error) in [
+        (
+            r#"use "Limited" "Private""#,
+            r#"use "Missing""#,
+            "Unknown template 'Missing'. Templates must be defined in the same file as the services that use them",
+        ),
+        (
+            r#"use "Limited" "Private""#,
+            r#"use "Limited" "Limited""#,
+            "Template 'Limited' is already used",
+        ),
+        (
+            "    Private {\n        path-control {",
+            "    Private {\n        websocket {\n            allow true\n        }\n        path-control {",
+            "Section 'websocket' is in both templates 'Limited' and 'Private', it must be set by the service itself",
+        ),
+        (
+            "    Private {\n",
+            "    Private {\n        use \"Limited\"\n",
+            "templates can't use other templates",
+        ),
+        (
+            "    Private {\n",
+            "    Limited {\n",
+            "Template 'Limited' is already defined",
+        ),
+    ] {
+        let kdl = TEMPLATES_TEST.replacen(from, to, 1);
+        let doc: ::kdl::KdlDocument = kdl.parse().unwrap();
+        let err = crate::config::internal::Config::try_from(doc).unwrap_err();
+        let bad = err.downcast_ref::<super::Bad>().unwrap();
+        assert_eq!(bad.error, error);
+    }
+}
+
+const MANY_ERRORS_TEST: &str = r#"
+system {
+    threads-per-service "eight"
+}
+services {
+    NoConnectors {
+        listeners {
+            "127.0.0.1:8080"
+        }
+    }
+    Fine {
+        listeners {
+            "127.0.0.1:8081"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+    }
+    BadListener {
+        listeners {
+            "127.0.0.1:8443" cert-path="./assets/test.crt"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+    }
+}
+"#;
+
+#[test]
+fn many_errors() {
+    let doc: ::kdl::KdlDocument = MANY_ERRORS_TEST.parse().unwrap();
+    let err = crate::config::internal::Config::try_from(doc).unwrap_err();
+    let errors = err.downcast_ref::<super::Errors>().unwrap();
+    // The system section, and both broken services
+    assert_eq!(errors.errors.len(), 3);
+    assert_eq!(
+        miette::Diagnostic::related(errors).unwrap().count(),
+        errors.errors.len()
+    );
+}
+
+const FILTER_ERRORS_TEST: &str = r#"
+services {
+    BadPattern {
+        listeners {
+            "127.0.0.1:8080"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+        path-control {
+            upstream-request {
+                filter kind="upsert-header" key="x-proxy-friend" value="river"
+                filter kind="remove-header-key-regex" pattern="(unclosed"
+            }
+        }
+    }
+    BadKind {
+        listeners {
+            "127.0.0.1:8081"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+        path-control {
+            request-filters {
+                filter kind="block-everything"
+            }
+        }
+    }
+    BadRange {
+        listeners {
+            "127.0.0.1:5432"
+        }
+        connectors {
+            "127.0.0.1:6432"
+        }
+        stream-proxy {
+            connection-filters {
+                filter kind="block-cidr-range" addrs="not-a-range"
+            }
+        }
+    }
+}
+"#;
+
+#[test]
+fn filter_errors() {
+    let doc: ::kdl::KdlDocument = FILTER_ERRORS_TEST.parse().unwrap();
+    let err = crate::config::internal::Config::try_from(doc).unwrap_err();
+    let errors = err.downcast_ref::<super::Errors>().unwrap();
+    assert_eq!(errors.errors.len(), 3);
+
+    // Each error points at the broken filter
+    let expected = [
+        (
+            r#"filter kind="remove-header-key-regex" pattern="(unclosed""#,
+            "Bad pattern: '(unclosed'",
+        ),
+        (
+            r#"filter kind="block-everything""#,
+            "Unknown request filter: 'block-everything'",
+        ),
+        (
+            r#"filter kind="block-cidr-range" addrs="not-a-range""#,
+            "Failed to parse 'not-a-range' as a valid CIDR notation range",
+        ),
+    ];
+    for (err, (node, message)) in errors.errors.iter().zip(expected) {
+        let bad = err.downcast_ref::<super::Bad>().unwrap();
+        assert!(bad.error.starts_with(message), "{}", bad.error);
+        let span = bad.err_span.offset()..bad.err_span.offset() + bad.err_span.len();
+        assert_eq!(FILTER_ERRORS_TEST[span].trim(), node);
+    }
+}
+
+/// Errors in the sections of one service are all reported, not just the first
+const SERVICE_ERRORS_TEST: &str = r#"
+services {
+    Example {
+        listeners {
+            "127.0.0.1:8080"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+        path-control {
+            request-filters {
+                filter kind="block-cidr-range" addrs="not-a-range"
+                filter kind="block-everything"
+            }
+            upstream-request {
+                filter kind="remove-header-key-regex" pattern="(unclosed"
+            }
+        }
+        rate-limiting {
+            rule kind="per-moon-phase" tokens-per-bucket=1 refill-qty=1 refill-rate-ms=10
+        }
+    }
+}
+"#;
+
+#[test]
+fn service_errors() {
+    let doc: ::kdl::KdlDocument = SERVICE_ERRORS_TEST.parse().unwrap();
+    let err = crate::config::internal::Config::try_from(doc).unwrap_err();
+    let errors = err.downcast_ref::<super::Errors>().unwrap();
+    let messages = errors
+        .errors
+        .iter()
+        .map(|e| e.downcast_ref::<super::Bad>().unwrap().error.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(messages.len(), 4, "{messages:?}");
+    assert!(messages[0].starts_with("Failed to parse 'not-a-range'"));
+    assert!(messages[1].starts_with("Unknown request filter: 'block-everything'"));
+    assert!(messages[2].starts_with("Bad pattern: '(unclosed'"));
+    assert!(messages[3].contains("'per-moon-phase is not a known kind of rate limiting"));
+}
+
+const PRIVILEGE_DROP_TEST: &str = r#"
+system {
+    daemonize true
+    pid-file "/tmp/river.pidfile"
+    user "root"
+    group "root"
+}
+services {
+    Example {
+        listeners {
+            "127.0.0.1:8080"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+    }
+}
+"#;
+
+#[test]
+fn privilege_drop() {
+    let doc: ::kdl::KdlDocument = PRIVILEGE_DROP_TEST.parse().unwrap();
+    let mut config = crate::config::internal::Config::try_from(doc).unwrap();
+    assert_eq!(config.user.as_deref(), Some("root"));
+    assert_eq!(config.group.as_deref(), Some("root"));
+    let server_conf = config.pingora_server_conf();
+    assert_eq!(server_conf.user.as_deref(), Some("root"));
+    assert_eq!(server_conf.group.as_deref(), Some("root"));
+    config.validate().unwrap();
+
+    // Privileges are also dropped in the foreground, where no pid file is written
+    config.daemonize = false;
+    config.pid_file = Some("/river-test-no-such-dir/river.pid".into());
+    config.validate().unwrap();
+    config.daemonize = true;
+    assert!(config
+        .validate()
+        .unwrap_err()
+        .starts_with("Error reading pid file directory"));
+    config.pid_file = Some("/tmp/river.pidfile".into());
+
+    config.user = Some("river-test-no-such-user".into());
+    assert_eq!(
+        config.validate().unwrap_err(),
+        "User 'river-test-no-such-user' does not exist"
+    );
+}
+
+const INSTANCE_NAME_TEST: &str = r#"
+system {
+    instance-name "edge"
+}
+services {
+    Example {
+        listeners {
+            "127.0.0.1:8080"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+    }
+}
+"#;
+
+#[test]
+fn instance_name() {
+    let doc: ::kdl::KdlDocument = INSTANCE_NAME_TEST.parse().unwrap();
+    let config = crate::config::internal::Config::try_from(doc).unwrap();
+    assert_eq!(config.instance_name, "edge");
+
+    // Without explicit paths, both files are in the runtime directory of the instance
+    let dir = crate::config::runtime::default_dir("edge");
+    assert_eq!(config.pid_file_path(), dir.join("river.pid"));
+    assert_eq!(config.upgrade_socket_path(), dir.join("upgrade.sock"));
+    assert_eq!(
+        config.pingora_server_conf().pid_file,
+        dir.join("river.pid").to_str().unwrap()
+    );
+
+    // Names are used as a directory name, so paths are rejected
+    let doc: ::kdl::KdlDocument = INSTANCE_NAME_TEST
+        .replace("\"edge\"", "\"../edge\"")
+        .parse()
+        .unwrap();
+    assert!(crate::config::internal::Config::try_from(doc).is_err());
+}
+
+const SYSTEMD_LISTENERS_TEST: &str = r#"
+services {
+    Example {
+        listeners {
+            "systemd:http"
+            "systemd:https" cert-path="./assets/test.crt" key-path="./assets/test.key"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+    }
+}
+"#;
+
+#[test]
+fn systemd_listeners() {
+    let doc: ::kdl::KdlDocument = SYSTEMD_LISTENERS_TEST.parse().unwrap();
+    let config = crate::config::internal::Config::try_from(doc).unwrap();
+    let listeners = &config.basic_proxies[0].listeners;
+    assert_eq!(
+        listeners[0].source,
+        ListenerKind::Systemd {
+            name: "http".into(),
+            tls: None,
+            offer_h2: false,
+        }
+    );
+    assert_eq!(
+        listeners[1].source,
+        ListenerKind::Systemd {
+            name: "https".into(),
+            tls: Some(TlsConfig {
+                cert_path: "./assets/test.crt".into(),
+                key_path: "./assets/test.key".into(),
+                ocsp_stapling: None,
+            }),
+            offer_h2: true,
+        }
+    );
+
+    // Names are separated by colons in `LISTEN_FDNAMES`
+    let doc: ::kdl::KdlDocument = SYSTEMD_LISTENERS_TEST
+        .replace("systemd:http\"", "systemd:a:b\"")
+        .parse()
+        .unwrap();
+    assert!(crate::config::internal::Config::try_from(doc).is_err());
+}
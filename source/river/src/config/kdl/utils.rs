@@ -175,6 +175,20 @@ pub(crate) fn extract_one_bool_arg(
     .or_bail(format!("Incorrect argument for '{name}'"), doc, node.span())
 }
 
+/// Extract a single un-named non-negative integer argument, like `idle-timeout-secs 300`
+pub(crate) fn extract_one_u64_arg(
+    doc: &KdlDocument,
+    node: &KdlNode,
+    name: &str,
+    args: &[KdlEntry],
+) -> miette::Result<u64> {
+    match args {
+        [one] => one.value().as_i64().and_then(|v| u64::try_from(v).ok()),
+        _ => None,
+    }
+    .or_bail(format!("Incorrect argument for '{name}'"), doc, node.span())
+}
+
 /// Like `extract_one_str_arg`, but with bonus "str:str" key/val pairs
 ///
 /// `selection "Ketama" key="UriPath"`
@@ -1,103 +1,241 @@
 pub mod cli;
 pub mod internal;
 pub mod kdl;
+pub mod runtime;
 pub mod toml;
+mod validate;
 
-use std::fs::read_to_string;
+use std::{sync::OnceLock, time::Duration};
 
 use clap::Parser;
-use cli::Cli;
+use cli::{Cli, ConfigFormat};
+use miette::Diagnostic;
+use pingora::upstreams::peer::HttpPeer;
 
 use crate::config::toml::Toml;
 
-pub fn render_config() -> internal::Config {
-    // To begin with, start with the blank internal config. We will layer on top of that.
-    let mut config = internal::Config::default();
+/// The command line options River was started with, kept for [reload_config]
+static CLI: OnceLock<Cli> = OnceLock::new();
 
-    // Then, obtain the command line information, as that may
-    // change the paths to look for configuration files. It also handles
-    // bailing immediately if the user passes `--help`.
-    tracing::info!("Parsing CLI options");
-    let c = Cli::parse();
+pub fn render_config() -> (internal::Config, kdl::Secrets) {
+    // Obtain the command line information, as that may change the paths to
+    // look for configuration files. It also handles bailing immediately if
+    // the user passes `--help`.
+    let c = CLI.get_or_init(Cli::parse);
+
+    // Options that print something and exit use stdout, so logs are moved out of
+    // the way
+    if c.list_env_vars
+        || c.emit_default_config.is_some()
+        || c.emit_effective_config
+        || c.validate_configs
+    {
+        let log = internal::LogConfig {
+            output: internal::LogOutput::Stderr,
+            ..Default::default()
+        };
+        if let Err(e) = crate::logging::apply(&log) {
+            panic!("Failed to configure logging: {e}");
+        }
+    }
     tracing::info!(
         config = ?c,
         "CLI config"
     );
 
-    let toml_opts = c.config_toml.as_ref().map(Toml::from_path);
-
-    let kdl_opts = c.config_kdl.as_ref().map(|kdl_path| {
-        let kdl_contents = read_to_string(kdl_path).unwrap_or_else(|e| {
-            panic!("Error loading KDL file: {e:?}");
-        });
-        let doc: ::kdl::KdlDocument = kdl_contents.parse().unwrap_or_else(|e| {
-            panic!("Error parsing KDL file: {e:?}");
-        });
-        let val: internal::Config = doc.try_into().unwrap_or_else(|e| {
-            panic!("Error rendering config from KDL file: {e:?}");
-        });
-        val
-    });
+    if c.list_env_vars {
+        let vars = cli::env_vars();
+        let width = vars
+            .iter()
+            .map(|(env, _)| env.len())
+            .max()
+            .unwrap_or_default();
+        for (env, help) in vars {
+            println!("{env:width$}  {help}");
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(format) = c.emit_default_config {
+        match format {
+            ConfigFormat::Kdl => print!("{}", kdl::emit(&default_config())),
+            ConfigFormat::Toml => print!("{}", Toml::default_document()),
+        }
+        std::process::exit(0);
+    }
+
+    if c.validate_configs {
+        validate::run(c);
+    }
+
+    let (config, secrets) = load_config(c).unwrap_or_else(|e| panic!("{e}"));
+
+    if c.emit_effective_config {
+        print!("{}", effective_config(&config, &secrets));
+        std::process::exit(0);
+    }
+    (config, secrets)
+}
+
+/// The rendered configuration as a KDL document, see `--emit-effective-config`
+///
+/// Values read from secret files are replaced by the `${file:...}` expressions they
+/// were read from, so the output can be shared, and still loaded.
+pub fn effective_config(config: &internal::Config, secrets: &kdl::Secrets) -> String {
+    secrets.unresolve(&kdl::emit(config))
+}
+
+/// A configuration with every setting at its default value, and an example service,
+/// see `--emit-default-config`
+///
+/// At least one service is required, so this can be loaded as-is.
+pub fn default_config() -> internal::Config {
+    internal::Config {
+        basic_proxies: vec![internal::ProxyConfig {
+            name: "Example".into(),
+            listeners: vec![internal::ListenerConfig {
+                source: internal::ListenerKind::Tcp {
+                    addr: "0.0.0.0:8080".into(),
+                    tls: None,
+                    offer_h2: false,
+                },
+            }],
+            upstream_options: internal::UpstreamOptions::default(),
+            upstreams: vec![HttpPeer::new("127.0.0.1:8000", false, String::new())],
+            path_control: internal::PathControl::default(),
+            rate_limiting: internal::RateLimitingConfig::default(),
+            websocket: internal::WebSocketConfig::default(),
+            access_log: None,
+        }],
+        ..internal::Config::default()
+    }
+}
+
+/// Render the configuration again, from the same files and command line options
+/// that River was started with
+///
+/// Unlike [render_config], errors are returned rather than panicking, as this is
+/// used to reload the configuration of a running instance, see [crate::reload].
+pub fn reload_config() -> Result<(internal::Config, kdl::Secrets), String> {
+    let c = CLI.get().ok_or("configuration has not been rendered yet")?;
+    load_config(c)
+}
+
+fn load_config(c: &Cli) -> Result<(internal::Config, kdl::Secrets), String> {
+    load(c).map_err(|e| format!("{e:?}"))
+}
+
+/// Render the configuration, keeping the diagnostics of errors found in KDL files
+fn load(c: &Cli) -> miette::Result<(internal::Config, kdl::Secrets)> {
+    // To begin with, start with the blank internal config. We will layer on top of that.
+    let mut config = internal::Config::default();
+    // Values of secret files, which must not be logged
+    let mut secrets = kdl::Secrets::default();
+
+    let toml_opts = c
+        .config_toml
+        .as_ref()
+        .map(Toml::from_path)
+        .transpose()
+        .map_err(|e| miette::miette!("{e}"))?;
+
+    let kdl_opts = c
+        .config_kdl
+        .as_ref()
+        .map(|kdl_path| kdl::load(kdl_path))
+        .transpose()?;
 
     // 2.6.7: River MUST give the following priority to configuration:
     //   1. Command Line Options (highest priority)
     //   2. Environment Variable Options
     //   3. Configuration File Options (lowest priority)
     //
-    // Apply in reverse order as we are layering.
-    // match (toml_opts)
+    // Apply in reverse order as we are layering. Environment variables are read by
+    // clap, which only uses them for options not given on the command line, so both
+    // are applied together by `apply_cli`.
     match (toml_opts, kdl_opts) {
         (Some(tf), None) => {
             tracing::info!("Applying TOML options");
-            apply_toml(&mut config, &tf);
+            config = internal::Config::try_from(tf).map_err(|errors| {
+                Errors::combine(
+                    errors
+                        .into_iter()
+                        .map(|e| miette::miette!("Error rendering config from TOML file: {e}"))
+                        .collect(),
+                )
+            })?;
         }
-        (None, Some(kf)) => {
+        (None, Some((kf, kdl_secrets))) => {
             tracing::info!("Applying KDL options");
             config = kf;
+            secrets = kdl_secrets;
         }
         (None, None) => {
             tracing::info!("No configuration file provided");
         }
         (Some(_), Some(_)) => {
             tracing::error!("Refusing to merge KDL and TOML options: Please choose one.");
-            panic!("Too many configuration options selected!");
+            miette::bail!("Too many configuration options selected!");
         }
     }
 
     tracing::info!("Applying CLI options");
-    apply_cli(&mut config, &c);
+    apply_cli(&mut config, c).map_err(|e| miette::miette!("{e}"))?;
 
     // We always validate the configuration - if the user selected "validate"
-    // then pingora will exit when IT also validates the config.
-    tracing::info!(?config, "Full configuration",);
+    // then we exit after reporting the result, see [validate::run].
+    tracing::info!(
+        config = %secrets.redact(&format!("{config:?}")),
+        "Full configuration",
+    );
     tracing::info!("Validating...");
-    config.validate();
+    config.validate().map_err(|e| miette::miette!("{e}"))?;
     tracing::info!("Validation complete");
-    config
+    Ok((config, secrets))
 }
 
-fn apply_cli(conf: &mut internal::Config, cli: &Cli) {
+fn apply_cli(conf: &mut internal::Config, cli: &Cli) -> Result<(), String> {
     let Cli {
         validate_configs,
+        format: _,
         threads_per_service,
+        instance_name,
         config_toml: _,
         config_kdl: _,
         daemonize,
+        user,
+        group,
         upgrade,
         pidfile,
         upgrade_socket,
+        grace_period_secs,
+        shutdown_timeout_secs,
+        log_level,
+        log_format,
+        log_file,
+        log_filter,
+        list_env_vars: _,
+        emit_default_config: _,
+        emit_effective_config: _,
     } = cli;
 
     conf.validate_configs |= validate_configs;
     conf.daemonize |= daemonize;
     conf.upgrade |= upgrade;
 
+    if let Some(user) = user {
+        conf.user = Some(user.clone());
+    }
+    if let Some(group) = group {
+        conf.group = Some(group.clone());
+    }
+
     if let Some(pidfile) = pidfile {
         if let Some(current_pidfile) = conf.pid_file.as_ref() {
             if pidfile != current_pidfile {
-                panic!(
+                return Err(format!(
                     "Mismatched commanded PID files. CLI: {pidfile:?}, Config: {current_pidfile:?}"
-                );
+                ));
             }
         }
         conf.pid_file = Some(pidfile.into());
@@ -106,36 +244,88 @@ fn apply_cli(conf: &mut internal::Config, cli: &Cli) {
     if let Some(upgrade_socket) = upgrade_socket {
         if let Some(current_upgrade_socket) = conf.upgrade_socket.as_ref() {
             if upgrade_socket != current_upgrade_socket {
-                panic!(
+                return Err(format!(
                     "Mismatched commanded upgrade sockets. CLI: {upgrade_socket:?}, Config: {current_upgrade_socket:?}"
-                );
+                ));
             }
         }
         conf.upgrade_socket = Some(upgrade_socket.into());
     }
 
+    if let Some(secs) = grace_period_secs {
+        conf.shutdown.grace_period = Some(Duration::from_secs(*secs));
+    }
+    if let Some(secs) = shutdown_timeout_secs {
+        conf.shutdown.shutdown_timeout = Some(Duration::from_secs(*secs));
+    }
+
+    if let Some(name) = instance_name {
+        conf.instance_name = name.clone();
+    }
+
     if let Some(tps) = threads_per_service {
         conf.threads_per_service = *tps;
     }
+
+    if let Some(level) = log_level {
+        let level = level
+            .parse()
+            .map_err(|_| format!("Invalid log level: {level:?}"))?;
+        conf.log.level = Some(level);
+    }
+    if let Some(format) = log_format {
+        conf.log.format = format
+            .parse()
+            .map_err(|_| format!("Invalid log format: {format:?}"))?;
+    }
+    if let Some(path) = log_file {
+        conf.log.output = internal::LogOutput::File(path.clone());
+    }
+    for directive in log_filter {
+        crate::logging::validate_directive(directive)?;
+        conf.log.filters.push(directive.clone());
+    }
+    Ok(())
 }
 
-fn apply_toml(conf: &mut internal::Config, toml: &Toml) {
-    let Toml {
-        system,
-        basic_proxy,
-    } = toml;
-
-    let basic_proxy: Vec<internal::ProxyConfig> =
-        basic_proxy.iter().cloned().map(Into::into).collect();
-
-    // As toml is a configuration file, it should SET the value. We have to later consider
-    // if we EXTEND or REPLACE when used with more config file formats, or allow for setting
-    // of proxies in env/cli options.
-    assert!(
-        conf.basic_proxies.is_empty(),
-        "Non-empty 'basic proxies' list when applying TOML settings. This is unexpected."
-    );
-    conf.basic_proxies = basic_proxy;
+/// Several errors found in the configuration, shown as related diagnostics
+#[derive(thiserror::Error, Debug)]
+#[error("Found {} errors in the configuration", .errors.len())]
+struct Errors {
+    errors: Vec<miette::Report>,
+}
 
-    conf.threads_per_service = system.threads_per_service;
+impl Diagnostic for Errors {
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn Diagnostic> + 'a>> {
+        Some(Box::new(
+            self.errors.iter().map(|e| &**e as &dyn Diagnostic),
+        ))
+    }
+}
+
+impl Errors {
+    /// Fail if there were any errors, see [Errors::combine]
+    fn check(errors: Vec<miette::Report>) -> miette::Result<()> {
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(Errors::combine(errors)),
+        }
+    }
+
+    /// Combine errors into one, a single error is returned as-is
+    ///
+    /// Errors collected from nested sections are flattened into one list.
+    fn combine(errors: Vec<miette::Report>) -> miette::Report {
+        let mut errors = errors
+            .into_iter()
+            .flat_map(|e| match e.downcast::<Errors>() {
+                Ok(Errors { errors }) => errors,
+                Err(e) => vec![e],
+            })
+            .collect::<Vec<_>>();
+        match errors.len() {
+            1 => errors.remove(0),
+            _ => Errors { errors }.into(),
+        }
+    }
 }
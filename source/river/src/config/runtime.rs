@@ -0,0 +1,289 @@
+//! The runtime directory of an instance, holding its pidfile and upgrade socket
+//!
+//! Unless their paths are configured, the pidfile and upgrade socket are created in
+//! `$XDG_RUNTIME_DIR/river/<instance-name>`, or in `/run/river/<instance-name>` if
+//! `XDG_RUNTIME_DIR` is not set. Instances running on the same host need different
+//! names, so that they don't replace each other's files.
+//!
+//! The pidfile is only written when daemonizing, and the upgrade socket is only
+//! listened on by an instance started with `--upgrade`. Otherwise, the runtime
+//! directory is not used, and not created.
+
+use std::{
+    fs::DirBuilder,
+    io::ErrorKind,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    errno::Errno,
+    sys::signal::kill,
+    unistd::{access, chown, AccessFlags, Gid, Pid, Uid, User},
+};
+
+use super::internal::Config;
+
+/// The instance name used if none is configured
+pub const DEFAULT_INSTANCE_NAME: &str = "default";
+
+/// Is this name usable as the name of a directory?
+pub fn is_instance_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// The directory holding the default pidfile and upgrade socket of an instance
+pub fn default_dir(instance_name: &str) -> PathBuf {
+    let base = match std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        // Relative paths are invalid, and ignored, as in the XDG specification
+        Some(dir) if dir.is_absolute() => dir.join("river"),
+        _ => PathBuf::from("/run/river"),
+    };
+    base.join(instance_name)
+}
+
+/// The default path of the pidfile of an instance
+pub fn default_pid_file(instance_name: &str) -> PathBuf {
+    default_dir(instance_name).join("river.pid")
+}
+
+/// The default path of the upgrade socket of an instance
+pub fn default_upgrade_socket(instance_name: &str) -> PathBuf {
+    default_dir(instance_name).join("upgrade.sock")
+}
+
+/// The pidfile and upgrade socket, if this instance uses them, and whether each has
+/// its default path
+fn used_files(config: &Config) -> Vec<(PathBuf, bool)> {
+    let mut files = vec![];
+    if config.daemonize {
+        files.push((config.pid_file_path(), config.pid_file.is_none()));
+    }
+    if config.upgrade {
+        files.push((
+            config.upgrade_socket_path(),
+            config.upgrade_socket.is_none(),
+        ));
+    }
+    files
+}
+
+/// Check that the runtime directory can be created, if it is used
+///
+/// Without `XDG_RUNTIME_DIR`, the default is in `/run`, which usually only root can
+/// write to.
+pub fn check(config: &Config) -> Result<(), String> {
+    for (path, is_default) in used_files(config) {
+        if !is_default {
+            continue;
+        }
+        // The nearest parent that exists is where the directory would be created
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        let Some(existing) = dir.ancestors().find(|d| d.exists()) else {
+            continue;
+        };
+        if existing != dir && access(existing, AccessFlags::W_OK).is_err() {
+            return Err(format!(
+                "The runtime directory '{}' can't be created, as '{}' is not writable. Set \
+                 XDG_RUNTIME_DIR, or configure 'pid-file' and 'upgrade-socket'",
+                dir.display(),
+                existing.display(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Prepare the directories of the pidfile and upgrade socket, before starting
+///
+/// Only the files this instance uses are prepared, see [used_files].
+///
+/// * The runtime directory is created if the defaults are used, and must only be
+///   writable by River, so that other users can't replace the files in it
+/// * Configured paths in directories that other users can write to are warned about
+/// * A pidfile left behind by an instance that is no longer running is removed.
+///   If that instance is still running, River must be started with `--upgrade`
+pub fn prepare(config: &Config) -> Result<(), String> {
+    // The directory must stay usable after dropping privileges, see `system.user`
+    let owner = match &config.user {
+        Some(name) => User::from_name(name)
+            .map_err(|e| format!("Error looking up user '{name}': {e}"))?
+            .map(|u| (u.uid, u.gid)),
+        None => None,
+    };
+
+    for (path, is_default) in used_files(config) {
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        if is_default {
+            create_dir(dir, owner)?;
+            check_dir(dir, owner.map(|(uid, _)| uid))?;
+        } else if let Ok(meta) = std::fs::metadata(dir) {
+            if meta.mode() & 0o002 != 0 {
+                tracing::warn!(
+                    "'{}' is in a directory that any user can write to, consider using the default path",
+                    path.display(),
+                );
+            }
+        }
+    }
+
+    if config.daemonize {
+        check_pid_file(&config.pid_file_path(), config.upgrade)?;
+    }
+    Ok(())
+}
+
+/// Create the runtime directory, if it does not exist yet
+fn create_dir(dir: &Path, owner: Option<(Uid, Gid)>) -> Result<(), String> {
+    if dir.exists() {
+        return Ok(());
+    }
+    // Parents are shared by all instances, and must be traversable after dropping
+    // privileges
+    if let Some(parent) = dir.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(parent)
+            .map_err(|e| format!("Error creating directory '{}': {e}", parent.display()))?;
+    }
+    DirBuilder::new()
+        .mode(0o700)
+        .create(dir)
+        .map_err(|e| format!("Error creating directory '{}': {e}", dir.display()))?;
+    if let Some((uid, gid)) = owner {
+        chown(dir, Some(uid), Some(gid))
+            .map_err(|e| format!("Error changing the owner of '{}': {e}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Check that only River can write to the runtime directory
+fn check_dir(dir: &Path, owner: Option<Uid>) -> Result<(), String> {
+    let meta = std::fs::symlink_metadata(dir)
+        .map_err(|e| format!("Error reading directory '{}': {e}", dir.display()))?;
+    if !meta.is_dir() {
+        return Err(format!("'{}' is not a directory", dir.display()));
+    }
+    let uid = meta.uid();
+    if uid != Uid::effective().as_raw() && Some(uid) != owner.map(|u| u.as_raw()) {
+        return Err(format!(
+            "Directory '{}' is owned by another user",
+            dir.display()
+        ));
+    }
+    if meta.mode() & 0o022 != 0 {
+        return Err(format!(
+            "Directory '{}' can be written by other users, it should have mode 0700",
+            dir.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Check for a pidfile left behind by another instance
+fn check_pid_file(path: &Path, upgrade: bool) -> Result<(), String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Error reading pidfile '{}': {e}", path.display())),
+    };
+    match contents
+        .trim()
+        .parse::<i32>()
+        .ok()
+        .filter(|pid| is_running(*pid))
+    {
+        // The instance that is being upgraded
+        Some(_) if upgrade => Ok(()),
+        Some(pid) => Err(format!(
+            "River is already running with pid {pid}, see '{}'. Use --upgrade to take over \
+             from it, or set a different instance-name",
+            path.display()
+        )),
+        None => {
+            tracing::warn!("Removing stale pidfile '{}'", path.display());
+            std::fs::remove_file(path)
+                .map_err(|e| format!("Error removing stale pidfile '{}': {e}", path.display()))
+        }
+    }
+}
+
+/// Is there a process with this pid?
+fn is_running(pid: i32) -> bool {
+    // Signal 0 only checks whether the process exists. EPERM means it belongs to
+    // another user, but it does exist
+    pid > 0 && !matches!(kill(Pid::from_raw(pid), None), Err(Errno::ESRCH))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn instance_names() {
+        assert!(is_instance_name("default"));
+        assert!(is_instance_name("edge-1.example_com"));
+        assert!(!is_instance_name(""));
+        assert!(!is_instance_name(".."));
+        assert!(!is_instance_name("a/b"));
+        assert!(!is_instance_name("with space"));
+    }
+
+    #[test]
+    fn unused_runtime_dir() {
+        let mut config = Config {
+            instance_name: format!("runtime-test-{}", std::process::id()),
+            ..Default::default()
+        };
+        let dir = default_dir(&config.instance_name);
+
+        // Running in the foreground, neither file is used
+        assert!(used_files(&config).is_empty());
+        check(&config).unwrap();
+        prepare(&config).unwrap();
+        assert!(!dir.exists());
+
+        config.daemonize = true;
+        assert_eq!(used_files(&config), vec![(dir.join("river.pid"), true)]);
+        config.upgrade = true;
+        config.upgrade_socket = Some("/tmp/river-test-upgrade.sock".into());
+        assert_eq!(
+            used_files(&config),
+            vec![
+                (dir.join("river.pid"), true),
+                ("/tmp/river-test-upgrade.sock".into(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_pid_files() {
+        let dir = TempDir::new();
+        let path = dir.join("river.pid");
+
+        // No pidfile at all
+        check_pid_file(&path, false).unwrap();
+
+        // This process is running
+        std::fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+        assert!(check_pid_file(&path, false)
+            .unwrap_err()
+            .contains("already running"));
+        check_pid_file(&path, true).unwrap();
+        assert!(path.exists());
+
+        // Larger than any pid Linux hands out, and garbage
+        for contents in ["2147483647\n", "not a pid"] {
+            std::fs::write(&path, contents).unwrap();
+            check_pid_file(&path, false).unwrap();
+            assert!(!path.exists());
+        }
+    }
+}
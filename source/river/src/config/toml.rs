@@ -1,17 +1,39 @@
 //! Configuration sourced from a TOML file
+//!
+//! This supports the same settings as the KDL format, with the same names, see
+//! `assets/test-config-full.toml` for an example of each.
 
 use std::{
     collections::BTreeMap,
+    net::SocketAddr,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-use pingora::upstreams::peer::HttpPeer;
+use pingora::upstreams::peer::{BasicPeer, HttpPeer};
 use serde::{Deserialize, Serialize};
+use tracing_subscriber::filter::LevelFilter;
 
-use super::internal::{RateLimitingConfig, UpstreamOptions};
+use crate::{
+    access_log::Template,
+    logging,
+    proxy::{
+        rate_limiting::{
+            multi::{MultiRaterConfig, MultiRequestKeyKind},
+            single::{SingleInstanceConfig, SingleRequestKeyKind},
+            AllRateConfig, RegexShim,
+        },
+        request_selector::{null_selector, RequestSelector},
+    },
+};
+
+use super::{
+    internal::{self, http_peer, is_server_name_pattern},
+    runtime,
+};
 
 /// Configuration used for TOML formatted files
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
 #[serde(rename_all = "kebab-case")]
 pub struct Toml {
     /// System-wide configuration valies
@@ -21,19 +43,216 @@ pub struct Toml {
     /// Configuration for each Basic Proxy instance
     #[serde(default = "Vec::new")]
     pub basic_proxy: Vec<ProxyConfig>,
+
+    /// Configuration for each File Server instance
+    #[serde(default = "Vec::new")]
+    pub file_server: Vec<FileServerConfig>,
+
+    /// Configuration for each Stream Proxy instance
+    #[serde(default = "Vec::new")]
+    pub stream_proxy: Vec<StreamProxyConfig>,
 }
 
 impl Toml {
-    pub fn from_path<P>(path: &P) -> Self
+    pub fn from_path<P>(path: &P) -> Result<Self, String>
     where
         P: AsRef<Path> + core::fmt::Debug + ?Sized,
     {
         tracing::info!("Loading TOML from {path:?}");
         let f = std::fs::read_to_string(path)
-            .unwrap_or_else(|_| panic!("Failed to load file at {path:?}"));
-        let t = ::toml::from_str(&f).expect("failed to deserialize");
+            .map_err(|e| format!("Failed to load file at {path:?}: {e}"))?;
+        let t = ::toml::from_str(&f).map_err(|e| format!("failed to deserialize: {e}"))?;
         tracing::info!("TOML file contents: {t:?}");
-        t
+        Ok(t)
+    }
+
+    /// A configuration file with every setting at its default value, and an example
+    /// service, see `--emit-default-config`
+    pub fn default_document() -> String {
+        let example = Toml {
+            system: System {
+                log: LogConfig {
+                    format: Some("full".into()),
+                    ..LogConfig::default()
+                },
+                ..System::default()
+            },
+            basic_proxy: vec![ProxyConfig {
+                name: "Example".into(),
+                listeners: vec![ListenerConfig {
+                    source: ListenerKind::Tcp {
+                        addr: "0.0.0.0:8080".into(),
+                        tls: None,
+                        offer_h2: Some(false),
+                    },
+                }],
+                connector: None,
+                connectors: vec![ConnectorConfig {
+                    proxy_addr: "127.0.0.1:8000".into(),
+                    tls_sni: None,
+                    proto: Some("h1-only".into()),
+                }],
+                load_balance: Some(LoadBalanceConfig {
+                    selection: Some("RoundRobin".into()),
+                    key: None,
+                    discovery: Some("Static".into()),
+                    health_check: Some("None".into()),
+                }),
+                path_control: PathControl::default(),
+                rate_limiting: RateLimitingConfig::default(),
+                websocket: WebSocketConfig {
+                    allow: Some(true),
+                    idle_timeout_secs: None,
+                },
+                access_log: None,
+            }],
+            ..Toml::default()
+        };
+        let body = ::toml::to_string_pretty(&example)
+            .expect("the default configuration should be serializable");
+        format!(
+            "# River configuration\n\
+             #\n\
+             # Every setting is shown with its default value, along with an example service.\n\
+             # Optional sections that are not set by default, like `system.metrics`, are\n\
+             # not included.\n\
+             \n\
+             {body}"
+        )
+    }
+}
+
+/// This is the primary interface for loading the document, as with KDL
+impl TryFrom<Toml> for internal::Config {
+    /// Every error found in the configuration, not just the first
+    type Error = Vec<String>;
+
+    fn try_from(value: Toml) -> Result<Self, Self::Error> {
+        let Toml {
+            system,
+            basic_proxy,
+            file_server,
+            stream_proxy,
+        } = value;
+        let System {
+            threads_per_service,
+            instance_name,
+            daemonize,
+            user,
+            group,
+            pid_file,
+            upgrade_socket,
+            upgrade,
+            log,
+            metrics,
+            tracing,
+            admin,
+        } = system;
+
+        let mut errors = vec![];
+        let mut service_errors = |kind: &str, name: &str, e: Vec<String>| {
+            errors.extend(e.into_iter().map(|e| format!("{kind} '{name}': {e}")));
+        };
+
+        let mut basic_proxies = vec![];
+        for p in basic_proxy {
+            let name = p.name.clone();
+            match p.into_internal(threads_per_service) {
+                Ok(p) => basic_proxies.push(p),
+                Err(e) => service_errors("basic-proxy", &name, e),
+            }
+        }
+        let mut file_servers = vec![];
+        for f in file_server {
+            let name = f.name.clone();
+            match internal::FileServerConfig::try_from(f) {
+                Ok(f) => file_servers.push(f),
+                Err(e) => service_errors("file-server", &name, e),
+            }
+        }
+        let mut stream_proxies = vec![];
+        for s in stream_proxy {
+            let name = s.name.clone();
+            match s.into_internal(threads_per_service) {
+                Ok(s) => stream_proxies.push(s),
+                Err(e) => service_errors("stream-proxy", &name, e),
+            }
+        }
+
+        let log = internal::LogConfig::try_from(log).map_err(|e| format!("system.log: {e}"));
+        let tracing = tracing
+            .map(internal::TracingConfig::try_from)
+            .transpose()
+            .map_err(|e| format!("system.tracing: {e}"));
+        let admin = admin
+            .map(internal::AdminConfig::try_from)
+            .transpose()
+            .map_err(|e| format!("system.admin: {e}"));
+        let log = keep(log, &mut errors);
+        let tracing = keep(tracing, &mut errors);
+        let admin = keep(admin, &mut errors);
+        check(errors)?;
+
+        Ok(internal::Config {
+            threads_per_service,
+            instance_name: instance_name
+                .unwrap_or_else(|| runtime::DEFAULT_INSTANCE_NAME.to_string()),
+            daemonize,
+            user,
+            group,
+            pid_file,
+            upgrade_socket,
+            shutdown: upgrade.into(),
+            basic_proxies,
+            file_servers,
+            stream_proxies,
+            metrics: metrics.map(|m| internal::MetricsConfig { listen: m.listen }),
+            log,
+            tracing,
+            admin,
+            ..internal::Config::default()
+        })
+    }
+}
+
+/// The value of a conversion, or its default if it failed, in which case the error
+/// is kept so that the rest of the configuration is still checked
+fn keep<T: Default>(res: Result<T, String>, errors: &mut Vec<String>) -> T {
+    res.unwrap_or_else(|e| {
+        errors.push(e);
+        T::default()
+    })
+}
+
+/// Like [keep], for conversions that report several errors
+fn keep_all<T: Default>(res: Result<T, Vec<String>>, errors: &mut Vec<String>) -> T {
+    res.unwrap_or_else(|e| {
+        errors.extend(e);
+        T::default()
+    })
+}
+
+/// Convert each item, keeping the errors of those that fail
+fn convert_all<T, U>(
+    items: impl IntoIterator<Item = T>,
+    convert: impl Fn(T) -> Result<U, String>,
+    errors: &mut Vec<String>,
+) -> Vec<U> {
+    let mut out = vec![];
+    for item in items {
+        match convert(item) {
+            Ok(item) => out.push(item),
+            Err(e) => errors.push(e),
+        }
+    }
+    out
+}
+
+/// Fail if there were any errors
+fn check(errors: Vec<String>) -> Result<(), Vec<String>> {
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
     }
 }
 
@@ -47,12 +266,61 @@ impl Toml {
 pub struct System {
     #[serde(default = "System::default_threads_per_service")]
     pub threads_per_service: usize,
+
+    /// Name of this instance, used for the default pidfile and upgrade socket paths
+    pub instance_name: Option<String>,
+
+    /// Should the server daemonize and run in the background?
+    #[serde(default)]
+    pub daemonize: bool,
+
+    /// The user to run as after binding listeners
+    pub user: Option<String>,
+
+    /// The group to run as after binding listeners
+    pub group: Option<String>,
+
+    /// Path to the pidfile used when daemonizing, defaults to one in the
+    /// instance's runtime directory
+    pub pid_file: Option<PathBuf>,
+
+    /// Path to the upgrade socket, defaults to one in the instance's runtime
+    /// directory
+    pub upgrade_socket: Option<PathBuf>,
+
+    /// Graceful upgrades and shutdowns
+    #[serde(default)]
+    pub upgrade: UpgradeConfig,
+
+    /// Application logs
+    #[serde(default)]
+    pub log: LogConfig,
+
+    /// Prometheus metrics
+    pub metrics: Option<MetricsConfig>,
+
+    /// Export of request spans with OpenTelemetry
+    pub tracing: Option<TracingConfig>,
+
+    /// The admin API
+    pub admin: Option<AdminConfig>,
 }
 
 impl Default for System {
     fn default() -> Self {
         System {
             threads_per_service: Self::default_threads_per_service(),
+            instance_name: None,
+            daemonize: false,
+            user: None,
+            group: None,
+            pid_file: None,
+            upgrade_socket: None,
+            upgrade: UpgradeConfig::default(),
+            log: LogConfig::default(),
+            metrics: None,
+            tracing: None,
+            admin: None,
         }
     }
 }
@@ -63,6 +331,158 @@ impl System {
     }
 }
 
+/// Graceful upgrades and shutdowns, see [internal::ShutdownConfig]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct UpgradeConfig {
+    /// Time to keep serving before services are told to shut down
+    pub grace_period_secs: Option<u64>,
+    /// Time services have to stop, before anything still running is terminated
+    pub shutdown_timeout_secs: Option<u64>,
+}
+
+impl From<UpgradeConfig> for internal::ShutdownConfig {
+    fn from(value: UpgradeConfig) -> Self {
+        Self {
+            grace_period: value.grace_period_secs.map(Duration::from_secs),
+            shutdown_timeout: value.shutdown_timeout_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+/// Application logs, see [internal::LogConfig]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogConfig {
+    /// One of "trace", "debug", "info", "warn", "error", or "off"
+    pub level: Option<String>,
+    /// One of "full", "compact", "pretty", or "json"
+    pub format: Option<String>,
+    #[serde(default)]
+    pub output: LogOutput,
+    /// Per-module filters, like "pingora_core=warn"
+    #[serde(default = "Vec::new")]
+    pub filters: Vec<String>,
+}
+
+/// Where application logs are written, like `{ kind = "file", path = "river.log" }`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum LogOutput {
+    #[default]
+    Stdout,
+    Stderr,
+    File {
+        path: PathBuf,
+    },
+}
+
+impl TryFrom<LogConfig> for internal::LogConfig {
+    type Error = String;
+
+    fn try_from(value: LogConfig) -> Result<Self, Self::Error> {
+        let level = value
+            .level
+            .map(|level| {
+                level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| format!("Invalid log level: {level:?}"))
+            })
+            .transpose()?;
+        let format = value
+            .format
+            .map(|format| format.parse::<internal::LogFormat>())
+            .transpose()?
+            .unwrap_or_default();
+        let output = match value.output {
+            LogOutput::Stdout => internal::LogOutput::Stdout,
+            LogOutput::Stderr => internal::LogOutput::Stderr,
+            LogOutput::File { path } => internal::LogOutput::File(path),
+        };
+        for directive in &value.filters {
+            logging::validate_directive(directive)?;
+        }
+        Ok(Self {
+            level,
+            format,
+            output,
+            filters: value.filters,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetricsConfig {
+    pub listen: SocketAddr,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct TracingConfig {
+    /// The OTLP/gRPC endpoint of the collector, like "http://127.0.0.1:4317"
+    pub otlp_endpoint: String,
+    /// The fraction of new traces that are sampled, from 0.0 to 1.0
+    #[serde(default = "TracingConfig::default_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+impl TracingConfig {
+    fn default_sample_ratio() -> f64 {
+        1.0
+    }
+}
+
+impl TryFrom<TracingConfig> for internal::TracingConfig {
+    type Error = String;
+
+    fn try_from(value: TracingConfig) -> Result<Self, Self::Error> {
+        let has_scheme = value
+            .otlp_endpoint
+            .parse::<http::Uri>()
+            .is_ok_and(|uri| uri.scheme().is_some());
+        if !has_scheme {
+            return Err("'otlp-endpoint' should be a URL, like \"http://127.0.0.1:4317\"".into());
+        }
+        if !(0.0..=1.0).contains(&value.sample_ratio) {
+            return Err("'sample-ratio' should be a number from 0.0 to 1.0".into());
+        }
+        Ok(Self {
+            otlp_endpoint: value.otlp_endpoint,
+            sample_ratio: value.sample_ratio,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AdminConfig {
+    /// A loopback address, or an absolute path to a unix socket
+    pub listen: String,
+    pub token: Option<String>,
+}
+
+impl std::fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The contents of the file are logged when it is loaded
+        f.debug_struct("AdminConfig")
+            .field("listen", &self.listen)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl TryFrom<AdminConfig> for internal::AdminConfig {
+    type Error = String;
+
+    fn try_from(value: AdminConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            listen: value.listen.parse()?,
+            token: value.token.filter(|t| !t.is_empty()),
+        })
+    }
+}
+
 /// Add Path Control Modifiers
 ///
 /// Note that we use `BTreeMap` and NOT `HashMap`, as we want to maintain the
@@ -70,6 +490,8 @@ impl System {
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
 #[serde(rename_all = "kebab-case")]
 pub struct PathControl {
+    #[serde(default = "Vec::new")]
+    pub request_filters: Vec<BTreeMap<String, String>>,
     #[serde(default = "Vec::new")]
     pub upstream_request_filters: Vec<BTreeMap<String, String>>,
     #[serde(default = "Vec::new")]
@@ -90,12 +512,81 @@ pub struct ProxyConfig {
     #[serde(default = "Vec::new")]
     pub listeners: Vec<ListenerConfig>,
 
-    /// Connector - our (currently single) "upstream" server
-    pub connector: ConnectorConfig,
-    #[serde(default = "Default::default")]
+    /// Connector - a single "upstream" server. This may be used instead of, or
+    /// along with, `connectors`
+    pub connector: Option<ConnectorConfig>,
+
+    /// Connectors - the "upstream" servers requests are distributed between
+    #[serde(default = "Vec::new")]
+    pub connectors: Vec<ConnectorConfig>,
+
+    /// How requests are distributed between the connectors
+    pub load_balance: Option<LoadBalanceConfig>,
 
     /// Path Control, for modifying and filtering requests
+    #[serde(default = "Default::default")]
     pub path_control: PathControl,
+
+    #[serde(default = "Default::default")]
+    pub rate_limiting: RateLimitingConfig,
+
+    #[serde(default = "Default::default")]
+    pub websocket: WebSocketConfig,
+
+    pub access_log: Option<AccessLogConfig>,
+}
+
+impl ProxyConfig {
+    fn into_internal(
+        self,
+        threads_per_service: usize,
+    ) -> Result<internal::ProxyConfig, Vec<String>> {
+        let mut errors = vec![];
+        if self.listeners.is_empty() {
+            errors.push("nonzero listeners required".into());
+        }
+        let listeners = convert_all(
+            self.listeners,
+            internal::ListenerConfig::try_from,
+            &mut errors,
+        );
+        keep(internal::check_h2c_listeners(&listeners), &mut errors);
+
+        let connectors = self.connector.into_iter().chain(self.connectors);
+        let upstreams = convert_all(connectors, HttpPeer::try_from, &mut errors);
+        if upstreams.is_empty() && errors.is_empty() {
+            errors.push("We require at least one connector".into());
+        }
+
+        let upstream_options = self
+            .load_balance
+            .map(|lb| lb.into_internal(internal::HTTP_SELECTOR_KEYS))
+            .transpose()
+            .map(Option::unwrap_or_default);
+        let upstream_options = keep(upstream_options, &mut errors);
+        let path_control = keep_all(self.path_control.try_into(), &mut errors);
+        let rate_limiting = keep_all(
+            self.rate_limiting.into_internal(threads_per_service),
+            &mut errors,
+        );
+        let access_log = self
+            .access_log
+            .map(internal::AccessLogConfig::try_from)
+            .transpose();
+        let access_log = keep(access_log, &mut errors);
+        check(errors)?;
+
+        Ok(internal::ProxyConfig {
+            name: self.name,
+            listeners,
+            upstreams,
+            upstream_options,
+            path_control,
+            rate_limiting,
+            websocket: self.websocket.into(),
+            access_log,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -104,30 +595,483 @@ pub struct ConnectorConfig {
     pub proxy_addr: String,
     /// TLS SNI, if TLS should be used
     pub tls_sni: Option<String>,
+    /// One of "h1-only", "h2-only", "h2-or-h1", or "h2c"
+    pub proto: Option<String>,
+}
+
+impl TryFrom<ConnectorConfig> for HttpPeer {
+    type Error = String;
+
+    fn try_from(val: ConnectorConfig) -> Result<Self, Self::Error> {
+        let addr = parse_addr(&val.proxy_addr)?;
+        http_peer(addr, val.proto.as_deref(), val.tls_sni.as_deref())
+    }
+}
+
+/// NOTE: BasicPeer uses TLS when the SNI is not empty
+impl TryFrom<ConnectorConfig> for BasicPeer {
+    type Error = String;
+
+    fn try_from(val: ConnectorConfig) -> Result<Self, Self::Error> {
+        let addr = parse_addr(&val.proxy_addr)?;
+        if val.proto.is_some() {
+            return Err(format!(
+                "'{addr}': 'proto' can't be used with a stream connector"
+            ));
+        }
+        let mut peer = BasicPeer::new(&addr.to_string());
+        peer.sni = val.tls_sni.unwrap_or_default();
+        Ok(peer)
+    }
+}
+
+fn parse_addr(addr: &str) -> Result<SocketAddr, String> {
+    addr.parse()
+        .map_err(|_| format!("'{addr}' is not a valid socket address"))
 }
 
-impl From<ConnectorConfig> for HttpPeer {
-    fn from(val: ConnectorConfig) -> Self {
-        let (tls, sni) = if let Some(sni) = val.tls_sni {
-            (true, sni)
-        } else {
-            (false, String::new())
+/// The `load-balance` options of the connectors, see [internal::UpstreamOptions]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct LoadBalanceConfig {
+    /// One of "RoundRobin", "Random", "FNV", or "Ketama"
+    pub selection: Option<String>,
+    /// The key hashed by "FNV" and "Ketama" selection
+    pub key: Option<String>,
+    pub discovery: Option<String>,
+    pub health_check: Option<String>,
+}
+
+impl LoadBalanceConfig {
+    fn into_internal(
+        self,
+        keys: &[(&str, RequestSelector)],
+    ) -> Result<internal::UpstreamOptions, String> {
+        let selection = self
+            .selection
+            .map(|s| s.parse::<internal::SelectionKind>())
+            .transpose()?
+            .unwrap_or(internal::SelectionKind::RoundRobin);
+        let selector = match selection {
+            // No key required, selection is random
+            internal::SelectionKind::RoundRobin | internal::SelectionKind::Random => null_selector,
+            internal::SelectionKind::Fnv | internal::SelectionKind::Ketama => {
+                let key = self
+                    .key
+                    .ok_or_else(|| format!("selection {selection:?} requires a 'key'"))?;
+                match keys.iter().find(|(k, _)| *k == key) {
+                    Some((_, selector)) => *selector,
+                    None => return Err(format!("Unknown key: '{key}'")),
+                }
+            }
+        };
+        let discovery = match self.discovery.as_deref() {
+            None | Some("Static") => internal::DiscoveryKind::Static,
+            Some(other) => return Err(format!("Unknown discovery: '{other}'")),
         };
-        HttpPeer::new(&val.proxy_addr, tls, sni)
+        let health_checks = match self.health_check.as_deref() {
+            None | Some("None") => internal::HealthCheckKind::None,
+            Some(other) => return Err(format!("Unknown health-check: '{other}'")),
+        };
+        Ok(internal::UpstreamOptions {
+            selection,
+            selector,
+            health_checks,
+            discovery,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct RateLimitingConfig {
+    #[serde(default = "Vec::new")]
+    pub rules: Vec<RateLimitRule>,
+}
+
+impl RateLimitingConfig {
+    fn into_internal(
+        self,
+        threads_per_service: usize,
+    ) -> Result<internal::RateLimitingConfig, Vec<String>> {
+        let mut errors = vec![];
+        let rules = convert_all(
+            self.rules,
+            |r| r.into_internal(threads_per_service),
+            &mut errors,
+        );
+        check(errors)?;
+        Ok(internal::RateLimitingConfig { rules })
     }
 }
 
+/// A rate limiting rule, like the `rule` nodes of the KDL format
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
-pub struct ConnectorTlsConfig {
-    pub proxy_sni: String,
-    pub cert_path: PathBuf,
-    pub key_path: PathBuf,
+#[serde(rename_all = "kebab-case")]
+pub struct RateLimitRule {
+    /// One of "source-ip", "specific-uri", or "any-matching-uri"
+    pub kind: String,
+    /// The regular expression URIs are matched with, for "specific-uri" and
+    /// "any-matching-uri" rules
+    pub pattern: Option<String>,
+    /// Required for "source-ip" and "specific-uri" rules
+    pub max_buckets: Option<usize>,
+    pub tokens_per_bucket: usize,
+    pub refill_qty: usize,
+    pub refill_rate_ms: usize,
+}
+
+impl RateLimitRule {
+    fn into_internal(self, threads_per_service: usize) -> Result<AllRateConfig, String> {
+        let multi_cfg = || -> Result<MultiRaterConfig, String> {
+            let max_buckets = self.max_buckets.ok_or("Missing key: 'max-buckets'")?;
+            Ok(MultiRaterConfig {
+                threads: threads_per_service,
+                max_buckets,
+                max_tokens_per_bucket: self.tokens_per_bucket,
+                refill_interval_millis: self.refill_rate_ms,
+                refill_qty: self.refill_qty,
+            })
+        };
+        let regex_pattern = || -> Result<RegexShim, String> {
+            let pattern = self.pattern.as_deref().ok_or("Missing key: 'pattern'")?;
+            RegexShim::new(pattern)
+                .map_err(|_| format!("'{pattern}' should be a valid regular expression"))
+        };
+
+        match self.kind.as_str() {
+            "source-ip" => Ok(AllRateConfig::Multi {
+                kind: MultiRequestKeyKind::SourceIp,
+                config: multi_cfg()?,
+            }),
+            "specific-uri" => Ok(AllRateConfig::Multi {
+                kind: MultiRequestKeyKind::Uri {
+                    pattern: regex_pattern()?,
+                },
+                config: multi_cfg()?,
+            }),
+            "any-matching-uri" => Ok(AllRateConfig::Single {
+                kind: SingleRequestKeyKind::UriGroup {
+                    pattern: regex_pattern()?,
+                },
+                config: SingleInstanceConfig {
+                    max_tokens_per_bucket: self.tokens_per_bucket,
+                    refill_interval_millis: self.refill_rate_ms,
+                    refill_qty: self.refill_qty,
+                },
+            }),
+            other => Err(format!("'{other}' is not a known kind of rate limiting")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct WebSocketConfig {
+    /// Should WebSocket upgrade requests be proxied? Defaults to true
+    pub allow: Option<bool>,
+    /// Idle timeout for connections that have been upgraded
+    pub idle_timeout_secs: Option<u64>,
 }
 
+impl From<WebSocketConfig> for internal::WebSocketConfig {
+    fn from(value: WebSocketConfig) -> Self {
+        let default = internal::WebSocketConfig::default();
+        Self {
+            allow: value.allow.unwrap_or(default.allow),
+            idle_timeout: value.idle_timeout_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+//
+// Access Log Configuration
+//
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    #[serde(default)]
+    pub output: AccessLogOutput,
+}
+
+/// Like `{ kind = "template", pattern = "$remote_addr $method $uri $status" }`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum AccessLogFormat {
+    Json,
+    #[default]
+    Combined,
+    Template {
+        pattern: String,
+    },
+}
+
+/// Like `{ kind = "file", path = "/var/log/river/access.log" }`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum AccessLogOutput {
+    #[default]
+    Stdout,
+    File {
+        path: PathBuf,
+    },
+    UnixDatagram {
+        path: PathBuf,
+    },
+}
+
+impl TryFrom<AccessLogConfig> for internal::AccessLogConfig {
+    type Error = String;
+
+    fn try_from(value: AccessLogConfig) -> Result<Self, Self::Error> {
+        let format = match value.format {
+            AccessLogFormat::Json => internal::AccessLogFormat::Json,
+            AccessLogFormat::Combined => internal::AccessLogFormat::Combined,
+            AccessLogFormat::Template { pattern } => {
+                internal::AccessLogFormat::Template(Template::parse(&pattern)?)
+            }
+        };
+        let output = match value.output {
+            AccessLogOutput::Stdout => internal::AccessLogOutput::Stdout,
+            AccessLogOutput::File { path } => internal::AccessLogOutput::File(path),
+            AccessLogOutput::UnixDatagram { path } => internal::AccessLogOutput::UnixDatagram(path),
+        };
+        Ok(Self { format, output })
+    }
+}
+
+//
+// File Server Configuration
+//
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileServerConfig {
+    pub name: String,
+    #[serde(default = "Vec::new")]
+    pub listeners: Vec<ListenerConfig>,
+    /// The root of the served files
+    pub base_path: Option<PathBuf>,
+    pub access_log: Option<AccessLogConfig>,
+}
+
+impl TryFrom<FileServerConfig> for internal::FileServerConfig {
+    type Error = Vec<String>;
+
+    fn try_from(value: FileServerConfig) -> Result<Self, Self::Error> {
+        let mut errors = vec![];
+        if value.listeners.is_empty() {
+            errors.push("nonzero listeners required".into());
+        }
+        let listeners = convert_all(
+            value.listeners,
+            internal::ListenerConfig::try_from,
+            &mut errors,
+        );
+        keep(internal::check_h2c_listeners(&listeners), &mut errors);
+        let access_log = value
+            .access_log
+            .map(internal::AccessLogConfig::try_from)
+            .transpose();
+        let access_log = keep(access_log, &mut errors);
+        check(errors)?;
+
+        Ok(Self {
+            name: value.name,
+            listeners,
+            base_path: value.base_path,
+            access_log,
+        })
+    }
+}
+
+//
+// Stream Proxy Configuration
+//
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct StreamProxyConfig {
+    pub name: String,
+
+    #[serde(default = "Vec::new")]
+    pub listeners: Vec<ListenerConfig>,
+
+    /// Connections are forwarded to these. With `tls-passthrough`, they are used
+    /// when no server name matches, and may be empty
+    #[serde(default = "Vec::new")]
+    pub connectors: Vec<ConnectorConfig>,
+
+    pub load_balance: Option<LoadBalanceConfig>,
+
+    #[serde(default = "Vec::new")]
+    pub connection_filters: Vec<BTreeMap<String, String>>,
+
+    /// Only "source-ip" rules can be used
+    #[serde(default = "Default::default")]
+    pub rate_limiting: RateLimitingConfig,
+
+    /// Connectors selected by the SNI of the TLS ClientHello, keyed by server name
+    pub tls_passthrough: Option<BTreeMap<String, Vec<ConnectorConfig>>>,
+}
+
+impl StreamProxyConfig {
+    fn into_internal(
+        self,
+        threads_per_service: usize,
+    ) -> Result<internal::StreamProxyConfig, Vec<String>> {
+        let mut errors = vec![];
+        if self.listeners.is_empty() {
+            errors.push("nonzero listeners required".into());
+        }
+        let listeners = convert_all(
+            self.listeners,
+            |listener| {
+                if let ListenerKind::Tcp {
+                    offer_h2: Some(_), ..
+                }
+                | ListenerKind::Systemd {
+                    offer_h2: Some(_), ..
+                } = listener.source
+                {
+                    return Err("'offer-h2' can't be used with a stream proxy".into());
+                }
+                let mut listener = internal::ListenerConfig::try_from(listener)?;
+                // Streams are forwarded as-is, we never offer HTTP2 via ALPN
+                if let internal::ListenerKind::Tcp { offer_h2, .. }
+                | internal::ListenerKind::Systemd { offer_h2, .. } = &mut listener.source
+                {
+                    *offer_h2 = false;
+                }
+                Ok(listener)
+            },
+            &mut errors,
+        );
+
+        let tls_passthrough = self.tls_passthrough.map(tls_passthrough_routes).transpose();
+        let tls_passthrough = keep(tls_passthrough, &mut errors);
+
+        // Passed through TLS connections are never terminated
+        if tls_passthrough.is_some() {
+            if listeners.iter().any(|l| l.source.tls().is_some()) {
+                errors.push("listeners can't use TLS together with 'tls-passthrough'".into());
+            }
+            if self.connectors.iter().any(|c| c.tls_sni.is_some()) {
+                errors.push("connectors can't use TLS together with 'tls-passthrough'".into());
+            }
+        }
+
+        let upstreams = convert_all(self.connectors, BasicPeer::try_from, &mut errors);
+        if upstreams.is_empty() && tls_passthrough.is_none() && errors.is_empty() {
+            errors.push("We require at least one connector".into());
+        }
+
+        let upstream_options = self
+            .load_balance
+            .map(|lb| lb.into_internal(internal::STREAM_SELECTOR_KEYS))
+            .transpose()
+            .map(Option::unwrap_or_default);
+        let upstream_options = keep(upstream_options, &mut errors);
+        let connection_filters = convert_all(
+            self.connection_filters,
+            |f| {
+                internal::ConnectionFilterConfig::from_settings(f)
+                    .map_err(|e| format!("Invalid connection filter: {e}"))
+            },
+            &mut errors,
+        );
+        let rate_limiting = keep_all(
+            self.rate_limiting.into_internal(threads_per_service),
+            &mut errors,
+        );
+        if !rate_limiting.per_connection() {
+            errors.push("stream proxies only support 'source-ip' rate limiting rules".into());
+        }
+        check(errors)?;
+
+        Ok(internal::StreamProxyConfig {
+            name: self.name,
+            listeners,
+            upstream_options,
+            upstreams,
+            connection_filters,
+            rate_limiting,
+            tls_passthrough,
+        })
+    }
+}
+
+/// The routes of a `tls-passthrough` section, see [internal::StreamProxyConfig]
+fn tls_passthrough_routes(
+    routes: BTreeMap<String, Vec<ConnectorConfig>>,
+) -> Result<BTreeMap<String, Vec<BasicPeer>>, String> {
+    let mut out = BTreeMap::new();
+    for (name, connectors) in routes {
+        if !is_server_name_pattern(&name) {
+            return Err(format!(
+                "'{name}': expected a server name, or a wildcard such as '*.example.com'"
+            ));
+        }
+        if connectors.iter().any(|c| c.tls_sni.is_some()) {
+            return Err("connectors can't use TLS together with 'tls-passthrough'".into());
+        }
+        let peers = connectors
+            .into_iter()
+            .map(BasicPeer::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        if peers.is_empty() {
+            return Err(format!("'{name}' should contain one or more connectors"));
+        }
+
+        // Server names are case insensitive
+        if out.insert(name.to_ascii_lowercase(), peers).is_some() {
+            return Err(format!("Duplicate server name: '{name}'"));
+        }
+    }
+    if out.is_empty() {
+        return Err("'tls-passthrough' requires at least one server name".into());
+    }
+    Ok(out)
+}
+
+//
+// Listener Configuration
+//
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct ListenerTlsConfig {
     pub cert_path: PathBuf,
     pub key_path: PathBuf,
+    /// Staple OCSP responses, fetched from the responder named in the certificate,
+    /// or loaded from `ocsp_response_path`
+    pub ocsp_stapling: Option<bool>,
+    pub ocsp_response_path: Option<PathBuf>,
+}
+
+impl ListenerTlsConfig {
+    /// The certificate, key, and OCSP settings, as taken by [internal::ListenerKind::tcp]
+    #[allow(clippy::type_complexity)]
+    fn into_parts(
+        tls: Option<Self>,
+    ) -> (
+        Option<PathBuf>,
+        Option<PathBuf>,
+        Option<bool>,
+        Option<PathBuf>,
+    ) {
+        match tls {
+            Some(tls) => (
+                Some(tls.cert_path),
+                Some(tls.key_path),
+                tls.ocsp_stapling,
+                tls.ocsp_response_path,
+            ),
+            None => (None, None, None, None),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -141,60 +1085,100 @@ pub enum ListenerKind {
     Tcp {
         addr: String,
         tls: Option<ListenerTlsConfig>,
+        /// Offer HTTP2 via ALPN, defaults to true with TLS
+        offer_h2: Option<bool>,
     },
     Uds(PathBuf),
+    /// A socket passed by systemd socket activation, by its `FileDescriptorName=`
+    Systemd {
+        name: String,
+        tls: Option<ListenerTlsConfig>,
+        /// Offer HTTP2 via ALPN, defaults to true with TLS
+        offer_h2: Option<bool>,
+    },
 }
 
-impl From<ProxyConfig> for super::internal::ProxyConfig {
-    fn from(other: ProxyConfig) -> Self {
-        Self {
-            name: other.name,
-            listeners: other.listeners.into_iter().map(Into::into).collect(),
-            upstreams: vec![other.connector.into()],
-            path_control: other.path_control.into(),
-            upstream_options: UpstreamOptions::default(),
-            rate_limiting: RateLimitingConfig::default(),
-        }
-    }
-}
+impl TryFrom<PathControl> for internal::PathControl {
+    type Error = Vec<String>;
 
-impl From<PathControl> for super::internal::PathControl {
-    fn from(value: PathControl) -> Self {
-        Self {
-            request_filters: vec![],
-            upstream_request_filters: value.upstream_request_filters,
-            upstream_response_filters: value.upstream_response_filters,
-        }
-    }
-}
+    fn try_from(value: PathControl) -> Result<Self, Self::Error> {
+        let mut errors = vec![];
+        let request_filters = convert_all(
+            value.request_filters,
+            |f| {
+                internal::RequestFilterConfig::from_settings(f)
+                    .map_err(|e| format!("Invalid request filter: {e}"))
+            },
+            &mut errors,
+        );
+        let upstream_request_filters = convert_all(
+            value.upstream_request_filters,
+            |f| {
+                internal::HeaderModifierConfig::from_settings(f)
+                    .map_err(|e| format!("Invalid upstream request filter: {e}"))
+            },
+            &mut errors,
+        );
+        let upstream_response_filters = convert_all(
+            value.upstream_response_filters,
+            |f| {
+                internal::HeaderModifierConfig::from_settings(f)
+                    .map_err(|e| format!("Invalid upstream response filter: {e}"))
+            },
+            &mut errors,
+        );
+        check(errors)?;
 
-impl From<ListenerTlsConfig> for super::internal::TlsConfig {
-    fn from(other: ListenerTlsConfig) -> Self {
-        Self {
-            cert_path: other.cert_path,
-            key_path: other.key_path,
-        }
+        Ok(Self {
+            request_filters,
+            upstream_request_filters,
+            upstream_response_filters,
+        })
     }
 }
 
-impl From<ListenerConfig> for super::internal::ListenerConfig {
-    fn from(other: ListenerConfig) -> Self {
-        Self {
-            source: other.source.into(),
-        }
-    }
-}
+impl TryFrom<ListenerConfig> for internal::ListenerConfig {
+    type Error = String;
 
-impl From<ListenerKind> for super::internal::ListenerKind {
-    fn from(other: ListenerKind) -> Self {
-        match other {
-            ListenerKind::Tcp { addr, tls } => super::internal::ListenerKind::Tcp {
+    fn try_from(other: ListenerConfig) -> Result<Self, Self::Error> {
+        let source = match other.source {
+            ListenerKind::Tcp {
                 addr,
-                tls: tls.map(Into::into),
-                offer_h2: false,
-            },
-            ListenerKind::Uds(a) => super::internal::ListenerKind::Uds(a),
-        }
+                tls,
+                offer_h2,
+            } => {
+                let (cert_path, key_path, ocsp_stapling, ocsp_response_path) =
+                    ListenerTlsConfig::into_parts(tls);
+                internal::ListenerKind::tcp(
+                    &addr,
+                    cert_path,
+                    key_path,
+                    offer_h2,
+                    ocsp_stapling,
+                    ocsp_response_path,
+                )
+                .map_err(|e| format!("'{addr}': {e}"))?
+            }
+            ListenerKind::Uds(a) => internal::ListenerKind::Uds(a),
+            ListenerKind::Systemd {
+                name,
+                tls,
+                offer_h2,
+            } => {
+                let (cert_path, key_path, ocsp_stapling, ocsp_response_path) =
+                    ListenerTlsConfig::into_parts(tls);
+                internal::ListenerKind::systemd(
+                    &name,
+                    cert_path,
+                    key_path,
+                    offer_h2,
+                    ocsp_stapling,
+                    ocsp_response_path,
+                )
+                .map_err(|e| format!("'systemd:{name}': {e}"))?
+            }
+        };
+        Ok(Self { source })
     }
 }
 
@@ -202,12 +1186,16 @@ impl From<ListenerKind> for super::internal::ListenerKind {
 pub mod test {
     use std::collections::BTreeMap;
 
-    use pingora::upstreams::peer::HttpPeer;
+    use pingora::{protocols::ALPN, upstreams::peer::HttpPeer};
 
-    use crate::config::{
-        apply_toml,
-        internal::{self, RateLimitingConfig, UpstreamOptions},
-        toml::{ConnectorConfig, ListenerConfig, ProxyConfig, System},
+    use crate::{
+        config::{
+            internal::{
+                self, HeaderModifierConfig, RateLimitingConfig, UpstreamOptions, WebSocketConfig,
+            },
+            toml::{ConnectorConfig, ListenerConfig, ProxyConfig, System},
+        },
+        proxy::rate_limiting::RegexShim,
     };
 
     use super::Toml;
@@ -217,15 +1205,16 @@ pub mod test {
         let snapshot: Toml = Toml {
             system: System {
                 threads_per_service: 8,
+                ..System::default()
             },
             basic_proxy: vec![],
+            ..Toml::default()
         };
-        let loaded = Toml::from_path("./assets/example-config.toml");
+        let loaded = Toml::from_path("./assets/example-config.toml").unwrap();
         assert_eq!(snapshot, loaded);
 
         let def = internal::Config::default();
-        let mut cfg = internal::Config::default();
-        apply_toml(&mut cfg, &loaded);
+        let cfg = internal::Config::try_from(loaded).unwrap();
 
         // These don't impl PartialEq, largely due to `BasicPeer` and `Tracer` not
         // implementing the trait. Since we only need this for testing, this is...
@@ -233,11 +1222,38 @@ pub mod test {
         assert_eq!(format!("{def:?}"), format!("{cfg:?}"));
     }
 
+    #[test]
+    fn default_document() {
+        let doc = Toml::default_document();
+        let loaded: Toml = ::toml::from_str(&doc).unwrap();
+        let cfg = internal::Config::try_from(loaded).unwrap();
+
+        // The same as the default KDL configuration
+        let def = crate::config::default_config();
+        assert_eq!(format!("{def:?}"), format!("{cfg:?}"));
+    }
+
+    #[test]
+    fn admin_token_redacted() {
+        let loaded: Toml = ::toml::from_str(
+            r#"
+            [system.admin]
+            listen = "127.0.0.1:9901"
+            token = "hunter2"
+            "#,
+        )
+        .unwrap();
+        let debug = format!("{loaded:?}");
+        assert!(debug.contains("127.0.0.1:9901"));
+        assert!(!debug.contains("hunter2"));
+    }
+
     #[test]
     fn load_test() {
         let toml_snapshot: Toml = Toml {
             system: System {
                 threads_per_service: 8,
+                ..System::default()
             },
             basic_proxy: vec![
                 ProxyConfig {
@@ -247,6 +1263,7 @@ pub mod test {
                             source: crate::config::toml::ListenerKind::Tcp {
                                 addr: "0.0.0.0:8080".into(),
                                 tls: None,
+                                offer_h2: None,
                             },
                         },
                         ListenerConfig {
@@ -255,15 +1272,22 @@ pub mod test {
                                 tls: Some(crate::config::toml::ListenerTlsConfig {
                                     cert_path: "./assets/test.crt".into(),
                                     key_path: "./assets/test.key".into(),
+                                    ocsp_stapling: None,
+                                    ocsp_response_path: None,
                                 }),
+                                offer_h2: None,
                             },
                         },
                     ],
-                    connector: ConnectorConfig {
+                    connector: Some(ConnectorConfig {
                         proxy_addr: "91.107.223.4:443".into(),
                         tls_sni: Some(String::from("onevariable.com")),
-                    },
+                        proto: None,
+                    }),
+                    connectors: vec![],
+                    load_balance: None,
                     path_control: crate::config::toml::PathControl {
+                        request_filters: vec![],
                         upstream_request_filters: vec![
                             BTreeMap::from([
                                 ("kind".to_string(), "remove-header-key-regex".to_string()),
@@ -287,6 +1311,9 @@ pub mod test {
                             ]),
                         ],
                     },
+                    rate_limiting: Default::default(),
+                    websocket: Default::default(),
+                    access_log: None,
                 },
                 ProxyConfig {
                     name: "Example2".into(),
@@ -294,22 +1321,35 @@ pub mod test {
                         source: crate::config::toml::ListenerKind::Tcp {
                             addr: "0.0.0.0:8000".into(),
                             tls: None,
+                            offer_h2: None,
                         },
                     }],
-                    connector: ConnectorConfig {
+                    connector: Some(ConnectorConfig {
                         proxy_addr: "91.107.223.4:80".into(),
                         tls_sni: None,
-                    },
+                        proto: None,
+                    }),
+                    connectors: vec![],
+                    load_balance: None,
                     path_control: crate::config::toml::PathControl {
+                        request_filters: vec![],
                         upstream_request_filters: vec![],
                         upstream_response_filters: vec![],
                     },
+                    rate_limiting: Default::default(),
+                    websocket: Default::default(),
+                    access_log: None,
                 },
             ],
+            ..Toml::default()
         };
-        let loaded = Toml::from_path("./assets/test-config.toml");
+        let loaded = Toml::from_path("./assets/test-config.toml").unwrap();
         assert_eq!(toml_snapshot, loaded);
 
+        // As with KDL, TLS upstreams offer HTTP2, and TLS listeners offer it by default
+        let mut tls_peer = HttpPeer::new("91.107.223.4:443", true, String::from("onevariable.com"));
+        tls_peer.options.alpn = ALPN::H2H1;
+
         let sys_snapshot = internal::Config {
             validate_configs: false,
             threads_per_service: 8,
@@ -330,43 +1370,38 @@ pub mod test {
                                 tls: Some(internal::TlsConfig {
                                     cert_path: "./assets/test.crt".into(),
                                     key_path: "./assets/test.key".into(),
+                                    ocsp_stapling: None,
                                 }),
-                                offer_h2: false,
+                                offer_h2: true,
                             },
                         },
                     ],
-                    upstreams: vec![HttpPeer::new(
-                        "91.107.223.4:443",
-                        true,
-                        String::from("onevariable.com"),
-                    )],
+                    upstreams: vec![tls_peer],
                     path_control: internal::PathControl {
                         upstream_request_filters: vec![
-                            BTreeMap::from([
-                                ("kind".to_string(), "remove-header-key-regex".to_string()),
-                                ("pattern".to_string(), ".*(secret|SECRET).*".to_string()),
-                            ]),
-                            BTreeMap::from([
-                                ("key".to_string(), "x-proxy-friend".to_string()),
-                                ("kind".to_string(), "upsert-header".to_string()),
-                                ("value".to_string(), "river".to_string()),
-                            ]),
+                            HeaderModifierConfig::RemoveHeaderKeyRegex {
+                                pattern: RegexShim::new(".*(secret|SECRET).*").unwrap(),
+                            },
+                            HeaderModifierConfig::UpsertHeader {
+                                key: "x-proxy-friend".into(),
+                                value: "river".into(),
+                            },
                         ],
                         upstream_response_filters: vec![
-                            BTreeMap::from([
-                                ("kind".to_string(), "remove-header-key-regex".to_string()),
-                                ("pattern".to_string(), ".*ETag.*".to_string()),
-                            ]),
-                            BTreeMap::from([
-                                ("key".to_string(), "x-with-love-from".to_string()),
-                                ("kind".to_string(), "upsert-header".to_string()),
-                                ("value".to_string(), "river".to_string()),
-                            ]),
+                            HeaderModifierConfig::RemoveHeaderKeyRegex {
+                                pattern: RegexShim::new(".*ETag.*").unwrap(),
+                            },
+                            HeaderModifierConfig::UpsertHeader {
+                                key: "x-with-love-from".into(),
+                                value: "river".into(),
+                            },
                         ],
                         request_filters: vec![],
                     },
                     upstream_options: UpstreamOptions::default(),
                     rate_limiting: RateLimitingConfig::default(),
+                    websocket: WebSocketConfig::default(),
+                    access_log: None,
                 },
                 internal::ProxyConfig {
                     name: "Example2".into(),
@@ -385,21 +1420,193 @@ pub mod test {
                     },
                     upstream_options: UpstreamOptions::default(),
                     rate_limiting: RateLimitingConfig::default(),
+                    websocket: WebSocketConfig::default(),
+                    access_log: None,
                 },
             ],
             file_servers: Vec::new(),
+            stream_proxies: Vec::new(),
+            metrics: None,
+            log: internal::LogConfig::default(),
+            tracing: None,
+            admin: None,
+            instance_name: "default".into(),
             daemonize: false,
+            user: None,
+            group: None,
             pid_file: None,
             upgrade_socket: None,
             upgrade: false,
+            shutdown: internal::ShutdownConfig::default(),
         };
 
-        let mut cfg = internal::Config::default();
-        apply_toml(&mut cfg, &loaded);
+        let cfg = internal::Config::try_from(loaded).unwrap();
 
         // These don't impl PartialEq, largely due to `BasicPeer` and `Tracer` not
         // implementing the trait. Since we only need this for testing, this is...
         // sort of acceptable
         assert_eq!(format!("{sys_snapshot:?}"), format!("{cfg:?}"));
     }
+
+    /// Load a configuration from KDL
+    fn from_kdl(kdl: &str) -> internal::Config {
+        let doc: ::kdl::KdlDocument = kdl.parse().unwrap();
+        doc.try_into().unwrap()
+    }
+
+    #[test]
+    fn kdl_parity() {
+        let kdl = from_kdl(&std::fs::read_to_string("./assets/test-config.kdl").unwrap());
+        let toml = Toml::from_path("./assets/test-config-full.toml").unwrap();
+        let toml = internal::Config::try_from(toml).unwrap();
+        assert_eq!(format!("{kdl:?}"), format!("{toml:?}"));
+    }
+
+    const STREAM_PROXY_KDL: &str = r#"
+services {
+    Postgres {
+        listeners {
+            "127.0.0.1:5432"
+            "127.0.0.1:6432" cert-path="./assets/test.crt" key-path="./assets/test.key"
+        }
+        connectors {
+            load-balance {
+                selection "Ketama" key="SourceAddr"
+            }
+            "10.0.0.1:5432"
+            "10.0.0.2:5432" tls-sni="db.example.com"
+        }
+        stream-proxy {
+            connection-filters {
+                filter kind="block-cidr-range" addrs="192.168.0.0/16"
+            }
+        }
+        rate-limiting {
+            rule kind="source-ip" max-buckets=4000 tokens-per-bucket=10 refill-qty=1 refill-rate-ms=100
+        }
+    }
+    Tenants {
+        listeners {
+            "127.0.0.1:443"
+        }
+        stream-proxy {
+            tls-passthrough {
+                "A.example.com" {
+                    "10.0.1.1:443"
+                }
+                "*.example.com" {
+                    "10.0.2.1:443"
+                }
+            }
+        }
+    }
+}
+"#;
+
+    const STREAM_PROXY_TOML: &str = r#"
+[[stream-proxy]]
+name = "Postgres"
+listeners = [
+    { source = { kind = "Tcp", value = { addr = "127.0.0.1:5432" } } },
+    { source = { kind = "Tcp", value = { addr = "127.0.0.1:6432", tls = { cert_path = "./assets/test.crt", key_path = "./assets/test.key" } } } },
+]
+connectors = [
+    { proxy_addr = "10.0.0.1:5432" },
+    { proxy_addr = "10.0.0.2:5432", tls_sni = "db.example.com" },
+]
+load-balance = { selection = "Ketama", key = "SourceAddr" }
+connection-filters = [
+    { kind = "block-cidr-range", addrs = "192.168.0.0/16" },
+]
+rate-limiting = { rules = [
+    { kind = "source-ip", max-buckets = 4000, tokens-per-bucket = 10, refill-qty = 1, refill-rate-ms = 100 },
+] }
+
+[[stream-proxy]]
+name = "Tenants"
+listeners = [
+    { source = { kind = "Tcp", value = { addr = "127.0.0.1:443" } } },
+]
+tls-passthrough = { "A.example.com" = [{ proxy_addr = "10.0.1.1:443" }], "*.example.com" = [{ proxy_addr = "10.0.2.1:443" }] }
+"#;
+
+    #[test]
+    fn stream_proxy_kdl_parity() {
+        let kdl = from_kdl(STREAM_PROXY_KDL);
+        let toml: Toml = ::toml::from_str(STREAM_PROXY_TOML).unwrap();
+        let toml = internal::Config::try_from(toml).unwrap();
+        assert_eq!(format!("{kdl:?}"), format!("{toml:?}"));
+    }
+
+    #[test]
+    fn h2c_mixed_listeners() {
+        let toml: Toml = ::toml::from_str(
+            r#"
+[[basic-proxy]]
+name = "Example"
+listeners = [
+    { source = { kind = "Tcp", value = { addr = "127.0.0.1:80", offer_h2 = true } } },
+    { source = { kind = "Tcp", value = { addr = "127.0.0.1:8080" } } },
+]
+connector = { proxy_addr = "127.0.0.1:8000" }
+"#,
+        )
+        .unwrap();
+        let err = internal::Config::try_from(toml).unwrap_err();
+        assert!(err[0].contains("only accept HTTP2"), "{err:?}");
+    }
+
+    #[test]
+    fn service_errors() {
+        let toml: Toml = ::toml::from_str(
+            r#"
+[[basic-proxy]]
+name = "Example"
+listeners = [
+    { source = { kind = "Tcp", value = { addr = "127.0.0.1:8080" } } },
+]
+connector = { proxy_addr = "127.0.0.1:8000" }
+
+[basic-proxy.path-control]
+request-filters = [
+    { kind = "block-cidr-range", addrs = "not-a-range" },
+    { kind = "block-everything" },
+]
+upstream-request-filters = [
+    { kind = "remove-header-key-regex", pattern = "(unclosed" },
+]
+
+[[basic-proxy.rate-limiting.rules]]
+kind = "per-moon-phase"
+tokens-per-bucket = 1
+refill-qty = 1
+refill-rate-ms = 10
+"#,
+        )
+        .unwrap();
+        let errors = internal::Config::try_from(toml).unwrap_err();
+        assert_eq!(errors.len(), 4, "{errors:?}");
+        assert!(errors[0].starts_with(
+            "basic-proxy 'Example': Invalid request filter: Failed to parse 'not-a-range'"
+        ));
+        assert!(errors[1].contains("Unknown request filter: 'block-everything'"));
+        assert!(errors[2].contains("Invalid upstream request filter: Bad pattern: '(unclosed'"));
+        assert!(errors[3].contains("'per-moon-phase' is not a known kind of rate limiting"));
+    }
+
+    #[test]
+    fn stream_proxy_offer_h2() {
+        let toml: Toml = ::toml::from_str(
+            r#"
+[[stream-proxy]]
+name = "Postgres"
+listeners = [
+    { source = { kind = "Tcp", value = { addr = "127.0.0.1:5432", offer_h2 = true } } },
+]
+connectors = [{ proxy_addr = "10.0.0.1:5432" }]
+"#,
+        )
+        .unwrap();
+        assert!(internal::Config::try_from(toml).is_err());
+    }
 }
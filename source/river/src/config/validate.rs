@@ -0,0 +1,56 @@
+//! Reporting of all configuration errors, for `--validate-configs`
+
+use miette::{Diagnostic, JSONReportHandler};
+
+use crate::config::cli::{Cli, ReportFormat};
+
+/// Check the configuration, print a report of all errors, and exit
+///
+/// Exits with a non-zero status if any errors were found.
+pub fn run(c: &Cli) -> ! {
+    let errors = match super::load(c) {
+        Ok(_) => vec![],
+        Err(e) => vec![e],
+    };
+
+    // Errors found together, like all errors of a KDL file, are reported one
+    // by one
+    let mut flat: Vec<&dyn Diagnostic> = vec![];
+    for e in &errors {
+        match e.related() {
+            Some(related) => flat.extend(related),
+            None => flat.push(&**e),
+        }
+    }
+
+    match c.format.unwrap_or_default() {
+        ReportFormat::Human => {
+            for e in &errors {
+                eprintln!("{e:?}");
+            }
+            match flat.len() {
+                0 => eprintln!("Configuration is valid"),
+                n => eprintln!("Found {n} error(s) in the configuration"),
+            }
+        }
+        ReportFormat::Json => {
+            let errors = flat
+                .into_iter()
+                .map(|diagnostic| {
+                    let mut out = String::new();
+                    JSONReportHandler::new()
+                        .render_report(&mut out, diagnostic)
+                        .expect("rendering to a string can't fail");
+                    serde_json::from_str(&out).unwrap_or(serde_json::Value::String(out))
+                })
+                .collect::<Vec<serde_json::Value>>();
+            let report = serde_json::json!({
+                "valid": errors.is_empty(),
+                "errors": errors,
+            });
+            println!("{report}");
+        }
+    }
+
+    std::process::exit(if errors.is_empty() { 0 } else { 1 });
+}
@@ -1,6 +1,9 @@
 //! File Serving
 
-use std::ops::{Deref, DerefMut};
+use std::{
+    ops::{Deref, DerefMut},
+    time::Instant,
+};
 
 use pandora_module_utils::{pingora::SessionWrapper, RequestFilter, RequestFilterResult};
 use pingora::{server::Server, upstreams::peer::HttpPeer};
@@ -8,11 +11,15 @@ use pingora_core::Result;
 use pingora_proxy::{ProxyHttp, Session};
 use static_files_module::{StaticFilesConf, StaticFilesHandler};
 
-use crate::{config::internal::FileServerConfig, populate_listners};
+use crate::{
+    access_log::AccessLog, config::internal::FileServerConfig, h2c_options, h2c_requested,
+    metrics::ServiceMetrics, populate_listners, tls::CertStore,
+};
 
 /// Create a new file serving service
 pub fn river_file_server(
     conf: FileServerConfig,
+    certs: &CertStore,
     server: &Server,
 ) -> Box<dyn pingora::services::Service> {
     let fsconf = StaticFilesConf {
@@ -26,17 +33,37 @@ pub fn river_file_server(
     let file_server = FileServer {
         server: StaticFilesHandler::try_from(fsconf)
             .expect("Creation of a Static File Service should not fail"),
+        metrics: ServiceMetrics::new(&conf.name),
+        access_log: conf.access_log.map(|al| {
+            AccessLog::from_conf(&conf.name, al)
+                .unwrap_or_else(|e| panic!("Error opening access log for '{}': {e}", conf.name))
+        }),
     };
     let mut my_proxy =
         pingora_proxy::http_proxy_service_with_name(&server.configuration, file_server, &conf.name);
 
-    populate_listners(conf.listeners, &mut my_proxy);
+    if h2c_requested(&conf.listeners) {
+        my_proxy
+            .app_logic_mut()
+            .expect("file service should not be shared yet")
+            .server_options = Some(h2c_options());
+    }
+    populate_listners(conf.listeners, &mut my_proxy, certs);
 
     Box::new(my_proxy)
 }
 
 pub struct FileServer {
     pub server: StaticFilesHandler,
+    pub metrics: ServiceMetrics,
+    pub access_log: Option<AccessLog>,
+}
+
+/// Per-request context
+pub struct FileServerContext {
+    extensions: http::Extensions,
+    /// When handling of this request started
+    started: Instant,
 }
 
 /// Implementation detail for integrating pingora-web-server's file server
@@ -75,10 +102,13 @@ impl<'a> DerefMut for SesWrap<'a> {
 /// A small wrapper for delegating requests to a file server
 #[async_trait::async_trait]
 impl ProxyHttp for FileServer {
-    type CTX = http::Extensions;
+    type CTX = FileServerContext;
 
     fn new_ctx(&self) -> Self::CTX {
-        http::Extensions::new()
+        FileServerContext {
+            extensions: http::Extensions::new(),
+            started: self.metrics.request_started(),
+        }
     }
 
     async fn upstream_peer(
@@ -94,7 +124,7 @@ impl ProxyHttp for FileServer {
 
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
         let mut wrap = SesWrap {
-            extensions: ctx,
+            extensions: &mut ctx.extensions,
             session,
         };
         match self.server.request_filter(&mut wrap, &mut ()).await? {
@@ -102,4 +132,21 @@ impl ProxyHttp for FileServer {
             _ => Err(pingora_core::Error::new_str("Request Failed")),
         }
     }
+
+    async fn logging(
+        &self,
+        session: &mut Session,
+        _e: Option<&pingora_core::Error>,
+        ctx: &mut Self::CTX,
+    ) {
+        self.metrics.request_finished(
+            ctx.started,
+            session.response_written().map(|r| r.status.as_u16()),
+            session.as_downstream().body_bytes_read(),
+            session.as_downstream().body_bytes_sent(),
+        );
+        if let Some(access_log) = &self.access_log {
+            access_log.log(session, ctx.started.elapsed(), None);
+        }
+    }
 }
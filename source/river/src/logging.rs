@@ -0,0 +1,341 @@
+//! Application logging
+//!
+//! Logging is set up before the configuration is loaded, so that loading the
+//! configuration can itself be logged. Once loaded, [apply] swaps in the configured
+//! level, filters, format, and output.
+//!
+//! The filter can also be changed while River is running, with [set_filter], or by
+//! sending `SIGUSR2` to toggle between the configured filter and `debug` logging.
+//!
+//! Other consumers of spans, like the OpenTelemetry exporter, are added once the
+//! server has started with [set_extra_layer]. They have their own filter, so that
+//! changing what is logged doesn't change what they receive.
+
+use std::{
+    any::TypeId,
+    fs::OpenOptions,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    filter::{Directive, Filtered, LevelFilter, Targets},
+    fmt::writer::BoxMakeWriter,
+    layer::{Context, Layered, SubscriberExt},
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::config::internal::{LogConfig, LogFormat, LogOutput};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+type FmtLayered = Layered<
+    Filtered<reload::Layer<BoxedLayer, Registry>, reload::Layer<EnvFilter, Registry>, Registry>,
+    Registry,
+>;
+/// A layer added with [set_extra_layer]
+pub type ExtraLayer = Box<dyn Layer<FmtLayered> + Send + Sync>;
+type FmtHandle = reload::Handle<BoxedLayer, Registry>;
+type ExtraFilterHandle = reload::Handle<Targets, FmtLayered>;
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Handles used to change logging after [init]
+struct Handles {
+    fmt: FmtHandle,
+    extra: Arc<OnceLock<ExtraLayer>>,
+    extra_filter: ExtraFilterHandle,
+    filter: FilterHandle,
+    /// The filter from the configuration, restored when toggling back from debug
+    configured: Mutex<String>,
+    /// Has debug logging been toggled on with `SIGUSR2`?
+    debug: AtomicBool,
+}
+
+static HANDLES: OnceLock<Handles> = OnceLock::new();
+
+/// Set up logging to stdout, with the filter taken from `RUST_LOG` if set
+///
+/// This should be called once, as early as possible.
+pub fn init() {
+    subscriber().init();
+}
+
+/// The subscriber installed by [init], which the other functions of this module
+/// change
+///
+/// This can only be called once.
+pub fn subscriber() -> impl Subscriber + Send + Sync {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    let configured = filter.to_string();
+
+    // Each layer has its own filter. The extra layer receives nothing until one is set
+    let (fmt, fmt_handle) = reload::Layer::new(fmt_layer(&LogFormat::Full, stdout()));
+    let (filter, filter_handle) = reload::Layer::new(filter);
+    let extra = Deferred::default();
+    let extra_handle = extra.layer.clone();
+    let (extra_filter, extra_filter_handle) = reload::Layer::new(Targets::new());
+    let subscriber = tracing_subscriber::registry()
+        .with(fmt.with_filter(filter))
+        .with(extra.with_filter(extra_filter));
+
+    let handles = Handles {
+        fmt: fmt_handle,
+        extra: extra_handle,
+        extra_filter: extra_filter_handle,
+        filter: filter_handle,
+        configured: Mutex::new(configured),
+        debug: AtomicBool::new(false),
+    };
+    if HANDLES.set(handles).is_err() {
+        panic!("logging should only be initialized once");
+    }
+    subscriber
+}
+
+/// Apply the logging configuration
+pub fn apply(conf: &LogConfig) -> Result<(), String> {
+    let handles = HANDLES.get().ok_or("logging has not been initialized")?;
+
+    let writer = match &conf.output {
+        LogOutput::Stdout => stdout(),
+        LogOutput::Stderr => BoxMakeWriter::new(std::io::stderr),
+        LogOutput::File(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Failed to open log file {path:?}: {e}"))?;
+            BoxMakeWriter::new(Arc::new(file))
+        }
+    };
+    handles
+        .fmt
+        .reload(fmt_layer(&conf.format, writer))
+        .map_err(|e| format!("Failed to change log format: {e}"))?;
+
+    // Without a configured level, keep using `RUST_LOG`
+    if conf.level.is_some() || !conf.filters.is_empty() {
+        let level = conf.level.unwrap_or(LevelFilter::INFO);
+        let directives = std::iter::once(level.to_string())
+            .chain(conf.filters.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(",");
+        set_filter(&directives)?;
+        *handles.configured.lock().unwrap() = directives;
+    }
+    Ok(())
+}
+
+/// Replace the current filter, using the same syntax as `RUST_LOG`
+pub fn set_filter(directives: &str) -> Result<(), String> {
+    let handles = HANDLES.get().ok_or("logging has not been initialized")?;
+    let filter = EnvFilter::builder()
+        .parse(directives)
+        .map_err(|e| format!("Invalid log filter '{directives}': {e}"))?;
+    handles
+        .filter
+        .reload(filter)
+        .map_err(|e| format!("Failed to change log filter: {e}"))?;
+    tracing::info!(filter = directives, "Log filter changed");
+    Ok(())
+}
+
+/// Add a layer that receives the spans and events enabled by `filter`, regardless
+/// of the log filter
+///
+/// Only one extra layer is supported, and it can't be replaced once set.
+pub fn set_extra_layer(layer: ExtraLayer, filter: Targets) -> Result<(), String> {
+    let handles = HANDLES.get().ok_or("logging has not been initialized")?;
+    handles
+        .extra
+        .set(layer)
+        .map_err(|_| "A tracing layer has already been added")?;
+    handles
+        .extra_filter
+        .reload(filter)
+        .map_err(|e| format!("Failed to add tracing layer: {e}"))
+}
+
+/// The current filter, using the same syntax as `RUST_LOG`
+pub fn current_filter() -> Option<String> {
+    let handles = HANDLES.get()?;
+    handles.filter.with_current(|f| f.to_string()).ok()
+}
+
+/// Toggle between the configured filter and `debug` logging
+fn toggle_debug() {
+    let Some(handles) = HANDLES.get() else {
+        return;
+    };
+    let debug = !handles.debug.fetch_xor(true, Ordering::Relaxed);
+    let directives = if debug {
+        LevelFilter::DEBUG.to_string()
+    } else {
+        handles.configured.lock().unwrap().clone()
+    };
+    if let Err(e) = set_filter(&directives) {
+        tracing::error!("{e}");
+    }
+}
+
+/// A layer that is set once, after the subscriber has been installed
+///
+/// Unlike a [reload::Layer], this can be downcast to the layer it holds, which the
+/// OpenTelemetry layer relies on to set the parent of spans and to propagate them.
+struct Deferred<L> {
+    layer: Arc<OnceLock<L>>,
+}
+
+impl<L> Default for Deferred<L> {
+    fn default() -> Self {
+        Self {
+            layer: Arc::new(OnceLock::new()),
+        }
+    }
+}
+
+impl<S: Subscriber, L: Layer<S>> Layer<S> for Deferred<L> {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        match self.layer.get() {
+            Some(layer) => layer.register_callsite(metadata),
+            None => Interest::always(),
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        self.layer.get().is_none_or(|l| l.enabled(metadata, ctx))
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(layer) = self.layer.get() {
+            layer.on_new_span(attrs, id, ctx);
+        }
+    }
+
+    fn on_record(&self, span: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(layer) = self.layer.get() {
+            layer.on_record(span, values, ctx);
+        }
+    }
+
+    fn on_follows_from(&self, span: &Id, follows: &Id, ctx: Context<'_, S>) {
+        if let Some(layer) = self.layer.get() {
+            layer.on_follows_from(span, follows, ctx);
+        }
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: Context<'_, S>) -> bool {
+        self.layer.get().is_none_or(|l| l.event_enabled(event, ctx))
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if let Some(layer) = self.layer.get() {
+            layer.on_event(event, ctx);
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(layer) = self.layer.get() {
+            layer.on_enter(id, ctx);
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(layer) = self.layer.get() {
+            layer.on_exit(id, ctx);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(layer) = self.layer.get() {
+            layer.on_close(id, ctx);
+        }
+    }
+
+    fn on_id_change(&self, old: &Id, new: &Id, ctx: Context<'_, S>) {
+        if let Some(layer) = self.layer.get() {
+            layer.on_id_change(old, new, ctx);
+        }
+    }
+
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            return Some(self as *const Self as *const ());
+        }
+        // SAFETY: once set, the layer is never moved or dropped while `self` lives
+        self.layer.get()?.downcast_raw(id)
+    }
+}
+
+fn stdout() -> BoxMakeWriter {
+    BoxMakeWriter::new(std::io::stdout)
+}
+
+fn fmt_layer(format: &LogFormat, writer: BoxMakeWriter) -> BoxedLayer {
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+/// Is this a valid filter directive, like `pingora_core=warn`?
+pub fn validate_directive(directive: &str) -> Result<(), String> {
+    Directive::from_str(directive)
+        .map(drop)
+        .map_err(|e| format!("Invalid log filter '{directive}': {e}"))
+}
+
+/// Background service that toggles debug logging on `SIGUSR2`
+pub struct DebugToggle;
+
+#[async_trait]
+impl BackgroundService for DebugToggle {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut usr2 = match signal(SignalKind::user_defined2()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(
+                    "Failed to listen for SIGUSR2, debug logging can't be toggled: {e}"
+                );
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = usr2.recv() => toggle_debug(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::validate_directive;
+
+    #[test]
+    fn directives() {
+        assert!(validate_directive("info").is_ok());
+        assert!(validate_directive("pingora_core=warn").is_ok());
+        assert!(validate_directive("river::proxy[span]=debug").is_ok());
+        assert!(validate_directive("pingora_core=loud").is_err());
+    }
+}
@@ -1,23 +1,95 @@
+mod access_log;
+mod admin;
 mod config;
 mod files;
+mod logging;
+mod metrics;
+mod privileges;
 mod proxy;
+mod reload;
+mod shutdown;
+mod stream;
+mod systemd;
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod tls;
 
-use crate::{files::river_file_server, proxy::river_proxy_service};
+use crate::{
+    access_log::AccessLogReopener,
+    admin::river_admin_service,
+    files::river_file_server,
+    proxy::river_proxy_service,
+    stream::river_stream_proxy,
+    tls::{CertCallback, CertStore},
+};
 use config::internal::{ListenerConfig, ListenerKind};
-use pingora::{server::Server, services::Service};
-use pingora_core::listeners::TlsSettings;
+use pingora::{
+    server::Server,
+    services::{listening::Service as ListeningService, Service},
+};
+use pingora_core::{
+    apps::HttpServerOptions, listeners::TlsSettings, services::background::background_service,
+};
 
 fn main() {
     // Set up tracing, including catching `log` crate logs from pingora crates
-    tracing_subscriber::fmt::init();
+    logging::init();
 
     // Read from the various configuration files
-    let conf = config::render_config();
+    let (conf, secrets) = config::render_config();
+
+    // Switch to the configured log level, format, and output
+    if let Err(e) = logging::apply(&conf.log) {
+        panic!("Failed to configure logging: {e}");
+    }
+
+    // Create the runtime directory for the pidfile and upgrade socket, if they are
+    // used, and clean up after a previous instance that didn't exit cleanly
+    if let Err(e) = config::runtime::prepare(&conf) {
+        panic!("Failed to prepare runtime directory: {e}");
+    }
+
+    // Sockets passed by systemd socket activation, for `systemd:` listeners
+    let activation = systemd::Activation::from_env()
+        .and_then(|activation| activation.check(&conf).map(|()| activation))
+        .unwrap_or_else(|e| {
+            panic!("Failed to use sockets passed by systemd: {e}");
+        });
+
+    // Listeners are bound before dropping privileges, which Pingora does when
+    // daemonizing, and River does after bootstrapping otherwise
+    let prebound = privileges::Prebound::bind(&conf).unwrap_or_else(|e| {
+        panic!("Failed to bind listeners: {e}");
+    });
+    let run_as = privileges::RunAs::foreground(&conf).unwrap_or_else(|e| {
+        panic!("Failed to look up user and group: {e}");
+    });
+
+    // Read before daemonizing, as the watchdog is only meant for the process started
+    // by systemd
+    let notify = systemd::SystemdNotify::from_env(&conf);
+
+    // Reloads on SIGHUP are compared against the configuration we started with
+    reload::init(&conf, secrets);
 
     // Start the Server, which we will add services to.
     let mut my_server =
         Server::new_with_opt_and_conf(conf.pingora_opt(), conf.pingora_server_conf());
 
+    // Load all TLS certificates up front, so they can be shared between listeners
+    // and reloaded in the background when they change on disk
+    let certs = CertStore::from_config(&conf).unwrap_or_else(|e| {
+        panic!("Error loading TLS certificates: {e}");
+    });
+
+    // The admin API lists every service, so it is created before the other services
+    // take their parts of the configuration
+    let admin = conf.admin.clone().map(|admin| {
+        tracing::info!("Serving the admin API on {:?}", admin.listen);
+        river_admin_service(admin, conf.clone())
+    });
+
     tracing::info!("Applying Basic Proxies...");
     let mut services: Vec<Box<dyn Service>> = vec![];
 
@@ -25,19 +97,74 @@ fn main() {
     // control, but don't support things like load balancing, health checks, etc.
     for beep in conf.basic_proxies {
         tracing::info!("Configuring Basic Proxy: {}", beep.name);
-        let service = river_proxy_service(beep, &my_server);
-        services.push(service);
+        let sockets = activation.sockets(&beep.listeners);
+        let service = river_proxy_service(beep, &certs, &my_server);
+        services.push(prebound.wrap(sockets.wrap(service)));
     }
 
     for fs in conf.file_servers {
         tracing::info!("Configuring File Server: {}", fs.name);
-        let service = river_file_server(fs, &my_server);
-        services.push(service);
+        let sockets = activation.sockets(&fs.listeners);
+        let service = river_file_server(fs, &certs, &my_server);
+        services.push(prebound.wrap(sockets.wrap(service)));
+    }
+
+    for sp in conf.stream_proxies {
+        tracing::info!("Configuring Stream Proxy: {}", sp.name);
+        let sockets = activation.sockets(&sp.listeners);
+        let service = river_stream_proxy(sp, &certs, &my_server);
+        services.push(prebound.wrap(sockets.wrap(service)));
+    }
+
+    if let Some(metrics) = conf.metrics {
+        tracing::info!("Serving Prometheus metrics on {}", metrics.listen);
+        let mut prom = ListeningService::prometheus_http_service();
+        prom.add_tcp(&metrics.listen.to_string());
+        services.push(prebound.wrap(Box::new(prom)));
+    }
+
+    if let Some(admin) = admin {
+        services.push(prebound.wrap(admin));
+    }
+
+    services.push(Box::new(background_service(
+        "TLS certificate watcher",
+        certs.watcher(),
+    )));
+    services.push(Box::new(background_service(
+        "Access log reopener",
+        AccessLogReopener,
+    )));
+    services.push(Box::new(background_service(
+        "Debug log toggle",
+        logging::DebugToggle,
+    )));
+    services.push(Box::new(background_service(
+        "Configuration reloader",
+        reload::ConfigReloader,
+    )));
+    services.push(Box::new(background_service(
+        "Drain progress",
+        shutdown::DrainProgress::new(conf.shutdown),
+    )));
+    if let Some(notify) = notify {
+        services.push(Box::new(notify));
+    }
+    if let Some(tracing_conf) = conf.tracing {
+        services.push(Box::new(background_service(
+            "OpenTelemetry exporter",
+            telemetry::TelemetryExporter::new(tracing_conf),
+        )));
     }
 
     // Now we hand it over to pingora to run forever.
     tracing::info!("Bootstrapping...");
     my_server.bootstrap();
+    if let Some(run_as) = run_as {
+        if let Err(e) = run_as.drop_privileges() {
+            panic!("Failed to drop privileges: {e}");
+        }
+    }
     tracing::info!("Bootstrapped. Adding Services...");
     my_server.add_services(services);
     tracing::info!("Starting Server...");
@@ -47,6 +174,7 @@ fn main() {
 pub fn populate_listners<T>(
     listeners: Vec<ListenerConfig>,
     service: &mut pingora_core::services::listening::Service<T>,
+    certs: &CertStore,
 ) {
     for list_cfg in listeners {
         // NOTE: See https://github.com/cloudflare/pingora/issues/182 for tracking "paths aren't
@@ -54,41 +182,74 @@ pub fn populate_listners<T>(
         //
         // See also https://github.com/cloudflare/pingora/issues/183 for tracking "ip addrs shouldn't
         // be strings"
-        match list_cfg.source {
+        let (addr, tls, offer_h2) = match list_cfg.source {
             ListenerKind::Tcp {
                 addr,
-                tls: Some(tls_cfg),
+                tls,
+                offer_h2,
+            } => (addr, tls, offer_h2),
+            // The socket is added to Pingora's listener table under this address,
+            // see [systemd::Activation]
+            ListenerKind::Systemd {
+                name,
+                tls,
                 offer_h2,
-            } => {
-                let cert_path = tls_cfg
-                    .cert_path
-                    .to_str()
-                    .expect("cert path should be utf8");
-                let key_path = tls_cfg.key_path.to_str().expect("key path should be utf8");
+            } => (systemd::listener_key(&name), tls, offer_h2),
+            ListenerKind::Uds(path) => {
+                let path = path.to_str().unwrap();
+                service.add_uds(path, None); // todo
+                continue;
+            }
+        };
+        match tls {
+            Some(tls_cfg) => {
+                // Certificates are provided per-handshake, so that they can be
+                // swapped out if they are rotated on disk
+                let cert = certs
+                    .get(&tls_cfg)
+                    .expect("certificates should be loaded for all TLS listeners");
 
                 // TODO: Make conditional!
-                let mut settings = TlsSettings::intermediate(cert_path, key_path)
-                    .expect("adding TLS listener shouldn't fail");
+                let mut settings =
+                    TlsSettings::with_callbacks(Box::new(CertCallback(cert.clone())))
+                        .expect("adding TLS listener shouldn't fail");
                 if offer_h2 {
                     settings.enable_h2();
                 }
+                if cert.ocsp_enabled() {
+                    settings
+                        .set_status_callback(move |ssl| match cert.ocsp_staple() {
+                            Some(staple) => {
+                                ssl.set_ocsp_status(staple.der())?;
+                                Ok(true)
+                            }
+                            None => Ok(false),
+                        })
+                        .expect("setting OCSP status callback shouldn't fail");
+                }
 
                 service.add_tls_with_settings(&addr, None, settings);
             }
-            ListenerKind::Tcp {
-                addr,
-                tls: None,
-                offer_h2,
-            } => {
-                if offer_h2 {
-                    panic!("Unsupported configuration: {addr:?} configured without TLS, but H2 enabled which requires TLS");
-                }
+            None => {
+                // NOTE: h2c is enabled for the whole service, see `h2c_requested`
                 service.add_tcp(&addr);
             }
-            ListenerKind::Uds(path) => {
-                let path = path.to_str().unwrap();
-                service.add_uds(path, None); // todo
-            }
         }
     }
 }
+
+/// Server options for a service that accepts h2c, see [h2c_requested]
+pub fn h2c_options() -> HttpServerOptions {
+    let mut options = HttpServerOptions::default();
+    options.h2c = true;
+    options
+}
+
+/// Do the listeners of this service accept h2c (HTTP2 with prior knowledge)?
+///
+/// Pingora enables h2c per service rather than per listener, and then handles ALL
+/// connections of the service as HTTP2, so h2c listeners can't be combined with
+/// other listeners, see [config::internal::check_h2c_listeners].
+pub fn h2c_requested(listeners: &[ListenerConfig]) -> bool {
+    listeners.iter().any(|l| l.source.h2c())
+}
@@ -0,0 +1,276 @@
+//! Prometheus metrics
+//!
+//! All metrics are registered with the default [prometheus] registry, which is
+//! served by pingora's Prometheus service when `system.metrics` is configured.
+//!
+//! Each service holds a [ServiceMetrics] with its labelled metrics, to avoid looking
+//! them up for every request.
+
+use std::{path::Path, sync::LazyLock, time::Instant};
+
+use prometheus::{
+    core::Collector, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+};
+
+static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "river_requests_total",
+        "Number of completed requests, by response status class",
+        &["service", "status"]
+    )
+    .unwrap()
+});
+
+static REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "river_request_duration_seconds",
+        "Time from the start of a request until the response was completed",
+        &["service"]
+    )
+    .unwrap()
+});
+
+static UPSTREAM_CONNECT_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "river_upstream_connect_errors_total",
+        "Number of failed attempts to connect to an upstream",
+        &["service"]
+    )
+    .unwrap()
+});
+
+static BYTES_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "river_downstream_bytes_received_total",
+        "Number of body (or stream) bytes received from downstream clients",
+        &["service"]
+    )
+    .unwrap()
+});
+
+static BYTES_SENT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "river_downstream_bytes_sent_total",
+        "Number of body (or stream) bytes sent to downstream clients",
+        &["service"]
+    )
+    .unwrap()
+});
+
+static ACTIVE_REQUESTS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "river_active_requests",
+        "Number of requests currently being handled by an HTTP service",
+        &["service"]
+    )
+    .unwrap()
+});
+
+static ACTIVE_CONNECTIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "river_active_connections",
+        "Number of connections currently being forwarded by a stream service",
+        &["service"]
+    )
+    .unwrap()
+});
+
+static ACTIVE_UPGRADED_STREAMS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "river_active_upgraded_streams",
+        "Number of upgraded (e.g. WebSocket) streams currently open in an HTTP service",
+        &["service"]
+    )
+    .unwrap()
+});
+
+static RATE_LIMIT_REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "river_rate_limit_rejections_total",
+        "Number of requests or connections rejected by a rate limiting rule",
+        &["service", "rule"]
+    )
+    .unwrap()
+});
+
+static OCSP_STAPLE_STALE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "river_ocsp_staple_stale",
+        "Whether the OCSP response of a certificate has expired, and is no longer stapled",
+        &["cert"]
+    )
+    .unwrap()
+});
+
+/// The metrics of a single service
+pub struct ServiceMetrics {
+    service: String,
+    request_duration: Histogram,
+    upstream_connect_errors: IntCounter,
+    bytes_received: IntCounter,
+    bytes_sent: IntCounter,
+    active_requests: IntGauge,
+    active_connections: IntGauge,
+    active_upgraded_streams: IntGauge,
+}
+
+impl ServiceMetrics {
+    /// Create the metrics for the service with the given name
+    pub fn new(service: &str) -> Self {
+        Self {
+            service: service.to_string(),
+            request_duration: REQUEST_DURATION.with_label_values(&[service]),
+            upstream_connect_errors: UPSTREAM_CONNECT_ERRORS.with_label_values(&[service]),
+            bytes_received: BYTES_RECEIVED.with_label_values(&[service]),
+            bytes_sent: BYTES_SENT.with_label_values(&[service]),
+            active_requests: ACTIVE_REQUESTS.with_label_values(&[service]),
+            active_connections: ACTIVE_CONNECTIONS.with_label_values(&[service]),
+            active_upgraded_streams: ACTIVE_UPGRADED_STREAMS.with_label_values(&[service]),
+        }
+    }
+
+    /// A request has started, returns the time used to measure its duration
+    pub fn request_started(&self) -> Instant {
+        self.active_requests.inc();
+        Instant::now()
+    }
+
+    /// A request started with [Self::request_started] has completed
+    ///
+    /// `status` is `None` if no response header was sent.
+    pub fn request_finished(
+        &self,
+        started: Instant,
+        status: Option<u16>,
+        bytes_received: usize,
+        bytes_sent: usize,
+    ) {
+        self.active_requests.dec();
+        self.request_duration
+            .observe(started.elapsed().as_secs_f64());
+        REQUESTS
+            .with_label_values(&[&self.service, status_class(status)])
+            .inc();
+        self.bytes_received.inc_by(bytes_received as u64);
+        self.bytes_sent.inc_by(bytes_sent as u64);
+    }
+
+    /// A stream connection has been accepted
+    pub fn connection_started(&self) {
+        self.active_connections.inc();
+    }
+
+    /// A stream connection started with [Self::connection_started] has closed
+    pub fn connection_finished(&self, bytes_received: u64, bytes_sent: u64) {
+        self.active_connections.dec();
+        self.bytes_received.inc_by(bytes_received);
+        self.bytes_sent.inc_by(bytes_sent);
+    }
+
+    /// The upstream accepted a request to upgrade the connection,
+    /// returns the number of upgraded streams now open
+    pub fn upgrade_started(&self) -> i64 {
+        self.active_upgraded_streams.inc();
+        self.active_upgraded_streams.get()
+    }
+
+    /// A stream started with [Self::upgrade_started] has closed,
+    /// returns the number of upgraded streams still open
+    pub fn upgrade_finished(&self) -> i64 {
+        self.active_upgraded_streams.dec();
+        self.active_upgraded_streams.get()
+    }
+
+    /// Connecting to an upstream failed
+    pub fn upstream_connect_error(&self) {
+        self.upstream_connect_errors.inc();
+    }
+
+    /// A request or connection was rejected by the given rate limiting rule
+    pub fn rate_limited(&self, rule: &str) {
+        RATE_LIMIT_REJECTIONS
+            .with_label_values(&[&self.service, rule])
+            .inc();
+    }
+}
+
+/// The number of requests and stream connections being handled by all services
+pub fn in_flight() -> (u64, u64) {
+    (
+        gauge_total(&ACTIVE_REQUESTS),
+        gauge_total(&ACTIVE_CONNECTIONS),
+    )
+}
+
+/// Record whether the OCSP response of the certificate at `cert` has expired
+pub fn ocsp_staple_stale(cert: &Path, stale: bool) {
+    OCSP_STAPLE_STALE
+        .with_label_values(&[&cert.display().to_string()])
+        .set(i64::from(stale));
+}
+
+/// The sum of a gauge over all of its labels
+fn gauge_total(gauge: &IntGaugeVec) -> u64 {
+    gauge
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| metric.get_gauge().get_value() as u64)
+        .sum()
+}
+
+/// The label used for the class of a response status
+fn status_class(status: Option<u16>) -> &'static str {
+    match status {
+        Some(100..=199) => "1xx",
+        Some(200..=299) => "2xx",
+        Some(300..=399) => "3xx",
+        Some(400..=499) => "4xx",
+        Some(500..=599) => "5xx",
+        _ => "none",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{status_class, ServiceMetrics, ACTIVE_UPGRADED_STREAMS, REQUESTS};
+
+    #[test]
+    fn status_classes() {
+        assert_eq!(status_class(Some(101)), "1xx");
+        assert_eq!(status_class(Some(204)), "2xx");
+        assert_eq!(status_class(Some(404)), "4xx");
+        assert_eq!(status_class(Some(503)), "5xx");
+        assert_eq!(status_class(Some(999)), "none");
+        assert_eq!(status_class(None), "none");
+    }
+
+    #[test]
+    fn request_counts() {
+        let metrics = ServiceMetrics::new("metrics-test");
+        let started = metrics.request_started();
+        metrics.request_finished(started, Some(200), 10, 20);
+
+        let count = REQUESTS.with_label_values(&["metrics-test", "2xx"]).get();
+        assert_eq!(count, 1);
+        assert_eq!(metrics.active_requests.get(), 0);
+        assert_eq!(metrics.bytes_received.get(), 10);
+        assert_eq!(metrics.bytes_sent.get(), 20);
+    }
+
+    #[test]
+    fn upgraded_streams() {
+        let metrics = ServiceMetrics::new("metrics-upgrade-test");
+        let gauge = || {
+            ACTIVE_UPGRADED_STREAMS
+                .with_label_values(&["metrics-upgrade-test"])
+                .get()
+        };
+        assert_eq!(metrics.upgrade_started(), 1);
+        assert_eq!(metrics.upgrade_started(), 2);
+        assert_eq!(gauge(), 2);
+        assert_eq!(metrics.upgrade_finished(), 1);
+        assert_eq!(gauge(), 1);
+    }
+}
@@ -0,0 +1,246 @@
+//! Binding listeners before dropping privileges
+//!
+//! Pingora switches to `system.user` and `system.group` when it daemonizes, which
+//! happens before any service starts and binds its listeners. When running in the
+//! foreground, River switches to them itself at the same point, see [RunAs]. So that
+//! River can be started as root to listen on ports below 1024, and then serve as an
+//! unprivileged user, all listeners are bound before dropping privileges. They are
+//! handed to their services through Pingora's listener table, the same way as
+//! sockets passed by systemd, see [crate::systemd].
+//!
+//! During an upgrade, the previous instance passes its listeners to the new one, so
+//! nothing is bound here. Listeners that the previous instance did not have are
+//! bound by Pingora after dropping privileges.
+
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    io::ErrorKind,
+    net::TcpListener,
+    os::{
+        fd::{IntoRawFd, RawFd},
+        unix::net::UnixListener,
+    },
+    path::Path,
+};
+
+use nix::unistd::{chown, initgroups, setgid, setuid, Gid, Uid};
+use pingora::services::Service;
+
+use crate::{
+    config::internal::{AdminListener, Config, ListenerKind},
+    systemd::ActivatedSockets,
+};
+
+/// Listening sockets bound before dropping privileges, by their address in
+/// Pingora's listener table
+#[derive(Debug, Default)]
+pub struct Prebound {
+    sockets: BTreeMap<String, RawFd>,
+}
+
+impl Prebound {
+    /// Bind every listener of the configuration, if privileges will be dropped
+    ///
+    /// This includes the metrics and admin API listeners. `systemd:` listeners are
+    /// already bound by systemd.
+    pub fn bind(config: &Config) -> Result<Self, String> {
+        let mut prebound = Self::default();
+        let Some((uid, gid)) = config.run_as()? else {
+            return Ok(prebound);
+        };
+        if config.upgrade {
+            return Ok(prebound);
+        }
+
+        for listener in config.listeners() {
+            match &listener.source {
+                ListenerKind::Tcp { addr, .. } => prebound.bind_tcp(addr)?,
+                ListenerKind::Uds(path) => prebound.bind_uds(path, uid, gid)?,
+                ListenerKind::Systemd { .. } => {}
+            }
+        }
+        if let Some(metrics) = &config.metrics {
+            prebound.bind_tcp(&metrics.listen.to_string())?;
+        }
+        if let Some(admin) = &config.admin {
+            match &admin.listen {
+                AdminListener::Tcp(addr) => prebound.bind_tcp(&addr.to_string())?,
+                AdminListener::Uds(path) => prebound.bind_uds(path, uid, gid)?,
+            }
+        }
+        Ok(prebound)
+    }
+
+    /// Make the sockets available to the service when it starts
+    ///
+    /// All services share one listener table, so each service adds all of the
+    /// sockets, and its listeners use the ones for their addresses.
+    pub fn wrap(&self, service: Box<dyn Service>) -> Box<dyn Service> {
+        let sockets = self
+            .sockets
+            .iter()
+            .map(|(addr, fd)| (addr.clone(), *fd))
+            .collect();
+        ActivatedSockets::new(sockets).wrap(service)
+    }
+
+    /// Bind a TCP listener, with the address as written in the configuration
+    fn bind_tcp(&mut self, addr: &str) -> Result<(), String> {
+        if self.sockets.contains_key(addr) {
+            return Ok(());
+        }
+        let listener = TcpListener::bind(addr)
+            .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+            .map_err(|e| format!("Failed to bind listener '{addr}': {e}"))?;
+        tracing::info!("Bound listener '{addr}' before dropping privileges");
+        self.sockets
+            .insert(addr.to_string(), listener.into_raw_fd());
+        Ok(())
+    }
+
+    /// Bind a unix domain socket listener, owned by the user and group River will
+    /// run as, so that Pingora can set its permissions
+    fn bind_uds(&mut self, path: &Path, uid: Uid, gid: Gid) -> Result<(), String> {
+        let addr = path
+            .to_str()
+            .ok_or_else(|| format!("Listener path '{}' is not UTF-8", path.display()))?;
+        if self.sockets.contains_key(addr) {
+            return Ok(());
+        }
+
+        // Remove the socket of a previous instance, as Pingora does when binding
+        match std::fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to remove old socket '{addr}': {e}")),
+        }
+        let listener = UnixListener::bind(path)
+            .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+            .map_err(|e| format!("Failed to bind listener '{addr}': {e}"))?;
+        chown(path, Some(uid), Some(gid))
+            .map_err(|e| format!("Failed to change the owner of socket '{addr}': {e}"))?;
+        tracing::info!("Bound listener '{addr}' before dropping privileges");
+        self.sockets
+            .insert(addr.to_string(), listener.into_raw_fd());
+        Ok(())
+    }
+}
+
+/// The user and group to switch to, when running in the foreground
+///
+/// Read from the configuration before it is handed to the services, and applied
+/// after Pingora is bootstrapped, where it would daemonize. Users are switched in
+/// the same way as Pingora: the supplementary groups of the user are set, then the
+/// group, then the user. When daemonizing, Pingora does this itself.
+#[derive(Debug)]
+pub struct RunAs {
+    user: Option<String>,
+    uid: Uid,
+    gid: Gid,
+}
+
+impl RunAs {
+    /// The user and group to switch to, or `None` if privileges are not dropped,
+    /// or dropped by Pingora when daemonizing
+    pub fn foreground(config: &Config) -> Result<Option<Self>, String> {
+        if config.daemonize {
+            return Ok(None);
+        }
+        Ok(config.run_as()?.map(|(uid, gid)| Self {
+            user: config.user.clone(),
+            uid,
+            gid,
+        }))
+    }
+
+    /// Switch to the user and group
+    pub fn drop_privileges(&self) -> Result<(), String> {
+        let Self { user, uid, gid } = self;
+        if let Some(user) = user {
+            let name = CString::new(user.as_str())
+                .map_err(|_| format!("User name '{user}' contains a NUL byte"))?;
+            initgroups(&name, *gid)
+                .map_err(|e| format!("Failed to set the groups of user '{user}': {e}"))?;
+        }
+        setgid(*gid).map_err(|e| format!("Failed to switch to group {gid}: {e}"))?;
+        setuid(*uid).map_err(|e| format!("Failed to switch to user {uid}: {e}"))?;
+        tracing::info!("Running as user {uid} and group {gid}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::{
+        fd::{BorrowedFd, RawFd},
+        unix::fs::MetadataExt,
+    };
+
+    use nix::{
+        fcntl::{fcntl, FcntlArg, OFlag},
+        sys::socket::{getsockopt, sockopt},
+        unistd::{Uid, User},
+    };
+
+    use crate::{
+        config::internal::{
+            AdminConfig, AdminListener, Config, FileServerConfig, ListenerConfig, ListenerKind,
+        },
+        testing::TempDir,
+    };
+
+    use super::Prebound;
+
+    fn listening(fd: RawFd) -> bool {
+        // SAFETY: the sockets stay open for the lifetime of the test
+        let socket = unsafe { BorrowedFd::borrow_raw(fd) };
+        let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL).unwrap());
+        getsockopt(&socket, sockopt::AcceptConn) == Ok(true) && flags.contains(OFlag::O_NONBLOCK)
+    }
+
+    #[test]
+    fn bind_listeners() {
+        let dir = TempDir::new();
+        let admin_socket = dir.join("admin.sock");
+
+        let user = User::from_uid(Uid::effective()).unwrap().unwrap();
+        let mut config = Config {
+            file_servers: vec![FileServerConfig {
+                name: "Files".into(),
+                listeners: vec![ListenerConfig {
+                    source: ListenerKind::Tcp {
+                        addr: "127.0.0.1:0".into(),
+                        tls: None,
+                        offer_h2: false,
+                    },
+                }],
+                base_path: None,
+                access_log: None,
+            }],
+            admin: Some(AdminConfig {
+                listen: AdminListener::Uds(admin_socket.clone()),
+                token: None,
+            }),
+            ..Default::default()
+        };
+
+        // Nothing is bound unless privileges are dropped
+        assert!(Prebound::bind(&config).unwrap().sockets.is_empty());
+
+        config.user = Some(user.name);
+        let prebound = Prebound::bind(&config).unwrap();
+        assert_eq!(
+            prebound.sockets.keys().collect::<Vec<_>>(),
+            vec![admin_socket.to_str().unwrap(), "127.0.0.1:0"]
+        );
+        assert!(prebound.sockets.values().all(|fd| listening(*fd)));
+        let meta = std::fs::metadata(&admin_socket).unwrap();
+        assert_eq!(meta.uid(), user.uid.as_raw());
+        assert_eq!(meta.gid(), user.gid.as_raw());
+
+        // The previous instance passes its listeners during an upgrade
+        config.upgrade = true;
+        assert!(Prebound::bind(&config).unwrap().sockets.is_empty());
+    }
+}
@@ -4,34 +4,47 @@
 //! this includes creation of HTTP proxy services, as well as Path Control
 //! modifiers.
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::{sync::Arc, time::Instant};
 
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use futures_util::FutureExt;
 
-use pingora::{server::Server, Error, ErrorType};
+use http::{header, HeaderName, StatusCode};
+use pingora::{server::Server, Error};
 use pingora_core::{upstreams::peer::HttpPeer, Result};
 use pingora_http::{RequestHeader, ResponseHeader};
 use pingora_load_balancing::{
-    discovery,
     selection::{
         consistent::KetamaHashing, BackendIter, BackendSelection, FVNHash, Random, RoundRobin,
     },
-    Backend, Backends, LoadBalancer,
+    LoadBalancer,
 };
 use pingora_proxy::{ProxyHttp, Session};
+use tracing::{Instrument, Span};
 
 use crate::{
-    config::internal::{PathControl, ProxyConfig, SelectionKind},
+    access_log::AccessLog,
+    admin::registry::{
+        self, ActiveRequest, BackendAction, BackendStats, ServiceState, UpstreamStatus,
+    },
+    config::internal::{
+        Config, HeaderModifierConfig, PathControl, ProxyConfig, RateLimitingConfig,
+        RequestFilterConfig, SelectionKind, UpstreamOptions, WebSocketConfig,
+    },
+    h2c_options, h2c_requested,
+    metrics::ServiceMetrics,
     populate_listners,
     proxy::{
         request_modifiers::RequestModifyMod, request_selector::RequestSelector,
         response_modifiers::ResponseModifyMod,
     },
+    reload::{self, ServiceReload},
+    telemetry,
+    tls::CertStore,
 };
 
 use self::{
-    rate_limiting::{multi::MultiRaterInstance, single::SingleInstance, Outcome},
+    rate_limiting::{multi::MultiRaterInstance, single::SingleInstance, Outcome, RateLimitStatus},
     request_filters::RequestFilterMod,
 };
 
@@ -46,6 +59,172 @@ pub struct RateLimiters {
     request_filter_stage_single: Vec<SingleInstance>,
 }
 
+impl RateLimiters {
+    /// Create the rate limiters for all rules of the given [RateLimitingConfig]
+    pub fn from_conf(conf: &RateLimitingConfig) -> Self {
+        let mut request_filter_stage_multi = vec![];
+        let mut request_filter_stage_single = vec![];
+
+        for rule in conf.rules.iter().cloned() {
+            match rule {
+                rate_limiting::AllRateConfig::Single { kind, config } => {
+                    let rater = SingleInstance::new(config, kind);
+                    request_filter_stage_single.push(rater);
+                }
+                rate_limiting::AllRateConfig::Multi { kind, config } => {
+                    let rater = MultiRaterInstance::new(config, kind);
+                    request_filter_stage_multi.push(rater);
+                }
+            }
+        }
+
+        Self {
+            request_filter_stage_multi,
+            request_filter_stage_single,
+        }
+    }
+}
+
+/// The settings of a [RiverProxyService] that can be changed while it is running,
+/// see [crate::reload]
+pub struct ProxyState<BS: BackendSelection> {
+    /// The configuration these settings were created from
+    conf: ProxyConfig,
+    /// All modifiers used when implementing the [ProxyHttp] trait.
+    pub modifiers: Modifiers,
+    /// Load Balancer
+    pub load_balancer: Arc<LoadBalancer<BS>>,
+    pub request_selector: RequestSelector,
+    pub rate_limiters: Arc<RateLimiters>,
+    /// WebSocket upgrade handling
+    pub websocket: WebSocketConfig,
+}
+
+impl<BS> ProxyState<BS>
+where
+    BS: BackendSelection + Send + Sync + 'static,
+    BS::Iter: BackendIter,
+{
+    /// Create the settings from the given [ProxyConfig]
+    ///
+    /// The load balancer and rate limiters of `previous` are kept if their part of
+    /// the configuration has not changed, so that they keep their state.
+    fn from_conf(conf: ProxyConfig, previous: Option<&Self>) -> Self {
+        let modifiers = Modifiers::from_conf(&conf.path_control);
+
+        let load_balancer = match previous {
+            Some(p) if reload::same_peers(&p.conf.upstreams, &conf.upstreams) => {
+                p.load_balancer.clone()
+            }
+            _ => Arc::new(registry::static_load_balancer(
+                conf.upstreams.clone(),
+                previous.map(|p| &*p.load_balancer),
+            )),
+        };
+        let rate_limiters = match previous {
+            Some(p) if p.conf.rate_limiting == conf.rate_limiting => p.rate_limiters.clone(),
+            _ => Arc::new(RateLimiters::from_conf(&conf.rate_limiting)),
+        };
+
+        Self {
+            modifiers,
+            load_balancer,
+            request_selector: conf.upstream_options.selector,
+            rate_limiters,
+            websocket: conf.websocket.clone(),
+            conf,
+        }
+    }
+}
+
+impl<BS> ServiceState for ArcSwap<ProxyState<BS>>
+where
+    BS: BackendSelection + Send + Sync + 'static,
+    BS::Iter: BackendIter,
+{
+    fn upstreams(&self) -> Vec<UpstreamStatus> {
+        registry::pool_status(None, &self.load().load_balancer)
+    }
+
+    fn set_backend_state(&self, addr: &str, action: BackendAction) -> Vec<UpstreamStatus> {
+        registry::pool_set_backend_state(None, &self.load().load_balancer, addr, action)
+            .into_iter()
+            .collect()
+    }
+
+    fn rate_limits(&self) -> Vec<RateLimitStatus> {
+        let state = self.load();
+        let singles = state
+            .rate_limiters
+            .request_filter_stage_single
+            .iter()
+            .map(SingleInstance::status);
+        let multis = state
+            .rate_limiters
+            .request_filter_stage_multi
+            .iter()
+            .map(MultiRaterInstance::status);
+        singles.chain(multis).collect()
+    }
+
+    fn prepare_reload(
+        self: Arc<Self>,
+        config: &Config,
+    ) -> std::result::Result<ServiceReload, String> {
+        let current = self.load_full();
+        let old = &current.conf;
+        let Some(new) = config.basic_proxies.iter().find(|p| p.name == old.name) else {
+            // Reported as a change to the set of services
+            return Ok(ServiceReload::default());
+        };
+        let mut prepared = ServiceReload::default();
+
+        // Listeners and access logs are only set up when the service is created, and
+        // the selection can't change without changing the type of the load balancer
+        let mut fixed = |setting, differs| {
+            if differs {
+                prepared.requires_upgrade.push(setting);
+            }
+        };
+        fixed("listeners", new.listeners != old.listeners);
+        fixed(
+            "connectors.load-balance.selection",
+            new.upstream_options.selection != old.upstream_options.selection,
+        );
+        fixed("access-log", new.access_log != old.access_log);
+
+        let conf = ProxyConfig {
+            listeners: old.listeners.clone(),
+            access_log: old.access_log.clone(),
+            upstream_options: UpstreamOptions {
+                selector: new.upstream_options.selector,
+                ..old.upstream_options.clone()
+            },
+            ..new.clone()
+        };
+        let mut changed = |setting, differs| {
+            if differs {
+                prepared.applied.push(setting);
+            }
+        };
+        changed("path-control", conf.path_control != old.path_control);
+        changed("rate-limiting", conf.rate_limiting != old.rate_limiting);
+        changed(
+            "connectors",
+            !reload::same_peers(&conf.upstreams, &old.upstreams)
+                || conf.upstream_options != old.upstream_options,
+        );
+        changed("websocket", conf.websocket != old.websocket);
+        if prepared.applied.is_empty() {
+            return Ok(prepared);
+        }
+
+        let state = ProxyState::from_conf(conf, Some(&current));
+        prepared.commit = Some(Box::new(move || self.store(Arc::new(state))));
+        Ok(prepared)
+    }
+}
+
 /// The [RiverProxyService] is intended to capture the behaviors used to extend
 /// the [HttpProxy] functionality by providing a [ProxyHttp] trait implementation.
 ///
@@ -54,22 +233,23 @@ pub struct RateLimiters {
 ///
 /// [request/response lifecycle]: https://github.com/cloudflare/pingora/blob/7ce6f4ac1c440756a63b0766f72dbeca25c6fc94/docs/user_guide/phase_chart.md
 pub struct RiverProxyService<BS: BackendSelection> {
-    /// All modifiers used when implementing the [ProxyHttp] trait.
-    pub modifiers: Modifiers,
-    /// Load Balancer
-    pub load_balancer: LoadBalancer<BS>,
-    pub request_selector: RequestSelector,
-    pub rate_limiters: RateLimiters,
+    /// The name of this service
+    pub name: String,
+    /// Modifiers, upstreams, and other settings that may be reloaded
+    pub state: Arc<ArcSwap<ProxyState<BS>>>,
+    pub metrics: ServiceMetrics,
+    pub access_log: Option<AccessLog>,
 }
 
 /// Create a proxy service, with the type parameters chosen based on the config file
 pub fn river_proxy_service(
     conf: ProxyConfig,
+    certs: &CertStore,
     server: &Server,
 ) -> Box<dyn pingora::services::Service> {
     // Pick the correctly monomorphized function. This makes the functions all have the
     // same signature of `fn(...) -> Box<dyn Service>`.
-    type ServiceMaker = fn(ProxyConfig, &Server) -> Box<dyn pingora::services::Service>;
+    type ServiceMaker = fn(ProxyConfig, &CertStore, &Server) -> Box<dyn pingora::services::Service>;
 
     let service_maker: ServiceMaker = match conf.upstream_options.selection {
         SelectionKind::RoundRobin => RiverProxyService::<RoundRobin>::from_basic_conf,
@@ -77,7 +257,7 @@ pub fn river_proxy_service(
         SelectionKind::Fnv => RiverProxyService::<FVNHash>::from_basic_conf,
         SelectionKind::Ketama => RiverProxyService::<KetamaHashing>::from_basic_conf,
     };
-    service_maker(conf, server)
+    service_maker(conf, certs, server)
 }
 
 impl<BS> RiverProxyService<BS>
@@ -88,62 +268,107 @@ where
     /// Create a new [RiverProxyService] from the given [ProxyConfig]
     pub fn from_basic_conf(
         conf: ProxyConfig,
+        certs: &CertStore,
         server: &Server,
     ) -> Box<dyn pingora::services::Service> {
-        let modifiers = Modifiers::from_conf(&conf.path_control).unwrap();
-
-        // TODO: This maybe could be done cleaner? This is a sort-of inlined
-        // version of `LoadBalancer::try_from_iter` with the ability to add
-        // metadata extensions
-        let mut backends = BTreeSet::new();
-        for uppy in conf.upstreams {
-            let mut backend = Backend::new(&uppy._address.to_string()).unwrap();
-            assert!(backend.ext.insert::<HttpPeer>(uppy).is_none());
-            backends.insert(backend);
-        }
-        let disco = discovery::Static::new(backends);
-        let upstreams = LoadBalancer::<BS>::from_backends(Backends::new(disco));
-        upstreams
-            .update()
-            .now_or_never()
-            .expect("static should not block")
-            .expect("static should not error");
-        // end of TODO
-
-        let mut request_filter_stage_multi = vec![];
-        let mut request_filter_stage_single = vec![];
+        let access_log = conf.access_log.clone().map(|al| {
+            AccessLog::from_conf(&conf.name, al)
+                .unwrap_or_else(|e| panic!("Error opening access log for '{}': {e}", conf.name))
+        });
 
-        for rule in conf.rate_limiting.rules {
-            match rule {
-                rate_limiting::AllRateConfig::Single { kind, config } => {
-                    let rater = SingleInstance::new(config, kind);
-                    request_filter_stage_single.push(rater);
-                }
-                rate_limiting::AllRateConfig::Multi { kind, config } => {
-                    let rater = MultiRaterInstance::new(config, kind);
-                    request_filter_stage_multi.push(rater);
-                }
-            }
-        }
+        let state = ProxyState::from_conf(conf.clone(), None);
+        let state = Arc::new(ArcSwap::from_pointee(state));
+        registry::register(&conf.name, state.clone());
 
         let mut my_proxy = pingora_proxy::http_proxy_service_with_name(
             &server.configuration,
             Self {
-                modifiers,
-                load_balancer: upstreams,
-                request_selector: conf.upstream_options.selector,
-                rate_limiters: RateLimiters {
-                    request_filter_stage_multi,
-                    request_filter_stage_single,
-                },
+                name: conf.name.clone(),
+                state,
+                metrics: ServiceMetrics::new(&conf.name),
+                access_log,
             },
             &conf.name,
         );
 
-        populate_listners(conf.listeners, &mut my_proxy);
+        if h2c_requested(&conf.listeners) {
+            my_proxy
+                .app_logic_mut()
+                .expect("proxy service should not be shared yet")
+                .server_options = Some(h2c_options());
+        }
+        populate_listners(conf.listeners, &mut my_proxy, certs);
 
         Box::new(my_proxy)
     }
+
+    /// The "Request filter" stage, run within a span for this phase
+    async fn request_filter_phase(
+        &self,
+        session: &mut Session,
+        ctx: &mut RiverContext,
+    ) -> Result<bool> {
+        let state = self.state.load_full();
+
+        if session.is_upgrade_req() {
+            if !state.websocket.allow && is_websocket_req(session) {
+                tracing::trace!("Rejecting WebSocket upgrade, not allowed for this service");
+                session.downstream_session.respond_error(403).await;
+                return Ok(true);
+            }
+            // Pingora can't limit how long we wait for the downstream to send, so
+            // idleness is detected by the upstream read timeout, see `upstream_peer`
+            if let Some(timeout) = state.websocket.idle_timeout {
+                session.downstream_session.set_write_timeout(timeout);
+            }
+        }
+
+        let multis = state
+            .rate_limiters
+            .request_filter_stage_multi
+            .iter()
+            .filter_map(|l| Some((l.rule.as_str(), l.get_ticket(session)?)));
+
+        let singles = state
+            .rate_limiters
+            .request_filter_stage_single
+            .iter()
+            .filter_map(|l| Some((l.rule.as_str(), l.get_ticket(session)?)));
+
+        // Attempt to get all tokens
+        //
+        // TODO: If https://github.com/udoprog/leaky-bucket/issues/17 is resolved we could
+        // remember the buckets that we did get approved for, and "return" the unused tokens.
+        //
+        // For now, if some tickets succeed but subsequent tickets fail, the preceeding
+        // approved tokens are just "burned".
+        //
+        // TODO: If https://github.com/udoprog/leaky-bucket/issues/34 is resolved we could
+        // support a "max debt" number, allowing us to delay if acquisition of the token
+        // would happen soon-ish, instead of immediately 429-ing if the token we need is
+        // about to become available.
+        let declined = singles
+            .chain(multis)
+            .find_map(|(rule, t)| (t.now_or_never() == Outcome::Declined).then_some(rule));
+        if let Some(rule) = declined {
+            tracing::trace!(rule, "Rejecting due to rate limiting failure");
+            self.metrics.rate_limited(rule);
+            session.downstream_session.respond_error(429).await;
+            return Ok(true);
+        }
+
+        for filter in &state.modifiers.request_filters {
+            match filter.request_filter(session, ctx).await {
+                // If Ok true: we're done handling this request
+                o @ Ok(true) => return o,
+                // If Err: we return that
+                e @ Err(_) => return e,
+                // If Ok(false), we move on to the next filter
+                Ok(false) => {}
+            }
+        }
+        Ok(false)
+    }
 }
 
 //
@@ -186,71 +411,70 @@ pub struct Modifiers {
 
 impl Modifiers {
     /// Build all modifiers from the provided [PathControl]
-    pub fn from_conf(conf: &PathControl) -> Result<Self> {
-        let mut conf = conf.clone();
-
-        let mut request_filter_mods: Vec<Box<dyn RequestFilterMod>> = vec![];
-        for mut filter in conf.request_filters.drain(..) {
-            let kind = filter.remove("kind").unwrap();
-            let f: Box<dyn RequestFilterMod> = match kind.as_str() {
-                "block-cidr-range" => {
-                    Box::new(request_filters::CidrRangeFilter::from_settings(filter).unwrap())
-                }
-                other => {
-                    tracing::warn!("Unknown request filter: '{other}'");
-                    return Err(Error::new(ErrorType::Custom("Bad configuration")));
+    pub fn from_conf(conf: &PathControl) -> Self {
+        let request_filters = conf
+            .request_filters
+            .iter()
+            .map(|filter| -> Box<dyn RequestFilterMod> {
+                match filter {
+                    RequestFilterConfig::BlockCidrRange { addrs } => {
+                        Box::new(request_filters::CidrRangeFilter::new(addrs.clone()))
+                    }
                 }
-            };
-            request_filter_mods.push(f);
-        }
+            })
+            .collect();
 
-        let mut upstream_request_filters: Vec<Box<dyn RequestModifyMod>> = vec![];
-        for mut filter in conf.upstream_request_filters.drain(..) {
-            let kind = filter.remove("kind").unwrap();
-            let f: Box<dyn RequestModifyMod> = match kind.as_str() {
-                "remove-header-key-regex" => Box::new(
-                    request_modifiers::RemoveHeaderKeyRegex::from_settings(filter).unwrap(),
-                ),
-                "upsert-header" => {
-                    Box::new(request_modifiers::UpsertHeader::from_settings(filter).unwrap())
-                }
-                other => {
-                    tracing::warn!("Unknown upstream request filter: '{other}'");
-                    return Err(Error::new(ErrorType::Custom("Bad configuration")));
+        let upstream_request_filters = conf
+            .upstream_request_filters
+            .iter()
+            .map(|filter| -> Box<dyn RequestModifyMod> {
+                match filter {
+                    HeaderModifierConfig::RemoveHeaderKeyRegex { pattern } => Box::new(
+                        request_modifiers::RemoveHeaderKeyRegex::new(pattern.0.clone()),
+                    ),
+                    HeaderModifierConfig::UpsertHeader { key, value } => Box::new(
+                        request_modifiers::UpsertHeader::new(key.clone(), value.clone()),
+                    ),
                 }
-            };
-            upstream_request_filters.push(f);
-        }
+            })
+            .collect();
 
-        let mut upstream_response_filters: Vec<Box<dyn ResponseModifyMod>> = vec![];
-        for mut filter in conf.upstream_response_filters.drain(..) {
-            let kind = filter.remove("kind").unwrap();
-            let f: Box<dyn ResponseModifyMod> = match kind.as_str() {
-                "remove-header-key-regex" => Box::new(
-                    response_modifiers::RemoveHeaderKeyRegex::from_settings(filter).unwrap(),
-                ),
-                "upsert-header" => {
-                    Box::new(response_modifiers::UpsertHeader::from_settings(filter).unwrap())
-                }
-                other => {
-                    tracing::warn!("Unknown upstream response filter: '{other}'");
-                    return Err(Error::new(ErrorType::Custom("Bad configuration")));
+        let upstream_response_filters = conf
+            .upstream_response_filters
+            .iter()
+            .map(|filter| -> Box<dyn ResponseModifyMod> {
+                match filter {
+                    HeaderModifierConfig::RemoveHeaderKeyRegex { pattern } => Box::new(
+                        response_modifiers::RemoveHeaderKeyRegex::new(pattern.0.clone()),
+                    ),
+                    HeaderModifierConfig::UpsertHeader { key, value } => Box::new(
+                        response_modifiers::UpsertHeader::new(key.clone(), value.clone()),
+                    ),
                 }
-            };
-            upstream_response_filters.push(f);
-        }
+            })
+            .collect();
 
-        Ok(Self {
-            request_filters: request_filter_mods,
+        Self {
+            request_filters,
             upstream_request_filters,
             upstream_response_filters,
-        })
+        }
     }
 }
 
-/// Per-peer context. Not currently used
+/// Per-request context
 pub struct RiverContext {
     selector_buf: Vec<u8>,
+    /// Did the upstream accept an upgrade of this connection?
+    upgraded: bool,
+    /// When handling of this request started
+    started: Instant,
+    /// The address of the selected upstream, if any
+    upstream: Option<String>,
+    /// Counts this request as active for the selected backend
+    backend: Option<ActiveRequest>,
+    /// The span covering the whole request, see [crate::telemetry]
+    span: Span,
 }
 
 #[async_trait]
@@ -264,6 +488,11 @@ where
     fn new_ctx(&self) -> Self::CTX {
         RiverContext {
             selector_buf: Vec::new(),
+            upgraded: false,
+            started: self.metrics.request_started(),
+            upstream: None,
+            backend: None,
+            span: Span::none(),
         }
     }
 
@@ -272,50 +501,11 @@ where
     where
         Self::CTX: Send + Sync,
     {
-        let multis = self
-            .rate_limiters
-            .request_filter_stage_multi
-            .iter()
-            .filter_map(|l| l.get_ticket(session));
-
-        let singles = self
-            .rate_limiters
-            .request_filter_stage_single
-            .iter()
-            .filter_map(|l| l.get_ticket(session));
-
-        // Attempt to get all tokens
-        //
-        // TODO: If https://github.com/udoprog/leaky-bucket/issues/17 is resolved we could
-        // remember the buckets that we did get approved for, and "return" the unused tokens.
-        //
-        // For now, if some tickets succeed but subsequent tickets fail, the preceeding
-        // approved tokens are just "burned".
-        //
-        // TODO: If https://github.com/udoprog/leaky-bucket/issues/34 is resolved we could
-        // support a "max debt" number, allowing us to delay if acquisition of the token
-        // would happen soon-ish, instead of immediately 429-ing if the token we need is
-        // about to become available.
-        if singles
-            .chain(multis)
-            .any(|t| t.now_or_never() == Outcome::Declined)
-        {
-            tracing::trace!("Rejecting due to rate limiting failure");
-            session.downstream_session.respond_error(429).await;
-            return Ok(true);
-        }
-
-        for filter in &self.modifiers.request_filters {
-            match filter.request_filter(session, ctx).await {
-                // If Ok true: we're done handling this request
-                o @ Ok(true) => return o,
-                // If Err: we return that
-                e @ Err(_) => return e,
-                // If Ok(false), we move on to the next filter
-                Ok(false) => {}
-            }
-        }
-        Ok(false)
+        ctx.span = telemetry::request_span(&self.name, session.req_header());
+        let span = tracing::info_span!(parent: &ctx.span, "request_filter");
+        self.request_filter_phase(session, ctx)
+            .instrument(span)
+            .await
     }
 
     /// Handle the "upstream peer" phase, where we pick which upstream to proxy to.
@@ -327,22 +517,40 @@ where
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
-        let key = (self.request_selector)(ctx, session);
+        let _span = tracing::info_span!(parent: &ctx.span, "upstream_peer").entered();
+        let state = self.state.load();
+
+        let key = (state.request_selector)(ctx, session);
 
-        let backend = self.load_balancer.select(key, 256);
+        let backend = state.load_balancer.select(key, 256);
 
         // Manually clear the selector buf to avoid accidental leaks
         ctx.selector_buf.clear();
 
         let backend =
             backend.ok_or_else(|| pingora::Error::new_str("Unable to determine backend"))?;
+        // Replaces the backend of a previous attempt, if this request is retried
+        ctx.backend = backend.ext.get::<Arc<BackendStats>>().map(|s| s.selected());
 
         // Retrieve the HttpPeer from the associated backend metadata
-        backend
+        let mut peer = backend
             .ext
             .get::<HttpPeer>()
             .map(|p| Box::new(p.clone()))
-            .ok_or_else(|| pingora::Error::new_str("Fatal: Missing selected backend metadata"))
+            .ok_or_else(|| pingora::Error::new_str("Fatal: Missing selected backend metadata"))?;
+
+        ctx.upstream = Some(peer._address.to_string());
+        ctx.span.record("upstream", ctx.upstream.as_deref());
+
+        // Upgraded connections may be idle for much longer than regular requests
+        if session.is_upgrade_req() {
+            if let Some(timeout) = state.websocket.idle_timeout {
+                peer.options.read_timeout = Some(timeout);
+                peer.options.write_timeout = Some(timeout);
+            }
+        }
+
+        Ok(peer)
     }
 
     /// Handle the "upstream request filter" phase, where we can choose to make
@@ -358,10 +566,19 @@ where
         header: &mut RequestHeader,
         ctx: &mut Self::CTX,
     ) -> Result<()> {
-        for filter in &self.modifiers.upstream_request_filters {
-            filter.upstream_request_filter(session, header, ctx).await?;
+        // Added before the modifiers, so they can still replace the trace context
+        telemetry::inject(&ctx.span, header);
+
+        let span = tracing::info_span!(parent: &ctx.span, "upstream_request_filter");
+        let state = self.state.load_full();
+        async {
+            for filter in &state.modifiers.upstream_request_filters {
+                filter.upstream_request_filter(session, header, ctx).await?;
+            }
+            Ok(())
         }
-        Ok(())
+        .instrument(span)
+        .await
     }
 
     /// Handle the "upstream response filter" phase, where we can choose to make
@@ -375,33 +592,179 @@ where
         upstream_response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) {
-        for filter in &self.modifiers.upstream_response_filters {
+        let _span = tracing::info_span!(parent: &ctx.span, "upstream_response_filter").entered();
+
+        if upstream_response.status == StatusCode::SWITCHING_PROTOCOLS {
+            ctx.upgraded = true;
+            let active = self.metrics.upgrade_started();
+            tracing::debug!(active, "Upgraded stream started");
+        }
+
+        for filter in &self.state.load().modifiers.upstream_response_filters {
             filter.upstream_response_filter(session, upstream_response, ctx);
         }
     }
+
+    /// Handle a failure to connect to the selected upstream
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        _ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        self.metrics.upstream_connect_error();
+        e
+    }
+
+    /// Handle the "logging" phase, which happens once the request is complete
+    async fn logging(&self, session: &mut Session, e: Option<&pingora::Error>, ctx: &mut Self::CTX)
+    where
+        Self::CTX: Send + Sync,
+    {
+        let status = session.response_written().map(|r| r.status.as_u16());
+        ctx.span.record("http.response.status_code", status);
+        if e.is_some() || status.is_some_and(|s| s >= 500) {
+            ctx.span.record("otel.status_code", "ERROR");
+        }
+
+        self.metrics.request_finished(
+            ctx.started,
+            status,
+            session.as_downstream().body_bytes_read(),
+            session.as_downstream().body_bytes_sent(),
+        );
+        if let Some(access_log) = &self.access_log {
+            access_log.log(session, ctx.started.elapsed(), ctx.upstream.as_deref());
+        }
+
+        if ctx.upgraded {
+            let active = self.metrics.upgrade_finished();
+            tracing::debug!(active, "Upgraded stream finished");
+        }
+    }
 }
 
-/// Helper function that extracts the value of a given key.
-///
-/// Returns an error if the key does not exist
-fn extract_val(key: &str, map: &mut BTreeMap<String, String>) -> Result<String> {
-    map.remove(key).ok_or_else(|| {
-        // TODO: better "Error" creation
-        tracing::error!("Missing key: '{key}'");
-        Error::new_str("Missing configuration field!")
-    })
+/// Is this a request to upgrade to the WebSocket protocol?
+fn is_websocket_req(session: &Session) -> bool {
+    session
+        .req_header()
+        .headers
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
 }
 
-/// Helper function to make sure the map is empty
-///
-/// This is used to reject unknown configuration keys
-fn ensure_empty(map: &BTreeMap<String, String>) -> Result<()> {
-    if !map.is_empty() {
-        let keys = map.keys().map(String::as_str).collect::<Vec<&str>>();
-        let all_keys = keys.join(", ");
-        tracing::error!("Extra keys found: '{all_keys}'");
-        Err(Error::new_str("Extra settings found!"))
-    } else {
-        Ok(())
+/// Is this a hop-by-hop header that is required for upgrading a connection?
+fn is_upgrade_header(key: &HeaderName) -> bool {
+    *key == header::CONNECTION || *key == header::UPGRADE
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arc_swap::ArcSwap;
+    use pingora_http::RequestHeader;
+    use pingora_load_balancing::selection::RoundRobin;
+    use pingora_proxy::{ProxyHttp, Session};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use crate::{config::internal::Config, metrics::ServiceMetrics};
+
+    use super::{ProxyState, RiverProxyService};
+
+    const CONFIG: &str = r#"
+services {
+    Example {
+        listeners {
+            "127.0.0.1:8080"
+        }
+        connectors {
+            "127.0.0.1:8000"
+        }
+        path-control {
+            upstream-request {
+                filter kind="remove-header-key-regex" pattern="^(connection|upgrade|x-remove)$"
+            }
+        }
+        websocket {
+            allow true
+        }
+    }
+}
+"#;
+
+    fn service(websocket_allow: bool) -> RiverProxyService<RoundRobin> {
+        let config = CONFIG.replace("allow true", &format!("allow {websocket_allow}"));
+        let doc: ::kdl::KdlDocument = config.parse().unwrap();
+        let conf = Config::try_from(doc).unwrap().basic_proxies.remove(0);
+        RiverProxyService {
+            name: conf.name.clone(),
+            metrics: ServiceMetrics::new(&conf.name),
+            state: Arc::new(ArcSwap::from_pointee(ProxyState::from_conf(conf, None))),
+            access_log: None,
+        }
+    }
+
+    /// A downstream session that has read `request`, and the client end of it
+    async fn session(request: &str) -> (Session, DuplexStream) {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut session = Session::new_h1(Box::new(server));
+        assert!(session.read_request().await.unwrap());
+        (session, client)
+    }
+
+    const WEBSOCKET_REQUEST: &str = "GET /chat HTTP/1.1\r\nHost: example.com\r\n\
+        Connection: Upgrade\r\nUpgrade: websocket\r\nX-Remove: 1\r\n\r\n";
+
+    /// The headers sent upstream, after the `upstream-request` filters
+    async fn upstream_headers(request: &str) -> RequestHeader {
+        let service = service(true);
+        let (mut session, _client) = session(request).await;
+        let mut ctx = service.new_ctx();
+        let mut header = session.req_header().clone();
+        service
+            .upstream_request_filter(&mut session, &mut header, &mut ctx)
+            .await
+            .unwrap();
+        header
+    }
+
+    #[tokio::test]
+    async fn upgrade_headers_kept() {
+        let header = upstream_headers(WEBSOCKET_REQUEST).await;
+        assert_eq!(header.headers.get("connection").unwrap(), "Upgrade");
+        assert_eq!(header.headers.get("upgrade").unwrap(), "websocket");
+        assert!(header.headers.get("x-remove").is_none());
+    }
+
+    #[tokio::test]
+    async fn hop_by_hop_headers_removed() {
+        let header = upstream_headers(
+            "GET / HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive\r\nX-Remove: 1\r\n\r\n",
+        )
+        .await;
+        assert!(header.headers.get("connection").is_none());
+        assert!(header.headers.get("x-remove").is_none());
+        assert_eq!(header.headers.get("host").unwrap(), "example.com");
+    }
+
+    #[tokio::test]
+    async fn websocket_not_allowed() {
+        let service = service(false);
+        let (mut session, mut client) = session(WEBSOCKET_REQUEST).await;
+        let mut ctx = service.new_ctx();
+        assert!(service
+            .request_filter(&mut session, &mut ctx)
+            .await
+            .unwrap());
+
+        drop(session);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
     }
 }
@@ -53,6 +53,22 @@ impl RegexShim {
     }
 }
 
+/// The current state of a rate limiting rule, as shown by the admin API
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitStatus {
+    /// The name of the rule, like `source-ip`
+    pub rule: String,
+    /// The number of buckets created for this rule
+    ///
+    /// Buckets of keys that have not been seen recently are evicted, so this may be
+    /// larger than the number of buckets currently held.
+    pub buckets: u64,
+    /// The number of buckets this rule aims to hold at once
+    pub max_buckets: usize,
+    /// The tokens currently available, for rules with a single bucket
+    pub tokens: Option<usize>,
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Outcome {
     Approved,
@@ -4,14 +4,23 @@
 //!
 //! See the [`Rater`] structure for more details
 
-use std::{fmt::Debug, hash::Hash, net::IpAddr, sync::Arc, time::Duration};
+use std::{
+    fmt::Debug,
+    hash::Hash,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use concread::arcache::{ARCache, ARCacheBuilder};
 use leaky_bucket::RateLimiter;
 use pandora_module_utils::pingora::SocketAddr;
 use pingora_proxy::Session;
 
-use crate::proxy::rate_limiting::Ticket;
+use crate::proxy::rate_limiting::{RateLimitStatus, Ticket};
 
 use super::RegexShim;
 
@@ -48,6 +57,16 @@ pub enum MultiRequestKeyKind {
     Uri { pattern: RegexShim },
 }
 
+impl MultiRequestKeyKind {
+    /// A name for this rule, as used in the configuration
+    pub fn rule_name(&self) -> String {
+        match self {
+            MultiRequestKeyKind::SourceIp => "source-ip".to_string(),
+            MultiRequestKeyKind::Uri { pattern } => format!("specific-uri:{}", pattern.as_str()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum MultiRequestKey {
     Source(IpAddr),
@@ -58,16 +77,29 @@ pub enum MultiRequestKey {
 pub struct MultiRaterInstance {
     pub rater: Rater<MultiRequestKey>,
     pub kind: MultiRequestKeyKind,
+    /// The name of this rule, see [MultiRequestKeyKind::rule_name]
+    pub rule: String,
 }
 
 impl MultiRaterInstance {
     pub fn new(config: MultiRaterConfig, kind: MultiRequestKeyKind) -> Self {
         Self {
             rater: Rater::new(config),
+            rule: kind.rule_name(),
             kind,
         }
     }
 
+    /// The current state of this rule
+    pub fn status(&self) -> RateLimitStatus {
+        RateLimitStatus {
+            rule: self.rule.clone(),
+            buckets: self.rater.buckets_created.load(Ordering::Relaxed),
+            max_buckets: self.rater.max_buckets,
+            tokens: None,
+        }
+    }
+
     pub fn get_ticket(&self, session: &Session) -> Option<Ticket> {
         let key = self.get_key(session)?;
         Some(self.rater.get_ticket(key))
@@ -146,6 +178,9 @@ where
     Key: Hash + Eq + Ord + Clone + Debug + Sync + Send + 'static,
 {
     cache: ARCache<Key, Arc<RateLimiter>>,
+    /// The number of buckets created, see [RateLimitStatus::buckets]
+    buckets_created: AtomicU64,
+    max_buckets: usize,
     max_tokens_per_bucket: usize,
     refill_interval_millis: usize,
     refill_qty: usize,
@@ -203,6 +238,8 @@ where
 
         Self {
             cache,
+            buckets_created: AtomicU64::new(0),
+            max_buckets,
             max_tokens_per_bucket,
             refill_interval_millis,
             refill_qty: refill_qty.min(max_tokens_per_bucket),
@@ -226,6 +263,7 @@ where
             tracing::debug!(?key, "rate limiting cache miss",);
             reader.insert(key, new_limiter.clone());
             reader.finish();
+            self.buckets_created.fetch_add(1, Ordering::Relaxed);
             Ticket {
                 limiter: new_limiter,
             }
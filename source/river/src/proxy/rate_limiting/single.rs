@@ -3,7 +3,7 @@ use std::{sync::Arc, time::Duration};
 use leaky_bucket::RateLimiter;
 use pingora_proxy::Session;
 
-use super::{RegexShim, Ticket};
+use super::{RateLimitStatus, RegexShim, Ticket};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct SingleInstanceConfig {
@@ -22,10 +22,23 @@ pub enum SingleRequestKeyKind {
     UriGroup { pattern: RegexShim },
 }
 
+impl SingleRequestKeyKind {
+    /// A name for this rule, as used in the configuration
+    pub fn rule_name(&self) -> String {
+        match self {
+            SingleRequestKeyKind::UriGroup { pattern } => {
+                format!("any-matching-uri:{}", pattern.as_str())
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SingleInstance {
     pub limiter: Arc<RateLimiter>,
     pub kind: SingleRequestKeyKind,
+    /// The name of this rule, see [SingleRequestKeyKind::rule_name]
+    pub rule: String,
 }
 
 impl SingleInstance {
@@ -48,7 +61,21 @@ impl SingleInstance {
             .build();
         let limiter = Arc::new(limiter);
 
-        Self { limiter, kind }
+        Self {
+            limiter,
+            rule: kind.rule_name(),
+            kind,
+        }
+    }
+
+    /// The current state of this rule
+    pub fn status(&self) -> RateLimitStatus {
+        RateLimitStatus {
+            rule: self.rule.clone(),
+            buckets: 1,
+            max_buckets: 1,
+            tokens: Some(self.limiter.balance()),
+        }
     }
 
     pub fn get_ticket(&self, session: &Session) -> Option<Ticket> {
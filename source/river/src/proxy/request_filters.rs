@@ -1,12 +1,11 @@
-use std::collections::BTreeMap;
+use std::net::IpAddr;
 
 use async_trait::async_trait;
 use cidr::IpCidr;
-use pingora::ErrorType;
-use pingora_core::{protocols::l4::socket::SocketAddr, Error, Result};
+use pingora_core::{protocols::l4::socket::SocketAddr, Result};
 use pingora_proxy::Session;
 
-use crate::proxy::{extract_val, RiverContext};
+use crate::proxy::RiverContext;
 
 /// This is a single-serving trait for modifiers that provide actions for
 /// [ProxyHttp::request_filter] methods
@@ -21,27 +20,14 @@ pub struct CidrRangeFilter {
 }
 
 impl CidrRangeFilter {
-    /// Create from the settings field
-    pub fn from_settings(mut settings: BTreeMap<String, String>) -> Result<Self> {
-        let mat = extract_val("addrs", &mut settings)?;
-
-        let addrs = mat.split(',');
-
-        let mut blocks = vec![];
-        for addr in addrs {
-            let addr = addr.trim();
-            match addr.parse::<IpCidr>() {
-                Ok(a) => {
-                    blocks.push(a);
-                }
-                Err(_) => {
-                    tracing::error!("Failed to parse '{addr}' as a valid CIDR notation range");
-                    return Err(Error::new(ErrorType::Custom("Invalid configuration")));
-                }
-            };
-        }
+    /// Create from ranges that were checked by the configuration
+    pub fn new(blocks: Vec<IpCidr>) -> Self {
+        Self { blocks }
+    }
 
-        Ok(Self { blocks })
+    /// Is the given address contained in any of the blocked ranges?
+    pub fn is_blocked(&self, addr: &IpAddr) -> bool {
+        self.blocks.iter().any(|b| b.contains(addr))
     }
 }
 
@@ -59,7 +45,7 @@ impl RequestFilterMod for CidrRangeFilter {
         };
         let ip_addr = addr.ip();
 
-        if self.blocks.iter().any(|b| b.contains(&ip_addr)) {
+        if self.is_blocked(&ip_addr) {
             session.downstream_session.respond_error(401).await;
             Ok(true)
         } else {
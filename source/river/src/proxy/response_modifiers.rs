@@ -1,11 +1,8 @@
-use std::collections::BTreeMap;
-
-use pingora_core::{Error, Result};
 use pingora_http::ResponseHeader;
 use pingora_proxy::Session;
 use regex::Regex;
 
-use super::{ensure_empty, extract_val, RiverContext};
+use super::{is_upgrade_header, RiverContext};
 
 /// This is a single-serving trait for modifiers that provide actions for
 /// [ProxyHttp::upstream_response_filter] methods
@@ -29,33 +26,32 @@ pub struct RemoveHeaderKeyRegex {
 }
 
 impl RemoveHeaderKeyRegex {
-    /// Create from the settings field
-    pub fn from_settings(mut settings: BTreeMap<String, String>) -> Result<Self> {
-        let mat = extract_val("pattern", &mut settings)?;
-
-        let reg = Regex::new(&mat).map_err(|e| {
-            tracing::error!("Bad pattern: '{mat}': {e:?}");
-            Error::new_str("Error building regex")
-        })?;
-
-        ensure_empty(&settings)?;
-
-        Ok(Self { regex: reg })
+    /// Create from a pattern that was checked by the configuration
+    pub fn new(regex: Regex) -> Self {
+        Self { regex }
     }
 }
 
 impl ResponseModifyMod for RemoveHeaderKeyRegex {
     fn upstream_response_filter(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         header: &mut ResponseHeader,
         _ctx: &mut RiverContext,
     ) {
+        // Hop-by-hop headers are required for upgrades (e.g. WebSockets) to work,
+        // so never remove them from upgrade requests
+        let upgrade = session.is_upgrade_req();
+
         // Find all the headers that have keys that match the regex...
         let headers = header
             .headers
             .keys()
             .filter_map(|k| {
+                if upgrade && is_upgrade_header(k) {
+                    tracing::debug!("Preserving header for upgrade: {k:?}");
+                    return None;
+                }
                 if self.regex.is_match(k.as_str()) {
                     tracing::debug!("Removing header: {k:?}");
                     Some(k.to_owned())
@@ -83,11 +79,9 @@ pub struct UpsertHeader {
 }
 
 impl UpsertHeader {
-    /// Create from the settings field
-    pub fn from_settings(mut settings: BTreeMap<String, String>) -> Result<Self> {
-        let key = extract_val("key", &mut settings)?;
-        let value = extract_val("value", &mut settings)?;
-        Ok(Self { key, value })
+    /// Create from a key and value that were checked by the configuration
+    pub fn new(key: String, value: String) -> Self {
+        Self { key, value }
     }
 }
 
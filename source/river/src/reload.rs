@@ -0,0 +1,284 @@
+//! Reloading the configuration of a running instance
+//!
+//! When River receives SIGHUP, or a `POST /reload` request to the admin API, it
+//! renders its configuration again and compares it to the one it is running with.
+//!
+//! Settings that running services can switch to, like path control, rate limiting,
+//! and connectors, are applied. Each service switches all of its changed settings
+//! at once, and new requests and connections use them. Settings that are only used
+//! when River starts, like listeners, are reported, and require an upgrade to a new
+//! instance of River to apply.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use arc_swap::ArcSwapOption;
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora_core::{services::background::BackgroundService, upstreams::peer::Peer};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    admin::registry,
+    config::{self, internal::Config, kdl::Secrets},
+    logging,
+};
+
+/// The configuration River was started with, along with any settings reloaded
+/// since then that aren't part of a service
+///
+/// This also makes sure only one reload happens at a time.
+static RUNNING: Mutex<Option<Config>> = Mutex::new(None);
+
+/// The configuration that was most recently loaded, including changes that require
+/// an upgrade to apply, see [applied]
+static APPLIED: ArcSwapOption<Applied> = ArcSwapOption::const_empty();
+
+/// A loaded configuration, along with the values of the secret files it contains
+pub struct Applied {
+    pub config: Config,
+    pub secrets: Secrets,
+}
+
+/// Remember the configuration River was started with, to compare reloads against
+pub fn init(config: &Config, secrets: Secrets) {
+    *RUNNING.lock().unwrap() = Some(config.clone());
+    APPLIED.store(Some(Arc::new(Applied {
+        config: config.clone(),
+        secrets,
+    })));
+}
+
+/// The configuration River was started with, or the last one that was successfully
+/// reloaded
+pub fn applied() -> Option<Arc<Applied>> {
+    APPLIED.load_full()
+}
+
+/// The changes made by a reload
+#[derive(Debug, Default, PartialEq)]
+pub struct ReloadReport {
+    /// The settings that were changed, like `services.Example1.path-control`
+    pub applied: Vec<String>,
+    /// The settings that were changed in the configuration, but are only used when
+    /// River starts, and so have not been applied
+    pub requires_upgrade: Vec<String>,
+}
+
+/// The changes to one service, see [registry::ServiceState::prepare_reload]
+///
+/// Settings are named relative to the service, like `path-control`.
+#[derive(Default)]
+pub struct ServiceReload {
+    pub applied: Vec<&'static str>,
+    pub requires_upgrade: Vec<&'static str>,
+    /// Switches the service to its new settings, if any were changed
+    pub commit: Option<Box<dyn FnOnce() + Send>>,
+}
+
+/// Render the configuration again, and apply it to all running services
+///
+/// If the configuration can't be rendered, or any service can't use it, nothing is
+/// changed.
+pub fn reload() -> Result<ReloadReport, String> {
+    let mut running = RUNNING.lock().unwrap();
+    let running = running
+        .as_mut()
+        .ok_or("reloading has not been initialized")?;
+    let (new, secrets) = config::reload_config()?;
+
+    let mut report = ReloadReport {
+        applied: vec![],
+        requires_upgrade: startup_changes(running, &new),
+    };
+
+    let mut commits = vec![];
+    for (name, service) in registry::services() {
+        let prepared = service
+            .prepare_reload(&new)
+            .map_err(|e| format!("Failed to reload service '{name}': {e}"))?;
+        let setting = |s: &&str| format!("services.{name}.{s}");
+        report.applied.extend(prepared.applied.iter().map(setting));
+        report
+            .requires_upgrade
+            .extend(prepared.requires_upgrade.iter().map(setting));
+        commits.extend(prepared.commit);
+    }
+
+    // This is the last step that can fail, so services are only changed once it
+    // has succeeded
+    if new.log != running.log {
+        logging::apply(&new.log)?;
+        running.log = new.log.clone();
+        report.applied.push("system.log".into());
+    }
+
+    for commit in commits {
+        commit();
+    }
+    APPLIED.store(Some(Arc::new(Applied {
+        config: new,
+        secrets,
+    })));
+
+    tracing::info!(applied = ?report.applied, "Reloaded configuration");
+    if !report.requires_upgrade.is_empty() {
+        tracing::warn!(
+            requires_upgrade = ?report.requires_upgrade,
+            "Some changes to the configuration require an upgrade to apply"
+        );
+    }
+    Ok(report)
+}
+
+/// The settings only used when River starts that differ between two configurations
+fn startup_changes(old: &Config, new: &Config) -> Vec<String> {
+    let mut changes = vec![];
+    let mut changed = |setting: &str, differs: bool| {
+        if differs {
+            changes.push(setting.to_string());
+        }
+    };
+    changed(
+        "system.threads-per-service",
+        old.threads_per_service != new.threads_per_service,
+    );
+    changed(
+        "system.instance-name",
+        old.instance_name != new.instance_name,
+    );
+    changed("system.daemonize", old.daemonize != new.daemonize);
+    changed("system.user", old.user != new.user);
+    changed("system.group", old.group != new.group);
+    changed("system.pid-file", old.pid_file != new.pid_file);
+    changed(
+        "system.upgrade-socket",
+        old.upgrade_socket != new.upgrade_socket,
+    );
+    changed("system.upgrade", old.shutdown != new.shutdown);
+    changed("system.metrics", old.metrics != new.metrics);
+    changed("system.tracing", old.tracing != new.tracing);
+    changed("system.admin", old.admin != new.admin);
+
+    // Services can't be added or removed, or change their kind
+    let (old_kinds, new_kinds) = (service_kinds(old), service_kinds(new));
+    let mut names = old_kinds.keys().chain(new_kinds.keys()).collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    for name in names {
+        changed(
+            &format!("services.{name}"),
+            old_kinds.get(name) != new_kinds.get(name),
+        );
+    }
+
+    // File servers have no settings that can be changed while running
+    for fs in &new.file_servers {
+        let differs = old
+            .file_servers
+            .iter()
+            .any(|old_fs| old_fs.name == fs.name && old_fs != fs);
+        changed(&format!("services.{}", fs.name), differs);
+    }
+    changes
+}
+
+/// The kind of each service, by name
+fn service_kinds(config: &Config) -> BTreeMap<&str, &'static str> {
+    let proxies = config
+        .basic_proxies
+        .iter()
+        .map(|p| (p.name.as_str(), "http-proxy"));
+    let file_servers = config
+        .file_servers
+        .iter()
+        .map(|f| (f.name.as_str(), "file-server"));
+    let stream_proxies = config
+        .stream_proxies
+        .iter()
+        .map(|s| (s.name.as_str(), "stream-proxy"));
+    proxies.chain(file_servers).chain(stream_proxies).collect()
+}
+
+/// Are these the same peers, in the same order, with the same connection settings?
+pub fn same_peers<P: Peer>(a: &[P], b: &[P]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.address() == b.address() && a.reuse_hash() == b.reuse_hash())
+}
+
+/// Reloads the configuration when River receives SIGHUP
+pub struct ConfigReloader;
+
+#[async_trait]
+impl BackgroundService for ConfigReloader {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut hup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(
+                    "Failed to listen for SIGHUP, configuration won't be reloaded: {e}"
+                );
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = hup.recv() => {
+                    tracing::info!("Reloading configuration");
+                    if let Err(e) = reload() {
+                        tracing::error!("Failed to reload configuration: {e}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use crate::config::internal::{Config, FileServerConfig, MetricsConfig};
+
+    use super::startup_changes;
+
+    fn file_server(name: &str, base_path: &str) -> FileServerConfig {
+        FileServerConfig {
+            name: name.into(),
+            listeners: vec![],
+            base_path: Some(base_path.into()),
+            access_log: None,
+        }
+    }
+
+    #[test]
+    fn startup_settings() {
+        let old = Config {
+            file_servers: vec![file_server("Files", "/srv"), file_server("Old", "/srv")],
+            ..Default::default()
+        };
+        assert!(startup_changes(&old, &old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.threads_per_service += 1;
+        new.metrics = Some(MetricsConfig {
+            listen: SocketAddr::from(([127, 0, 0, 1], 9000)),
+        });
+        new.file_servers = vec![file_server("Files", "/var/www"), file_server("New", "/srv")];
+        assert_eq!(
+            startup_changes(&old, &new),
+            vec![
+                "system.threads-per-service",
+                "system.metrics",
+                "services.New",
+                "services.Old",
+                "services.Files",
+            ]
+        );
+    }
+}
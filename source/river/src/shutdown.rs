@@ -0,0 +1,82 @@
+//! Progress of graceful upgrades and shutdowns
+//!
+//! Pingora handles `SIGQUIT` (upgrade) and `SIGTERM` (shutdown) by serving
+//! in-flight requests and connections for the grace period, then telling services
+//! to shut down, and terminating anything still running after the shutdown
+//! timeout. See the `system.upgrade` section of the configuration.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{config::internal::ShutdownConfig, metrics};
+
+/// How often the remaining requests and connections are logged
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Background service that logs the remaining requests and connections while
+/// River is draining
+pub struct DrainProgress {
+    conf: ShutdownConfig,
+}
+
+impl DrainProgress {
+    pub fn new(conf: ShutdownConfig) -> Self {
+        Self { conf }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for DrainProgress {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let (mut quit, mut term) =
+            match (signal(SignalKind::quit()), signal(SignalKind::terminate())) {
+                (Ok(quit), Ok(term)) => (quit, term),
+                (Err(e), _) | (_, Err(e)) => {
+                    tracing::error!(
+                        "Failed to listen for SIGQUIT and SIGTERM, draining won't be logged: {e}"
+                    );
+                    return;
+                }
+            };
+
+        let grace_period_secs = self.conf.grace_period.map(|d| d.as_secs());
+        let shutdown_timeout_secs = self.conf.shutdown_timeout.map(|d| d.as_secs());
+        tokio::select! {
+            _ = quit.recv() => tracing::info!(
+                ?grace_period_secs,
+                ?shutdown_timeout_secs,
+                "Graceful upgrade started, draining",
+            ),
+            _ = term.recv() => tracing::info!(
+                ?grace_period_secs,
+                ?shutdown_timeout_secs,
+                "Graceful shutdown started, draining",
+            ),
+            _ = shutdown.changed() => tracing::info!(
+                ?shutdown_timeout_secs,
+                "Shutting down, draining",
+            ),
+        }
+
+        // This keeps running after services are told to shut down, until all
+        // requests and connections are done, or the shutdown timeout ends
+        let mut progress = tokio::time::interval(PROGRESS_INTERVAL);
+        loop {
+            progress.tick().await;
+            let (requests, connections) = metrics::in_flight();
+            if requests == 0 && connections == 0 {
+                tracing::info!("All requests and connections have finished");
+                break;
+            }
+            tracing::info!(
+                requests,
+                connections,
+                "Waiting for in-flight requests and connections"
+            );
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! TLS ClientHello parsing
+//!
+//! For TLS passthrough, River needs the Server Name Indication (SNI) sent by the
+//! client to pick an upstream, without terminating TLS itself. This module extracts
+//! the SNI from the first TLS record of a connection. The record is NOT consumed: the
+//! caller is expected to replay the bytes to the selected upstream.
+//!
+//! See [RFC 8446 Section 4.1.2] and [RFC 6066 Section 3] for the relevant formats.
+//!
+//! [RFC 8446 Section 4.1.2]: https://www.rfc-editor.org/rfc/rfc8446#section-4.1.2
+//! [RFC 6066 Section 3]: https://www.rfc-editor.org/rfc/rfc6066#section-3
+
+/// The TLS record header: content type, legacy version, and length
+const RECORD_HEADER_LEN: usize = 5;
+
+/// Content type of a handshake record
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+
+/// Handshake type of a ClientHello message
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+
+/// Extension type of the Server Name Indication
+const EXTENSION_SERVER_NAME: u16 = 0;
+
+/// Server name type of a DNS hostname
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// The largest TLS record we are willing to buffer, see RFC 8446 Section 5.1
+pub const MAX_RECORD_LEN: usize = RECORD_HEADER_LEN + (1 << 14);
+
+/// The outcome of parsing the start of a connection
+#[derive(Debug, PartialEq)]
+pub enum ClientHello {
+    /// More data is required before the record can be parsed
+    Incomplete,
+    /// This doesn't look like a TLS ClientHello
+    Invalid,
+    /// A complete ClientHello, with the SNI hostname if one was sent
+    Complete { sni: Option<String> },
+}
+
+/// Attempt to parse the ClientHello at the start of `buf`
+///
+/// NOTE: A ClientHello split across multiple TLS records is treated as invalid. This
+/// is allowed by the specification, but not done by common TLS implementations.
+pub fn parse(buf: &[u8]) -> ClientHello {
+    let Some(header) = buf.get(..RECORD_HEADER_LEN) else {
+        return ClientHello::Incomplete;
+    };
+    if header[0] != CONTENT_TYPE_HANDSHAKE {
+        return ClientHello::Invalid;
+    }
+    let record_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    let Some(record) = buf.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len) else {
+        return ClientHello::Incomplete;
+    };
+
+    match parse_handshake(&mut Reader(record)) {
+        Some(sni) => ClientHello::Complete { sni },
+        None => ClientHello::Invalid,
+    }
+}
+
+/// Parse the handshake message, returning `None` if the message is malformed
+fn parse_handshake(rdr: &mut Reader<'_>) -> Option<Option<String>> {
+    if rdr.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return None;
+    }
+    let len = rdr.u24()?;
+    let mut hello = Reader(rdr.take(len)?);
+
+    // legacy_version, random
+    hello.take(2 + 32)?;
+    // legacy_session_id
+    let len = hello.u8()?.into();
+    hello.take(len)?;
+    // cipher_suites
+    let len = hello.u16()?.into();
+    hello.take(len)?;
+    // legacy_compression_methods
+    let len = hello.u8()?.into();
+    hello.take(len)?;
+
+    // Extensions are optional in older versions of TLS
+    if hello.0.is_empty() {
+        return Some(None);
+    }
+    let len = hello.u16()?.into();
+    let mut extensions = Reader(hello.take(len)?);
+    while !extensions.0.is_empty() {
+        let ty = extensions.u16()?;
+        let len = extensions.u16()?.into();
+        let mut data = Reader(extensions.take(len)?);
+        if ty == EXTENSION_SERVER_NAME {
+            return parse_server_name(&mut data).map(Some);
+        }
+    }
+    Some(None)
+}
+
+/// Parse the `server_name` extension, returning the first hostname
+fn parse_server_name(rdr: &mut Reader<'_>) -> Option<String> {
+    let len = rdr.u16()?.into();
+    let mut names = Reader(rdr.take(len)?);
+    while !names.0.is_empty() {
+        let ty = names.u8()?;
+        let len = names.u16()?.into();
+        let name = names.take(len)?;
+        if ty == NAME_TYPE_HOST_NAME {
+            let name = std::str::from_utf8(name).ok()?;
+            return Some(name.to_ascii_lowercase());
+        }
+    }
+    None
+}
+
+/// A minimal big-endian reader over a byte slice
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, ClientHello};
+
+    /// Build a ClientHello record with the given extensions
+    fn client_hello(extensions: Option<&[u8]>) -> Vec<u8> {
+        let mut body = vec![];
+        body.extend_from_slice(&[0x03, 0x03]);
+        body.extend_from_slice(&[0xAA; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        if let Some(ext) = extensions {
+            body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+            body.extend_from_slice(ext);
+        }
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    fn sni_extension(name: &str) -> Vec<u8> {
+        let mut list = vec![0x00];
+        list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        list.extend_from_slice(name.as_bytes());
+
+        let mut ext = vec![0x00, 0x00];
+        ext.extend_from_slice(&(list.len() as u16 + 2).to_be_bytes());
+        ext.extend_from_slice(&(list.len() as u16).to_be_bytes());
+        ext.extend_from_slice(&list);
+        ext
+    }
+
+    #[test]
+    fn sni() {
+        // An unrelated extension before the SNI
+        let mut ext = vec![0x00, 0x0b, 0x00, 0x02, 0x01, 0x00];
+        ext.extend(sni_extension("DB.Example.com"));
+        let record = client_hello(Some(&ext));
+
+        assert_eq!(
+            parse(&record),
+            ClientHello::Complete {
+                sni: Some("db.example.com".into())
+            }
+        );
+        // Nothing can be parsed until the whole record is available
+        for len in 0..record.len() {
+            assert_eq!(parse(&record[..len]), ClientHello::Incomplete);
+        }
+    }
+
+    #[test]
+    fn no_sni() {
+        assert_eq!(
+            parse(&client_hello(None)),
+            ClientHello::Complete { sni: None }
+        );
+        assert_eq!(
+            parse(&client_hello(Some(&[0x00, 0x0b, 0x00, 0x02, 0x01, 0x00]))),
+            ClientHello::Complete { sni: None }
+        );
+    }
+
+    #[test]
+    fn not_tls() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n"), ClientHello::Invalid);
+
+        // A handshake record with a truncated message
+        let mut record = client_hello(Some(&sni_extension("example.com")));
+        record.truncate(record.len() - 4);
+        let len = record.len() as u16 - 5;
+        record[3..5].copy_from_slice(&len.to_be_bytes());
+        assert_eq!(parse(&record), ClientHello::Invalid);
+    }
+}
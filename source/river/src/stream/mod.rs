@@ -0,0 +1,601 @@
+//! Stream (L4) Proxying
+//!
+//! Stream proxies forward TCP connections (optionally terminating or originating TLS)
+//! to an upstream without looking at the contents of the stream. This is useful for
+//! non-HTTP protocols, like database or mail traffic.
+//!
+//! With TLS passthrough, the upstream is instead selected by the server name in the
+//! client's TLS ClientHello, and TLS is terminated by the upstream rather than River.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use pingora::server::{Server, ShutdownWatch};
+use pingora_core::{
+    apps::ServerApp,
+    connectors::TransportConnector,
+    protocols::{l4::socket::SocketAddr, Stream},
+    services::listening::Service,
+    upstreams::peer::BasicPeer,
+};
+use pingora_load_balancing::{
+    selection::{
+        consistent::KetamaHashing, BackendIter, BackendSelection, FVNHash, Random, RoundRobin,
+    },
+    LoadBalancer,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    admin::registry::{self, BackendAction, BackendStats, ServiceState, UpstreamStatus},
+    config::internal::{Config, ConnectionFilterConfig, SelectionKind, StreamProxyConfig},
+    metrics::ServiceMetrics,
+    populate_listners,
+    proxy::{
+        rate_limiting::{
+            multi::{MultiRaterInstance, MultiRequestKey},
+            AllRateConfig, Outcome, RateLimitStatus,
+        },
+        request_filters::CidrRangeFilter,
+    },
+    reload::{self, ServiceReload},
+    tls::CertStore,
+};
+
+use self::client_hello::ClientHello;
+
+pub mod client_hello;
+
+/// How long a client may take to send its ClientHello, for TLS passthrough
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Create a stream proxy service, with the type parameters chosen based on the config file
+pub fn river_stream_proxy(
+    conf: StreamProxyConfig,
+    certs: &CertStore,
+    server: &Server,
+) -> Box<dyn pingora::services::Service> {
+    // Pick the correctly monomorphized function, see `river_proxy_service`
+    type ServiceMaker =
+        fn(StreamProxyConfig, &CertStore, &Server) -> Box<dyn pingora::services::Service>;
+
+    let service_maker: ServiceMaker = match conf.upstream_options.selection {
+        SelectionKind::RoundRobin => StreamProxy::<RoundRobin>::from_conf,
+        SelectionKind::Random => StreamProxy::<Random>::from_conf,
+        SelectionKind::Fnv => StreamProxy::<FVNHash>::from_conf,
+        SelectionKind::Ketama => StreamProxy::<KetamaHashing>::from_conf,
+    };
+    service_maker(conf, certs, server)
+}
+
+/// A filter applied to new connections, based on their source address
+pub enum ConnectionFilter {
+    /// Connections from this range are rejected
+    Block(CidrRangeFilter),
+    /// Connections NOT from this range are rejected
+    Allow(CidrRangeFilter),
+}
+
+impl ConnectionFilter {
+    /// Build all filters from the `connection-filters` of a [StreamProxyConfig]
+    pub fn from_conf(conf: &[ConnectionFilterConfig]) -> Vec<Self> {
+        conf.iter()
+            .map(|filter| match filter {
+                ConnectionFilterConfig::BlockCidrRange { addrs } => {
+                    ConnectionFilter::Block(CidrRangeFilter::new(addrs.clone()))
+                }
+                ConnectionFilterConfig::AllowCidrRange { addrs } => {
+                    ConnectionFilter::Allow(CidrRangeFilter::new(addrs.clone()))
+                }
+            })
+            .collect()
+    }
+}
+
+/// The settings of a [StreamProxy] that can be changed while it is running, see
+/// [crate::reload]
+pub struct StreamState<BS: BackendSelection> {
+    /// The configuration these settings were created from
+    conf: StreamProxyConfig,
+    /// Load Balancer, for connections that are not routed by SNI
+    pub load_balancer: Arc<LoadBalancer<BS>>,
+    /// Load Balancers per server name, if TLS passthrough is enabled
+    pub sni_routes: Option<Arc<BTreeMap<String, LoadBalancer<BS>>>>,
+    /// All filters must pass for a connection to be accepted
+    pub connection_filters: Vec<ConnectionFilter>,
+    /// Rate limiting of new connections, per source address
+    pub rate_limiters: Arc<Vec<MultiRaterInstance>>,
+}
+
+impl<BS> StreamState<BS>
+where
+    BS: BackendSelection + Send + Sync + 'static,
+    BS::Iter: BackendIter,
+{
+    /// Create the settings from the given [StreamProxyConfig]
+    ///
+    /// The load balancers and rate limiters of `previous` are kept if their part of
+    /// the configuration has not changed, so that they keep their state.
+    fn from_conf(conf: StreamProxyConfig, previous: Option<&Self>) -> Result<Self, String> {
+        let load_balancer = match previous {
+            Some(p) if reload::same_peers(&p.conf.upstreams, &conf.upstreams) => {
+                p.load_balancer.clone()
+            }
+            _ => Arc::new(registry::static_load_balancer(
+                conf.upstreams.clone(),
+                previous.map(|p| &*p.load_balancer),
+            )),
+        };
+
+        let unchanged = |p: &&Self| same_routes(&p.conf.tls_passthrough, &conf.tls_passthrough);
+        let sni_routes = match previous.filter(unchanged) {
+            Some(p) => p.sni_routes.clone(),
+            None => conf.tls_passthrough.clone().map(|routes| {
+                let previous = previous.and_then(|p| p.sni_routes.as_ref());
+                Arc::new(
+                    routes
+                        .into_iter()
+                        .map(|(name, peers)| {
+                            let lb = registry::static_load_balancer(
+                                peers,
+                                previous.and_then(|r| r.get(&name)),
+                            );
+                            (name, lb)
+                        })
+                        .collect(),
+                )
+            }),
+        };
+
+        let connection_filters = ConnectionFilter::from_conf(&conf.connection_filters);
+
+        // Only per-source rules are accepted when parsing the configuration
+        let rate_limiters = match previous {
+            Some(p) if p.conf.rate_limiting == conf.rate_limiting => p.rate_limiters.clone(),
+            _ => Arc::new(
+                conf.rate_limiting
+                    .rules
+                    .iter()
+                    .cloned()
+                    .map(|rule| match rule {
+                        AllRateConfig::Multi { kind, config } => {
+                            Ok(MultiRaterInstance::new(config, kind))
+                        }
+                        AllRateConfig::Single { .. } => {
+                            Err("Stream proxies only support per-connection rate limiting")
+                        }
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+        };
+
+        Ok(Self {
+            conf,
+            load_balancer,
+            sni_routes,
+            connection_filters,
+            rate_limiters,
+        })
+    }
+
+    /// Should a connection from this address be accepted?
+    fn allowed(&self, ip: Option<IpAddr>, metrics: &ServiceMetrics) -> bool {
+        let Some(ip) = ip else {
+            // Filters don't apply to UDS, and neither does rate limiting
+            return true;
+        };
+        let filtered = self.connection_filters.iter().any(|f| match f {
+            ConnectionFilter::Block(range) => range.is_blocked(&ip),
+            ConnectionFilter::Allow(range) => !range.is_blocked(&ip),
+        });
+        if filtered {
+            tracing::trace!(%ip, "Connection rejected by filter");
+            return false;
+        }
+        // Claim ALL applicable tokens before proceeding
+        let declined = self.rate_limiters.iter().find(|r| {
+            let ticket = r.rater.get_ticket(MultiRequestKey::Source(ip));
+            ticket.now_or_never() == Outcome::Declined
+        });
+        if let Some(r) = declined {
+            tracing::trace!(%ip, rule = r.rule, "Connection rate limited");
+            metrics.rate_limited(&r.rule);
+            return false;
+        }
+        true
+    }
+
+    /// Select the load balancer for a server name, if TLS passthrough is enabled
+    ///
+    /// An exact match is preferred over a wildcard match, and the default
+    /// load balancer is used if neither matches.
+    fn route(&self, sni: Option<&str>) -> &LoadBalancer<BS> {
+        let (Some(routes), Some(sni)) = (self.sni_routes.as_ref(), sni) else {
+            return &self.load_balancer;
+        };
+        let wildcard = sni.split_once('.').map(|(_, rest)| format!("*.{rest}"));
+        routes
+            .get(sni)
+            .or_else(|| wildcard.and_then(|w| routes.get(&w)))
+            .unwrap_or(&self.load_balancer)
+    }
+}
+
+impl<BS> ServiceState for ArcSwap<StreamState<BS>>
+where
+    BS: BackendSelection + Send + Sync + 'static,
+    BS::Iter: BackendIter,
+{
+    fn upstreams(&self) -> Vec<UpstreamStatus> {
+        let state = self.load();
+        let mut upstreams = registry::pool_status(None, &state.load_balancer);
+        for (name, lb) in state.sni_routes.iter().flat_map(|r| r.iter()) {
+            upstreams.extend(registry::pool_status(Some(name), lb));
+        }
+        upstreams
+    }
+
+    fn set_backend_state(&self, addr: &str, action: BackendAction) -> Vec<UpstreamStatus> {
+        let state = self.load();
+        let routes = state.sni_routes.iter().flat_map(|r| r.iter());
+        std::iter::once((None, &*state.load_balancer))
+            .chain(routes.map(|(name, lb)| (Some(name.as_str()), lb)))
+            .filter_map(|(pool, lb)| registry::pool_set_backend_state(pool, lb, addr, action))
+            .collect()
+    }
+
+    fn rate_limits(&self) -> Vec<RateLimitStatus> {
+        self.load()
+            .rate_limiters
+            .iter()
+            .map(MultiRaterInstance::status)
+            .collect()
+    }
+
+    fn prepare_reload(self: Arc<Self>, config: &Config) -> Result<ServiceReload, String> {
+        let current = self.load_full();
+        let old = &current.conf;
+        let Some(new) = config.stream_proxies.iter().find(|p| p.name == old.name) else {
+            // Reported as a change to the set of services
+            return Ok(ServiceReload::default());
+        };
+        let mut prepared = ServiceReload::default();
+
+        // Listeners are only set up when the service is created, and the selection
+        // can't change without changing the type of the load balancer
+        let mut fixed = |setting, differs| {
+            if differs {
+                prepared.requires_upgrade.push(setting);
+            }
+        };
+        fixed("listeners", new.listeners != old.listeners);
+        fixed(
+            "connectors.load-balance.selection",
+            new.upstream_options.selection != old.upstream_options.selection,
+        );
+
+        let conf = StreamProxyConfig {
+            listeners: old.listeners.clone(),
+            upstream_options: old.upstream_options.clone(),
+            ..new.clone()
+        };
+        let mut changed = |setting, differs| {
+            if differs {
+                prepared.applied.push(setting);
+            }
+        };
+        changed(
+            "connectors",
+            !reload::same_peers(&conf.upstreams, &old.upstreams),
+        );
+        changed(
+            "stream-proxy.connection-filters",
+            conf.connection_filters != old.connection_filters,
+        );
+        changed(
+            "stream-proxy.tls-passthrough",
+            !same_routes(&conf.tls_passthrough, &old.tls_passthrough),
+        );
+        changed("rate-limiting", conf.rate_limiting != old.rate_limiting);
+        if prepared.applied.is_empty() {
+            return Ok(prepared);
+        }
+
+        let state = StreamState::from_conf(conf, Some(&current))?;
+        prepared.commit = Some(Box::new(move || self.store(Arc::new(state))));
+        Ok(prepared)
+    }
+}
+
+/// Do these TLS passthrough routes have the same server names and peers?
+fn same_routes(
+    a: &Option<BTreeMap<String, Vec<BasicPeer>>>,
+    b: &Option<BTreeMap<String, Vec<BasicPeer>>>,
+) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|((a_name, a_peers), (b_name, b_peers))| {
+                        a_name == b_name && reload::same_peers(a_peers, b_peers)
+                    })
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// A proxy that forwards whole connections to one of its upstreams
+pub struct StreamProxy<BS: BackendSelection> {
+    /// Upstreams, filters, and other settings that may be reloaded
+    pub state: Arc<ArcSwap<StreamState<BS>>>,
+    pub metrics: ServiceMetrics,
+    connector: TransportConnector,
+}
+
+impl<BS> StreamProxy<BS>
+where
+    BS: BackendSelection + Send + Sync + 'static,
+    BS::Iter: BackendIter,
+{
+    /// Create a new [StreamProxy] service from the given [StreamProxyConfig]
+    pub fn from_conf(
+        conf: StreamProxyConfig,
+        certs: &CertStore,
+        _server: &Server,
+    ) -> Box<dyn pingora::services::Service> {
+        let state = StreamState::from_conf(conf.clone(), None)
+            .unwrap_or_else(|e| panic!("Error configuring '{}': {e}", conf.name));
+        let state = Arc::new(ArcSwap::from_pointee(state));
+        registry::register(&conf.name, state.clone());
+
+        let metrics = ServiceMetrics::new(&conf.name);
+        let mut service = Service::new(
+            conf.name,
+            Self {
+                state,
+                metrics,
+                connector: TransportConnector::new(None),
+            },
+        );
+        populate_listners(conf.listeners, &mut service, certs);
+
+        Box::new(service)
+    }
+}
+
+/// Read the ClientHello from the downstream, returning the bytes read and the SNI
+///
+/// Returns `None` if the client doesn't send a valid ClientHello in time.
+async fn read_client_hello(downstream: &mut Stream) -> Option<(Vec<u8>, Option<String>)> {
+    let mut buf = vec![0u8; client_hello::MAX_RECORD_LEN];
+    let mut len = 0;
+    let read = async {
+        loop {
+            match client_hello::parse(&buf[..len]) {
+                ClientHello::Complete { sni } => return Some(sni),
+                ClientHello::Invalid => return None,
+                ClientHello::Incomplete if len == buf.len() => return None,
+                ClientHello::Incomplete => {}
+            }
+            match downstream.read(&mut buf[len..]).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => len += n,
+            }
+        }
+    };
+    let sni = tokio::time::timeout(CLIENT_HELLO_TIMEOUT, read)
+        .await
+        .ok()
+        .flatten()?;
+    buf.truncate(len);
+    Some((buf, sni))
+}
+
+#[async_trait]
+impl<BS> ServerApp for StreamProxy<BS>
+where
+    BS: BackendSelection + Send + Sync + 'static,
+    BS::Iter: BackendIter,
+{
+    async fn process_new(
+        self: &Arc<Self>,
+        mut downstream: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let ip = downstream
+            .get_socket_digest()
+            .and_then(|d| match d.peer_addr() {
+                Some(SocketAddr::Inet(addr)) => Some(addr.ip()),
+                _ => None,
+            });
+
+        let state = self.state.load_full();
+        if !state.allowed(ip, &self.metrics) {
+            return None;
+        }
+
+        // With TLS passthrough, the ClientHello must be read to pick an upstream. It
+        // is replayed to the upstream once connected.
+        let mut replay = vec![];
+        let mut load_balancer: &LoadBalancer<BS> = &state.load_balancer;
+        if state.sni_routes.is_some() {
+            let Some((hello, sni)) = read_client_hello(&mut downstream).await else {
+                tracing::debug!("Closing connection without a valid TLS ClientHello");
+                return None;
+            };
+            tracing::trace!(?sni, "Routing TLS passthrough connection");
+            load_balancer = state.route(sni.as_deref());
+            replay = hello;
+        }
+
+        // Connections are "sticky" to their source address when a hashing
+        // selection is used
+        let key = match ip {
+            Some(IpAddr::V4(ip)) => ip.octets().to_vec(),
+            Some(IpAddr::V6(ip)) => ip.octets().to_vec(),
+            None => vec![],
+        };
+        let Some(backend) = load_balancer.select(&key, 256) else {
+            tracing::warn!("Unable to select an upstream for a stream connection");
+            return None;
+        };
+        let _active = backend.ext.get::<Arc<BackendStats>>().map(|s| s.selected());
+        let peer = backend
+            .ext
+            .get::<BasicPeer>()
+            .expect("Fatal: Missing selected backend metadata");
+
+        let mut upstream = match self.connector.new_stream(peer).await {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::warn!(upstream = ?peer._address, "Failed to connect upstream: {e}");
+                self.metrics.upstream_connect_error();
+                return None;
+            }
+        };
+
+        if let Err(e) = upstream.write_all(&replay).await {
+            tracing::debug!("Failed to forward the TLS ClientHello: {e}");
+            return None;
+        }
+
+        self.metrics.connection_started();
+        let (down, up) = forward(&mut downstream, &mut upstream, shutdown).await;
+        self.metrics
+            .connection_finished(down + replay.len() as u64, up);
+
+        // The connection is done, it can't be reused
+        None
+    }
+}
+
+/// Forward data both ways until both sides are closed, or the server shuts down
+///
+/// Returns the number of bytes sent upstream and downstream. They are counted as
+/// they are written, so data forwarded before an error is still counted. On
+/// shutdown, both sides are closed cleanly rather than reset.
+async fn forward<D, U>(downstream: D, upstream: U, shutdown: &ShutdownWatch) -> (u64, u64)
+where
+    D: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut downstream = Counted::new(downstream);
+    let mut upstream = Counted::new(upstream);
+    let mut shutdown = shutdown.clone();
+    tokio::select! {
+        res = tokio::io::copy_bidirectional(&mut downstream, &mut upstream) => match res {
+            Ok((down, up)) => tracing::trace!(down, up, "Stream connection finished"),
+            Err(e) => tracing::debug!("Stream connection closed with error: {e}"),
+        },
+        _ = shutdown.changed() => {
+            tracing::debug!("Closing stream connection for shutdown");
+            let _ = tokio::join!(downstream.shutdown(), upstream.shutdown());
+        }
+    }
+    (upstream.written, downstream.written)
+}
+
+/// A stream that counts the bytes written to it
+struct Counted<S> {
+    inner: S,
+    written: u64,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S) -> Self {
+        Self { inner, written: 0 }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.written += n as u64;
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::watch,
+    };
+
+    use super::forward;
+
+    #[tokio::test]
+    async fn bytes_counted_on_error() {
+        let (mut client, downstream) = tokio::io::duplex(64);
+        let (upstream, mut server) = tokio::io::duplex(64);
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let task = tokio::spawn(async move { forward(downstream, upstream, &shutdown).await });
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+
+        // Writing to the closed upstream fails
+        drop(server);
+        client.write_all(b"more").await.unwrap();
+        assert_eq!(task.await.unwrap(), (5, 2));
+    }
+
+    #[tokio::test]
+    async fn closed_on_shutdown() {
+        let (mut client, downstream) = tokio::io::duplex(64);
+        let (upstream, mut server) = tokio::io::duplex(64);
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let task = tokio::spawn(async move { forward(downstream, upstream, &shutdown).await });
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+
+        shutdown_tx.send(true).unwrap();
+        assert_eq!(task.await.unwrap(), (5, 0));
+
+        // Both sides see the end of the stream, rather than an error
+        let mut rest = vec![];
+        assert_eq!(client.read_to_end(&mut rest).await.unwrap(), 0);
+        assert_eq!(server.read_to_end(&mut rest).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,592 @@
+//! Integration with systemd
+//!
+//! * When started by a unit with `Type=notify`, River tells systemd when it is
+//!   ready and when it is stopping, and sends keep-alive pings if the unit has a
+//!   `WatchdogSec=`. These are sent to `$NOTIFY_SOCKET`, see `sd_notify(3)`. River
+//!   is ready once all of its listeners are bound.
+//! * Sockets passed by socket activation (`$LISTEN_FDS`, see `sd_listen_fds(3)`)
+//!   are used by `systemd:NAME` listeners, where `NAME` is the `FileDescriptorName=`
+//!   of the socket. This allows River to listen on ports below 1024 without
+//!   starting with the privileges to bind them.
+//!
+//! Pingora looks up the listening sockets of services by address, in the same table
+//! that it passes to new instances during an upgrade. The sockets from systemd are
+//! added to this table when their service starts, so new instances started with
+//! `--upgrade` take them over like any other listener.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    os::{
+        fd::{BorrowedFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    sys::socket::{getsockopt, sockopt, SockType},
+};
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::Interval,
+};
+
+use crate::config::internal::{
+    AdminListener, Config, ListenerConfig, ListenerKind, SYSTEMD_LISTENER_PREFIX,
+};
+
+/// The first file descriptor passed by socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+/// How often to check whether all listeners are bound, before notifying systemd
+const LISTENING_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The address a `systemd:NAME` listener is registered with in Pingora
+pub fn listener_key(name: &str) -> String {
+    format!("{SYSTEMD_LISTENER_PREFIX}{name}")
+}
+
+/// Listening sockets passed by systemd socket activation, by name
+#[derive(Debug, Default)]
+pub struct Activation {
+    sockets: BTreeMap<String, RawFd>,
+}
+
+impl Activation {
+    /// Take the sockets in `LISTEN_FDS`, if they were passed to this process
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name| std::env::var(name).ok();
+        let sockets = parse_listen_fds(
+            var("LISTEN_PID").as_deref(),
+            var("LISTEN_FDS").as_deref(),
+            var("LISTEN_FDNAMES").as_deref(),
+            std::process::id(),
+        )?;
+        for (name, fd) in &sockets {
+            prepare_socket(name, *fd)?;
+        }
+        Ok(Self { sockets })
+    }
+
+    /// Check that every `systemd:` listener has a socket of its own
+    pub fn check(&self, config: &Config) -> Result<(), String> {
+        let mut used = BTreeSet::new();
+        for listener in config.listeners() {
+            let ListenerKind::Systemd { name, .. } = &listener.source else {
+                continue;
+            };
+            if !used.insert(name.as_str()) {
+                return Err(format!(
+                    "systemd socket '{name}' is used by more than one listener"
+                ));
+            }
+            // During an upgrade, the previous instance passes the sockets instead
+            if !config.upgrade && !self.sockets.contains_key(name) {
+                return Err(format!(
+                    "systemd socket '{name}' was not passed to River, check the \
+                     'FileDescriptorName=' of the socket unit"
+                ));
+            }
+        }
+
+        for name in self.sockets.keys() {
+            if !used.contains(name.as_str()) {
+                tracing::warn!("systemd socket '{name}' is not used by any listener");
+            }
+        }
+        Ok(())
+    }
+
+    /// The sockets used by these listeners
+    pub fn sockets(&self, listeners: &[ListenerConfig]) -> ActivatedSockets {
+        let sockets = listeners
+            .iter()
+            .filter_map(|l| match &l.source {
+                ListenerKind::Systemd { name, .. } => {
+                    let fd = self.sockets.get(name)?;
+                    Some((listener_key(name), *fd))
+                }
+                _ => None,
+            })
+            .collect();
+        ActivatedSockets(sockets)
+    }
+}
+
+/// The sockets passed by systemd for the listeners of one service
+///
+/// This is also used for listeners bound before dropping privileges, see
+/// [crate::privileges].
+pub struct ActivatedSockets(Vec<(String, RawFd)>);
+
+impl ActivatedSockets {
+    /// Sockets to add to Pingora's listener table, by address
+    pub fn new(sockets: Vec<(String, RawFd)>) -> Self {
+        Self(sockets)
+    }
+
+    /// Make these sockets available to the service when it starts
+    pub fn wrap(self, service: Box<dyn Service>) -> Box<dyn Service> {
+        if self.0.is_empty() {
+            return service;
+        }
+        Box::new(Activated {
+            inner: service,
+            sockets: self.0,
+        })
+    }
+}
+
+/// A service whose listeners use sockets that were opened before it started
+struct Activated {
+    inner: Box<dyn Service>,
+    sockets: Vec<(String, RawFd)>,
+}
+
+#[async_trait]
+impl Service for Activated {
+    async fn start_service(&mut self, fds: Option<ListenFds>, shutdown: ShutdownWatch) {
+        // Listeners use a socket from the table instead of binding, if there is one
+        // for their address
+        if let Some(fds) = &fds {
+            let mut table = fds.lock().await;
+            for (key, fd) in &self.sockets {
+                if table.get(key).is_none() {
+                    table.add(key.clone(), *fd);
+                }
+            }
+        }
+        self.inner.start_service(fds, shutdown).await;
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.inner.threads()
+    }
+}
+
+/// Parse the sockets passed by socket activation, see `sd_listen_fds_with_names(3)`
+fn parse_listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Result<BTreeMap<String, RawFd>, String> {
+    let mut sockets = BTreeMap::new();
+
+    // The variables are inherited by child processes, but only meant for the one
+    // started by systemd
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(sockets);
+    };
+    if pid.parse::<u32>().ok() != Some(own_pid) {
+        return Ok(sockets);
+    }
+
+    let count = fds
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid LISTEN_FDS: '{fds}'"))?;
+    let names = match names {
+        Some(names) => names.split(':').collect::<Vec<_>>(),
+        None => vec!["unknown"; count],
+    };
+    if names.len() != count {
+        return Err(format!(
+            "LISTEN_FDNAMES has {} names, but {count} sockets were passed",
+            names.len()
+        ));
+    }
+
+    for (fd, name) in (LISTEN_FDS_START..).zip(names) {
+        if sockets.insert(name.to_string(), fd).is_some() {
+            return Err(format!(
+                "More than one socket named '{name}' was passed by systemd, give each \
+                 socket a different 'FileDescriptorName='"
+            ));
+        }
+    }
+    Ok(sockets)
+}
+
+/// Check that a socket passed by systemd can be used as a TCP listener
+fn prepare_socket(name: &str, fd: RawFd) -> Result<(), String> {
+    // SAFETY: sockets passed by systemd stay open for the lifetime of the process
+    let socket = unsafe { BorrowedFd::borrow_raw(fd) };
+
+    let listening = getsockopt(&socket, sockopt::SockType) == Ok(SockType::Stream)
+        && getsockopt(&socket, sockopt::AcceptConn) == Ok(true);
+    if !listening {
+        return Err(format!(
+            "systemd socket '{name}' is not a listening stream socket, use 'ListenStream='"
+        ));
+    }
+
+    // Pingora only accepts TCP connections on these sockets
+    let addr = socket
+        .try_clone_to_owned()
+        .map(std::net::TcpListener::from)
+        .and_then(|listener| listener.local_addr())
+        .map_err(|_| format!("systemd socket '{name}' is not a TCP socket"))?;
+
+    // Don't leak the socket to other programs, see `sd_listen_fds(3)`. Pingora
+    // hands the socket to tokio as-is, which requires it to be non-blocking, but
+    // systemd passes blocking sockets unless the unit sets `NonBlocking=`
+    let configure = || -> nix::Result<()> {
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+        fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+        Ok(())
+    };
+    configure().map_err(|e| format!("Error configuring systemd socket '{name}': {e}"))?;
+
+    tracing::info!("Using systemd socket '{name}', listening on {addr}");
+    Ok(())
+}
+
+/// Sends notifications to the socket in `NOTIFY_SOCKET`
+#[derive(Debug)]
+pub struct Notifier {
+    socket: String,
+}
+
+impl Notifier {
+    pub fn from_env() -> Option<Self> {
+        let socket = std::env::var("NOTIFY_SOCKET").ok()?;
+        (!socket.is_empty()).then_some(Self { socket })
+    }
+
+    /// Send newline separated `KEY=VALUE` assignments, like `READY=1`
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        match self.socket.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+                let addr = SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            _ => {
+                socket.send_to(state.as_bytes(), &self.socket)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The interval of watchdog pings, see `sd_watchdog_enabled(3)`
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok() != Some(own_pid) {
+            return None;
+        }
+    }
+    let usec = usec?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    // Pinging at half the timeout leaves time for a slow ping
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// The addresses of all listeners in Pingora's listener table, including those of
+/// the metrics and admin API services
+fn listener_addrs(config: &Config) -> Vec<String> {
+    let mut addrs = config
+        .listeners()
+        .map(|listener| match &listener.source {
+            ListenerKind::Tcp { addr, .. } => addr.clone(),
+            ListenerKind::Uds(path) => path.to_string_lossy().into(),
+            ListenerKind::Systemd { name, .. } => listener_key(name),
+        })
+        .collect::<Vec<_>>();
+    if let Some(metrics) = &config.metrics {
+        addrs.push(metrics.listen.to_string());
+    }
+    if let Some(admin) = &config.admin {
+        addrs.push(match &admin.listen {
+            AdminListener::Tcp(addr) => addr.to_string(),
+            AdminListener::Uds(path) => path.to_string_lossy().into(),
+        });
+    }
+    addrs
+}
+
+/// Service that notifies systemd of readiness and shutdown, and pings its watchdog
+pub struct SystemdNotify {
+    notifier: Notifier,
+    watchdog: Option<Duration>,
+    /// The listeners that must be bound before River is ready, see [listener_addrs]
+    listeners: Vec<String>,
+}
+
+impl SystemdNotify {
+    /// Notify systemd, if River was started by a unit with `Type=notify`
+    ///
+    /// This must be called before daemonizing, as the watchdog is only meant for
+    /// the process started by systemd
+    pub fn from_env(config: &Config) -> Option<Self> {
+        let notifier = Notifier::from_env()?;
+        let var = |name| std::env::var(name).ok();
+        let watchdog = watchdog_interval(
+            var("WATCHDOG_USEC").as_deref(),
+            var("WATCHDOG_PID").as_deref(),
+            std::process::id(),
+        );
+        Some(Self {
+            notifier,
+            watchdog,
+            listeners: listener_addrs(config),
+        })
+    }
+
+    /// Wait until all listeners are bound
+    ///
+    /// Pingora has no way to tell when the services have bound their listeners, but
+    /// each listener is added to the listener table once it is bound, or when it
+    /// is taken over from systemd, the previous instance, or from before dropping
+    /// privileges. If a listener fails to bind, this never returns, and systemd
+    /// reports that River failed to start once `TimeoutStartSec=` has passed.
+    async fn listening(&self, fds: Option<&ListenFds>) {
+        let Some(fds) = fds else {
+            return;
+        };
+        loop {
+            {
+                let table = fds.lock().await;
+                if self.listeners.iter().all(|addr| table.get(addr).is_some()) {
+                    return;
+                }
+            }
+            tokio::time::sleep(LISTENING_POLL_INTERVAL).await;
+        }
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(e) = self.notifier.notify(state) {
+            tracing::warn!("Failed to notify systemd with '{state}': {e}");
+        }
+    }
+}
+
+#[async_trait]
+impl Service for SystemdNotify {
+    async fn start_service(&mut self, fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
+        tokio::select! {
+            _ = self.listening(fds.as_ref()) => {}
+            _ = shutdown.changed() => {
+                self.send("STOPPING=1");
+                return;
+            }
+        }
+        // Services start after daemonizing, and after bootstrapping, which takes
+        // over the listeners of the previous instance during an upgrade. The main
+        // PID changes in both cases, which systemd only accepts from processes other
+        // than the main one with `NotifyAccess=all`
+        self.send(&format!("READY=1\nMAINPID={}", std::process::id()));
+
+        let (mut term, mut int) = match (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::interrupt()),
+        ) {
+            (Ok(term), Ok(int)) => (term, int),
+            (Err(e), _) | (_, Err(e)) => {
+                tracing::error!("Failed to listen for SIGTERM and SIGINT: {e}");
+                return;
+            }
+        };
+
+        let mut watchdog = self.watchdog.map(tokio::time::interval);
+        loop {
+            tokio::select! {
+                _ = tick(&mut watchdog) => self.send("WATCHDOG=1"),
+                _ = term.recv() => break,
+                _ = int.recv() => break,
+                _ = shutdown.changed() => break,
+            }
+        }
+        self.send("STOPPING=1");
+    }
+
+    fn name(&self) -> &str {
+        "systemd notifier"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::TcpListener,
+        os::{fd::AsRawFd, unix::net::UnixDatagram},
+        path::PathBuf,
+        sync::Arc,
+        time::Duration,
+    };
+
+    use nix::fcntl::{fcntl, FcntlArg, OFlag};
+
+    use pingora::{
+        server::{Fds, ListenFds},
+        services::Service,
+    };
+
+    use crate::testing::TempDir;
+
+    use super::{
+        parse_listen_fds, prepare_socket, watchdog_interval, Notifier, SystemdNotify,
+        LISTENING_POLL_INTERVAL,
+    };
+
+    /// A fake `NOTIFY_SOCKET`, removed when dropped
+    struct FakeNotifySocket {
+        _dir: TempDir,
+        path: PathBuf,
+        socket: UnixDatagram,
+    }
+
+    impl FakeNotifySocket {
+        fn new() -> Self {
+            let dir = TempDir::new();
+            let path = dir.join("notify.sock");
+            let socket = UnixDatagram::bind(&path).unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            Self {
+                _dir: dir,
+                path,
+                socket,
+            }
+        }
+
+        fn notifier(&self) -> Notifier {
+            Notifier {
+                socket: self.path.to_str().unwrap().into(),
+            }
+        }
+
+        fn recv(&self) -> String {
+            let mut buf = [0; 256];
+            let len = self.socket.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..len].to_vec()).unwrap()
+        }
+    }
+
+    #[test]
+    fn listen_fds() {
+        let sockets = parse_listen_fds(Some("42"), Some("2"), Some("http:https"), 42).unwrap();
+        assert_eq!(sockets.get("http"), Some(&3));
+        assert_eq!(sockets.get("https"), Some(&4));
+
+        // Sockets passed to another process are ignored
+        let sockets = parse_listen_fds(Some("41"), Some("2"), Some("http:https"), 42).unwrap();
+        assert!(sockets.is_empty());
+        let sockets = parse_listen_fds(None, None, None, 42).unwrap();
+        assert!(sockets.is_empty());
+
+        // Sockets are named "unknown" without a `FileDescriptorName=`
+        let sockets = parse_listen_fds(Some("42"), Some("1"), None, 42).unwrap();
+        assert_eq!(sockets.get("unknown"), Some(&3));
+
+        // Listeners can't tell sockets with the same name apart
+        assert!(parse_listen_fds(Some("42"), Some("2"), None, 42).is_err());
+        assert!(parse_listen_fds(Some("42"), Some("2"), Some("http"), 42).is_err());
+        assert!(parse_listen_fds(Some("42"), Some("two"), None, 42).is_err());
+    }
+
+    #[test]
+    fn nonblocking_sockets() {
+        // Sockets are passed blocking unless the socket unit sets `NonBlocking=`
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        prepare_socket("test", listener.as_raw_fd()).unwrap();
+        let flags =
+            OFlag::from_bits_truncate(fcntl(listener.as_raw_fd(), FcntlArg::F_GETFL).unwrap());
+        assert!(flags.contains(OFlag::O_NONBLOCK));
+
+        // Only listening stream sockets can be used
+        let socket = UnixDatagram::unbound().unwrap();
+        assert!(prepare_socket("test", socket.as_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn watchdog() {
+        assert_eq!(
+            watchdog_interval(Some("10000000"), None, 42),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            watchdog_interval(Some("10000000"), Some("42"), 42),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(watchdog_interval(Some("10000000"), Some("41"), 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval(None, None, 42), None);
+    }
+
+    #[test]
+    fn notify() {
+        let fake = FakeNotifySocket::new();
+        fake.notifier().notify("READY=1").unwrap();
+        assert_eq!(fake.recv(), "READY=1");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn notify_service() {
+        let fake = FakeNotifySocket::new();
+        let mut service = SystemdNotify {
+            notifier: fake.notifier(),
+            watchdog: Some(Duration::from_millis(10)),
+            listeners: vec!["127.0.0.1:8080".into()],
+        };
+        let fds: ListenFds = Arc::new(tokio::sync::Mutex::new(Fds::new()));
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn({
+            let fds = fds.clone();
+            async move { service.start_service(Some(fds), shutdown_rx).await }
+        });
+
+        // River isn't ready until its listeners are bound
+        tokio::time::sleep(LISTENING_POLL_INTERVAL * 2).await;
+        fake.socket.set_nonblocking(true).unwrap();
+        assert!(fake.socket.recv(&mut [0; 256]).is_err());
+        fake.socket.set_nonblocking(false).unwrap();
+        fds.lock().await.add("127.0.0.1:8080".into(), 3);
+
+        let ready = tokio::task::block_in_place(|| fake.recv());
+        assert_eq!(ready, format!("READY=1\nMAINPID={}", std::process::id()));
+        let ping = tokio::task::block_in_place(|| fake.recv());
+        assert_eq!(ping, "WATCHDOG=1");
+
+        shutdown_tx.send(true).unwrap();
+        task.await.unwrap();
+
+        // Watchdog pings may have been sent before stopping
+        fake.socket.set_nonblocking(true).unwrap();
+        let last = std::iter::from_fn(|| {
+            let mut buf = [0; 256];
+            let len = fake.socket.recv(&mut buf).ok()?;
+            Some(String::from_utf8(buf[..len].to_vec()).unwrap())
+        })
+        .last();
+        assert_eq!(last.as_deref(), Some("STOPPING=1"));
+    }
+}
@@ -0,0 +1,291 @@
+//! OpenTelemetry tracing
+//!
+//! When `system.tracing` is configured, each proxied request gets a `request` span,
+//! with a child span for each phase of handling the request. These spans are exported
+//! to an OTLP collector, whatever the log level is.
+//!
+//! The W3C Trace Context headers (`traceparent` and `tracestate`) of incoming
+//! requests are used as the parent of the request span, and the context of the
+//! request span is sent to upstreams in the same headers.
+
+use async_trait::async_trait;
+use http::HeaderMap;
+use opentelemetry::{
+    propagation::{Extractor, Injector, TextMapPropagator},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{self, Sampler, Tracer},
+    Resource,
+};
+use pingora::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use pingora_http::RequestHeader;
+use tracing::{field::Empty, Level, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::Targets, Layer};
+
+use crate::{config::internal::TracingConfig, logging};
+
+/// Create the span for a request, continuing the trace of the client if one was sent
+pub fn request_span(service: &str, req: &RequestHeader) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", req.method, req.uri.path()),
+        otel.kind = "server",
+        otel.status_code = Empty,
+        service,
+        http.request.method = %req.method,
+        url.path = req.uri.path(),
+        upstream = Empty,
+        http.response.status_code = Empty,
+    );
+    span.set_parent(extract_context(&req.headers));
+    span
+}
+
+/// Add the context of `span` to a request sent to an upstream
+///
+/// Nothing is added if tracing is not enabled, leaving any headers sent by the client
+/// in place.
+pub fn inject(span: &Span, header: &mut RequestHeader) {
+    inject_context(&span.context(), header);
+}
+
+fn extract_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+fn inject_context(cx: &Context, header: &mut RequestHeader) {
+    TraceContextPropagator::new().inject_context(cx, &mut HeaderInjector(header));
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut RequestHeader);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let Err(e) = self.0.insert_header(key.to_string(), value) {
+            tracing::warn!(key, "Failed to add trace context header: {e}");
+        }
+    }
+}
+
+/// Background service that exports spans to the configured collector
+///
+/// Exporting is started from a service, rather than when River starts, so that the
+/// exporter runs on a runtime that exists after the server has daemonized.
+pub struct TelemetryExporter {
+    conf: TracingConfig,
+}
+
+impl TelemetryExporter {
+    pub fn new(conf: TracingConfig) -> Self {
+        Self { conf }
+    }
+
+    fn tracer(&self) -> Result<Tracer, String> {
+        let exporter = opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(self.conf.otlp_endpoint.clone());
+        let sampler =
+            Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(self.conf.sample_ratio)));
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(exporter)
+            .with_trace_config(
+                trace::config()
+                    .with_sampler(sampler)
+                    .with_resource(Resource::new([KeyValue::new("service.name", "river")])),
+            )
+            .install_batch(runtime::Tokio)
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for TelemetryExporter {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let tracer = match self.tracer() {
+            Ok(t) => t,
+            Err(e) => {
+                tracing::error!("Failed to start OpenTelemetry exporter: {e}");
+                return;
+            }
+        };
+        let layer = tracing_opentelemetry::layer().with_tracer(tracer).boxed();
+        let filter = Targets::new().with_target("river", Level::INFO);
+        if let Err(e) = logging::set_extra_layer(layer, filter) {
+            tracing::error!("{e}");
+            return;
+        }
+        tracing::info!(endpoint = self.conf.otlp_endpoint, "Exporting traces");
+
+        let _ = shutdown.changed().await;
+
+        // Flush spans that have not been exported yet. This blocks until the export
+        // has finished, which runs on this same runtime.
+        let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use http::HeaderMap;
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+    };
+    use opentelemetry::Context;
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        trace_service_server::{TraceService, TraceServiceServer},
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    };
+    use pingora_core::services::background::BackgroundService;
+    use pingora_http::RequestHeader;
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    use crate::{config::internal::TracingConfig, logging};
+
+    use super::{extract_context, inject_context, request_span, TelemetryExporter};
+
+    /// An OTLP collector, passing on the requests it receives
+    struct Collector(mpsc::UnboundedSender<ExportTraceServiceRequest>);
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportTraceServiceRequest>,
+        ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+            let _ = self.0.send(request.into_inner());
+            Ok(tonic::Response::new(ExportTraceServiceResponse {
+                partial_success: None,
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn export() {
+        // Other tests may set the global subscriber, so this one is only used here,
+        // which needs a single threaded runtime
+        let _subscriber = tracing::subscriber::set_default(logging::subscriber());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = futures_util::stream::unfold(listener, |listener| async move {
+            let conn = listener.accept().await.map(|(stream, _)| stream);
+            Some((conn, listener))
+        });
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(Collector(tx)))
+                .serve_with_incoming(incoming),
+        );
+
+        // Spans are exported even when only warnings are logged
+        logging::set_filter("warn").unwrap();
+
+        let exporter = TelemetryExporter::new(TracingConfig {
+            otlp_endpoint: endpoint,
+            sample_ratio: 1.0,
+        });
+        let (shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+        let exporting = tokio::spawn(async move { exporter.start(shutdown).await });
+
+        // The exporter is ready once spans are sampled
+        let req = RequestHeader::build("GET", b"/export", None).unwrap();
+        let mut ready = false;
+        for _ in 0..100 {
+            let span = request_span("Example", &req);
+            if span.context().span().span_context().is_sampled() {
+                ready = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(
+            ready,
+            "spans should be sampled once the exporter has started"
+        );
+
+        // Shutting down flushes the span
+        shutdown_tx.send(true).unwrap();
+        exporting.await.unwrap();
+        let request = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let names = request
+            .resource_spans
+            .iter()
+            .flat_map(|r| &r.scope_spans)
+            .flat_map(|s| &s.spans)
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>();
+        assert!(names.contains(&"GET /export"), "{names:?}");
+    }
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn extract() {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", TRACEPARENT.parse().unwrap());
+        headers.insert("tracestate", "vendor=value".parse().unwrap());
+
+        let cx = extract_context(&headers);
+        let span = cx.span();
+        let sc = span.span_context();
+        assert!(sc.is_remote());
+        assert!(sc.is_sampled());
+        assert_eq!(
+            sc.trace_id(),
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+        );
+        assert_eq!(sc.span_id(), SpanId::from_hex("00f067aa0ba902b7").unwrap());
+        assert_eq!(sc.trace_state().get("vendor"), Some("value"));
+
+        // Without headers, there is no parent
+        let cx = extract_context(&HeaderMap::new());
+        assert!(!cx.span().span_context().is_valid());
+    }
+
+    #[test]
+    fn inject() {
+        let sc = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            false,
+            TraceState::default(),
+        );
+        let cx = Context::new().with_remote_span_context(sc);
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("traceparent", "replaced").unwrap();
+        inject_context(&cx, &mut req);
+        assert_eq!(req.headers.get("traceparent").unwrap(), TRACEPARENT);
+
+        // An invalid context leaves existing headers alone
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("traceparent", TRACEPARENT).unwrap();
+        inject_context(&Context::new(), &mut req);
+        assert_eq!(req.headers.get("traceparent").unwrap(), TRACEPARENT);
+    }
+}
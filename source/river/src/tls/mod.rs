@@ -0,0 +1,346 @@
+//! TLS certificate handling
+//!
+//! Certificates used by TLS listeners are loaded once at startup into a [CertStore].
+//! Rather than handing the certificate paths to pingora directly, each listener is
+//! given a [CertCallback], which provides the *current* certificate and key for each
+//! new handshake.
+//!
+//! The [CertWatcher] background service periodically checks the certificate and key
+//! files on disk, and swaps in the new pair when either file changes. This allows
+//! rotating certificates without performing a full upgrade of River. The same service
+//! keeps OCSP responses up to date for listeners with stapling enabled, see [ocsp].
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora_core::{
+    listeners::TlsAccept,
+    services::background::BackgroundService,
+    tls::{
+        ext,
+        pkey::{PKey, Private},
+        ssl::SslRef,
+        x509::X509,
+    },
+};
+
+use crate::config::internal::{Config, OcspSource, TlsConfig};
+
+use self::ocsp::{OcspStapler, Staple};
+
+pub mod ocsp;
+
+/// How often the certificate files on disk are checked for changes
+const CERT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A certificate chain and matching private key, ready for use in handshakes
+pub struct CertifiedKey {
+    cert_path: PathBuf,
+    leaf: X509,
+    chain: Vec<X509>,
+    key: PKey<Private>,
+    not_after: String,
+}
+
+impl CertifiedKey {
+    /// Load the certificate chain and key from the given paths.
+    ///
+    /// Returns an error if either file can't be parsed, or if the private key
+    /// does not belong to the leaf certificate.
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, String> {
+        let cert_pem = std::fs::read(cert_path)
+            .map_err(|e| format!("Failed to read certificate {cert_path:?}: {e}"))?;
+        let key_pem =
+            std::fs::read(key_path).map_err(|e| format!("Failed to read key {key_path:?}: {e}"))?;
+
+        let mut certs = X509::stack_from_pem(&cert_pem)
+            .map_err(|e| format!("Failed to parse certificate {cert_path:?}: {e}"))?;
+        if certs.is_empty() {
+            return Err(format!("No certificates found in {cert_path:?}"));
+        }
+        let leaf = certs.remove(0);
+        let key = PKey::private_key_from_pem(&key_pem)
+            .map_err(|e| format!("Failed to parse key {key_path:?}: {e}"))?;
+
+        let matches = leaf
+            .public_key()
+            .map(|pubkey| pubkey.public_eq(&key))
+            .unwrap_or(false);
+        if !matches {
+            return Err(format!(
+                "Key {key_path:?} does not match certificate {cert_path:?}"
+            ));
+        }
+
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            not_after: leaf.not_after().to_string(),
+            leaf,
+            chain: certs,
+            key,
+        })
+    }
+
+    /// The expiry date of the leaf certificate
+    pub fn not_after(&self) -> &str {
+        &self.not_after
+    }
+}
+
+/// A certificate/key pair that may be replaced while River is running
+pub struct ReloadableCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: ArcSwap<CertifiedKey>,
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+    ocsp: Option<OcspStapler>,
+}
+
+impl ReloadableCert {
+    /// Load the initial certificate for the given [TlsConfig]
+    pub fn new(tls: &TlsConfig) -> Result<Self, String> {
+        let certified = CertifiedKey::load(&tls.cert_path, &tls.key_path)?;
+        tracing::info!(
+            cert = ?tls.cert_path,
+            not_after = certified.not_after(),
+            "Loaded TLS certificate",
+        );
+
+        let ocsp = tls.ocsp_stapling.clone().map(OcspStapler::new);
+        if let Some(stapler) = ocsp.as_ref() {
+            // Failing to reach the responder at startup is not fatal, we'll retry
+            // in the background. A missing or broken response file is a configuration
+            // error, however.
+            if let Err(e) = stapler.refresh_if_needed(&certified) {
+                match tls.ocsp_stapling {
+                    Some(OcspSource::File(_)) => return Err(e),
+                    _ => {
+                        tracing::warn!(cert = ?tls.cert_path, "Unable to fetch OCSP response: {e}")
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            modified: Mutex::new(modified_times(&tls.cert_path, &tls.key_path)),
+            cert_path: tls.cert_path.clone(),
+            key_path: tls.key_path.clone(),
+            current: ArcSwap::from_pointee(certified),
+            ocsp,
+        })
+    }
+
+    /// Does this certificate have OCSP stapling enabled?
+    pub fn ocsp_enabled(&self) -> bool {
+        self.ocsp.is_some()
+    }
+
+    /// Get the OCSP response to staple to the next handshake, if one is available
+    pub fn ocsp_staple(&self) -> Option<Arc<Staple>> {
+        self.ocsp.as_ref().and_then(OcspStapler::current)
+    }
+
+    /// Refresh the OCSP response, if stapling is enabled and the response is
+    /// missing or about to expire.
+    ///
+    /// This performs blocking IO, and should not be called from an async context.
+    pub fn refresh_ocsp(&self) {
+        let Some(stapler) = self.ocsp.as_ref() else {
+            return;
+        };
+        if let Err(e) = stapler.refresh_if_needed(&self.current()) {
+            tracing::warn!(cert = ?self.cert_path, "Unable to refresh OCSP response: {e}");
+        }
+    }
+
+    /// Get the certificate that will be used for the next handshake
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        self.current.load_full()
+    }
+
+    /// Reload the certificate and key if either file has changed on disk.
+    ///
+    /// If the new files can't be loaded, the current certificate is kept.
+    pub fn reload_if_changed(&self) {
+        let now = modified_times(&self.cert_path, &self.key_path);
+        {
+            let mut modified = self.modified.lock().expect("cert mtime lock poisoned");
+            if now.is_none() || *modified == now {
+                return;
+            }
+            *modified = now;
+        }
+
+        match CertifiedKey::load(&self.cert_path, &self.key_path) {
+            Ok(certified) => {
+                tracing::info!(
+                    cert = ?self.cert_path,
+                    not_after = certified.not_after(),
+                    "Reloaded TLS certificate",
+                );
+                self.current.store(Arc::new(certified));
+                // Any existing OCSP response was for the old certificate
+                if let Some(stapler) = self.ocsp.as_ref() {
+                    stapler.invalidate();
+                }
+            }
+            Err(e) => {
+                tracing::error!(
+                    cert = ?self.cert_path,
+                    "Refusing to reload TLS certificate, keeping the current one: {e}",
+                );
+            }
+        }
+    }
+}
+
+fn modified_times(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(cert_path)
+        .and_then(|m| m.modified())
+        .ok()?;
+    let key = std::fs::metadata(key_path)
+        .and_then(|m| m.modified())
+        .ok()?;
+    Some((cert, key))
+}
+
+/// All certificates used by TLS listeners, keyed by their [TlsConfig]
+///
+/// Listeners with the same TLS configuration share the same [ReloadableCert].
+#[derive(Default)]
+pub struct CertStore {
+    certs: BTreeMap<TlsConfig, Arc<ReloadableCert>>,
+}
+
+impl CertStore {
+    /// Load the certificates for all TLS listeners in the configuration
+    pub fn from_config(conf: &Config) -> Result<Self, String> {
+        let listeners = conf
+            .basic_proxies
+            .iter()
+            .flat_map(|p| p.listeners.iter())
+            .chain(conf.file_servers.iter().flat_map(|f| f.listeners.iter()))
+            .chain(conf.stream_proxies.iter().flat_map(|s| s.listeners.iter()));
+
+        let mut store = Self::default();
+        for list_cfg in listeners {
+            let Some(tls_cfg) = list_cfg.source.tls() else {
+                continue;
+            };
+            if !store.certs.contains_key(tls_cfg) {
+                store
+                    .certs
+                    .insert(tls_cfg.clone(), Arc::new(ReloadableCert::new(tls_cfg)?));
+            }
+        }
+        Ok(store)
+    }
+
+    /// Get the certificate for the given [TlsConfig]
+    pub fn get(&self, tls: &TlsConfig) -> Option<Arc<ReloadableCert>> {
+        self.certs.get(tls).cloned()
+    }
+
+    /// Create a background service that watches all certificates for changes
+    pub fn watcher(&self) -> CertWatcher {
+        CertWatcher {
+            certs: self.certs.values().cloned().collect(),
+            interval: CERT_POLL_INTERVAL,
+        }
+    }
+}
+
+/// Provides the current certificate of a [ReloadableCert] to each handshake
+pub struct CertCallback(pub Arc<ReloadableCert>);
+
+#[async_trait]
+impl TlsAccept for CertCallback {
+    async fn certificate_callback(&self, ssl: &mut SslRef) {
+        let certified = self.0.current();
+        let res = ext::ssl_use_certificate(ssl, &certified.leaf)
+            .and_then(|_| {
+                certified
+                    .chain
+                    .iter()
+                    .try_for_each(|c| ext::ssl_add_chain_cert(ssl, c))
+            })
+            .and_then(|_| ext::ssl_use_private_key(ssl, &certified.key));
+        if let Err(e) = res {
+            tracing::error!(cert = ?self.0.cert_path, "Failed to apply TLS certificate: {e}");
+        }
+    }
+}
+
+/// Background service that reloads certificates when they change on disk
+pub struct CertWatcher {
+    certs: Vec<Arc<ReloadableCert>>,
+    interval: Duration,
+}
+
+#[async_trait]
+impl BackgroundService for CertWatcher {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        if self.certs.is_empty() {
+            return;
+        }
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = interval.tick() => {
+                    // Loading certificates and fetching OCSP responses is blocking IO
+                    let certs = self.certs.clone();
+                    let res = tokio::task::spawn_blocking(move || {
+                        for cert in certs.iter() {
+                            cert.reload_if_changed();
+                            cert.refresh_ocsp();
+                        }
+                    })
+                    .await;
+                    if let Err(e) = res {
+                        tracing::error!("TLS certificate watcher failed: {e}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use pingora_core::tls::pkey::PKey;
+
+    use crate::testing::TempDir;
+
+    use super::CertifiedKey;
+
+    #[test]
+    fn load_matching_pair() {
+        let certified = CertifiedKey::load(
+            Path::new("./assets/test.crt"),
+            Path::new("./assets/test.key"),
+        )
+        .unwrap();
+        assert!(certified.not_after().contains("2034"));
+    }
+
+    #[test]
+    fn refuse_mismatched_key() {
+        let other = PKey::generate_ed25519().unwrap();
+        let dir = TempDir::new();
+        let path = dir.join("mismatch.key");
+        std::fs::write(&path, other.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        let res = CertifiedKey::load(Path::new("./assets/test.crt"), &path);
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,486 @@
+//! OCSP stapling
+//!
+//! When enabled for a TLS listener, River attaches a recent OCSP response for its
+//! certificate to each handshake, so that clients don't need to contact the
+//! Certificate Authority themselves.
+//!
+//! Responses are either fetched from the OCSP responder named in the certificate, or
+//! loaded from a file which is kept up to date by some external process. In both cases
+//! the response is cached until its `nextUpdate` time, and refreshed by the
+//! [CertWatcher][super::CertWatcher] background service.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwapOption;
+use openssl::{
+    asn1::{Asn1GeneralizedTimeRef, Asn1Time},
+    ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus},
+    stack::Stack,
+};
+use pingora_core::tls::{
+    hash::MessageDigest,
+    x509::{store::X509StoreBuilder, X509},
+};
+
+use super::CertifiedKey;
+use crate::{config::internal::OcspSource, metrics};
+
+/// Start trying to refresh a response this long before it expires
+const REFRESH_MARGIN: Duration = Duration::from_secs(60 * 60);
+
+/// Don't retry a failed refresh more often than this
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Timeout used when talking to an OCSP responder
+const RESPONDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A validated OCSP response, ready for stapling
+pub struct Staple {
+    der: Vec<u8>,
+    next_update: SystemTime,
+}
+
+impl Staple {
+    /// The DER encoded OCSP response
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    fn is_stale(&self) -> bool {
+        SystemTime::now() >= self.next_update
+    }
+}
+
+/// Keeps an OCSP response for a single certificate up to date
+pub struct OcspStapler {
+    source: OcspSource,
+    staple: ArcSwapOption<Staple>,
+    state: Mutex<RefreshState>,
+}
+
+#[derive(Default)]
+struct RefreshState {
+    last_attempt: Option<SystemTime>,
+    file_modified: Option<SystemTime>,
+    warned_stale: bool,
+}
+
+impl OcspStapler {
+    pub fn new(source: OcspSource) -> Self {
+        Self {
+            source,
+            staple: ArcSwapOption::empty(),
+            state: Mutex::new(RefreshState::default()),
+        }
+    }
+
+    /// The response to staple to the next handshake, if we have one that is still valid
+    pub fn current(&self) -> Option<Arc<Staple>> {
+        self.staple.load_full().filter(|s| !s.is_stale())
+    }
+
+    /// Drop the current response, for example because the certificate was replaced
+    pub fn invalidate(&self) {
+        self.staple.store(None);
+        *self.state.lock().expect("ocsp state lock poisoned") = RefreshState::default();
+    }
+
+    /// Fetch or load a new response if the current one is missing or about to expire.
+    ///
+    /// This performs blocking IO, and should not be called from an async context.
+    pub fn refresh_if_needed(&self, certified: &CertifiedKey) -> Result<(), String> {
+        let now = SystemTime::now();
+        let current = self.staple.load_full();
+        {
+            let mut state = self.state.lock().expect("ocsp state lock poisoned");
+
+            if let Some(staple) = current.as_ref() {
+                if staple.is_stale() && !state.warned_stale {
+                    tracing::warn!(
+                        cert = ?certified.cert_path,
+                        "OCSP response is stale, no longer stapling until it is refreshed",
+                    );
+                    metrics::ocsp_staple_stale(&certified.cert_path, true);
+                    state.warned_stale = true;
+                }
+            }
+
+            let file_changed = match &self.source {
+                OcspSource::File(path) => file_modified(path) != state.file_modified,
+                OcspSource::Responder => false,
+            };
+            let expiring = match current.as_ref() {
+                Some(staple) => now + REFRESH_MARGIN >= staple.next_update,
+                None => true,
+            };
+            let may_retry = match state.last_attempt {
+                Some(last) => file_changed || last + RETRY_INTERVAL <= now,
+                None => true,
+            };
+            if !(file_changed || expiring) || !may_retry {
+                return Ok(());
+            }
+            state.last_attempt = Some(now);
+            if let OcspSource::File(path) = &self.source {
+                state.file_modified = file_modified(path);
+            }
+        }
+
+        let der = match &self.source {
+            OcspSource::Responder => fetch_response(certified)?,
+            OcspSource::File(path) => std::fs::read(path)
+                .map_err(|e| format!("Failed to read OCSP response {path:?}: {e}"))?,
+        };
+        let staple = validate_response(certified, der)?;
+        tracing::info!(
+            cert = ?certified.cert_path,
+            next_update = ?staple.next_update,
+            "Loaded OCSP response for stapling",
+        );
+        self.staple.store(Some(Arc::new(staple)));
+        metrics::ocsp_staple_stale(&certified.cert_path, false);
+        self.state
+            .lock()
+            .expect("ocsp state lock poisoned")
+            .warned_stale = false;
+        Ok(())
+    }
+}
+
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Create the certificate ID used to ask about, and find, our certificate's status
+fn cert_id(certified: &CertifiedKey) -> Result<OcspCertId, String> {
+    let issuer = certified.chain.first().ok_or_else(|| {
+        format!(
+            "OCSP stapling requires the issuer certificate to be included in {:?}",
+            certified.cert_path
+        )
+    })?;
+    OcspCertId::from_cert(MessageDigest::sha1(), &certified.leaf, issuer)
+        .map_err(|e| format!("Failed to create OCSP certificate id: {e}"))
+}
+
+/// Check that the response is successful, covers our certificate, and is currently valid
+fn validate_response(certified: &CertifiedKey, der: Vec<u8>) -> Result<Staple, String> {
+    let response =
+        OcspResponse::from_der(&der).map_err(|e| format!("Invalid OCSP response: {e}"))?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(format!(
+            "OCSP responder returned status {:?}",
+            response.status()
+        ));
+    }
+    let basic = response
+        .basic()
+        .map_err(|e| format!("Invalid OCSP response: {e}"))?;
+
+    // The responder is either our issuer, or a delegate that was signed by our issuer
+    let mut certs = Stack::<X509>::new().map_err(|e| e.to_string())?;
+    for cert in certified.chain.iter() {
+        certs.push(cert.clone()).map_err(|e| e.to_string())?;
+    }
+    let store = X509StoreBuilder::new().map_err(|e| e.to_string())?.build();
+    basic
+        .verify(&certs, &store, OcspFlag::TRUST_OTHER)
+        .map_err(|e| format!("OCSP response signature is not valid: {e}"))?;
+
+    let id = cert_id(certified)?;
+    let status = basic
+        .find_status(&id)
+        .ok_or("OCSP response does not contain the status of our certificate")?;
+    if status.status != OcspCertStatus::GOOD {
+        return Err(format!(
+            "OCSP response reports certificate status {:?}",
+            status.status
+        ));
+    }
+    status
+        .check_validity(300, None)
+        .map_err(|e| format!("OCSP response is not currently valid: {e}"))?;
+
+    let next_update = system_time(status.next_update).ok_or_else(|| {
+        format!(
+            "Unable to understand OCSP nextUpdate time '{}'",
+            status.next_update
+        )
+    })?;
+
+    Ok(Staple { der, next_update })
+}
+
+/// Ask the OCSP responder named in the certificate for a fresh response
+fn fetch_response(certified: &CertifiedKey) -> Result<Vec<u8>, String> {
+    let responders = certified
+        .leaf
+        .ocsp_responders()
+        .map_err(|e| format!("Failed to read OCSP responders from certificate: {e}"))?;
+    let url = responders
+        .iter()
+        .next()
+        .map(|r| r.to_string())
+        .ok_or("Certificate does not name an OCSP responder")?;
+
+    let mut request = OcspRequest::new().map_err(|e| e.to_string())?;
+    request
+        .add_id(cert_id(certified)?)
+        .map_err(|e| e.to_string())?;
+    let body = request.to_der().map_err(|e| e.to_string())?;
+
+    let (host, port, path) = split_http_url(&url)
+        .ok_or_else(|| format!("Unsupported OCSP responder URL '{url}', only http is supported"))?;
+
+    let addr = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve OCSP responder '{host}': {e}"))?
+        .next()
+        .ok_or_else(|| format!("Failed to resolve OCSP responder '{host}'"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, RESPONDER_TIMEOUT)
+        .map_err(|e| format!("Failed to connect to OCSP responder '{url}': {e}"))?;
+    let _ = stream.set_read_timeout(Some(RESPONDER_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(RESPONDER_TIMEOUT));
+
+    let head = format!(
+        "POST {path} HTTP/1.0\r\nHost: {host}\r\nContent-Type: application/ocsp-request\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    );
+    stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(&body))
+        .map_err(|e| format!("Failed to send OCSP request to '{url}': {e}"))?;
+
+    let mut response = vec![];
+    stream
+        .read_to_end(&mut response)
+        .map_err(|e| format!("Failed to read OCSP response from '{url}': {e}"))?;
+
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| format!("Malformed HTTP response from '{url}'"))?;
+    let status_line = response[..split]
+        .split(|b| *b == b'\n')
+        .next()
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(format!(
+            "OCSP responder '{url}' replied '{}'",
+            status_line.trim()
+        ));
+    }
+    Ok(response.split_off(split + 4))
+}
+
+/// Split an `http://host[:port]/path` URL into its parts
+fn split_http_url(url: &str) -> Option<(String, u16, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port, path.to_string()))
+}
+
+/// Convert an ASN.1 time, as used in OCSP responses, to a [SystemTime]
+///
+/// The openssl crate can only print a generalized time, e.g. `Jan  1 00:00:00 2124 GMT`,
+/// so that is parsed back into an [Asn1Time], which can be compared with the epoch.
+fn system_time(time: &Asn1GeneralizedTimeRef) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let printed = time.to_string();
+    let mut parts = printed.split_whitespace();
+    let month = parts
+        .next()
+        .and_then(|m| MONTHS.iter().position(|n| *n == m))?
+        + 1;
+    let day: u32 = parts.next()?.parse().ok()?;
+    // Fractions of a second may follow the seconds
+    let mut hms = parts.next()?.split(['.', ':']);
+    let [h, m, s] = [hms.next()?, hms.next()?, hms.next()?].map(|v| v.parse::<u32>().ok());
+    let year: u32 = parts.next()?.parse().ok()?;
+    // Times in OCSP responses are always UTC
+    if parts.next() != Some("GMT") {
+        return None;
+    }
+    let time = Asn1Time::from_str(&format!(
+        "{year:04}{month:02}{day:02}{:02}{:02}{:02}Z",
+        h?, m?, s?
+    ))
+    .ok()?;
+
+    let epoch = Asn1Time::from_unix(0).ok()?;
+    let diff = epoch.diff(&time).ok()?;
+    let secs = i64::from(diff.days) * 86400 + i64::from(diff.secs);
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+#[cfg(test)]
+mod test {
+    //! The fixtures in `assets/ocsp` were made with a throwaway CA, which also signs the
+    //! OCSP responses. `good.der` and `stale.der` both report `leaf.crt` as good, valid
+    //! from 2024-01-01 until 2124-01-01 and 2024-01-08 respectively.
+
+    use std::{
+        io::Write,
+        path::Path,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use super::{split_http_url, validate_response, OcspStapler, Staple};
+    use crate::{
+        config::internal::{OcspSource, TlsConfig},
+        testing::TempDir,
+        tls::{CertifiedKey, ReloadableCert},
+    };
+
+    const GOOD: &str = "./assets/ocsp/good.der";
+    const STALE: &str = "./assets/ocsp/stale.der";
+
+    fn certified(name: &str) -> CertifiedKey {
+        CertifiedKey::load(
+            Path::new(&format!("./assets/ocsp/{name}.crt")),
+            Path::new(&format!("./assets/ocsp/{name}.key")),
+        )
+        .unwrap()
+    }
+
+    /// Collects everything logged while it is the default subscriber
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn valid_response() {
+        let der = std::fs::read(GOOD).unwrap();
+        let staple = validate_response(&certified("leaf"), der.clone()).unwrap();
+        assert_eq!(staple.der(), der);
+        // 2124-01-01T00:00:00Z
+        assert_eq!(
+            staple.next_update,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(4859740800),
+        );
+        assert!(!staple.is_stale());
+    }
+
+    #[test]
+    fn wrong_certificate() {
+        let der = std::fs::read(GOOD).unwrap();
+        let err = validate_response(&certified("other"), der).err().unwrap();
+        assert!(err.contains("does not contain the status of our certificate"));
+    }
+
+    #[test]
+    fn stale_response_keeps_staple() {
+        let dir = TempDir::new();
+        let path = dir.join("ocsp.der");
+        std::fs::copy(GOOD, &path).unwrap();
+
+        let cert = ReloadableCert::new(&TlsConfig {
+            cert_path: "./assets/ocsp/leaf.crt".into(),
+            key_path: "./assets/ocsp/leaf.key".into(),
+            ocsp_stapling: Some(OcspSource::File(path.clone())),
+        })
+        .unwrap();
+        let good = std::fs::read(GOOD).unwrap();
+        assert_eq!(cert.ocsp_staple().unwrap().der(), good);
+
+        // Replace the response with one that has already expired, and make sure the
+        // change is noticed even if the filesystem's timestamps are coarse
+        std::fs::copy(STALE, &path).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+
+        let logs = Captured::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || cert.refresh_ocsp());
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("WARN"), "{logs}");
+        assert!(logs.contains("Unable to refresh OCSP response"), "{logs}");
+        assert!(logs.contains("not currently valid"), "{logs}");
+        assert_eq!(cert.ocsp_staple().unwrap().der(), good);
+    }
+
+    #[test]
+    fn stale_staple_metric() {
+        let dir = TempDir::new();
+        let (cert_path, key_path, response) = (
+            dir.join("leaf.crt"),
+            dir.join("leaf.key"),
+            dir.join("ocsp.der"),
+        );
+        std::fs::copy("./assets/ocsp/leaf.crt", &cert_path).unwrap();
+        std::fs::copy("./assets/ocsp/leaf.key", &key_path).unwrap();
+        let certified = CertifiedKey::load(&cert_path, &key_path).unwrap();
+        let gauge = || {
+            prometheus::gather()
+                .iter()
+                .filter(|family| family.get_name() == "river_ocsp_staple_stale")
+                .flat_map(|family| family.get_metric())
+                .find(|m| m.get_label()[0].get_value() == cert_path.to_str().unwrap())
+                .map(|m| m.get_gauge().get_value())
+        };
+
+        // An expired response is reported until a new one is loaded
+        let stapler = OcspStapler::new(OcspSource::File(response.clone()));
+        stapler.staple.store(Some(Arc::new(Staple {
+            der: vec![],
+            next_update: SystemTime::now() - Duration::from_secs(1),
+        })));
+        assert!(stapler.refresh_if_needed(&certified).is_err());
+        assert_eq!(gauge(), Some(1.0));
+
+        std::fs::copy(GOOD, &response).unwrap();
+        stapler.refresh_if_needed(&certified).unwrap();
+        assert_eq!(gauge(), Some(0.0));
+    }
+
+    #[test]
+    fn http_urls() {
+        assert_eq!(
+            split_http_url("http://ocsp.example.com"),
+            Some(("ocsp.example.com".into(), 80, "/".into())),
+        );
+        assert_eq!(
+            split_http_url("http://127.0.0.1:8888/ocsp"),
+            Some(("127.0.0.1".into(), 8888, "/ocsp".into())),
+        );
+        assert_eq!(split_http_url("https://ocsp.example.com/"), None);
+    }
+}